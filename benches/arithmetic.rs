@@ -0,0 +1,47 @@
+//! Arithmetic-heavy loops, timed end to end through the `nlox` binary.
+//!
+//! Compare the two value representations with
+//!
+//! ```text
+//! cargo bench --bench arithmetic
+//! cargo bench --bench arithmetic --features nan-boxing
+//! ```
+
+mod common;
+
+const BENCHES: &[(&str, &str)] = &[
+    (
+        "local_loop",
+        "fun run() {
+            var sum = 0;
+            for (var i = 0; i < 3000000; i = i + 1) {
+                sum = sum + i * 2 - i / 2;
+            }
+            return sum;
+        }
+        print(run());",
+    ),
+    (
+        "global_loop",
+        "var sum = 0;
+        for (var i = 0; i < 1000000; i = i + 1) {
+            sum = sum + i * 2 - i / 2;
+        }
+        print(sum);",
+    ),
+    (
+        "comparisons",
+        "fun run() {
+            var hits = 0;
+            for (var i = 0; i < 2000000; i = i + 1) {
+                if (i * 3 > 1000 and i / 7 <= 200000 or i == 5) hits = hits + 1;
+            }
+            return hits;
+        }
+        print(run());",
+    ),
+];
+
+fn main() {
+    common::run_suite("arithmetic", BENCHES);
+}
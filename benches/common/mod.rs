@@ -0,0 +1,119 @@
+//! Shared harness for the benchmarks: runs each script through the `nlox`
+//! binary under both backends, reports the best and median wall-clock time
+//! and the instructions executed, and appends the result to a history file
+//! so changes can be tracked across commits.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const RUNS: usize = 5;
+const BACKENDS: [&str; 2] = ["stack", "register"];
+
+pub fn run_suite(suite: &str, benches: &[(&str, &str)]) {
+    let representation = if cfg!(feature = "nan-boxing") {
+        "nan-boxing"
+    } else {
+        "enum"
+    };
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let history = dir.join("bench-history.jsonl");
+    let previous = std::fs::read_to_string(&history).unwrap_or_default();
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&history)
+        .unwrap();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    println!("{} ({} values)", suite, representation);
+    for (name, source) in benches {
+        let path = dir.join(format!("{}-{}.nox", suite, name));
+        std::fs::write(&path, source).unwrap();
+        for backend in BACKENDS {
+            let times = time(&path, backend);
+            let (best, median) = (times[0], times[RUNS / 2]);
+            let instructions = instructions(&path, backend);
+            // Stack results keep the ids recorded before there was a choice.
+            let id = match backend {
+                "stack" => format!("{}/{}/{}", suite, name, representation),
+                _ => format!("{}/{}/{}/{}", suite, name, representation, backend),
+            };
+            let change = last_best(&previous, &id)
+                .map(|last| {
+                    format!(
+                        "  {:+.1}% vs last",
+                        (best.as_secs_f64() / last - 1.0) * 100.0
+                    )
+                })
+                .unwrap_or_default();
+            println!(
+                "  {:<16} {:<8} best {:>9.2?}  median {:>9.2?}  {:>11} instructions{}",
+                name, backend, best, median, instructions, change
+            );
+            let record = serde_json::json!({
+                "bench": id,
+                "timestamp": timestamp,
+                "best": best.as_secs_f64(),
+                "median": median.as_secs_f64(),
+                "instructions": instructions,
+            });
+            writeln!(log, "{}", record).unwrap();
+        }
+    }
+    println!("history: {}", history.display());
+}
+
+/// Sorted wall-clock times of `RUNS` runs of the script.
+fn time(path: &PathBuf, backend: &str) -> Vec<Duration> {
+    let mut times: Vec<_> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            nlox(path, &[&format!("--backend={}", backend)]);
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times
+}
+
+/// Instructions dispatched by one run, as reported on stderr.
+fn instructions(path: &PathBuf, backend: &str) -> u64 {
+    let stderr = nlox(
+        path,
+        &[&format!("--backend={}", backend), "--instruction-count"],
+    );
+    stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("[vm] ")?.strip_suffix(" instructions"))
+        .and_then(|count| count.parse().ok())
+        .expect("nlox did not report an instruction count")
+}
+
+/// Runs the script and returns its stderr.
+fn nlox(path: &PathBuf, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_nlox"))
+        .args(args)
+        .arg(path)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(output.status.success(), "{}", stderr);
+    stderr
+}
+
+fn last_best(history: &str, id: &str) -> Option<f64> {
+    history
+        .lines()
+        .rev()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .find(|record| record["bench"] == id)
+        .and_then(|record| record["best"].as_f64())
+}
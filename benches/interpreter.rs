@@ -0,0 +1,83 @@
+//! Interpreter throughput: calls, plain loops, string building and property
+//! access.
+//!
+//! ```text
+//! cargo bench --bench interpreter
+//! ```
+//!
+//! Each run is appended to `bench-history.jsonl` in Cargo's target tmpdir
+//! and compared with the previous run of the same benchmark.
+
+mod common;
+
+const BENCHES: &[(&str, &str)] = &[
+    (
+        "fib",
+        "fun fib(n) {
+            if (n < 2) return n;
+            return fib(n - 1) + fib(n - 2);
+        }
+        print(fib(27));",
+    ),
+    (
+        "loops",
+        "fun run() {
+            var count = 0;
+            for (var i = 0; i < 1000; i = i + 1) {
+                var j = 0;
+                while (j < 1000) {
+                    j = j + 1;
+                    count = count + 1;
+                }
+            }
+            return count;
+        }
+        print(run());",
+    ),
+    (
+        "closures",
+        "fun counter() {
+            var n = 0;
+            fun count() { n = n + 1; return n; }
+            return count;
+        }
+        fun run() {
+            var c = counter();
+            for (var i = 0; i < 500000; i = i + 1) c();
+            return c();
+        }
+        print(run());",
+    ),
+    (
+        "string_building",
+        "fun run() {
+            var total = 0;
+            for (var i = 0; i < 2000; i = i + 1) {
+                var s = \"\";
+                for (var j = 0; j < 50; j = j + 1) s = s + \"ab\";
+                if (s == s + \"\") total = total + 1;
+            }
+            return total;
+        }
+        print(run());",
+    ),
+    (
+        "properties",
+        "class Vec {
+            init(x, y) { this.x = x; this.y = y; }
+            add(other) { return Vec(this.x + other.x, this.y + other.y); }
+            length() { return this.x + this.y; }
+        }
+        fun run() {
+            var total = Vec(0, 0);
+            var step = Vec(1, 2);
+            for (var i = 0; i < 200000; i = i + 1) total = total.add(step);
+            return total.length();
+        }
+        print(run());",
+    ),
+];
+
+fn main() {
+    common::run_suite("interpreter", BENCHES);
+}
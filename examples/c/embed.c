@@ -0,0 +1,45 @@
+/* Embeds nlox in C: a native with userdata, globals both ways, and errors. */
+
+#include <stdio.h>
+
+#include "nlox.h"
+
+static bool add(void *userdata, const NloxValue *args, size_t arg_count, NloxValue *result) {
+    int *calls = userdata;
+    (*calls)++;
+    if (args[0].kind != NLOX_TYPE_NUMBER || args[1].kind != NLOX_TYPE_NUMBER) {
+        result->kind = NLOX_TYPE_STRING;
+        result->string = "add takes two numbers.";
+        return false;
+    }
+    result->kind = NLOX_TYPE_NUMBER;
+    result->number = args[0].number + args[1].number;
+    return true;
+}
+
+int main(void) {
+    NloxVm *vm = nlox_vm_new();
+    int calls = 0;
+    nlox_register(vm, "add", 2, add, &calls);
+
+    NloxValue name = {.kind = NLOX_TYPE_STRING, .string = "C"};
+    nlox_set_global(vm, "name", name);
+    const char *source = "var greeting = \"hello \" + name; var sum = add(1, 2);";
+    if (nlox_eval(vm, source, "example") != NLOX_STATUS_OK) {
+        fprintf(stderr, "%s\n", nlox_last_error(vm));
+        return 1;
+    }
+
+    NloxValue value;
+    nlox_get_global(vm, "greeting", &value);
+    printf("greeting: %s\n", value.string);
+    nlox_get_global(vm, "sum", &value);
+    printf("sum: %g\n", value.number);
+
+    if (nlox_eval(vm, "add(1, nil);", "bad") == NLOX_STATUS_RUNTIME_ERROR) {
+        printf("runtime error: %s\n", nlox_last_error(vm));
+    }
+    printf("calls: %d\n", calls);
+    nlox_vm_free(vm);
+    return 0;
+}
@@ -0,0 +1,140 @@
+/* The C API of the nlox interpreter. Generated from src/capi.rs; do not edit. */
+
+#ifndef NLOX_H
+#define NLOX_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum NloxStatus {
+  NLOX_STATUS_OK,
+  NLOX_STATUS_COMPILE_ERROR,
+  /**
+   * The script failed, or ran into one of the VM's limits.
+   */
+  NLOX_STATUS_RUNTIME_ERROR,
+  /**
+   * The call itself was wrong, such as a global that does not exist.
+   */
+  NLOX_STATUS_ERROR,
+} NloxStatus;
+
+typedef enum NloxType {
+  NLOX_TYPE_NIL,
+  NLOX_TYPE_BOOL,
+  NLOX_TYPE_NUMBER,
+  NLOX_TYPE_STRING,
+  /**
+   * A function, class, instance, list or map, which C cannot look into.
+   */
+  NLOX_TYPE_OBJECT,
+} NloxType;
+
+/**
+ * An interpreter with its own heap and globals.
+ */
+typedef struct NloxVm NloxVm;
+
+/**
+ * A value crossing into or out of C. Only the field for `kind` is read.
+ */
+typedef struct NloxValue {
+  enum NloxType kind;
+  bool boolean;
+  double number;
+  const char *string;
+} NloxValue;
+
+/**
+ * A native implemented in C. It gets the `userdata` it was registered
+ * with and `arg_count` arguments, and fills in `result`. Returning false
+ * raises a runtime error, whose message is `result`'s string if it has
+ * one. A string result must outlive the call, like a static string or a
+ * buffer in `userdata`; it is copied as soon as the native returns.
+ */
+typedef bool (*NloxNative)(void *userdata,
+                           const struct NloxValue *args,
+                           size_t arg_count,
+                           struct NloxValue *result);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates a VM, to be freed with `nlox_vm_free`.
+ */
+struct NloxVm *nlox_vm_new(void);
+
+/**
+ * Frees `vm`, and with it everything its scripts allocated.
+ *
+ * # Safety
+ *
+ * `vm` must be null or come from `nlox_vm_new`, and not be used again.
+ */
+void nlox_vm_free(struct NloxVm *vm);
+
+/**
+ * Compiles and runs `source`. `name` identifies the script in errors.
+ *
+ * # Safety
+ *
+ * `vm` must come from `nlox_vm_new`; the strings must be NUL-terminated.
+ */
+enum NloxStatus nlox_eval(struct NloxVm *vm, const char *source, const char *name);
+
+/**
+ * Defines the global `name` as a native taking `arity` arguments that
+ * calls `native` with `userdata`.
+ *
+ * # Safety
+ *
+ * `vm` must come from `nlox_vm_new` and `name` be NUL-terminated.
+ * `userdata` must stay valid for as long as the VM may call `native`.
+ */
+enum NloxStatus nlox_register(struct NloxVm *vm,
+                              const char *name,
+                              size_t arity,
+                              NloxNative native,
+                              void *userdata);
+
+/**
+ * Reads the global `name` into `value`. A string in it lasts until the
+ * next call on `vm`.
+ *
+ * # Safety
+ *
+ * `vm` must come from `nlox_vm_new`, `name` be NUL-terminated and `value`
+ * point to an `NloxValue`.
+ */
+enum NloxStatus nlox_get_global(struct NloxVm *vm, const char *name, struct NloxValue *value);
+
+/**
+ * Defines the global `name` as `value`, or overwrites it.
+ *
+ * # Safety
+ *
+ * `vm` must come from `nlox_vm_new`, and `name` and any string in
+ * `value` be NUL-terminated.
+ */
+enum NloxStatus nlox_set_global(struct NloxVm *vm, const char *name, struct NloxValue value);
+
+/**
+ * Why the last call on `vm` failed, or NULL if it succeeded. Valid until
+ * the next call on `vm`.
+ *
+ * # Safety
+ *
+ * `vm` must come from `nlox_vm_new`.
+ */
+const char *nlox_last_error(const struct NloxVm *vm);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* NLOX_H */
@@ -0,0 +1,469 @@
+//! Abstract syntax tree, lowered from the `cst` once it parses cleanly.
+//!
+//! Declarations and variable references carry a `NodeId` for the resolver
+//! and code generators; everything else is plain data, serializable for
+//! `--emit=ast` and for tools.
+
+use serde::Serialize;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{
+    cst::{self, NodeKind, SyntaxElement, SyntaxError, SyntaxNode, SyntaxToken},
+    token::Kind,
+};
+
+/// Identity of a declaration or variable reference, assigned in source order.
+/// The resolver keys its results by these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct NodeId(pub u32);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Program {
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Identifier {
+    pub name: String,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Param {
+    pub id: NodeId,
+    pub name: Identifier,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Function {
+    pub id: NodeId,
+    pub name: Identifier,
+    pub params: Vec<Param>,
+    pub body: Vec<Stmt>,
+    /// Line of the closing brace, where the implicit `return nil` lives.
+    pub end_line: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Class {
+    pub id: NodeId,
+    pub name: Identifier,
+    pub methods: Vec<Function>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum Stmt {
+    Var {
+        id: NodeId,
+        name: Identifier,
+        initializer: Option<Expr>,
+    },
+    Function(Function),
+    Class(Class),
+    Expression {
+        expr: Expr,
+        line: usize,
+    },
+    Print {
+        expr: Expr,
+        line: usize,
+    },
+    Block {
+        body: Vec<Stmt>,
+        end_line: usize,
+    },
+    If {
+        condition: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+        line: usize,
+    },
+    While {
+        condition: Expr,
+        body: Box<Stmt>,
+        line: usize,
+    },
+    For {
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        increment: Option<Expr>,
+        body: Box<Stmt>,
+        line: usize,
+    },
+    Return {
+        value: Option<Expr>,
+        line: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Literal {
+    Number(f64),
+    String(String),
+    Bool(bool),
+    Nil,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum Expr {
+    Literal {
+        value: Literal,
+        line: usize,
+    },
+    Grouping {
+        expr: Box<Expr>,
+    },
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+        line: usize,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+        line: usize,
+    },
+    Logical {
+        op: LogicalOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+        line: usize,
+    },
+    Variable {
+        id: NodeId,
+        name: Identifier,
+    },
+    Assign {
+        id: NodeId,
+        name: Identifier,
+        value: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        args: Vec<Expr>,
+        line: usize,
+    },
+    Get {
+        object: Box<Expr>,
+        name: Identifier,
+    },
+    Set {
+        object: Box<Expr>,
+        name: Identifier,
+        value: Box<Expr>,
+    },
+    /// Resolved like a variable named `this`.
+    This {
+        id: NodeId,
+        line: usize,
+    },
+}
+
+impl Expr {
+    pub fn line(&self) -> usize {
+        match self {
+            Expr::Literal { line, .. }
+            | Expr::Unary { line, .. }
+            | Expr::Binary { line, .. }
+            | Expr::Logical { line, .. }
+            | Expr::Call { line, .. }
+            | Expr::This { line, .. } => *line,
+            Expr::Grouping { expr } => expr.line(),
+            Expr::Variable { name, .. }
+            | Expr::Assign { name, .. }
+            | Expr::Get { name, .. }
+            | Expr::Set { name, .. } => name.line,
+        }
+    }
+}
+
+/// Parses `source` into a typed AST, or returns every syntax error found.
+pub fn parse(source: &str) -> Result<Program, Vec<SyntaxError>> {
+    let parse = cst::parse(source);
+    if !parse.errors.is_empty() {
+        return Err(parse.errors);
+    }
+    Ok(lower(&parse.root))
+}
+
+/// Lowers an error-free CST. Trivia and punctuation are dropped; identifiers
+/// are NFC-normalized the same way the scanner normalizes them.
+pub fn lower(root: &SyntaxNode) -> Program {
+    let mut lowering = Lowering { next_id: 0 };
+    Program {
+        body: root.child_nodes().map(|node| lowering.stmt(node)).collect(),
+    }
+}
+
+struct Lowering {
+    next_id: u32,
+}
+
+impl Lowering {
+    fn id(&mut self) -> NodeId {
+        self.next_id += 1;
+        NodeId(self.next_id)
+    }
+
+    fn identifier(token: &SyntaxToken) -> Identifier {
+        Identifier {
+            name: token.text.nfc().collect(),
+            line: token.line,
+        }
+    }
+
+    fn stmt(&mut self, node: &SyntaxNode) -> Stmt {
+        let line = node.first_token().line;
+        match node.kind {
+            NodeKind::VarDecl => Stmt::Var {
+                id: self.id(),
+                name: Self::identifier(node.child_token(Kind::IdentifierLiteral).unwrap()),
+                initializer: node.child_nodes().next().map(|expr| self.expr(expr)),
+            },
+            NodeKind::FunDecl => Stmt::Function(self.function(node)),
+            NodeKind::ClassDecl => Stmt::Class(Class {
+                id: self.id(),
+                name: Self::identifier(node.child_token(Kind::IdentifierLiteral).unwrap()),
+                methods: node
+                    .child_nodes()
+                    .map(|method| self.function(method))
+                    .collect(),
+            }),
+            NodeKind::ExprStmt => Stmt::Expression {
+                expr: self.expr(node.child_node(0)),
+                line,
+            },
+            NodeKind::PrintStmt => Stmt::Print {
+                expr: self.expr(node.child_node(0)),
+                line,
+            },
+            NodeKind::Block => Stmt::Block {
+                body: node.child_nodes().map(|stmt| self.stmt(stmt)).collect(),
+                end_line: node.child_token(Kind::RightBrace).unwrap().line,
+            },
+            NodeKind::IfStmt => Stmt::If {
+                condition: self.expr(node.child_node(0)),
+                then_branch: Box::new(self.stmt(node.child_node(1))),
+                else_branch: node
+                    .child_nodes()
+                    .nth(2)
+                    .map(|stmt| Box::new(self.stmt(stmt))),
+                line,
+            },
+            NodeKind::WhileStmt => Stmt::While {
+                condition: self.expr(node.child_node(0)),
+                body: Box::new(self.stmt(node.child_node(1))),
+                line,
+            },
+            NodeKind::ForStmt => self.for_stmt(node, line),
+            NodeKind::ReturnStmt => Stmt::Return {
+                value: node.child_nodes().next().map(|expr| self.expr(expr)),
+                line,
+            },
+            kind => unreachable!("{:?} is not a statement", kind),
+        }
+    }
+
+    /// Lowers a `FunDecl` or `Method`; both hold the name, a `ParamList` and
+    /// the body `Block`.
+    fn function(&mut self, node: &SyntaxNode) -> Function {
+        let id = self.id();
+        let name = Self::identifier(node.child_token(Kind::IdentifierLiteral).unwrap());
+        let params = node
+            .child_node(0)
+            .children
+            .iter()
+            .filter_map(|child| match child {
+                SyntaxElement::Token(token) if token.kind == Kind::IdentifierLiteral => {
+                    Some(Param {
+                        id: self.id(),
+                        name: Self::identifier(token),
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        let block = node.child_node(1);
+        Function {
+            id,
+            name,
+            params,
+            body: block.child_nodes().map(|stmt| self.stmt(stmt)).collect(),
+            end_line: block.child_token(Kind::RightBrace).unwrap().line,
+        }
+    }
+
+    /// `for (init; cond; incr) body` keeps every clause optional, so walk the
+    /// children in order and use the separators to tell the clauses apart.
+    fn for_stmt(&mut self, node: &SyntaxNode, line: usize) -> Stmt {
+        let mut initializer = None;
+        let mut condition = None;
+        let mut increment = None;
+        let mut body = None;
+        let mut clause = 0;
+        for child in node.children.iter().skip(2) {
+            match child {
+                SyntaxElement::Token(token) => match token.kind {
+                    Kind::Semicolon | Kind::RightParen => clause += 1,
+                    _ => {}
+                },
+                SyntaxElement::Node(child) => match clause {
+                    0 => {
+                        initializer = Some(Box::new(self.stmt(child)));
+                        clause += 1;
+                    }
+                    1 => condition = Some(self.expr(child)),
+                    2 => increment = Some(self.expr(child)),
+                    _ => body = Some(Box::new(self.stmt(child))),
+                },
+            }
+        }
+        Stmt::For {
+            initializer,
+            condition,
+            increment,
+            body: body.expect("malformed syntax tree"),
+            line,
+        }
+    }
+
+    fn expr(&mut self, node: &SyntaxNode) -> Expr {
+        match node.kind {
+            NodeKind::Literal => {
+                let token = node.first_token();
+                let value = match token.kind {
+                    Kind::NumberLiteral => Literal::Number(token.text.parse().unwrap()),
+                    Kind::StringLiteral => {
+                        Literal::String(token.text[1..token.text.len() - 1].to_string())
+                    }
+                    Kind::True => Literal::Bool(true),
+                    Kind::False => Literal::Bool(false),
+                    _ => Literal::Nil,
+                };
+                Expr::Literal {
+                    value,
+                    line: token.line,
+                }
+            }
+            NodeKind::Grouping => Expr::Grouping {
+                expr: Box::new(self.expr(node.child_node(0))),
+            },
+            NodeKind::Unary => {
+                let token = node.first_token();
+                let op = match token.kind {
+                    Kind::Minus => UnaryOp::Negate,
+                    _ => UnaryOp::Not,
+                };
+                Expr::Unary {
+                    op,
+                    operand: Box::new(self.expr(node.child_node(0))),
+                    line: token.line,
+                }
+            }
+            NodeKind::Binary => {
+                let SyntaxElement::Token(token) = &node.children[1] else {
+                    unreachable!("malformed syntax tree");
+                };
+                let left = Box::new(self.expr(node.child_node(0)));
+                let right = Box::new(self.expr(node.child_node(1)));
+                let line = token.line;
+                let op = match token.kind {
+                    Kind::And => {
+                        return Expr::Logical {
+                            op: LogicalOp::And,
+                            left,
+                            right,
+                            line,
+                        };
+                    }
+                    Kind::Or => {
+                        return Expr::Logical {
+                            op: LogicalOp::Or,
+                            left,
+                            right,
+                            line,
+                        };
+                    }
+                    Kind::Plus => BinaryOp::Add,
+                    Kind::Minus => BinaryOp::Subtract,
+                    Kind::Star => BinaryOp::Multiply,
+                    Kind::Slash => BinaryOp::Divide,
+                    Kind::EqualEqual => BinaryOp::Equal,
+                    Kind::BangEqual => BinaryOp::NotEqual,
+                    Kind::Greater => BinaryOp::Greater,
+                    Kind::GreaterEqual => BinaryOp::GreaterEqual,
+                    Kind::Less => BinaryOp::Less,
+                    Kind::LessEqual => BinaryOp::LessEqual,
+                    ref kind => unreachable!("{:?} is not a binary operator", kind),
+                };
+                Expr::Binary {
+                    op,
+                    left,
+                    right,
+                    line,
+                }
+            }
+            NodeKind::Variable => Expr::Variable {
+                id: self.id(),
+                name: Self::identifier(node.first_token()),
+            },
+            NodeKind::Assign => Expr::Assign {
+                id: self.id(),
+                name: Self::identifier(node.first_token()),
+                value: Box::new(self.expr(node.child_node(1))),
+            },
+            NodeKind::Call => {
+                let args = node.child_node(1);
+                Expr::Call {
+                    callee: Box::new(self.expr(node.child_node(0))),
+                    args: args.child_nodes().map(|arg| self.expr(arg)).collect(),
+                    line: args.first_token().line,
+                }
+            }
+            NodeKind::Get => Expr::Get {
+                object: Box::new(self.expr(node.child_node(0))),
+                name: Self::identifier(node.child_token(Kind::IdentifierLiteral).unwrap()),
+            },
+            NodeKind::Set => Expr::Set {
+                object: Box::new(self.expr(node.child_node(0))),
+                name: Self::identifier(node.child_token(Kind::IdentifierLiteral).unwrap()),
+                value: Box::new(self.expr(node.child_node(1))),
+            },
+            NodeKind::This => Expr::This {
+                id: self.id(),
+                line: node.first_token().line,
+            },
+            kind => unreachable!("{:?} is not an expression", kind),
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! The C API, exported by the `cdylib` and declared in `include/nlox.h`.
+//!
+//! A C host owns an opaque `NloxVm` and trades `NloxValue`s with it: nil,
+//! booleans, numbers and strings. Anything else a script hands back shows
+//! up as `NLOX_TYPE_OBJECT`, without a payload. Every call that can fail
+//! returns an `NloxStatus`, and `nlox_last_error` then says why. Strings
+//! the API hands out stay valid until the next call on the same VM; those
+//! C passes in are copied, and must be UTF-8. Strings are cut short at an
+//! embedded NUL on the way out.
+
+use std::{
+    ffi::{CStr, CString, c_char, c_void},
+    ptr,
+};
+
+use crate::embed::{Error, Result, Value, Vm};
+
+/// An interpreter with its own heap and globals.
+pub struct NloxVm {
+    vm: Vm,
+    /// What `nlox_last_error` returns; cleared by each call.
+    error: Option<CString>,
+    /// The string of the last value `nlox_get_global` handed out.
+    string: Option<CString>,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NloxStatus {
+    Ok,
+    CompileError,
+    /// The script failed, or ran into one of the VM's limits.
+    RuntimeError,
+    /// The call itself was wrong, such as a global that does not exist.
+    Error,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NloxType {
+    Nil,
+    Bool,
+    Number,
+    String,
+    /// A function, class, instance, list or map, which C cannot look into.
+    Object,
+}
+
+/// A value crossing into or out of C. Only the field for `kind` is read.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct NloxValue {
+    pub kind: NloxType,
+    pub boolean: bool,
+    pub number: f64,
+    pub string: *const c_char,
+}
+
+/// A native implemented in C. It gets the `userdata` it was registered
+/// with and `arg_count` arguments, and fills in `result`. Returning false
+/// raises a runtime error, whose message is `result`'s string if it has
+/// one. A string result must outlive the call, like a static string or a
+/// buffer in `userdata`; it is copied as soon as the native returns.
+pub type NloxNative = Option<
+    unsafe extern "C" fn(
+        userdata: *mut c_void,
+        args: *const NloxValue,
+        arg_count: usize,
+        result: *mut NloxValue,
+    ) -> bool,
+>;
+
+impl NloxValue {
+    const NIL: Self = Self {
+        kind: NloxType::Nil,
+        boolean: false,
+        number: 0.0,
+        string: ptr::null(),
+    };
+
+    /// `value` for C, with its string kept in `strings`.
+    fn export(value: &Value, strings: &mut Vec<CString>) -> Self {
+        let mut exported = Self::NIL;
+        match value {
+            Value::Nil => {}
+            Value::Bool(bool) => {
+                exported.kind = NloxType::Bool;
+                exported.boolean = *bool;
+            }
+            Value::Number(number) => {
+                exported.kind = NloxType::Number;
+                exported.number = *number;
+            }
+            Value::String(string) => {
+                let string = string.split('\0').next().unwrap_or_default();
+                strings.push(CString::new(string).unwrap());
+                exported.kind = NloxType::String;
+                exported.string = strings.last().unwrap().as_ptr();
+            }
+            Value::List(_) | Value::Map(_) | Value::Object(_) => exported.kind = NloxType::Object,
+        }
+        exported
+    }
+
+    /// # Safety
+    ///
+    /// A string value must point to a NUL-terminated string.
+    unsafe fn import(&self) -> Result<Value> {
+        Ok(match self.kind {
+            NloxType::Nil => Value::Nil,
+            NloxType::Bool => Value::Bool(self.boolean),
+            NloxType::Number => Value::Number(self.number),
+            NloxType::String => Value::String(unsafe { string(self.string) }?.to_string()),
+            NloxType::Object => {
+                return Err(Error::Host(
+                    "Objects cannot be passed in from C.".to_string(),
+                ));
+            }
+        })
+    }
+}
+
+/// # Safety
+///
+/// `string` must be null or point to a NUL-terminated string that lives
+/// for `'a`.
+unsafe fn string<'a>(string: *const c_char) -> Result<&'a str> {
+    if string.is_null() {
+        return Err(Error::Host("Expected a string but got NULL.".to_string()));
+    }
+    let string = unsafe { CStr::from_ptr(string) };
+    string
+        .to_str()
+        .map_err(|_| Error::Host("Strings must be UTF-8.".to_string()))
+}
+
+impl NloxVm {
+    fn finish(&mut self, result: Result<()>) -> NloxStatus {
+        let status = match &result {
+            Ok(()) => NloxStatus::Ok,
+            Err(Error::Compile { .. }) => NloxStatus::CompileError,
+            Err(Error::Runtime { .. } | Error::Limit { .. }) => NloxStatus::RuntimeError,
+            Err(_) => NloxStatus::Error,
+        };
+        self.error = result.err().map(|error| {
+            let message = error.to_string();
+            CString::new(message.split('\0').next().unwrap_or_default()).unwrap()
+        });
+        status
+    }
+}
+
+/// Creates a VM, to be freed with `nlox_vm_free`.
+#[unsafe(no_mangle)]
+pub extern "C" fn nlox_vm_new() -> *mut NloxVm {
+    Box::into_raw(Box::new(NloxVm {
+        vm: Vm::new(),
+        error: None,
+        string: None,
+    }))
+}
+
+/// Frees `vm`, and with it everything its scripts allocated.
+///
+/// # Safety
+///
+/// `vm` must be null or come from `nlox_vm_new`, and not be used again.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn nlox_vm_free(vm: *mut NloxVm) {
+    if !vm.is_null() {
+        drop(unsafe { Box::from_raw(vm) });
+    }
+}
+
+/// Compiles and runs `source`. `name` identifies the script in errors.
+///
+/// # Safety
+///
+/// `vm` must come from `nlox_vm_new`; the strings must be NUL-terminated.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn nlox_eval(
+    vm: *mut NloxVm,
+    source: *const c_char,
+    name: *const c_char,
+) -> NloxStatus {
+    let vm = unsafe { &mut *vm };
+    let result = unsafe { string(source).and_then(|source| Ok((source, string(name)?))) };
+    let result = result.and_then(|(source, name)| vm.vm.eval(source, name));
+    vm.finish(result)
+}
+
+/// Defines the global `name` as a native taking `arity` arguments that
+/// calls `native` with `userdata`.
+///
+/// # Safety
+///
+/// `vm` must come from `nlox_vm_new` and `name` be NUL-terminated.
+/// `userdata` must stay valid for as long as the VM may call `native`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn nlox_register(
+    vm: *mut NloxVm,
+    name: *const c_char,
+    arity: usize,
+    native: NloxNative,
+    userdata: *mut c_void,
+) -> NloxStatus {
+    let vm = unsafe { &mut *vm };
+    let name = match unsafe { string(name) } {
+        Ok(name) => name,
+        Err(error) => return vm.finish(Err(error)),
+    };
+    let Some(native) = native else {
+        return vm.finish(Err(Error::Host(
+            "Expected a native but got NULL.".to_string(),
+        )));
+    };
+    vm.vm.register_untyped(name, arity, move |_, args| {
+        let mut strings = vec![];
+        let args: Vec<_> = args
+            .iter()
+            .map(|arg| NloxValue::export(arg, &mut strings))
+            .collect();
+        let mut result = NloxValue::NIL;
+        if unsafe { native(userdata, args.as_ptr(), args.len(), &mut result) } {
+            return unsafe { result.import() };
+        }
+        let message = match result.kind {
+            NloxType::String => unsafe { string(result.string) }?,
+            _ => "Native function failed.",
+        };
+        Err(Error::Host(message.to_string()))
+    });
+    vm.finish(Ok(()))
+}
+
+/// Reads the global `name` into `value`. A string in it lasts until the
+/// next call on `vm`.
+///
+/// # Safety
+///
+/// `vm` must come from `nlox_vm_new`, `name` be NUL-terminated and `value`
+/// point to an `NloxValue`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn nlox_get_global(
+    vm: *mut NloxVm,
+    name: *const c_char,
+    value: *mut NloxValue,
+) -> NloxStatus {
+    let vm = unsafe { &mut *vm };
+    let global = unsafe { string(name) }.and_then(|name| {
+        vm.vm
+            .get_global(name)
+            .ok_or_else(|| Error::UndefinedGlobal(name.to_string()))
+    });
+    let result = global.map(|global| {
+        let mut strings = vec![];
+        unsafe { *value = NloxValue::export(&global, &mut strings) };
+        vm.string = strings.pop();
+    });
+    vm.finish(result)
+}
+
+/// Defines the global `name` as `value`, or overwrites it.
+///
+/// # Safety
+///
+/// `vm` must come from `nlox_vm_new`, and `name` and any string in
+/// `value` be NUL-terminated.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn nlox_set_global(
+    vm: *mut NloxVm,
+    name: *const c_char,
+    value: NloxValue,
+) -> NloxStatus {
+    let vm = unsafe { &mut *vm };
+    let result = unsafe { string(name).and_then(|name| Ok((name, value.import()?))) };
+    let result = result.and_then(|(name, value)| vm.vm.set_global(name, value));
+    vm.finish(result)
+}
+
+/// Why the last call on `vm` failed, or NULL if it succeeded. Valid until
+/// the next call on `vm`.
+///
+/// # Safety
+///
+/// `vm` must come from `nlox_vm_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn nlox_last_error(vm: *const NloxVm) -> *const c_char {
+    let vm = unsafe { &*vm };
+    vm.error
+        .as_ref()
+        .map_or(ptr::null(), |error| error.as_ptr())
+}
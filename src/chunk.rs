@@ -1,10 +1,16 @@
+use std::{
+    io::{self, Write},
+    sync::Arc,
+};
+
 use crate::{
-    chunk,
-    value::{self, Value, ValueArray},
+    gc::Heap,
+    value::{Value, ValueArray, ValueType},
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
+#[allow(non_camel_case_types)]
 pub enum OpCode {
     Return,
     OP_NEGATE,
@@ -21,11 +27,43 @@ pub enum OpCode {
     OP_GREATER,
     OP_LESS,
     OP_PRINT,
+    OP_POP,
+    OP_DEFINE_GLOBAL,
+    OP_GET_GLOBAL,
+    OP_SET_GLOBAL,
+    OP_GET_LOCAL,
+    OP_SET_LOCAL,
+    OP_GET_UPVALUE,
+    OP_SET_UPVALUE,
+    OP_JUMP,
+    OP_JUMP_IF_FALSE,
+    OP_LOOP,
+    OP_CALL,
+    OP_CLOSURE,
+    OP_CLOSE_UPVALUE,
+    OP_NOT_EQUAL,
+    OP_LESS_EQUAL,
+    OP_GREATER_EQUAL,
+    OP_CLASS,
+    OP_METHOD,
+    /// Name constant, then a two-byte inline cache index.
+    OP_GET_PROPERTY,
+    OP_SET_PROPERTY,
+    /// Name constant, argument count, then a two-byte inline cache index.
+    OP_INVOKE,
+    /// `OP_CALL` and `OP_INVOKE` for `return f(args);`: the callee takes
+    /// over the running frame instead of pushing one above it.
+    OP_TAIL_CALL,
+    OP_TAIL_INVOKE,
 }
 
 impl TryFrom<u8> for OpCode {
     type Error = &'static str;
     fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value > OpCode::OP_TAIL_INVOKE as u8 {
+            return Err("Unknown opcode.");
+        }
+        // `OpCode` is `repr(u8)` with no gaps up to its last variant.
         let res = unsafe { std::mem::transmute::<u8, OpCode>(value) };
         Ok(res)
     }
@@ -33,32 +71,52 @@ impl TryFrom<u8> for OpCode {
 
 #[derive(Debug)]
 pub struct Chunk {
-    pub code: Vec<u8>,
-    pub lines: Vec<usize>,
+    /// The code and its lines are shared by every VM linking the same
+    /// `Program`; only the compiler changes them, before they are shared.
+    pub code: Arc<Vec<u8>>,
+    pub lines: Arc<Vec<usize>>,
+    /// Values of this VM's heap, so never shared.
     pub constants: ValueArray,
+    /// Stack slots a frame running the code can fill, from the callee's
+    /// slot up; set once the chunk is finished.
+    pub max_stack: usize,
 }
 
+#[allow(non_snake_case)]
 impl Chunk {
     pub fn new() -> Self {
         Self {
-            code: vec![],
-            lines: vec![],
+            code: Arc::default(),
+            lines: Arc::default(),
             constants: ValueArray::new(),
+            max_stack: 0,
         }
     }
 
-    pub fn disassembleChunk(&mut self, name: &str) {
-        println!("== {} ==", name);
+    pub fn disassembleChunk(&self, name: &str, heap: &Heap, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "== {} ==", name)?;
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassembleInstruction(offset)
+            offset = self.disassembleInstruction(offset, heap, out)?;
         }
+
+        // Function bodies live in the constant pool; list them after their
+        // parent so the whole program is shown.
+        for constant in &self.constants.values {
+            if let ValueType::VAL_FUNCTION(function) = constant.type_v() {
+                writeln!(out)?;
+                function
+                    .chunk
+                    .disassembleChunk(&function.to_string(), heap, out)?;
+            }
+        }
+        Ok(())
     }
 
     pub fn write_chunk(&mut self, opcode: u8, line: usize) {
         //println!("OP_code as u8{:?}" , opcode );
-        self.code.push(opcode);
-        self.lines.push(line);
+        Arc::make_mut(&mut self.code).push(opcode);
+        Arc::make_mut(&mut self.lines).push(line);
     }
 
     pub fn addConstant(&mut self, value: Value) -> usize {
@@ -66,91 +124,211 @@ impl Chunk {
         self.constants.values.len() - 1
     }
 
-    pub fn disassembleInstruction(&mut self, offset: usize) -> usize {
+    pub fn disassembleInstruction(
+        &self,
+        offset: usize,
+        heap: &Heap,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
         // println!("Code: {:?} Constants : {:?}",self.code,self.constants.values);
-        print!("{:04} ", offset);
+        write!(out, "{:04} ", offset)?;
         if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ")
+            write!(out, "   | ")?;
         } else {
-            print!("{:04} ", self.lines[offset]);
+            write!(out, "{:04} ", self.lines[offset])?;
         }
 
         let instruction = self.code[offset];
-        match OpCode::try_from(instruction).unwrap() {
-            OpCode::Return => {
-                println!("OP_RETURN");
-                offset + 1
-            }
-            OpCode::Op_Constnats => {
-                let constant = self.code[offset + 1];
-                print!("{:<16} {:>4}", "OP_CONSTANT", constant);
-                self.printValue(&self.constants.values[constant as usize]);
-                println!("");
-                offset + 2
+        Ok(match OpCode::try_from(instruction).unwrap() {
+            OpCode::Return => self.simpleInstruction("OP_RETURN", offset, out)?,
+            OpCode::Op_Constnats => self.constantInstruction("OP_CONSTANT", offset, heap, out)?,
+            OpCode::OP_NEGATE => self.simpleInstruction("OP_NEGATE", offset, out)?,
+            OpCode::OP_MULTIPLY => self.simpleInstruction("OP_MULTIPLY", offset, out)?,
+            OpCode::OP_TRUE => self.simpleInstruction("OP_TRUE", offset, out)?,
+            OpCode::OP_FALSE => self.simpleInstruction("OP_FALSE", offset, out)?,
+            OpCode::OP_NIL => self.simpleInstruction("OP_NIL", offset, out)?,
+            OpCode::OP_SUBTRACT => self.simpleInstruction("OP_SUBTRACT", offset, out)?,
+            OpCode::OP_DIVIDE => self.simpleInstruction("OP_DIVIDE", offset, out)?,
+            OpCode::OP_ADD => self.simpleInstruction("OP_ADD", offset, out)?,
+            OpCode::OP_NOT => self.simpleInstruction("OP_NOT", offset, out)?,
+            OpCode::OP_LESS => self.simpleInstruction("OP_LESS", offset, out)?,
+            OpCode::OP_GREATER => self.simpleInstruction("OP_GREATER", offset, out)?,
+            OpCode::OP_EQUAL => self.simpleInstruction("OP_EQUAL", offset, out)?,
+            OpCode::OP_PRINT => self.simpleInstruction("OP_PRINT", offset, out)?,
+            OpCode::OP_POP => self.simpleInstruction("OP_POP", offset, out)?,
+            OpCode::OP_DEFINE_GLOBAL => {
+                self.constantInstruction("OP_DEFINE_GLOBAL", offset, heap, out)?
             }
-            OpCode::OP_NEGATE => {
-                println!("OP_NEGATE");
-                offset + 1
+            OpCode::OP_GET_GLOBAL => {
+                self.constantInstruction("OP_GET_GLOBAL", offset, heap, out)?
             }
-            OpCode::OP_MULTIPLY => {
-                println!("OP_MULTIPLY");
-                offset + 1
+            OpCode::OP_SET_GLOBAL => {
+                self.constantInstruction("OP_SET_GLOBAL", offset, heap, out)?
             }
-            OpCode::OP_TRUE => {
-                println!("OP_TRUE");
-                offset + 1
-            }
-            OpCode::OP_FALSE => {
-                println!("OP_FALSE");
-                offset + 1
-            }
-            OpCode::OP_NIL => {
-                println!("OP_NIL");
-                offset + 1
-            }
-            OpCode::OP_SUBTRACT => {
-                println!("OP_SUBTRACT");
-                offset + 1
-            }
-            OpCode::OP_DIVIDE => {
-                println!("OP_DIVIDE");
-                offset + 1
-            }
-            OpCode::OP_ADD => {
-                println!("OP_ADD");
-                offset + 1
-            }
-            OpCode::OP_NOT => {
-                println!("OP_NOT");
-                offset + 1
-            }
-            OpCode::OP_LESS => {
-                println!("OP_LESS");
-                offset + 1
+            OpCode::OP_GET_LOCAL => self.byteInstruction("OP_GET_LOCAL", offset, out)?,
+            OpCode::OP_SET_LOCAL => self.byteInstruction("OP_SET_LOCAL", offset, out)?,
+            OpCode::OP_GET_UPVALUE => self.byteInstruction("OP_GET_UPVALUE", offset, out)?,
+            OpCode::OP_SET_UPVALUE => self.byteInstruction("OP_SET_UPVALUE", offset, out)?,
+            OpCode::OP_JUMP => self.jumpInstruction("OP_JUMP", 1, offset, out)?,
+            OpCode::OP_JUMP_IF_FALSE => self.jumpInstruction("OP_JUMP_IF_FALSE", 1, offset, out)?,
+            OpCode::OP_LOOP => self.jumpInstruction("OP_LOOP", -1, offset, out)?,
+            OpCode::OP_CALL => self.byteInstruction("OP_CALL", offset, out)?,
+            OpCode::OP_CLOSURE => {
+                let constant = self.code[offset + 1] as usize;
+                let value = &self.constants.values[constant];
+                writeln!(
+                    out,
+                    "{:<16} {:>4} {}",
+                    "OP_CLOSURE",
+                    constant,
+                    heap.display(value)
+                )?;
+
+                let ValueType::VAL_FUNCTION(function) = value.type_v() else {
+                    unreachable!("OP_CLOSURE operand must be a function");
+                };
+                let mut offset = offset + 2;
+                for _ in 0..function.upvalue_count {
+                    let kind = if self.code[offset] == 1 {
+                        "local"
+                    } else {
+                        "upvalue"
+                    };
+                    writeln!(
+                        out,
+                        "{:04}    |                     {} {}",
+                        offset,
+                        kind,
+                        self.code[offset + 1]
+                    )?;
+                    offset += 2;
+                }
+                offset
             }
-            OpCode::OP_GREATER => {
-                println!("OP_GREATER");
-                offset + 1
+            OpCode::OP_CLOSE_UPVALUE => self.simpleInstruction("OP_CLOSE_UPVALUE", offset, out)?,
+            OpCode::OP_NOT_EQUAL => self.simpleInstruction("OP_NOT_EQUAL", offset, out)?,
+            OpCode::OP_LESS_EQUAL => self.simpleInstruction("OP_LESS_EQUAL", offset, out)?,
+            OpCode::OP_GREATER_EQUAL => self.simpleInstruction("OP_GREATER_EQUAL", offset, out)?,
+            OpCode::OP_CLASS => self.constantInstruction("OP_CLASS", offset, heap, out)?,
+            OpCode::OP_METHOD => self.constantInstruction("OP_METHOD", offset, heap, out)?,
+            OpCode::OP_GET_PROPERTY => {
+                self.propertyInstruction("OP_GET_PROPERTY", offset, heap, out)?
             }
-            OpCode::OP_EQUAL => {
-                println!("OP_EQUAL");
-                offset + 1
+            OpCode::OP_SET_PROPERTY => {
+                self.propertyInstruction("OP_SET_PROPERTY", offset, heap, out)?
             }
-            OpCode::OP_PRINT => {
-                println!("OP_PRINT");
-                offset + 1
+            OpCode::OP_INVOKE => self.invokeInstruction("OP_INVOKE", offset, heap, out)?,
+            OpCode::OP_TAIL_CALL => self.byteInstruction("OP_TAIL_CALL", offset, out)?,
+            OpCode::OP_TAIL_INVOKE => {
+                self.invokeInstruction("OP_TAIL_INVOKE", offset, heap, out)?
             }
+        })
+    }
 
-            _ => todo!(),
-        }
+    fn simpleInstruction(
+        &self,
+        name: &str,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
+        writeln!(out, "{}", name)?;
+        Ok(offset + 1)
+    }
+
+    fn constantInstruction(
+        &self,
+        name: &str,
+        offset: usize,
+        heap: &Heap,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
+        let constant = self.code[offset + 1];
+        writeln!(
+            out,
+            "{:<16} {:>4} '{}'",
+            name,
+            constant,
+            heap.display(&self.constants.values[constant as usize])
+        )?;
+        Ok(offset + 2)
+    }
+
+    fn propertyInstruction(
+        &self,
+        name: &str,
+        offset: usize,
+        heap: &Heap,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
+        let constant = self.code[offset + 1];
+        let cache = u16::from_be_bytes([self.code[offset + 2], self.code[offset + 3]]);
+        writeln!(
+            out,
+            "{:<16} {:>4} '{}' ic {}",
+            name,
+            constant,
+            heap.display(&self.constants.values[constant as usize]),
+            cache
+        )?;
+        Ok(offset + 4)
+    }
+
+    fn invokeInstruction(
+        &self,
+        name: &str,
+        offset: usize,
+        heap: &Heap,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
+        let constant = self.code[offset + 1];
+        let arg_count = self.code[offset + 2];
+        let cache = u16::from_be_bytes([self.code[offset + 3], self.code[offset + 4]]);
+        writeln!(
+            out,
+            "{:<16} ({} args) {:>4} '{}' ic {}",
+            name,
+            arg_count,
+            constant,
+            heap.display(&self.constants.values[constant as usize]),
+            cache
+        )?;
+        Ok(offset + 5)
     }
 
-    pub fn printValue(&self, value: &Value) {
-        match value.type_v {
-            value::ValueType::VAL_NIL => println!("nil"),
-            value::ValueType::VAL_BOOL(a) => println!("{}", a),
-            value::ValueType::VAL_NUMBER(a) => println!("{}", a),
-            value::ValueType::VAL_STRING(ref a) => println!("{}", a.as_str()),
+    fn byteInstruction(&self, name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        writeln!(out, "{:<16} {:>4}", name, self.code[offset + 1])?;
+        Ok(offset + 2)
+    }
+
+    fn jumpInstruction(
+        &self,
+        name: &str,
+        sign: isize,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
+        let jump = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]);
+        let target = offset as isize + 3 + sign * jump as isize;
+        writeln!(out, "{:<16} {:>4} -> {}", name, offset, target)?;
+        Ok(offset + 3)
+    }
+
+    pub fn printValue(&self, value: &Value, heap: &Heap, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{}", heap.display(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OpCode;
+
+    #[test]
+    fn decodes_only_known_opcodes() {
+        assert_eq!(OpCode::try_from(0), Ok(OpCode::Return));
+        let last = OpCode::OP_TAIL_INVOKE as u8;
+        assert_eq!(OpCode::try_from(last), Ok(OpCode::OP_TAIL_INVOKE));
+        for byte in last + 1..=u8::MAX {
+            assert_eq!(OpCode::try_from(byte), Err("Unknown opcode."));
         }
     }
 }
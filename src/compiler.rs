@@ -1,309 +1,607 @@
-#![allow(warnings)]
-use std::{cell::RefCell, default, env::set_var, panic, rc::Rc, slice::SliceIndex, usize};
+use std::{rc::Rc, sync::Arc};
 
 use crate::{
+    ast::{self, BinaryOp, Expr, Function, Identifier, Literal, LogicalOp, Stmt, UnaryOp},
     chunk::{Chunk, OpCode},
-    scanner::{self, Scanner},
-    token::{Kind, Token},
-    value::{Value, ValueType},
+    cst,
+    gc::Heap,
+    lint::{self, Suppressions, Warning},
+    optimizer,
+    register_compiler::RegisterCompiler,
+    resolver::{self, Binding, FunctionKind, Resolution},
+    token::Kind,
+    value::{ObjFunction, Value, ValueType},
 };
 
-type ParseFn = for<'a> fn(&'a mut Compiler);
-
-#[derive(Clone)]
-pub struct ParseRule {
-    pub prefix: Option<ParseFn>,
-    pub infix: Option<ParseFn>,
-    pub precedence: Presidence,
+/// Settings that change what the compiler accepts or emits.
+#[derive(Debug, Clone, Copy)]
+pub struct CompilerOptions {
+    /// `-O` level handed to the optimizer for every finished chunk.
+    pub opt_level: u8,
+    /// Report warnings as errors and fail the compilation.
+    pub deny_warnings: bool,
+    /// Instruction set to generate; the VM runs whichever one it is given.
+    pub backend: Backend,
 }
 
-fn rule_for_token(token: &Kind) -> ParseRule {
-    use Kind::*;
-    match token {
-        LeftParen => run(
-            Some(Compiler::grouping as ParseFn),
-            None,
-            Presidence::PREC_NONE,
-        ),
-        RightParen => run(None, None, Presidence::PREC_NONE),
-        LeftBrace => run(None, None, Presidence::PREC_NONE),
-        RightBrace => run(None, None, Presidence::PREC_NONE),
-        Comma => run(None, None, Presidence::PREC_NONE),
-        Dot => run(None, None, Presidence::PREC_NONE),
-        Minus => run(
-            Some(Compiler::unary as ParseFn),
-            Some(Compiler::binary as ParseFn),
-            Presidence::PREC_TERM,
-        ),
-        Plus => run(
-            None,
-            Some(Compiler::binary as ParseFn),
-            Presidence::PREC_TERM,
-        ),
-        Slash => run(
-            None,
-            Some(Compiler::binary as ParseFn),
-            Presidence::PREC_FACTOR,
-        ),
-        Star => run(
-            None,
-            Some(Compiler::binary as ParseFn),
-            Presidence::PREC_FACTOR,
-        ),
-        Semicolon => run(None, None, Presidence::PREC_NONE),
-
-        Bang => run(Some(Compiler::unary), None, Presidence::PREC_UNARY),
-        BangEqual => run(None, Some(Compiler::binary), Presidence::PREC_EQUALITY),
-        Equal => run(None, None, Presidence::PREC_NONE),
-        EqualEqual => run(None, Some(Compiler::binary), Presidence::PREC_EQUALITY),
-        Greater => run(None, Some(Compiler::binary), Presidence::PREC_COMPARISON),
-        GreaterEqual => run(None, Some(Compiler::binary), Presidence::PREC_COMPARISON),
-        Less => run(None, Some(Compiler::binary), Presidence::PREC_COMPARISON),
-        LessEqual => run(None, Some(Compiler::binary), Presidence::PREC_COMPARISON),
-
-        IdentifierLiteral => run(None, None, Presidence::PREC_NONE),
-        StringLiteral => run(Some(Compiler::string), None, Presidence::PREC_NONE),
-        NumberLiteral => run(Some(Compiler::number), None, Presidence::PREC_NONE),
-
-        And => run(None, None, Presidence::PREC_NONE),
-        Class => run(None, None, Presidence::PREC_NONE),
-        Else => run(None, None, Presidence::PREC_NONE),
-        False => run(Some(Compiler::literal), None, Presidence::PREC_NONE),
-        For => run(None, None, Presidence::PREC_NONE),
-        Fun => run(None, None, Presidence::PREC_NONE),
-        If => run(None, None, Presidence::PREC_NONE),
-        Nil => run(Some(Compiler::literal), None, Presidence::PREC_NONE),
-        Or => run(None, None, Presidence::PREC_NONE),
-        Print => run(None, None, Presidence::PREC_NONE),
-        Return => run(None, None, Presidence::PREC_NONE),
-        Super => run(None, None, Presidence::PREC_NONE),
-        This => run(None, None, Presidence::PREC_NONE),
-        True => run(Some(Compiler::literal), None, Presidence::PREC_NONE),
-        Var => run(None, None, Presidence::PREC_NONE),
-        While => run(None, None, Presidence::PREC_NONE),
-        Error => run(None, None, Presidence::PREC_NONE),
-        Eof => run(None, None, Presidence::PREC_NONE),
+impl Default for CompilerOptions {
+    fn default() -> Self {
+        Self {
+            opt_level: 1,
+            deny_warnings: false,
+            backend: Backend::Stack,
+        }
     }
 }
 
-fn run(prefix: Option<ParseFn>, infix: Option<ParseFn>, presidence: Presidence) -> ParseRule {
-    ParseRule {
-        prefix,
-        infix,
-        precedence: presidence,
-    }
+/// Which instruction set functions are compiled to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// The `OpCode` bytecode in each function's chunk.
+    Stack,
+    /// The experimental three-address `register::Instruction`s.
+    Register,
 }
 
-#[derive(Clone)]
-pub enum Precedence {
-    None,
-    Term,   // + -
-    Factor, // * /
-    Unary,  // !, -
-    Primary,
+/// Bytecode generator. Runs the parser and resolver over the source and
+/// walks the resulting AST, emitting one chunk per function.
+pub struct Compiler<'h> {
+    source: String,
+    /// String constants are allocated straight into the VM's heap.
+    heap: &'h mut Heap,
+    resolution: Resolution,
+    /// Functions being compiled, innermost last.
+    functions: Vec<ObjFunction>,
+    /// What each entry of `functions` is compiled as.
+    kinds: Vec<FunctionKind>,
+    scope_depth: usize,
+    line: usize,
+    has_error: bool,
+    options: CompilerOptions,
+    /// Name of the script being compiled, recorded in each function.
+    pub script: Rc<str>,
+    /// Every error reported.
+    pub errors: Vec<String>,
+    /// Every warning and error reported, in order, for the caller to
+    /// print.
+    pub diagnostics: Vec<String>,
 }
 
-#[derive(Clone, PartialEq, PartialOrd, Debug)]
-#[repr(usize)]
-pub enum Presidence {
-    PREC_NONE,
-    PREC_ASSIGNMENT, // =
-    PREC_OR,         // or
-    PREC_AND,        // and
-    PREC_EQUALITY,   // == !=
-    PREC_COMPARISON, // < > <= >=
-    PREC_TERM,       // + -
-    PREC_FACTOR,     // * /
-    PREC_UNARY,      // ! -
-    PREC_CALL,       // . ()
-    PREC_PRIMARY,
-}
-impl Presidence {
-    pub fn next(&self) -> Option<Presidence> {
-        use Presidence::*;
-
-        match self {
-            PREC_NONE => Some(PREC_ASSIGNMENT),
-            PREC_ASSIGNMENT => Some(PREC_OR),
-            PREC_OR => Some(PREC_AND),
-            PREC_AND => Some(PREC_EQUALITY),
-            PREC_EQUALITY => Some(PREC_COMPARISON),
-            PREC_COMPARISON => Some(PREC_TERM),
-            PREC_TERM => Some(PREC_FACTOR),
-            PREC_FACTOR => Some(PREC_UNARY),
-            PREC_UNARY => Some(PREC_CALL),
-            PREC_CALL => Some(PREC_PRIMARY),
-            PREC_PRIMARY => None, // No next after highest precedence
+#[allow(non_snake_case)]
+impl<'h> Compiler<'h> {
+    pub fn new(source: String, options: CompilerOptions, heap: &'h mut Heap) -> Self {
+        Self {
+            source,
+            heap,
+            resolution: Resolution::default(),
+            functions: vec![],
+            kinds: vec![],
+            scope_depth: 0,
+            line: 0,
+            has_error: false,
+            options,
+            script: Rc::from(""),
+            errors: vec![],
+            diagnostics: vec![],
         }
     }
-}
-
-#[derive(Clone, Default)]
-pub struct Parser {
-    current: Token,
-    previous: Token,
-    has_error: bool,
-    panic_mode: bool,
-}
 
-pub struct Compiler {
-    scanner: Scanner,
-    parser: Parser,
-    current: Option<Rc<RefCell<Chunk>>>,
-}
+    pub fn compile(&mut self) -> Option<Rc<ObjFunction>> {
+        let parse = cst::parse(&self.source);
+        if !parse.errors.is_empty() {
+            self.report(&parse.root, parse.warnings);
+            for error in parse.errors {
+                let location = match error.kind {
+                    Kind::Eof => " at end".to_string(),
+                    Kind::Error => String::new(),
+                    _ => format!(" at '{}'", error.lexeme),
+                };
+                self.errorAt(error.line, &location, &error.message);
+            }
+            return None;
+        }
+        let program = ast::lower(&parse.root);
+        self.resolution = match resolver::resolve(&program) {
+            Ok(resolution) => resolution,
+            Err(errors) => {
+                self.report(&parse.root, parse.warnings);
+                for error in errors {
+                    self.errorAt(error.line, &format!(" at '{}'", error.name), &error.message);
+                }
+                return None;
+            }
+        };
+        let mut warnings = parse.warnings;
+        warnings.extend(lint::check(&program, &self.resolution));
+        self.report(&parse.root, warnings);
+
+        if self.options.backend == Backend::Register {
+            let compiler = RegisterCompiler::new(
+                self.heap,
+                &self.resolution,
+                self.options,
+                self.script.clone(),
+            );
+            let mut function = match compiler.compile(&program) {
+                Ok(function) => function,
+                Err(errors) => {
+                    for (line, message) in errors {
+                        self.errorAt(line, "", &message);
+                    }
+                    return None;
+                }
+            };
+            if self.has_error {
+                return None;
+            }
+            function.locals = self.resolution.locals[&None].as_slice().into();
+            return Some(Rc::new(function));
+        }
 
-impl Compiler {
-    pub fn new(source: String) -> Self {
-        Self {
-            scanner: Scanner::new(source),
-            current: None,
-            parser: Parser::default(),
+        self.begin_function(ObjFunction::new(None, 0), FunctionKind::Function);
+        for stmt in &program.body {
+            self.statement(stmt);
+        }
+        let mut function = self.endCompiler();
+        if self.has_error {
+            return None;
         }
+        function.locals = self.resolution.locals[&None].as_slice().into();
+        Some(Rc::new(function))
     }
 
-    pub fn string(&mut self) {
-        let str = self.parser.previous.string.clone();
-        self.emit_constant(Value::from(ValueType::VAL_STRING(str)));
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Var {
+                name, initializer, ..
+            } => {
+                match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => {
+                        self.line = name.line;
+                        self.emitByte(OpCode::OP_NIL as u8);
+                    }
+                }
+                self.defineVariable(name);
+            }
+            Stmt::Function(function) => {
+                self.function(function, FunctionKind::Function);
+                self.defineVariable(&function.name);
+            }
+            Stmt::Class(class) => {
+                self.line = class.name.line;
+                let name = self.identifierConstant(&class.name);
+                self.emit_Bytes(OpCode::OP_CLASS as u8, name);
+                // Each method closure is popped into the class below it.
+                for method in &class.methods {
+                    self.function(method, FunctionKind::of_method(method));
+                    let name = self.identifierConstant(&method.name);
+                    self.emit_Bytes(OpCode::OP_METHOD as u8, name);
+                }
+                self.defineVariable(&class.name);
+            }
+            Stmt::Expression { expr, line } => {
+                self.expression(expr);
+                self.line = *line;
+                self.emitByte(OpCode::OP_POP as u8);
+            }
+            Stmt::Print { expr, line } => {
+                self.expression(expr);
+                self.line = *line;
+                self.emitByte(OpCode::OP_PRINT as u8);
+            }
+            Stmt::Block { body, end_line } => {
+                self.scope_depth += 1;
+                for stmt in body {
+                    self.statement(stmt);
+                }
+                self.line = *end_line;
+                self.endScope(body);
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                line,
+            } => {
+                self.expression(condition);
+                self.line = *line;
+                let then_jump = self.emitJump(OpCode::OP_JUMP_IF_FALSE);
+                self.emitByte(OpCode::OP_POP as u8);
+                self.statement(then_branch);
+
+                let else_jump = self.emitJump(OpCode::OP_JUMP);
+                self.patchJump(then_jump);
+                self.emitByte(OpCode::OP_POP as u8);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+                self.patchJump(else_jump);
+            }
+            Stmt::While {
+                condition,
+                body,
+                line,
+            } => {
+                let loop_start = self.chunk().code.len();
+                self.expression(condition);
+                self.line = *line;
+                let exit_jump = self.emitJump(OpCode::OP_JUMP_IF_FALSE);
+                self.emitByte(OpCode::OP_POP as u8);
+                self.statement(body);
+                self.emitLoop(loop_start);
+
+                self.patchJump(exit_jump);
+                self.emitByte(OpCode::OP_POP as u8);
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                line,
+            } => self.forStatement(initializer, condition, increment, body, *line),
+            Stmt::Return { value, line } => match value {
+                Some(Expr::Call { callee, args, line }) => self.call(callee, args, *line, true),
+                Some(value) => {
+                    self.expression(value);
+                    self.line = *line;
+                    self.emit_return_value();
+                }
+                None => {
+                    self.line = *line;
+                    self.emit_return();
+                }
+            },
+        }
     }
 
-    pub fn literal(&mut self) {
-        match self.parser.previous.kind {
-            Kind::True => self.emitByte(OpCode::OP_TRUE as u8),
-            Kind::Nil => self.emitByte(OpCode::OP_NIL as u8),
-            Kind::False => self.emitByte(OpCode::OP_FALSE as u8),
-            _ => unreachable!(),
+    fn forStatement(
+        &mut self,
+        initializer: &Option<Box<Stmt>>,
+        condition: &Option<Expr>,
+        increment: &Option<Expr>,
+        body: &Stmt,
+        line: usize,
+    ) {
+        self.scope_depth += 1;
+        if let Some(initializer) = initializer {
+            self.statement(initializer);
         }
-    }
-    pub fn compile(&mut self, chunk: Rc<RefCell<Chunk>>) -> bool {
-        self.current = Some(chunk);
-        self.parser.panic_mode = false;
-        self.parser.has_error = false;
-        self.advance();
 
-        while !self.match_token(Kind::Eof) {
-            self.declaration();
-            if self.parser.panic_mode {
-                break; // or synchronize if you want to recover
-            }
+        let mut loop_start = self.chunk().code.len();
+        let mut exit_jump = None;
+        if let Some(condition) = condition {
+            self.expression(condition);
+            self.line = line;
+            exit_jump = Some(self.emitJump(OpCode::OP_JUMP_IF_FALSE));
+            self.emitByte(OpCode::OP_POP as u8);
         }
 
-        self.consume(Kind::Eof, "Expected End of expression".to_owned());
-        self.endCompiler();
-        !self.parser.has_error
-    }
+        if let Some(increment) = increment {
+            self.line = line;
+            let body_jump = self.emitJump(OpCode::OP_JUMP);
+            let increment_start = self.chunk().code.len();
+            self.expression(increment);
+            self.emitByte(OpCode::OP_POP as u8);
+            self.emitLoop(loop_start);
+            loop_start = increment_start;
+            self.patchJump(body_jump);
+        }
 
-    fn declaration(&mut self) {
-        self.statement();
-    }
-    fn statement(&mut self) {
-        if self.match_token(Kind::Print) {
-            self.printStatement();
+        self.statement(body);
+        self.emitLoop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patchJump(exit_jump);
+            self.emitByte(OpCode::OP_POP as u8);
+        }
+        match initializer {
+            Some(initializer) => self.endScope(std::slice::from_ref(initializer)),
+            None => self.endScope(&[]),
         }
     }
 
-    fn printStatement(&mut self) {
-        self.consume(Kind::LeftParen, "Expected ( before Expression ".to_string());
-        self.expression();
-        self.consume(Kind::RightParen, "Expected ( after Expression ".to_string());
-        self.consume(Kind::Semicolon, "Expected ; after Expression ".to_string());
-        self.emitByte(OpCode::OP_PRINT as u8);
+    fn function(&mut self, function: &Function, kind: FunctionKind) {
+        let name = Rc::new(function.name.name.clone());
+        self.begin_function(ObjFunction::new(Some(name), function.params.len()), kind);
+        self.scope_depth += 1;
+        for stmt in &function.body {
+            self.statement(stmt);
+        }
+        self.line = function.end_line;
+        self.emit_return();
+        self.scope_depth -= 1;
+
+        let mut compiled = self.functions.pop().unwrap();
+        self.kinds.pop();
+        optimizer::optimize(&mut compiled.chunk, self.options.opt_level, self.heap);
+        compiled.chunk.max_stack = optimizer::max_stack(&compiled.chunk, compiled.arity);
+        let upvalues = self.resolution.upvalues[&function.id].clone();
+        compiled.upvalue_count = upvalues.len();
+        compiled.locals = self.resolution.locals[&Some(function.id)].as_slice().into();
+        compiled.upvalue_names = self.resolution.upvalue_names[&function.id]
+            .as_slice()
+            .into();
+
+        self.line = function.name.line;
+        let constant = self.make_constnat(Value::from(ValueType::VAL_FUNCTION(Rc::new(compiled))));
+        self.emit_Bytes(OpCode::OP_CLOSURE as u8, constant);
+        for upvalue in upvalues {
+            self.emit_Bytes(upvalue.is_local as u8, upvalue.index);
+        }
     }
 
-    fn expression(&mut self) {
-        self.parsePrecedence(Presidence::PREC_ASSIGNMENT);
+    fn defineVariable(&mut self, name: &Identifier) {
+        // Locals live in the stack slot their initializer left behind.
+        if self.scope_depth > 0 {
+            return;
+        }
+        self.line = name.line;
+        let global = self.identifierConstant(name);
+        self.emit_Bytes(OpCode::OP_DEFINE_GLOBAL as u8, global);
     }
 
-    fn number(&mut self) {
-        //println!("{}",self.parser.previous.string);
-        let val = self.parser.previous.string.parse::<f64>().unwrap();
-        self.emit_constant(Value::from(val));
+    /// Discards the locals declared directly in `body`, innermost first.
+    fn endScope(&mut self, body: &[Stmt]) {
+        self.scope_depth -= 1;
+        for stmt in body.iter().rev() {
+            let declaration = match stmt {
+                Stmt::Var { id, .. } => *id,
+                Stmt::Function(function) => function.id,
+                Stmt::Class(class) => class.id,
+                _ => continue,
+            };
+            if self.resolution.captured.contains(&declaration) {
+                self.emitByte(OpCode::OP_CLOSE_UPVALUE as u8);
+            } else {
+                self.emitByte(OpCode::OP_POP as u8);
+            }
+        }
     }
 
-    fn emit_constant(&mut self, value: Value) {
-        let byte = self.make_constnat(value);
-        self.emit_Bytes(OpCode::Op_Constnats as u8, byte);
+    fn expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal { value, line } => {
+                self.line = *line;
+                match value {
+                    Literal::Number(n) => self.emit_constant(Value::from(*n)),
+                    Literal::String(s) => {
+                        let value = self.heap.alloc_string(s.clone());
+                        self.emit_constant(value)
+                    }
+                    Literal::Bool(true) => self.emitByte(OpCode::OP_TRUE as u8),
+                    Literal::Bool(false) => self.emitByte(OpCode::OP_FALSE as u8),
+                    Literal::Nil => self.emitByte(OpCode::OP_NIL as u8),
+                }
+            }
+            Expr::Grouping { expr } => self.expression(expr),
+            Expr::Unary { op, operand, line } => {
+                self.expression(operand);
+                self.line = *line;
+                match op {
+                    UnaryOp::Negate => self.emitByte(OpCode::OP_NEGATE as u8),
+                    UnaryOp::Not => self.emitByte(OpCode::OP_NOT as u8),
+                }
+            }
+            Expr::Binary {
+                op,
+                left,
+                right,
+                line,
+            } => {
+                self.expression(left);
+                self.expression(right);
+                self.line = *line;
+                self.binary(*op);
+            }
+            Expr::Logical {
+                op,
+                left,
+                right,
+                line,
+            } => {
+                self.expression(left);
+                self.line = *line;
+                match op {
+                    LogicalOp::And => {
+                        let end_jump = self.emitJump(OpCode::OP_JUMP_IF_FALSE);
+                        self.emitByte(OpCode::OP_POP as u8);
+                        self.expression(right);
+                        self.patchJump(end_jump);
+                    }
+                    LogicalOp::Or => {
+                        let else_jump = self.emitJump(OpCode::OP_JUMP_IF_FALSE);
+                        let end_jump = self.emitJump(OpCode::OP_JUMP);
+                        self.patchJump(else_jump);
+                        self.emitByte(OpCode::OP_POP as u8);
+                        self.expression(right);
+                        self.patchJump(end_jump);
+                    }
+                }
+            }
+            Expr::Variable { id, name } => {
+                self.line = name.line;
+                let (get, operand) = self.variableOperand(*id, name, false);
+                self.emit_Bytes(get as u8, operand);
+            }
+            Expr::Assign { id, name, value } => {
+                self.expression(value);
+                self.line = name.line;
+                let (set, operand) = self.variableOperand(*id, name, true);
+                self.emit_Bytes(set as u8, operand);
+            }
+            Expr::Call { callee, args, line } => self.call(callee, args, *line, false),
+            Expr::Get { object, name } => {
+                self.expression(object);
+                self.line = name.line;
+                self.property(OpCode::OP_GET_PROPERTY, name);
+            }
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expression(object);
+                self.expression(value);
+                self.line = name.line;
+                self.property(OpCode::OP_SET_PROPERTY, name);
+            }
+            Expr::This { id, line } => {
+                self.line = *line;
+                let this = Identifier {
+                    name: "this".to_string(),
+                    line: *line,
+                };
+                let (get, operand) = self.variableOperand(*id, &this, false);
+                self.emit_Bytes(get as u8, operand);
+            }
+        }
     }
 
-    fn match_token(&mut self, token: Kind) -> bool {
-        if !self.check(token) {
-            return false;
+    /// A call, or with `tail` one that replaces the running frame and so
+    /// also returns its result.
+    fn call(&mut self, callee: &Expr, args: &[Expr], line: usize, tail: bool) {
+        // `object.name(args)` calls the method without binding it.
+        if let Expr::Get { object, name } = callee {
+            return self.invoke(object, name, args, line, tail);
+        }
+        self.expression(callee);
+        for arg in args {
+            self.expression(arg);
+        }
+        self.line = line;
+        let op = if tail {
+            OpCode::OP_TAIL_CALL
+        } else {
+            OpCode::OP_CALL
         };
-        self.advance();
-        return true;
-    }
-    fn check(&self, token: Kind) -> bool {
-        self.parser.current.kind == token
+        self.emit_Bytes(op as u8, args.len() as u8);
     }
 
-    fn make_constnat(&mut self, value: Value) -> u8 {
-        let constnat = self
-            .current
-            .as_ref()
-            .unwrap()
-            .borrow_mut()
-            .addConstant(value) as u8;
-
-        if constnat > u8::MAX {
-            eprintln!("Too many constants");
-            return 0;
+    fn invoke(&mut self, object: &Expr, name: &Identifier, args: &[Expr], line: usize, tail: bool) {
+        self.expression(object);
+        for arg in args {
+            self.expression(arg);
         }
-        return constnat;
+        self.line = line;
+        let name = self.identifierConstant(name);
+        let op = if tail {
+            OpCode::OP_TAIL_INVOKE
+        } else {
+            OpCode::OP_INVOKE
+        };
+        self.emit_Bytes(op as u8, name);
+        self.emitByte(args.len() as u8);
+        let [high, low] = self.inline_cache();
+        self.emit_Bytes(high, low);
     }
 
-    fn grouping(&mut self) {
-        self.expression();
-        self.consume(Kind::RightParen, "Expected ) after expression".to_owned());
+    /// Emits a property opcode with its name operand and a fresh cache.
+    fn property(&mut self, op: OpCode, name: &Identifier) {
+        let name = self.identifierConstant(name);
+        self.emit_Bytes(op as u8, name);
+        let [high, low] = self.inline_cache();
+        self.emit_Bytes(high, low);
     }
 
-    pub fn unary(&mut self) {
-        let operator_type = self.parser.previous.kind.clone();
-        self.parsePrecedence(Presidence::PREC_UNARY);
-        match operator_type {
-            Kind::Minus => self.emitByte(OpCode::OP_NEGATE as u8),
-            Kind::Bang => self.emitByte(OpCode::OP_NOT as u8),
-            _ => (),
+    /// Gives the next property opcode its own slot in the function's caches.
+    fn inline_cache(&mut self) -> [u8; 2] {
+        let caches = &mut self.functions.last_mut().unwrap().caches;
+        caches.push(Default::default());
+        if caches.len() > u16::MAX as usize + 1 {
+            self.error("Too many property accesses in one function.");
+            return [0, 0];
         }
+        ((caches.len() - 1) as u16).to_be_bytes()
     }
 
-    pub fn binary(&mut self) {
-        let token_kind = self.parser.previous.kind.clone();
-        let rule = rule_for_token(&token_kind);
-        self.parsePrecedence(rule.precedence.next().unwrap());
-
-        use Kind::*;
-        match token_kind {
-            Plus => self.emitByte(OpCode::OP_ADD as u8),
-            Minus => self.emitByte(OpCode::OP_SUBTRACT as u8),
-            Bang => self.emitByte(OpCode::OP_NOT as u8),
-            Star => self.emitByte(OpCode::OP_MULTIPLY as u8),
-            Slash => self.emitByte(OpCode::OP_DIVIDE as u8),
-            EqualEqual => self.emitByte(OpCode::OP_EQUAL as u8),
-            BangEqual => self.emit_Bytes(OpCode::OP_EQUAL as u8, OpCode::OP_NOT as u8),
+    fn binary(&mut self, op: BinaryOp) {
+        use BinaryOp::*;
+        match op {
+            Add => self.emitByte(OpCode::OP_ADD as u8),
+            Subtract => self.emitByte(OpCode::OP_SUBTRACT as u8),
+            Multiply => self.emitByte(OpCode::OP_MULTIPLY as u8),
+            Divide => self.emitByte(OpCode::OP_DIVIDE as u8),
+            Equal => self.emitByte(OpCode::OP_EQUAL as u8),
+            NotEqual => self.emit_Bytes(OpCode::OP_EQUAL as u8, OpCode::OP_NOT as u8),
             Greater => self.emitByte(OpCode::OP_GREATER as u8),
             Less => self.emitByte(OpCode::OP_LESS as u8),
-            LessEqual => self.emit_Bytes(OpCode::OP_LESS as u8, OpCode::OP_NOT as u8),
-            GreaterEqual => self.emit_Bytes(OpCode::OP_GREATER as u8, OpCode::OP_NOT as u8),
-            LessEqual => self.emit_Bytes(OpCode::OP_LESS as u8, OpCode::OP_NOT as u8),
-            a => panic!("Unreachable: unexpected operator in binary({:?})", a),
+            GreaterEqual => self.emit_Bytes(OpCode::OP_LESS as u8, OpCode::OP_NOT as u8),
+            LessEqual => self.emit_Bytes(OpCode::OP_GREATER as u8, OpCode::OP_NOT as u8),
+        }
+    }
+
+    fn variableOperand(&mut self, id: ast::NodeId, name: &Identifier, set: bool) -> (OpCode, u8) {
+        match (self.resolution.bindings[&id], set) {
+            (Binding::Local(slot), false) => (OpCode::OP_GET_LOCAL, slot),
+            (Binding::Local(slot), true) => (OpCode::OP_SET_LOCAL, slot),
+            (Binding::Upvalue(index), false) => (OpCode::OP_GET_UPVALUE, index),
+            (Binding::Upvalue(index), true) => (OpCode::OP_SET_UPVALUE, index),
+            (Binding::Global, false) => (OpCode::OP_GET_GLOBAL, self.identifierConstant(name)),
+            (Binding::Global, true) => (OpCode::OP_SET_GLOBAL, self.identifierConstant(name)),
         }
     }
 
-    fn parsePrecedence(&mut self, precedence: Presidence) {
-        self.advance();
+    fn identifierConstant(&mut self, name: &Identifier) -> u8 {
+        let value = self.heap.alloc_string(name.name.clone());
+        self.make_constnat(value)
+    }
 
-        let prefix = rule_for_token(&self.parser.previous.kind).prefix;
-        if prefix == None {
-            println!("Expected expression {:?}", self.parser.previous.kind);
-            return;
+    fn emit_constant(&mut self, value: Value) {
+        let byte = self.make_constnat(value);
+        self.emit_Bytes(OpCode::Op_Constnats as u8, byte);
+    }
+
+    fn make_constnat(&mut self, value: Value) -> u8 {
+        // Interned strings (mostly global and property names) are worth
+        // sharing; numbers are not, since `0 == -0` would merge two
+        // different constants.
+        let existing = value.is_string().then(|| {
+            self.chunk()
+                .constants
+                .values
+                .iter()
+                .position(|constant| *constant == value)
+        });
+        let constnat = match existing.flatten() {
+            Some(index) => index,
+            None => self.chunk().addConstant(value),
+        };
+
+        if constnat > u8::MAX as usize {
+            self.error("Too many constants in one chunk.");
+            return 0;
         }
-        prefix.unwrap()(self);
+        constnat as u8
+    }
+
+    fn emitJump(&mut self, instruction: OpCode) -> usize {
+        self.emitByte(instruction as u8);
+        self.emit_Bytes(0xff, 0xff);
+        self.chunk().code.len() - 2
+    }
 
-        while precedence <= rule_for_token(&self.parser.current.kind).precedence {
-            // println!("While loop: {:?} <= {:?}", precedence, rule_for_token(&self.parser.current.kind).precedence);
-            self.advance();
-            let infix = rule_for_token(&self.parser.previous.kind).infix.unwrap();
-            infix(self);
+    fn patchJump(&mut self, offset: usize) {
+        // -2 to adjust for the bytecode for the jump offset itself.
+        let jump = self.chunk().code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
         }
+        let [high, low] = (jump as u16).to_be_bytes();
+        let code = Arc::make_mut(&mut self.chunk().code);
+        code[offset] = high;
+        code[offset + 1] = low;
+    }
+
+    fn emitLoop(&mut self, loop_start: usize) {
+        self.emitByte(OpCode::OP_LOOP as u8);
+        let offset = self.chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+        let [high, low] = (offset as u16).to_be_bytes();
+        self.emit_Bytes(high, low);
     }
 
     fn emit_Bytes(&mut self, byte_1: u8, byte_2: u8) {
@@ -311,66 +609,72 @@ impl Compiler {
         self.emitByte(byte_2);
     }
 
-    fn endCompiler(&mut self) {
+    fn begin_function(&mut self, mut function: ObjFunction, kind: FunctionKind) {
+        function.script = self.script.clone();
+        self.functions.push(function);
+        self.kinds.push(kind);
+    }
+
+    fn endCompiler(&mut self) -> ObjFunction {
         self.emit_return();
-        if !self.parser.has_error {
-            self.current
-                .as_ref()
-                .unwrap()
-                .borrow_mut()
-                .disassembleChunk("code");
-        }
+        let mut function = self.functions.pop().unwrap();
+        optimizer::optimize(&mut function.chunk, self.options.opt_level, self.heap);
+        function.chunk.max_stack = optimizer::max_stack(&function.chunk, function.arity);
+        function
     }
+
+    /// The implicit return: nil, or the receiver from an initializer.
     fn emit_return(&mut self) {
+        if self.kinds.last() == Some(&FunctionKind::Initializer) {
+            self.emit_Bytes(OpCode::OP_GET_LOCAL as u8, 0);
+        } else {
+            self.emitByte(OpCode::OP_NIL as u8);
+        }
+        self.emit_return_value();
+    }
+
+    fn emit_return_value(&mut self) {
         self.emitByte(OpCode::Return as u8);
     }
+
     fn emitByte(&mut self, byte: u8) {
-        self.current
-            .as_ref()
-            .unwrap()
-            .borrow_mut()
-            .write_chunk(byte, self.parser.previous.line);
-    }
-    pub fn consume(&mut self, token: Kind, message: String) {
-        if self.parser.current.kind == token {
-            self.advance();
-            return;
-        }
+        let line = self.line;
+        self.chunk().write_chunk(byte, line);
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.functions.last_mut().unwrap().chunk
+    }
 
-        self.errorAtCurrent(&message);
+    fn error(&mut self, message: &str) {
+        self.errorAt(self.line, "", message);
     }
 
-    pub fn advance(&mut self) {
-        self.parser.previous = self.parser.current.clone();
-        loop {
-            self.parser.current = self.scanner.next();
-            if self.parser.current.kind != Kind::Error {
-                break;
+    /// Prints the warnings not silenced by an `nlox-allow` comment, or fails
+    /// the compilation on them under `deny_warnings`.
+    fn report(&mut self, root: &cst::SyntaxNode, mut warnings: Vec<Warning>) {
+        let suppressions = Suppressions::from_tree(root, &self.source);
+        warnings.retain(|warning| !suppressions.allows(warning));
+        warnings.sort_by_key(|warning| warning.line);
+        for warning in warnings {
+            let message = format!("{} [{}]", warning.message, warning.lint);
+            if self.options.deny_warnings {
+                self.errorAt(warning.line, "", &message);
+            } else {
+                self.warningAt(warning.line, &message);
             }
-            let data = &self.parser.current.string.clone();
-            self.errorAtCurrent(data);
         }
     }
 
-    fn errorAtCurrent(&mut self, data: &String) {
-        self.errorAt(data);
+    fn warningAt(&mut self, line: usize, message: &str) {
+        self.diagnostics
+            .push(format!("[Line {}] Warning: {}", line, message));
     }
 
-    fn errorAt(&mut self, data: &String) {
-        let token = &self.parser.current;
-
-        // Don't bail out before printing!
-        eprintln!("[Line {}] Error", token.line);
-
-        if token.kind == Kind::Eof {
-            eprintln!(" at end: {}", data);
-        } else if token.kind == Kind::Error {
-            eprintln!(" error: {}", data);
-        } else {
-            eprintln!(" at '{}': {}", token.string, data);
-        }
-
-        self.parser.panic_mode = true;
-        self.parser.has_error = true;
+    fn errorAt(&mut self, line: usize, location: &str, message: &str) {
+        let error = format!("[Line {}] Error{}: {}", line, location, message);
+        self.diagnostics.push(error.clone());
+        self.errors.push(error);
+        self.has_error = true;
     }
 }
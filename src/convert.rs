@@ -0,0 +1,409 @@
+//! Conversions between Rust types and `Value`, for arguments, results and
+//! globals.
+//!
+//! `FromValue` checks the type and reports a mismatch as an
+//! `Error::Conversion` instead of panicking. Integers must be whole numbers
+//! within the target's range. Tuples of `IntoValue` types are argument
+//! lists for `Vm::call`, and closures over `FromValue` arguments that
+//! return `Result<impl IntoValue>` can be registered as natives, or as the
+//! methods and constructor of a `UserClass`.
+
+use std::collections::HashMap;
+
+use crate::embed::{Error, Object, Result, Value};
+
+pub trait FromValue: Sized {
+    fn from_value(value: Value) -> Result<Self>;
+}
+
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+fn mismatch<T>(expected: &'static str, value: &Value) -> Result<T> {
+    Err(Error::Conversion {
+        expected,
+        found: value.type_name(),
+    })
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> Result<Self> {
+        Ok(value)
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value) -> Result<Self> {
+        match value {
+            Value::Bool(bool) => Ok(bool),
+            _ => mismatch("bool", &value),
+        }
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: Value) -> Result<Self> {
+        match value {
+            Value::Number(number) => Ok(number),
+            _ => mismatch("number", &value),
+        }
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(value: Value) -> Result<Self> {
+        f64::from_value(value).map(|number| number as f32)
+    }
+}
+
+impl IntoValue for f32 {
+    fn into_value(self) -> Value {
+        Value::Number(self as f64)
+    }
+}
+
+macro_rules! integer {
+    ($($ty:ident),*) => {$(
+        impl FromValue for $ty {
+            fn from_value(value: Value) -> Result<Self> {
+                // One past `MAX`, which is a power of two and so exact, unlike
+                // `MAX as f64` for the 64-bit types.
+                let end = 2f64.powi(($ty::BITS - ($ty::MIN != 0) as u32) as i32);
+                match value {
+                    Value::Number(number)
+                        if number.fract() == 0.0
+                            && number >= $ty::MIN as f64
+                            && number < end =>
+                    {
+                        Ok(number as $ty)
+                    }
+                    _ => mismatch(stringify!($ty), &value),
+                }
+            }
+        }
+
+        impl IntoValue for $ty {
+            fn into_value(self) -> Value {
+                Value::Number(self as f64)
+            }
+        }
+    )*};
+}
+
+integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl FromValue for String {
+    fn from_value(value: Value) -> Result<Self> {
+        match value {
+            Value::String(string) => Ok(string),
+            _ => mismatch("string", &value),
+        }
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::String(self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::String(self.to_string())
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::Nil
+    }
+}
+
+/// `nil` is `None`.
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value) -> Result<Self> {
+        match value {
+            Value::Nil => Ok(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        match self {
+            Some(value) => value.into_value(),
+            None => Value::Nil,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: Value) -> Result<Self> {
+        match value {
+            Value::List(list) => list.into_iter().map(T::from_value).collect(),
+            _ => mismatch("list", &value),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        Value::List(self.into_iter().map(T::into_value).collect())
+    }
+}
+
+impl<T: FromValue> FromValue for HashMap<String, T> {
+    fn from_value(value: Value) -> Result<Self> {
+        match value {
+            Value::Map(map) => map
+                .into_iter()
+                .map(|(key, value)| Ok((key, T::from_value(value)?)))
+                .collect(),
+            _ => mismatch("map", &value),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for HashMap<String, T> {
+    fn into_value(self) -> Value {
+        Value::Map(
+            self.into_iter()
+                .map(|(key, value)| (key, value.into_value()))
+                .collect(),
+        )
+    }
+}
+
+impl FromValue for Object {
+    fn from_value(value: Value) -> Result<Self> {
+        match value {
+            Value::Object(object) => Ok(object),
+            _ => mismatch("object", &value),
+        }
+    }
+}
+
+impl IntoValue for Object {
+    fn into_value(self) -> Value {
+        Value::Object(self)
+    }
+}
+
+/// An argument list for `Vm::call`.
+pub trait IntoArgs {
+    fn into_args(self) -> Vec<Value>;
+}
+
+impl IntoArgs for Vec<Value> {
+    fn into_args(self) -> Vec<Value> {
+        self
+    }
+}
+
+impl IntoArgs for &[Value] {
+    fn into_args(self) -> Vec<Value> {
+        self.to_vec()
+    }
+}
+
+/// A Rust function that can be registered as a native taking `Args`, a
+/// tuple of its parameter types.
+pub trait NativeFunction<Args>: 'static {
+    const ARITY: usize;
+
+    fn invoke(&self, args: Vec<Value>) -> Result<Value>;
+}
+
+/// A Rust method of the host class `T` taking `Args`; it gets the
+/// instance's `T` as `&mut T` before them. With `Vm` for `T`, a native
+/// that gets the `Vm` running it.
+pub trait NativeMethod<T, Args>: 'static {
+    const ARITY: usize;
+
+    fn invoke(&self, receiver: &mut T, args: Vec<Value>) -> Result<Value>;
+}
+
+/// Builds the Rust value of a new instance of the host class `T` when a
+/// script calls the class with `Args`.
+pub trait Constructor<T, Args>: 'static {
+    const ARITY: usize;
+
+    fn construct(&self, args: Vec<Value>) -> Result<T>;
+}
+
+/// Implements the traits for tuples of each arity; the type parameters
+/// double as the names of the values they bind.
+macro_rules! tuples {
+    ($(($($arg:ident),*)),*) => {$(
+        impl<$($arg: IntoValue),*> IntoArgs for ($($arg,)*) {
+            #[allow(non_snake_case)]
+            fn into_args(self) -> Vec<Value> {
+                let ($($arg,)*) = self;
+                vec![$($arg.into_value()),*]
+            }
+        }
+
+        impl<Fun, R, $($arg),*> NativeFunction<($($arg,)*)> for Fun
+        where
+            Fun: Fn($($arg),*) -> Result<R> + 'static,
+            R: IntoValue,
+            $($arg: FromValue,)*
+        {
+            const ARITY: usize = <[&str]>::len(&[$(stringify!($arg)),*]);
+
+            #[allow(non_snake_case)]
+            fn invoke(&self, args: Vec<Value>) -> Result<Value> {
+                // The VM has checked the count against `ARITY`.
+                #[allow(unused_mut, unused_variables)]
+                let mut args = args.into_iter();
+                $(let $arg = $arg::from_value(args.next().unwrap())?;)*
+                self($($arg),*).map(R::into_value)
+            }
+        }
+
+        impl<Fun, T, R, $($arg),*> NativeMethod<T, ($($arg,)*)> for Fun
+        where
+            Fun: Fn(&mut T, $($arg),*) -> Result<R> + 'static,
+            R: IntoValue,
+            $($arg: FromValue,)*
+        {
+            const ARITY: usize = <[&str]>::len(&[$(stringify!($arg)),*]);
+
+            #[allow(non_snake_case)]
+            fn invoke(&self, receiver: &mut T, args: Vec<Value>) -> Result<Value> {
+                #[allow(unused_mut, unused_variables)]
+                let mut args = args.into_iter();
+                $(let $arg = $arg::from_value(args.next().unwrap())?;)*
+                self(receiver, $($arg),*).map(R::into_value)
+            }
+        }
+
+        impl<Fun, T, $($arg),*> Constructor<T, ($($arg,)*)> for Fun
+        where
+            Fun: Fn($($arg),*) -> Result<T> + 'static,
+            $($arg: FromValue,)*
+        {
+            const ARITY: usize = <[&str]>::len(&[$(stringify!($arg)),*]);
+
+            #[allow(non_snake_case)]
+            fn construct(&self, args: Vec<Value>) -> Result<T> {
+                #[allow(unused_mut, unused_variables)]
+                let mut args = args.into_iter();
+                $(let $arg = $arg::from_value(args.next().unwrap())?;)*
+                self($($arg),*)
+            }
+        }
+    )*};
+}
+
+tuples!(
+    (),
+    (A),
+    (A, B),
+    (A, B, C),
+    (A, B, C, D),
+    (A, B, C, D, E),
+    (A, B, C, D, E, F),
+    (A, B, C, D, E, F, G),
+    (A, B, C, D, E, F, G, H)
+);
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{FromValue, IntoValue};
+    use crate::embed::{Error, Value};
+
+    #[test]
+    fn integers_must_be_whole_and_in_range() {
+        assert_eq!(u8::from_value(Value::Number(255.0)), Ok(255));
+        assert_eq!(i64::from_value(Value::Number(-3.0)), Ok(-3));
+        for number in [256.0, -1.0, 0.5, f64::NAN] {
+            assert_eq!(
+                u8::from_value(Value::Number(number)),
+                Err(Error::Conversion {
+                    expected: "u8",
+                    found: "number",
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn integers_stop_at_the_64_bit_bounds() {
+        let (two_63, two_64) = (2f64.powi(63), 2f64.powi(64));
+        assert_eq!(i64::from_value(Value::Number(-two_63)), Ok(i64::MIN));
+        assert_eq!(u64::from_value(Value::Number(two_63)), Ok(1 << 63));
+        // The largest doubles below the bounds.
+        assert_eq!(
+            i64::from_value(Value::Number(two_63 - 1024.0)),
+            Ok(i64::MAX - 1023)
+        );
+        assert_eq!(
+            u64::from_value(Value::Number(two_64 - 2048.0)),
+            Ok(u64::MAX - 2047)
+        );
+
+        let out_of_range = |expected| Error::Conversion {
+            expected,
+            found: "number",
+        };
+        assert_eq!(
+            i64::from_value(Value::Number(two_63)),
+            Err(out_of_range("i64"))
+        );
+        assert_eq!(
+            i64::from_value(Value::Number(-two_63 - 2048.0)),
+            Err(out_of_range("i64"))
+        );
+        assert_eq!(
+            u64::from_value(Value::Number(two_64)),
+            Err(out_of_range("u64"))
+        );
+    }
+
+    #[test]
+    fn containers_convert_element_by_element() {
+        let value = vec![Some("a"), None].into_value();
+        assert_eq!(
+            value,
+            Value::List(vec![Value::String("a".to_string()), Value::Nil])
+        );
+        assert_eq!(
+            Vec::<Option<String>>::from_value(value.clone()),
+            Ok(vec![Some("a".to_string()), None])
+        );
+        assert_eq!(
+            Vec::<String>::from_value(value),
+            Err(Error::Conversion {
+                expected: "string",
+                found: "nil",
+            })
+        );
+
+        let map = HashMap::from([("x".to_string(), 1.5)]);
+        assert_eq!(HashMap::from_value(map.clone().into_value()), Ok(map));
+        assert!(HashMap::<String, f64>::from_value(Value::Bool(true)).is_err());
+    }
+}
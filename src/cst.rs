@@ -0,0 +1,925 @@
+//! Lossless concrete syntax tree.
+//!
+//! Every byte of the source ends up in exactly one token or trivia piece, so
+//! printing a tree gives back the original text. Trivia is attached to the
+//! token that follows it; whatever trails the last token hangs off `Eof`.
+
+use std::{fmt, ops::Range};
+
+use crate::scanner::Scanner;
+pub use crate::{
+    lint::{Lint, Warning},
+    scanner::TriviaKind,
+    token::Kind,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeKind {
+    Program,
+    VarDecl,
+    FunDecl,
+    ClassDecl,
+    Method,
+    ParamList,
+    ExprStmt,
+    PrintStmt,
+    IfStmt,
+    WhileStmt,
+    ForStmt,
+    ReturnStmt,
+    Block,
+    Literal,
+    Grouping,
+    Unary,
+    Binary,
+    Variable,
+    Assign,
+    Call,
+    ArgList,
+    Get,
+    Set,
+    This,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxTrivia {
+    pub kind: TriviaKind,
+    pub text: String,
+    pub span: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxToken {
+    pub kind: Kind,
+    pub text: String,
+    pub span: Range<usize>,
+    pub line: usize,
+    pub leading: Vec<SyntaxTrivia>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxNode {
+    pub kind: NodeKind,
+    pub children: Vec<SyntaxElement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub line: usize,
+    pub span: Range<usize>,
+    /// Kind and text of the offending token.
+    pub kind: Kind,
+    pub lexeme: String,
+    pub message: String,
+}
+
+pub struct Parse {
+    pub root: SyntaxNode,
+    pub errors: Vec<SyntaxError>,
+    pub warnings: Vec<Warning>,
+}
+
+impl SyntaxElement {
+    fn span(&self) -> Option<Range<usize>> {
+        match self {
+            SyntaxElement::Node(node) => node.span(),
+            SyntaxElement::Token(token) => Some(token.full_span()),
+        }
+    }
+
+    fn write_text(&self, out: &mut String) {
+        match self {
+            SyntaxElement::Node(node) => node.write_text(out),
+            SyntaxElement::Token(token) => token.write_text(out),
+        }
+    }
+}
+
+impl SyntaxToken {
+    /// Byte range of the token with its leading trivia.
+    pub fn full_span(&self) -> Range<usize> {
+        let start = self
+            .leading
+            .first()
+            .map_or(self.span.start, |trivia| trivia.span.start);
+        start..self.span.end
+    }
+
+    fn write_text(&self, out: &mut String) {
+        for trivia in &self.leading {
+            out.push_str(&trivia.text);
+        }
+        out.push_str(&self.text);
+    }
+}
+
+impl SyntaxNode {
+    fn new(kind: NodeKind, children: Vec<SyntaxElement>) -> Self {
+        Self { kind, children }
+    }
+
+    /// Byte range of the node's text, leading trivia included, so that it
+    /// holds the range of every child, trivia and token.
+    pub fn span(&self) -> Option<Range<usize>> {
+        let mut spans = self.children.iter().filter_map(SyntaxElement::span);
+        let first = spans.next()?;
+        let end = spans.next_back().map_or(first.end, |last| last.end);
+        Some(first.start..end)
+    }
+
+    pub fn child_nodes(&self) -> impl Iterator<Item = &SyntaxNode> {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    pub fn child_node(&self, index: usize) -> &SyntaxNode {
+        self.child_nodes()
+            .nth(index)
+            .expect("malformed syntax tree")
+    }
+
+    pub fn child_token(&self, kind: Kind) -> Option<&SyntaxToken> {
+        self.children.iter().find_map(|child| match child {
+            SyntaxElement::Token(token) if token.kind == kind => Some(token),
+            _ => None,
+        })
+    }
+
+    pub fn first_token(&self) -> &SyntaxToken {
+        match &self.children[0] {
+            SyntaxElement::Token(token) => token,
+            SyntaxElement::Node(node) => node.first_token(),
+        }
+    }
+
+    pub fn tokens(&self) -> Vec<&SyntaxToken> {
+        let mut tokens = vec![];
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens<'a>(&'a self, tokens: &mut Vec<&'a SyntaxToken>) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.collect_tokens(tokens),
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+    }
+
+    /// Source text of the node, trivia included.
+    pub fn text(&self) -> String {
+        let mut out = String::new();
+        self.write_text(&mut out);
+        out
+    }
+
+    fn write_text(&self, out: &mut String) {
+        for child in &self.children {
+            child.write_text(out);
+        }
+    }
+
+    /// Indented dump of the tree, one node, token or trivia per line.
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        self.dump_into(&mut out, 0);
+        out
+    }
+
+    fn dump_into(&self, out: &mut String, depth: usize) {
+        let span = self.span().unwrap_or(0..0);
+        out.push_str(&format!(
+            "{:indent$}{:?}@{}..{}\n",
+            "",
+            self.kind,
+            span.start,
+            span.end,
+            indent = depth * 2
+        ));
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.dump_into(out, depth + 1),
+                SyntaxElement::Token(token) => {
+                    for trivia in &token.leading {
+                        out.push_str(&format!(
+                            "{:indent$}{:?}@{}..{} {:?}\n",
+                            "",
+                            trivia.kind,
+                            trivia.span.start,
+                            trivia.span.end,
+                            trivia.text,
+                            indent = (depth + 1) * 2
+                        ));
+                    }
+                    out.push_str(&format!(
+                        "{:indent$}{:?}@{}..{} {:?}\n",
+                        "",
+                        token.kind,
+                        token.span.start,
+                        token.span.end,
+                        token.text,
+                        indent = (depth + 1) * 2
+                    ));
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text())
+    }
+}
+
+/// Parses `source` into a lossless tree, recovering from syntax errors.
+pub fn parse(source: &str) -> Parse {
+    let (tokens, warnings) = lex(source);
+    let mut parser = CstParser {
+        tokens,
+        pos: 0,
+        errors: vec![],
+        panic_mode: false,
+    };
+    let root = parser.program();
+    Parse {
+        root,
+        errors: parser.errors,
+        warnings,
+    }
+}
+
+fn lex(source: &str) -> (Vec<SyntaxToken>, Vec<Warning>) {
+    let mut scanner = Scanner::new(source.to_string());
+    let mut tokens = vec![];
+    loop {
+        let token = scanner.next();
+        let leading = scanner
+            .trivia
+            .iter()
+            .map(|trivia| SyntaxTrivia {
+                kind: trivia.kind,
+                text: source[trivia.span.clone()].to_string(),
+                span: trivia.span.clone(),
+            })
+            .collect();
+        let eof = token.kind == Kind::Eof;
+        tokens.push(SyntaxToken {
+            text: source[token.span.clone()].to_string(),
+            kind: token.kind,
+            span: token.span,
+            line: token.line,
+            leading,
+        });
+        if eof {
+            return (tokens, scanner.warnings);
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, PartialOrd, Debug)]
+#[repr(usize)]
+#[allow(non_camel_case_types)]
+pub enum Presidence {
+    PREC_NONE,
+    PREC_ASSIGNMENT, // =
+    PREC_OR,         // or
+    PREC_AND,        // and
+    PREC_EQUALITY,   // == !=
+    PREC_COMPARISON, // < > <= >=
+    PREC_TERM,       // + -
+    PREC_FACTOR,     // * /
+    PREC_UNARY,      // ! -
+    PREC_CALL,       // . ()
+    PREC_PRIMARY,
+}
+impl Presidence {
+    pub fn next(&self) -> Option<Presidence> {
+        use Presidence::*;
+
+        match self {
+            PREC_NONE => Some(PREC_ASSIGNMENT),
+            PREC_ASSIGNMENT => Some(PREC_OR),
+            PREC_OR => Some(PREC_AND),
+            PREC_AND => Some(PREC_EQUALITY),
+            PREC_EQUALITY => Some(PREC_COMPARISON),
+            PREC_COMPARISON => Some(PREC_TERM),
+            PREC_TERM => Some(PREC_FACTOR),
+            PREC_FACTOR => Some(PREC_UNARY),
+            PREC_UNARY => Some(PREC_CALL),
+            PREC_CALL => Some(PREC_PRIMARY),
+            PREC_PRIMARY => None, // No next after highest precedence
+        }
+    }
+}
+
+/// Binding power of `kind` when it appears after an operand.
+pub fn infix_precedence(kind: &Kind) -> Presidence {
+    use Kind::*;
+    match kind {
+        Minus | Plus => Presidence::PREC_TERM,
+        Slash | Star => Presidence::PREC_FACTOR,
+        BangEqual | EqualEqual => Presidence::PREC_EQUALITY,
+        Greater | GreaterEqual | Less | LessEqual => Presidence::PREC_COMPARISON,
+        And => Presidence::PREC_AND,
+        Or => Presidence::PREC_OR,
+        LeftParen | Dot => Presidence::PREC_CALL,
+        _ => Presidence::PREC_NONE,
+    }
+}
+
+struct CstParser {
+    tokens: Vec<SyntaxToken>,
+    pos: usize,
+    errors: Vec<SyntaxError>,
+    panic_mode: bool,
+}
+
+impl CstParser {
+    fn program(&mut self) -> SyntaxNode {
+        let mut children = vec![];
+        while !self.at(Kind::Eof) {
+            self.declaration(&mut children);
+        }
+        children.push(self.bump());
+        SyntaxNode::new(NodeKind::Program, children)
+    }
+
+    /// Parses one declaration into `children`, followed by an `Error` node
+    /// holding whatever had to be skipped to recover from a syntax error.
+    fn declaration(&mut self, children: &mut Vec<SyntaxElement>) {
+        let start = self.pos;
+        let node = match self.current().kind {
+            Kind::Class => self.class_declaration(),
+            Kind::Fun => self.fun_declaration(),
+            Kind::Var => self.var_declaration(),
+            _ => self.statement(),
+        };
+        children.push(SyntaxElement::Node(node));
+
+        if self.panic_mode || self.pos == start {
+            let skipped = self.synchronize(self.pos == start);
+            if !skipped.is_empty() {
+                children.push(SyntaxElement::Node(SyntaxNode::new(
+                    NodeKind::Error,
+                    skipped,
+                )));
+            }
+        }
+    }
+
+    fn synchronize(&mut self, must_advance: bool) -> Vec<SyntaxElement> {
+        self.panic_mode = false;
+        let mut skipped = vec![];
+        if must_advance && !self.at(Kind::Eof) {
+            skipped.push(self.bump());
+        }
+        while !self.at(Kind::Eof) {
+            if let Some(SyntaxElement::Token(last)) = skipped.last()
+                && last.kind == Kind::Semicolon
+            {
+                break;
+            }
+            match self.current().kind {
+                Kind::Class
+                | Kind::Fun
+                | Kind::Var
+                | Kind::For
+                | Kind::If
+                | Kind::While
+                | Kind::Print
+                | Kind::Return
+                | Kind::RightBrace => break,
+                _ => skipped.push(self.bump()),
+            }
+        }
+        skipped
+    }
+
+    fn class_declaration(&mut self) -> SyntaxNode {
+        let mut children = vec![self.bump()];
+        self.expect(Kind::IdentifierLiteral, "Expect class name.", &mut children);
+        self.expect(
+            Kind::LeftBrace,
+            "Expect '{' before class body.",
+            &mut children,
+        );
+        while !self.at(Kind::RightBrace) && !self.at(Kind::Eof) {
+            let start = self.pos;
+            let method = self.method();
+            children.push(SyntaxElement::Node(method));
+            // Nothing parsed as a method; leave the rest to recovery.
+            if self.pos == start {
+                break;
+            }
+        }
+        self.expect(
+            Kind::RightBrace,
+            "Expect '}' after class body.",
+            &mut children,
+        );
+        SyntaxNode::new(NodeKind::ClassDecl, children)
+    }
+
+    fn method(&mut self) -> SyntaxNode {
+        let mut children = vec![];
+        self.expect(
+            Kind::IdentifierLiteral,
+            "Expect method name.",
+            &mut children,
+        );
+        self.function(&mut children);
+        SyntaxNode::new(NodeKind::Method, children)
+    }
+
+    fn fun_declaration(&mut self) -> SyntaxNode {
+        let mut children = vec![self.bump()];
+        self.expect(
+            Kind::IdentifierLiteral,
+            "Expect function name.",
+            &mut children,
+        );
+        self.function(&mut children);
+        SyntaxNode::new(NodeKind::FunDecl, children)
+    }
+
+    /// Parameter list and body shared by functions and methods.
+    fn function(&mut self, children: &mut Vec<SyntaxElement>) {
+        let mut params = vec![];
+        self.expect(
+            Kind::LeftParen,
+            "Expect '(' after function name.",
+            &mut params,
+        );
+        if !self.at(Kind::RightParen) {
+            let mut arity = 0;
+            loop {
+                arity += 1;
+                if arity > 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                self.expect(
+                    Kind::IdentifierLiteral,
+                    "Expect parameter name.",
+                    &mut params,
+                );
+                if !self.eat(Kind::Comma, &mut params) {
+                    break;
+                }
+            }
+        }
+        self.expect(
+            Kind::RightParen,
+            "Expect ')' after parameters.",
+            &mut params,
+        );
+        children.push(SyntaxElement::Node(SyntaxNode::new(
+            NodeKind::ParamList,
+            params,
+        )));
+
+        if self.at(Kind::LeftBrace) {
+            children.push(SyntaxElement::Node(self.block()));
+        } else {
+            self.error_at_current("Expect '{' before function body.");
+        }
+    }
+
+    fn var_declaration(&mut self) -> SyntaxNode {
+        let mut children = vec![self.bump()];
+        self.expect(
+            Kind::IdentifierLiteral,
+            "Expect variable name.",
+            &mut children,
+        );
+        if self.eat(Kind::Equal, &mut children) {
+            children.push(SyntaxElement::Node(
+                self.expression(Presidence::PREC_ASSIGNMENT),
+            ));
+        }
+        self.expect(
+            Kind::Semicolon,
+            "Expect ';' after variable declaration.",
+            &mut children,
+        );
+        SyntaxNode::new(NodeKind::VarDecl, children)
+    }
+
+    fn statement(&mut self) -> SyntaxNode {
+        match self.current().kind {
+            Kind::Print => self.print_statement(),
+            Kind::If => self.if_statement(),
+            Kind::While => self.while_statement(),
+            Kind::For => self.for_statement(),
+            Kind::Return => self.return_statement(),
+            Kind::LeftBrace => self.block(),
+            _ => self.expression_statement(),
+        }
+    }
+
+    fn print_statement(&mut self) -> SyntaxNode {
+        let mut children = vec![self.bump()];
+        self.expect(
+            Kind::LeftParen,
+            "Expected ( before Expression ",
+            &mut children,
+        );
+        children.push(SyntaxElement::Node(
+            self.expression(Presidence::PREC_ASSIGNMENT),
+        ));
+        self.expect(
+            Kind::RightParen,
+            "Expected ( after Expression ",
+            &mut children,
+        );
+        self.expect(
+            Kind::Semicolon,
+            "Expected ; after Expression ",
+            &mut children,
+        );
+        SyntaxNode::new(NodeKind::PrintStmt, children)
+    }
+
+    fn if_statement(&mut self) -> SyntaxNode {
+        let mut children = vec![self.bump()];
+        self.condition("if", &mut children);
+        children.push(SyntaxElement::Node(self.statement()));
+        if self.eat(Kind::Else, &mut children) {
+            children.push(SyntaxElement::Node(self.statement()));
+        }
+        SyntaxNode::new(NodeKind::IfStmt, children)
+    }
+
+    fn while_statement(&mut self) -> SyntaxNode {
+        let mut children = vec![self.bump()];
+        self.condition("while", &mut children);
+        children.push(SyntaxElement::Node(self.statement()));
+        SyntaxNode::new(NodeKind::WhileStmt, children)
+    }
+
+    fn condition(&mut self, keyword: &str, children: &mut Vec<SyntaxElement>) {
+        self.expect(
+            Kind::LeftParen,
+            &format!("Expect '(' after '{}'.", keyword),
+            children,
+        );
+        children.push(SyntaxElement::Node(
+            self.expression(Presidence::PREC_ASSIGNMENT),
+        ));
+        self.expect(Kind::RightParen, "Expect ')' after condition.", children);
+    }
+
+    fn for_statement(&mut self) -> SyntaxNode {
+        let mut children = vec![self.bump()];
+        self.expect(Kind::LeftParen, "Expect '(' after 'for'.", &mut children);
+        match self.current().kind {
+            Kind::Semicolon => children.push(self.bump()),
+            Kind::Var => children.push(SyntaxElement::Node(self.var_declaration())),
+            _ => children.push(SyntaxElement::Node(self.expression_statement())),
+        }
+        if !self.at(Kind::Semicolon) {
+            children.push(SyntaxElement::Node(
+                self.expression(Presidence::PREC_ASSIGNMENT),
+            ));
+        }
+        self.expect(
+            Kind::Semicolon,
+            "Expect ';' after loop condition.",
+            &mut children,
+        );
+        if !self.at(Kind::RightParen) {
+            children.push(SyntaxElement::Node(
+                self.expression(Presidence::PREC_ASSIGNMENT),
+            ));
+        }
+        self.expect(
+            Kind::RightParen,
+            "Expect ')' after for clauses.",
+            &mut children,
+        );
+        children.push(SyntaxElement::Node(self.statement()));
+        SyntaxNode::new(NodeKind::ForStmt, children)
+    }
+
+    fn return_statement(&mut self) -> SyntaxNode {
+        let mut children = vec![self.bump()];
+        if !self.at(Kind::Semicolon) {
+            children.push(SyntaxElement::Node(
+                self.expression(Presidence::PREC_ASSIGNMENT),
+            ));
+        }
+        self.expect(
+            Kind::Semicolon,
+            "Expect ';' after return value.",
+            &mut children,
+        );
+        SyntaxNode::new(NodeKind::ReturnStmt, children)
+    }
+
+    fn block(&mut self) -> SyntaxNode {
+        let mut children = vec![self.bump()];
+        while !self.at(Kind::RightBrace) && !self.at(Kind::Eof) {
+            self.declaration(&mut children);
+        }
+        self.expect(Kind::RightBrace, "Expect '}' after block.", &mut children);
+        SyntaxNode::new(NodeKind::Block, children)
+    }
+
+    fn expression_statement(&mut self) -> SyntaxNode {
+        let mut children = vec![SyntaxElement::Node(
+            self.expression(Presidence::PREC_ASSIGNMENT),
+        )];
+        self.expect(
+            Kind::Semicolon,
+            "Expect ';' after expression.",
+            &mut children,
+        );
+        SyntaxNode::new(NodeKind::ExprStmt, children)
+    }
+
+    fn expression(&mut self, precedence: Presidence) -> SyntaxNode {
+        let can_assign = precedence <= Presidence::PREC_ASSIGNMENT;
+        let mut lhs = self.prefix(can_assign);
+        while precedence <= infix_precedence(&self.current().kind) {
+            lhs = if self.at(Kind::LeftParen) {
+                self.call(lhs)
+            } else if self.at(Kind::Dot) {
+                self.property(lhs, can_assign)
+            } else {
+                let operator = self.current().kind.clone();
+                let op = self.bump();
+                let next = infix_precedence(&operator).next().unwrap();
+                let rhs = self.expression(next);
+                SyntaxNode::new(
+                    NodeKind::Binary,
+                    vec![SyntaxElement::Node(lhs), op, SyntaxElement::Node(rhs)],
+                )
+            };
+        }
+
+        if can_assign && self.at(Kind::Equal) {
+            self.error_at_current("Invalid assignment target.");
+            let mut children = vec![SyntaxElement::Node(lhs), self.bump()];
+            children.push(SyntaxElement::Node(
+                self.expression(Presidence::PREC_ASSIGNMENT),
+            ));
+            lhs = SyntaxNode::new(NodeKind::Error, children);
+        }
+        lhs
+    }
+
+    fn call(&mut self, callee: SyntaxNode) -> SyntaxNode {
+        let mut args = vec![self.bump()];
+        if !self.at(Kind::RightParen) {
+            let mut count = 0;
+            loop {
+                count += 1;
+                if count > 255 {
+                    self.error_at_current("Can't have more than 255 arguments.");
+                }
+                args.push(SyntaxElement::Node(
+                    self.expression(Presidence::PREC_ASSIGNMENT),
+                ));
+                if !self.eat(Kind::Comma, &mut args) {
+                    break;
+                }
+            }
+        }
+        self.expect(Kind::RightParen, "Expect ')' after arguments.", &mut args);
+        SyntaxNode::new(
+            NodeKind::Call,
+            vec![
+                SyntaxElement::Node(callee),
+                SyntaxElement::Node(SyntaxNode::new(NodeKind::ArgList, args)),
+            ],
+        )
+    }
+
+    /// `object.name`, or `object.name = value` where assignment is allowed.
+    fn property(&mut self, object: SyntaxNode, can_assign: bool) -> SyntaxNode {
+        let mut children = vec![SyntaxElement::Node(object), self.bump()];
+        self.expect(
+            Kind::IdentifierLiteral,
+            "Expect property name after '.'.",
+            &mut children,
+        );
+        if !(can_assign && self.at(Kind::Equal)) {
+            return SyntaxNode::new(NodeKind::Get, children);
+        }
+        children.push(self.bump());
+        children.push(SyntaxElement::Node(
+            self.expression(Presidence::PREC_ASSIGNMENT),
+        ));
+        SyntaxNode::new(NodeKind::Set, children)
+    }
+
+    fn prefix(&mut self, can_assign: bool) -> SyntaxNode {
+        match self.current().kind {
+            Kind::NumberLiteral | Kind::StringLiteral | Kind::True | Kind::False | Kind::Nil => {
+                SyntaxNode::new(NodeKind::Literal, vec![self.bump()])
+            }
+            Kind::This => SyntaxNode::new(NodeKind::This, vec![self.bump()]),
+            Kind::IdentifierLiteral => {
+                let variable = SyntaxNode::new(NodeKind::Variable, vec![self.bump()]);
+                if !(can_assign && self.at(Kind::Equal)) {
+                    return variable;
+                }
+                let mut children = vec![SyntaxElement::Node(variable), self.bump()];
+                children.push(SyntaxElement::Node(
+                    self.expression(Presidence::PREC_ASSIGNMENT),
+                ));
+                SyntaxNode::new(NodeKind::Assign, children)
+            }
+            Kind::LeftParen => {
+                let mut children = vec![self.bump()];
+                children.push(SyntaxElement::Node(
+                    self.expression(Presidence::PREC_ASSIGNMENT),
+                ));
+                self.expect(
+                    Kind::RightParen,
+                    "Expected ) after expression",
+                    &mut children,
+                );
+                SyntaxNode::new(NodeKind::Grouping, children)
+            }
+            Kind::Minus | Kind::Bang => {
+                let op = self.bump();
+                let operand = self.expression(Presidence::PREC_UNARY);
+                SyntaxNode::new(NodeKind::Unary, vec![op, SyntaxElement::Node(operand)])
+            }
+            _ => {
+                self.error_at_current("Expected expression");
+                // Never swallow a token that could close the statement, so
+                // recovery lines up with what follows.
+                let children = match self.current().kind {
+                    Kind::RightParen | Kind::RightBrace | Kind::Semicolon | Kind::Eof => vec![],
+                    _ => vec![self.bump()],
+                };
+                SyntaxNode::new(NodeKind::Error, children)
+            }
+        }
+    }
+
+    fn expect(&mut self, kind: Kind, message: &str, children: &mut Vec<SyntaxElement>) {
+        if !self.eat(kind, children) {
+            self.error_at_current(message);
+        }
+    }
+
+    fn eat(&mut self, kind: Kind, children: &mut Vec<SyntaxElement>) -> bool {
+        if !self.at(kind) {
+            return false;
+        }
+        children.push(self.bump());
+        true
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+
+        let token = self.current();
+        // The scanner hands back unusable input as `Error` tokens; report
+        // what is wrong with it rather than what the grammar wanted.
+        let message = match token.kind {
+            Kind::Error if token.text.starts_with('"') => "Unterminated string.",
+            Kind::Error => "Unexpected character.",
+            _ => message,
+        };
+        let error = SyntaxError {
+            line: token.line,
+            span: token.span.clone(),
+            kind: token.kind.clone(),
+            lexeme: token.text.clone(),
+            message: message.to_string(),
+        };
+        self.errors.push(error);
+    }
+
+    fn current(&self) -> &SyntaxToken {
+        &self.tokens[self.pos]
+    }
+
+    fn at(&self, kind: Kind) -> bool {
+        self.current().kind == kind
+    }
+
+    fn bump(&mut self) -> SyntaxElement {
+        let token = self.tokens[self.pos].clone();
+        if token.kind != Kind::Eof {
+            self.pos += 1;
+        }
+        SyntaxElement::Token(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NodeKind, SyntaxElement, SyntaxNode, parse};
+
+    fn round_trip(source: &str) {
+        assert_eq!(parse(source).root.text(), source);
+    }
+
+    #[test]
+    fn round_trips_valid_programs() {
+        round_trip("");
+        round_trip("print(5+5);");
+        round_trip("  print ( 1 + 2 * -3 ) ;\n\n");
+        round_trip("// leading comment\nprint(\"a\" + \"b\"); // trailing\n// eof comment");
+        round_trip("print(!(1.5 >= 2) == true);\r\n\tprint(nil);");
+        round_trip("print(größe);");
+    }
+
+    #[test]
+    fn round_trips_broken_programs() {
+        round_trip("print(");
+        round_trip("print(1 +);");
+        round_trip("5; print 1");
+        round_trip("print(\"unterminated);\n");
+        round_trip("print(1) @ # $");
+        round_trip("1.");
+    }
+
+    #[test]
+    fn builds_expression_structure() {
+        let parse = parse("print(1 + 2 * 3);");
+        assert!(parse.errors.is_empty());
+
+        let SyntaxElement::Node(stmt) = &parse.root.children[0] else {
+            panic!("expected a statement node");
+        };
+        assert_eq!(stmt.kind, NodeKind::PrintStmt);
+        let SyntaxElement::Node(sum) = &stmt.children[2] else {
+            panic!("expected an expression node");
+        };
+        assert_eq!(sum.kind, NodeKind::Binary);
+        assert_eq!(sum.text(), "1 + 2 * 3");
+        assert_eq!(sum.span(), Some(6..15));
+        let SyntaxElement::Node(product) = &sum.children[2] else {
+            panic!("expected the product on the right");
+        };
+        assert_eq!(product.kind, NodeKind::Binary);
+        assert_eq!(product.text(), " 2 * 3");
+    }
+
+    #[test]
+    fn nodes_hold_the_ranges_of_their_children() {
+        fn check(node: &SyntaxNode) {
+            let span = node.span().unwrap();
+            for child in &node.children {
+                let inner = match child {
+                    SyntaxElement::Node(child) => {
+                        check(child);
+                        child.span().unwrap()
+                    }
+                    SyntaxElement::Token(token) => {
+                        for trivia in &token.leading {
+                            assert!(span.start <= trivia.span.start && trivia.span.end <= span.end);
+                        }
+                        token.span.clone()
+                    }
+                };
+                assert!(span.start <= inner.start && inner.end <= span.end);
+            }
+        }
+        let source = "var a = 1;\n// note\nfun f(x) { return  x  +  a; }\nprint  f( 2 ) ;";
+        let parse = parse(source);
+        check(&parse.root);
+        assert_eq!(parse.root.span(), Some(0..source.len()));
+
+        let print = parse.root.child_node(2);
+        assert_eq!(print.span(), Some(48..source.len()));
+        assert_eq!(&source[print.span().unwrap()], print.text());
+    }
+
+    #[test]
+    fn keeps_trivia_spans() {
+        let parse = parse("print(1); // done\n");
+        let tokens = parse.root.tokens();
+        let eof = tokens.last().unwrap();
+        assert_eq!(eof.leading.len(), 3);
+        assert_eq!(eof.leading[1].text, "// done");
+        assert_eq!(eof.leading[1].span, 10..17);
+    }
+
+    #[test]
+    fn reports_errors_without_losing_tokens() {
+        let parse = parse("print(1 +);");
+        assert_eq!(parse.errors.len(), 1);
+        assert_eq!(parse.errors[0].message, "Expected expression");
+        assert_eq!(parse.root.tokens().len(), 7);
+    }
+}
@@ -0,0 +1,177 @@
+//! Pausing scripts as they run, for debuggers.
+//!
+//! A `DebugHook` attached to a `Vm` is called each time the script reaches
+//! a new line, or another frame, with a `Paused` view of the VM. While the
+//! hook runs the script waits: the hook can read the call stack, the locals
+//! of any frame and the globals, and evaluate expressions, then returns to
+//! let the script go on. Whether a line is worth stopping at is up to the
+//! hook; a `Stepper` keeps the breakpoints and stepping state most hooks
+//! want. Detached, a hook costs nothing: the dispatch loops only look for
+//! one at the checkpoints where they already step the collector and check
+//! limits, and attaching one makes every instruction a checkpoint.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+pub use console::DebugConsole;
+pub use dap::serve as serve_dap;
+
+use crate::{
+    embed::{Result, Value},
+    limits::Limit,
+    vm::VM,
+};
+
+mod console;
+mod dap;
+
+pub trait DebugHook {
+    /// Called before the first instruction of each line a frame reaches.
+    fn on_line(&mut self, paused: &mut Paused<'_>);
+}
+
+/// A frame of the paused call stack.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame {
+    /// The function's name, or `script` for the top level.
+    pub function: String,
+    /// The name the function's script was run under.
+    pub script: String,
+    /// The line running, or for callers the line of the call.
+    pub line: usize,
+}
+
+/// The VM as a `DebugHook` sees it. Frames are numbered from the innermost,
+/// the one paused, at zero.
+pub struct Paused<'a> {
+    pub(crate) vm: &'a mut VM,
+}
+
+impl Paused<'_> {
+    /// The line about to run.
+    pub fn line(&self) -> usize {
+        self.vm.debug_line(0)
+    }
+
+    /// The name the paused function's script was run under.
+    pub fn script(&self) -> &str {
+        self.vm.debug_script()
+    }
+
+    /// Frames on the call stack, the script's own included.
+    pub fn depth(&self) -> usize {
+        self.vm.debug_depth()
+    }
+
+    /// The call stack, innermost first.
+    pub fn stack(&self) -> Vec<StackFrame> {
+        self.vm.debug_stack()
+    }
+
+    /// The locals in scope in `frame`, parameters first, each with its
+    /// current value. Empty for a frame that does not exist.
+    pub fn locals(&mut self, frame: usize) -> Vec<(String, Value)> {
+        let locals = self.vm.debug_locals(frame);
+        locals
+            .into_iter()
+            .map(|(name, value)| (name, self.vm.export(&value)))
+            .collect()
+    }
+
+    /// Every global, by name.
+    pub fn globals(&mut self) -> Vec<(String, Value)> {
+        self.vm.debug_globals()
+    }
+
+    /// Evaluates `expression` as if it were written in `frame`, which can
+    /// read the frame's locals (but not `this` or its upvalues) and any
+    /// global. Assigning a local changes only the expression's copy.
+    pub fn eval(&mut self, expression: &str, frame: usize) -> Result<Value> {
+        self.vm.debug_eval(expression, frame)
+    }
+
+    /// Ends the script once the hook returns, as if it were interrupted.
+    pub fn stop(&mut self) {
+        self.vm.debug_stop(Limit::Interrupted);
+    }
+}
+
+/// How far the script runs before a `Stepper` stops it again, breakpoints
+/// aside.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Step {
+    /// Until a breakpoint.
+    #[default]
+    Continue,
+    /// To the next line, entering calls.
+    In,
+    /// To the next line of this frame or a caller.
+    Over,
+    /// To the caller.
+    Out,
+}
+
+/// Why a `Stepper` stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    Breakpoint,
+    Step,
+}
+
+/// Breakpoints by script and line, and where the last step was taken from.
+#[derive(Debug, Clone, Default)]
+pub struct Stepper {
+    /// Lines to stop at, by the name of their script.
+    pub breakpoints: BTreeMap<String, BTreeSet<usize>>,
+    step: Step,
+    /// Frame depth the step was taken at.
+    depth: usize,
+}
+
+impl Stepper {
+    /// A stepper that stops as `step` says, counting from the script's own
+    /// frame: `Step::In` stops at its first line.
+    pub fn new(step: Step) -> Self {
+        Self {
+            breakpoints: BTreeMap::new(),
+            step,
+            depth: 1,
+        }
+    }
+
+    /// Whether, and why, to stop where the script is paused.
+    pub fn check(&self, paused: &Paused<'_>) -> Option<StopReason> {
+        let depth = paused.depth();
+        let stepped = match self.step {
+            Step::Continue => false,
+            Step::In => true,
+            Step::Over => depth <= self.depth,
+            Step::Out => depth < self.depth,
+        };
+        if stepped {
+            Some(StopReason::Step)
+        } else if self
+            .breakpoints
+            .get(paused.script())
+            .is_some_and(|lines| lines.contains(&paused.line()))
+        {
+            Some(StopReason::Breakpoint)
+        } else {
+            None
+        }
+    }
+
+    /// Lets the script go on from where it is paused, until `step` or a
+    /// breakpoint stops it.
+    pub fn resume(&mut self, step: Step, paused: &Paused<'_>) {
+        self.step = step;
+        self.depth = paused.depth();
+    }
+}
+
+/// `value` as `print` shows it, but with strings quoted.
+fn describe(value: &Value) -> String {
+    match value {
+        Value::String(string) => format!("{:?}", string),
+        value => value.to_string(),
+    }
+}
@@ -0,0 +1,407 @@
+//! The line-oriented debugger behind `nlox debug`.
+
+use std::io::{BufRead, Write};
+
+use super::{DebugHook, Paused, Step, Stepper, StopReason, describe};
+use crate::embed::Value;
+
+const HELP: &str = "\
+break [file:]<line>  stop at a line
+delete <line>        remove a breakpoint
+continue             run to the next breakpoint
+step                 run to the next line, entering calls
+next                 run to the next line of this frame or a caller
+out                  run back to the caller
+backtrace            show the call stack
+frame <n>            inspect frame n of the stack, from the innermost
+locals               show the frame's locals
+globals              show every global
+print <expression>   evaluate an expression in the frame
+quit                 end the script";
+
+/// A debugger that reads commands from `input` and writes to `output`
+/// whenever it stops, which it first does at the script's first line.
+/// Running out of input lets the script run to the end.
+pub struct DebugConsole<R, W> {
+    script: String,
+    lines: Vec<String>,
+    input: R,
+    output: W,
+    stepper: Stepper,
+    /// Frame `locals` and `print` look at, from the innermost.
+    frame: usize,
+}
+
+impl<R: BufRead, W: Write> DebugConsole<R, W> {
+    /// A debugger for `source`, which runs under the name `script`.
+    pub fn new(script: &str, source: &str, input: R, output: W) -> Self {
+        Self {
+            script: script.to_string(),
+            lines: source.lines().map(str::to_string).collect(),
+            input,
+            output,
+            stepper: Stepper::new(Step::In),
+            frame: 0,
+        }
+    }
+
+    /// Shows where the script stopped.
+    fn location(&mut self, paused: &Paused<'_>, reason: StopReason) {
+        let line = paused.line();
+        let text = self
+            .lines
+            .get(line.wrapping_sub(1))
+            .map_or("", |text| text.trim());
+        let _ = match reason {
+            StopReason::Breakpoint => writeln!(
+                self.output,
+                "Breakpoint at {}:{}\n{:<5}{}",
+                self.script, line, line, text
+            ),
+            StopReason::Step => writeln!(self.output, "{:<5}{}", line, text),
+        };
+    }
+
+    /// The line `location` names, `<line>` or `<file>:<line>`, if it is
+    /// one of the script's.
+    fn line(&self, location: &str) -> Result<usize, String> {
+        let line = match location.rsplit_once(':') {
+            Some((file, line)) if file == self.script => line,
+            Some((file, _)) => return Err(format!("No script named '{}'.", file)),
+            None => location,
+        };
+        match line.parse() {
+            Ok(line) if (1..=self.lines.len()).contains(&line) => Ok(line),
+            _ => Err(format!("No line '{}' in {}.", line, self.script)),
+        }
+    }
+
+    /// Runs one command, and returns how the script goes on if it does.
+    fn command(&mut self, paused: &mut Paused<'_>, command: &str) -> Option<Step> {
+        let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+        let argument = argument.trim();
+        match name {
+            "c" | "continue" => return Some(Step::Continue),
+            "s" | "step" => return Some(Step::In),
+            "n" | "next" => return Some(Step::Over),
+            "o" | "out" | "finish" => return Some(Step::Out),
+            "b" | "break" => match self.line(argument) {
+                Ok(line) => {
+                    let breakpoints = self.stepper.breakpoints.entry(self.script.clone());
+                    breakpoints.or_default().insert(line);
+                    let _ = writeln!(self.output, "Breakpoint set at {}:{}.", self.script, line);
+                }
+                Err(message) => {
+                    let _ = writeln!(self.output, "{}", message);
+                }
+            },
+            "d" | "delete" => match self.line(argument) {
+                Ok(line)
+                    if self
+                        .stepper
+                        .breakpoints
+                        .get_mut(&self.script)
+                        .is_some_and(|lines| lines.remove(&line)) =>
+                {
+                    let _ = writeln!(self.output, "Breakpoint removed.");
+                }
+                Ok(line) => {
+                    let _ = writeln!(self.output, "No breakpoint at line {}.", line);
+                }
+                Err(message) => {
+                    let _ = writeln!(self.output, "{}", message);
+                }
+            },
+            "bt" | "backtrace" => {
+                for (index, frame) in paused.stack().iter().enumerate() {
+                    let marker = if index == self.frame { '>' } else { ' ' };
+                    let _ = writeln!(
+                        self.output,
+                        "{}#{} {} at {}:{}",
+                        marker, index, frame.function, frame.script, frame.line
+                    );
+                }
+            }
+            "f" | "frame" => match argument.parse() {
+                Ok(frame) if frame < paused.depth() => self.frame = frame,
+                _ => {
+                    let _ = writeln!(self.output, "No frame '{}'.", argument);
+                }
+            },
+            "l" | "locals" => {
+                let locals = paused.locals(self.frame);
+                self.variables(locals);
+            }
+            "g" | "globals" => {
+                let globals = paused.globals();
+                self.variables(globals);
+            }
+            "p" | "print" => {
+                let _ = match paused.eval(argument, self.frame) {
+                    Ok(value) => writeln!(self.output, "{}", describe(&value)),
+                    Err(error) => writeln!(self.output, "{}", error),
+                };
+            }
+            "q" | "quit" => {
+                paused.stop();
+                return Some(Step::Continue);
+            }
+            "h" | "help" => {
+                let _ = writeln!(self.output, "{}", HELP);
+            }
+            "" => {}
+            _ => {
+                let _ = writeln!(
+                    self.output,
+                    "Unknown command '{}'. Type 'help' for a list.",
+                    name
+                );
+            }
+        }
+        None
+    }
+
+    fn variables(&mut self, variables: Vec<(String, Value)>) {
+        if variables.is_empty() {
+            let _ = writeln!(self.output, "None.");
+        }
+        for (name, value) in variables {
+            let _ = writeln!(self.output, "{} = {}", name, describe(&value));
+        }
+    }
+}
+
+impl<R: BufRead, W: Write> DebugHook for DebugConsole<R, W> {
+    fn on_line(&mut self, paused: &mut Paused<'_>) {
+        let Some(reason) = self.stepper.check(paused) else {
+            return;
+        };
+        self.frame = 0;
+        self.location(paused, reason);
+        loop {
+            let _ = write!(self.output, "(nox) ");
+            let _ = self.output.flush();
+            let mut command = String::new();
+            if !matches!(self.input.read_line(&mut command), Ok(1..)) {
+                let _ = writeln!(self.output);
+                self.stepper = Stepper::new(Step::Continue);
+                return;
+            }
+            if let Some(step) = self.command(paused, command.trim()) {
+                self.stepper.resume(step, paused);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        io::{self, Write},
+        rc::Rc,
+    };
+
+    use super::DebugConsole;
+    use crate::{
+        compiler::{Backend, CompilerOptions},
+        embed::{Error, Vm},
+        limits::Limit,
+    };
+
+    /// The console's output and the script's, interleaved.
+    #[derive(Clone, Default)]
+    struct Transcript(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Transcript {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const SCRIPT: &str = "fun add(a, b) {
+  var sum = a + b;
+  return sum;
+}
+var total = 0;
+for (var i = 0; i < 3; i = i + 1) {
+  total = add(total, i);
+}
+print(total);";
+
+    fn debug(backend: Backend, commands: &str) -> (Result<(), Error>, String) {
+        debug_script(backend, "add.nox", SCRIPT, commands)
+    }
+
+    fn debug_script(
+        backend: Backend,
+        script: &str,
+        source: &str,
+        commands: &str,
+    ) -> (Result<(), Error>, String) {
+        let transcript = Transcript::default();
+        let mut vm = Vm::new();
+        vm.set_compiler_options(CompilerOptions {
+            backend,
+            ..CompilerOptions::default()
+        });
+        vm.set_stdout(transcript.clone());
+        vm.set_stderr(io::sink());
+        let input = io::Cursor::new(commands.to_string());
+        vm.set_debug_hook(DebugConsole::new(script, source, input, transcript.clone()));
+        let result = vm.eval(source, script);
+        let output = String::from_utf8(transcript.0.take()).unwrap();
+        (result, output.replace("(nox) ", "> "))
+    }
+
+    #[test]
+    fn steps_through_a_script() {
+        let commands = "break 2\ncontinue\nbacktrace\nprint a + b * 10\nnext\nlocals\n\
+                        frame 1\nlocals\nglobals\nout\nout\ndelete add.nox:2\nbreak 12\n\
+                        print nope(\ncontinue\n";
+        let expected = "\
+1    fun add(a, b) {
+> Breakpoint set at add.nox:2.
+> Breakpoint at add.nox:2
+2    var sum = a + b;
+> >#0 add at add.nox:2
+ #1 script at add.nox:7
+> 0
+> 3    return sum;
+> a = 0
+b = 0
+sum = 0
+> > i = 0
+> add = <fn add>
+total = 0
+> 7    total = add(total, i);
+> Breakpoint at add.nox:2
+2    var sum = a + b;
+> Breakpoint removed.
+> No line '12' in add.nox.
+> eval: [Line 1] Error at ';': Expected expression
+> 3
+";
+        for backend in [Backend::Stack, Backend::Register] {
+            let (result, output) = debug(backend, commands);
+            assert_eq!(result, Ok(()));
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn sees_the_receiver_and_captured_variables() {
+        let source = "class P {
+  init(x) { this.x = x; }
+  show(y) {
+    return this.x + y;
+  }
+}
+fun counter() {
+  var count = 0;
+  fun inc() {
+    count = count + 1;
+    return count;
+  }
+  return inc;
+}
+P(5).show(2);
+var c = counter();
+c();";
+        let commands = "break 4\nbreak 10\ncontinue\nlocals\nprint this\nprint this.x * y\n\
+                        continue\nlocals\nprint count + 1\ncontinue\n";
+        let expected = "\
+1    class P {
+> Breakpoint set at p.nox:4.
+> Breakpoint set at p.nox:10.
+> Breakpoint at p.nox:4
+4    return this.x + y;
+> this = P instance
+y = 2
+> P instance
+> 10
+> Breakpoint at p.nox:10
+10   count = count + 1;
+> count = 0
+> 1
+> ";
+        for backend in [Backend::Stack, Backend::Register] {
+            let (result, output) = debug_script(backend, "p.nox", source, commands);
+            assert_eq!(result, Ok(()));
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn quitting_ends_the_script() {
+        let (result, output) = debug(Backend::Stack, "step\nwhat\nquit\n");
+        assert_eq!(
+            result,
+            Err(Error::Limit {
+                script: "add.nox".to_string(),
+                limit: Limit::Interrupted,
+            })
+        );
+        assert_eq!(
+            output,
+            "1    fun add(a, b) {\n> 5    var total = 0;\n\
+             > Unknown command 'what'. Type 'help' for a list.\n> "
+        );
+
+        // Without more commands the script runs to the end.
+        let (result, output) = debug(Backend::Stack, "");
+        assert_eq!(result, Ok(()));
+        assert_eq!(output, "1    fun add(a, b) {\n> \n3\n");
+    }
+
+    #[test]
+    fn one_line_loops_stop_each_time_round() {
+        let source = "var x = 0;\nwhile (x < 3) x = x + 1;\nprint(x);";
+        let expected = "\
+1    var x = 0;
+> Breakpoint set at loop.nox:2.
+> Breakpoint at loop.nox:2
+2    while (x < 3) x = x + 1;
+> 0
+> Breakpoint at loop.nox:2
+2    while (x < 3) x = x + 1;
+> 1
+> Breakpoint at loop.nox:2
+2    while (x < 3) x = x + 1;
+> 2
+> Breakpoint at loop.nox:2
+2    while (x < 3) x = x + 1;
+> 3
+> 3
+3
+";
+        for backend in [Backend::Stack, Backend::Register] {
+            let transcript = Transcript::default();
+            let mut vm = Vm::new();
+            vm.set_compiler_options(CompilerOptions {
+                backend,
+                ..CompilerOptions::default()
+            });
+            vm.set_stdout(transcript.clone());
+            let commands = "break 2\ncontinue\nprint x\ncontinue\nprint x\ncontinue\n\
+                            print x\ncontinue\nprint x\ncontinue\n";
+            let input = io::Cursor::new(commands);
+            vm.set_debug_hook(DebugConsole::new(
+                "loop.nox",
+                source,
+                input,
+                transcript.clone(),
+            ));
+            assert_eq!(vm.eval(source, "loop.nox"), Ok(()));
+            // The breakpoint is on loop.nox's line 2, not this script's.
+            assert_eq!(vm.eval(source, "other.nox"), Ok(()));
+            let output = String::from_utf8(transcript.0.take()).unwrap();
+            assert_eq!(output.replace("(nox) ", "> "), expected);
+        }
+    }
+}
@@ -0,0 +1,516 @@
+//! A Debug Adapter Protocol server, behind `nlox dap`.
+//!
+//! Editors speak to it over a pair of streams, one JSON message per
+//! `Content-Length` framed body. A session debugs one script on a single
+//! thread, so requests are only read while the script is paused, or before
+//! and after it runs; there is no `pause`. The script starts once the
+//! client sends `configurationDone`, and what it prints reaches the client
+//! as `output` events.
+
+use std::{
+    cell::RefCell,
+    io::{self, BufRead, Read, Write},
+    mem,
+    rc::Rc,
+};
+
+use serde_json::{Value as Json, json};
+
+use super::{DebugHook, Paused, Step, Stepper, StopReason, describe};
+use crate::embed::{Error, Value, Vm};
+
+/// The id of the one thread scripts run on.
+const THREAD: u64 = 1;
+/// Longest message body read; longer ones are skipped unread.
+const MAX_MESSAGE: usize = 16 << 20;
+/// `variablesReference` of the globals. The locals of the frame with id
+/// `n` use `n + 1`.
+const GLOBALS: u64 = 1;
+
+/// Serves one debugging session, reading requests from `input` and
+/// writing responses and events to `output`, until the client disconnects
+/// or hangs up.
+pub fn serve(input: impl BufRead + 'static, output: impl Write + 'static) -> io::Result<()> {
+    let connection = Rc::new(RefCell::new(Connection {
+        input: Box::new(input),
+        output: Box::new(output),
+        seq: 0,
+        closed: false,
+    }));
+    let session = Rc::new(RefCell::new(Session {
+        connection: connection.clone(),
+        script: None,
+        stepper: Stepper::default(),
+        entry: false,
+        ended: false,
+        failure: None,
+    }));
+    loop {
+        let Some(request) = connection.borrow_mut().read()? else {
+            return Ok(());
+        };
+        match session.borrow_mut().handle(&request, None)? {
+            Action::Run => {}
+            Action::Disconnect => return Ok(()),
+            Action::None | Action::Resume(_) => continue,
+        }
+        run(&session)?;
+        if session.borrow().ended {
+            return Ok(());
+        }
+    }
+}
+
+/// Runs the launched script to the end, or until the client disconnects.
+fn run(session: &Rc<RefCell<Session>>) -> io::Result<()> {
+    let (connection, script) = {
+        let session = session.borrow();
+        (session.connection.clone(), session.script.clone())
+    };
+    let Some(script) = script else {
+        return Ok(());
+    };
+    let mut vm = Vm::new();
+    vm.set_stdout(Output::new(&connection, "stdout"));
+    vm.set_stderr(Output::new(&connection, "stderr"));
+    vm.set_debug_hook(Hook(session.clone()));
+    let result = vm.eval(&script.source, &script.path);
+    // Sends what is left of the output.
+    drop(vm);
+
+    let mut session = session.borrow_mut();
+    if let Some(error) = session.failure.take() {
+        return Err(error);
+    }
+    if session.ended {
+        return Ok(());
+    }
+    // The exit codes of `nlox`.
+    let code = match result {
+        Ok(()) => 0,
+        Err(Error::Compile { .. }) => 65,
+        Err(_) => 70,
+    };
+    let mut connection = connection.borrow_mut();
+    connection.event("exited", json!({ "exitCode": code }))?;
+    connection.event("terminated", json!({}))
+}
+
+struct Connection {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    /// Sequence number of the last message sent.
+    seq: u64,
+    /// Set once the client has gone; nothing more is sent.
+    closed: bool,
+}
+
+impl Connection {
+    /// The next message, or `None` once the client has hung up. A message
+    /// without a valid length, too long, or not JSON is answered with a
+    /// failed response and skipped.
+    fn read(&mut self) -> io::Result<Option<Json>> {
+        // There is no request to answer; `seq` 0 is none.
+        let unknown = json!({ "seq": 0, "command": "" });
+        loop {
+            let mut headers = false;
+            let mut length = None;
+            loop {
+                let mut header = String::new();
+                if self.input.read_line(&mut header)? == 0 {
+                    self.closed = true;
+                    return Ok(None);
+                }
+                let header = header.trim_end();
+                if header.is_empty() && headers {
+                    break;
+                }
+                headers |= !header.is_empty();
+                // A body sent without a valid length runs into the next
+                // message's header.
+                if let Some((_, value)) = header.split_once("Content-Length:") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+            let Some(length) = length else {
+                self.fail(
+                    &unknown,
+                    "Malformed message: missing or invalid Content-Length.",
+                )?;
+                continue;
+            };
+            let mut body = Read::take(&mut self.input, length as u64);
+            if length > MAX_MESSAGE {
+                io::copy(&mut body, &mut io::sink())?;
+                let message = format!(
+                    "Malformed message: {} bytes is over the limit of {}.",
+                    length, MAX_MESSAGE
+                );
+                self.fail(&unknown, &message)?;
+                continue;
+            }
+            // Grown as the bytes arrive, rather than trusting the length.
+            let mut bytes = vec![];
+            body.read_to_end(&mut bytes)?;
+            if bytes.len() < length {
+                self.closed = true;
+                return Ok(None);
+            }
+            match serde_json::from_slice(&bytes) {
+                Ok(message) => return Ok(Some(message)),
+                Err(error) => {
+                    self.fail(&unknown, &format!("Malformed message: {}.", error))?;
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, mut message: Json) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": true,
+            "command": request["command"],
+            "body": body,
+        }))
+    }
+
+    fn fail(&mut self, request: &Json, message: &str) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": false,
+            "command": request["command"],
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+}
+
+#[derive(Clone)]
+struct Script {
+    path: String,
+    source: String,
+}
+
+struct Session {
+    connection: Rc<RefCell<Connection>>,
+    /// Set by `launch`.
+    script: Option<Script>,
+    stepper: Stepper,
+    /// Whether the next stop is the one `stopOnEntry` asked for.
+    entry: bool,
+    /// Set once the client has gone, which ends the script.
+    ended: bool,
+    /// Why the connection broke while the script was paused.
+    failure: Option<io::Error>,
+}
+
+/// What a request asks of the script.
+enum Action {
+    None,
+    Run,
+    Resume(Step),
+    Disconnect,
+}
+
+impl Session {
+    /// Answers `request`. Requests about the script's state need it
+    /// `paused`.
+    fn handle(&mut self, request: &Json, paused: Option<&mut Paused<'_>>) -> io::Result<Action> {
+        let arguments = &request["arguments"];
+        let connection = self.connection.clone();
+        let mut connection = connection.borrow_mut();
+        let command = request["command"].as_str().unwrap_or_default();
+        let step = match command {
+            "continue" => Some(Step::Continue),
+            "next" => Some(Step::Over),
+            "stepIn" => Some(Step::In),
+            "stepOut" => Some(Step::Out),
+            _ => None,
+        };
+        match (command, paused) {
+            ("initialize", _) => {
+                let capabilities = json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsEvaluateForHovers": true,
+                });
+                connection.respond(request, capabilities)?;
+                connection.event("initialized", json!({}))?;
+            }
+            ("launch", None) if self.script.is_none() => {
+                let path = arguments["program"].as_str().unwrap_or_default();
+                let source = match std::fs::read_to_string(path) {
+                    Ok(source) => source,
+                    Err(error) => {
+                        let message = format!("Could not read '{}': {}.", path, error);
+                        connection.fail(request, &message)?;
+                        return Ok(Action::None);
+                    }
+                };
+                self.script = Some(Script {
+                    path: path.to_string(),
+                    source,
+                });
+                self.entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+                let breakpoints = mem::take(&mut self.stepper.breakpoints);
+                let step = if self.entry { Step::In } else { Step::Continue };
+                self.stepper = Stepper::new(step);
+                self.stepper.breakpoints = breakpoints;
+                connection.respond(request, json!({}))?;
+            }
+            ("setBreakpoints", _) => {
+                let path = arguments["source"]["path"].as_str().unwrap_or_default();
+                // Lines of a script other than the one launched never run.
+                let count = self.script.as_ref().map(|script| {
+                    if script.path == path {
+                        script.source.lines().count()
+                    } else {
+                        0
+                    }
+                });
+                let requested = arguments["breakpoints"].as_array().cloned();
+                let lines = self.stepper.breakpoints.entry(path.to_string());
+                let lines = lines.or_default();
+                lines.clear();
+                let mut breakpoints = vec![];
+                for breakpoint in requested.unwrap_or_default() {
+                    let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+                    let verified = line > 0 && count.is_none_or(|count| line <= count);
+                    if verified {
+                        lines.insert(line);
+                    }
+                    breakpoints.push(json!({ "verified": verified, "line": line }));
+                }
+                connection.respond(request, json!({ "breakpoints": breakpoints }))?;
+            }
+            ("configurationDone", None) if self.script.is_some() => {
+                connection.respond(request, json!({}))?;
+                return Ok(Action::Run);
+            }
+            ("threads", _) => {
+                let threads = json!([{ "id": THREAD, "name": "main" }]);
+                connection.respond(request, json!({ "threads": threads }))?;
+            }
+            ("disconnect", _) => {
+                connection.respond(request, json!({}))?;
+                connection.closed = true;
+                return Ok(Action::Disconnect);
+            }
+            ("stackTrace", Some(paused)) => {
+                let frames: Vec<_> = paused
+                    .stack()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, frame)| {
+                        json!({
+                            "id": index + 1,
+                            "name": frame.function,
+                            "line": frame.line,
+                            "column": 1,
+                            "source": { "path": frame.script },
+                        })
+                    })
+                    .collect();
+                let total = frames.len();
+                let body = json!({ "stackFrames": frames, "totalFrames": total });
+                connection.respond(request, body)?;
+            }
+            ("scopes", Some(_)) => {
+                let frame = arguments["frameId"].as_u64().unwrap_or(1);
+                let scopes = json!([
+                    { "name": "Locals", "variablesReference": frame + 1, "expensive": false },
+                    { "name": "Globals", "variablesReference": GLOBALS, "expensive": true },
+                ]);
+                connection.respond(request, json!({ "scopes": scopes }))?;
+            }
+            ("variables", Some(paused)) => {
+                let reference = arguments["variablesReference"].as_u64().unwrap_or(0);
+                // Printing a value never runs the script, so the connection
+                // can stay borrowed.
+                let variables = match reference {
+                    GLOBALS => paused.globals(),
+                    0 => vec![],
+                    locals => paused.locals((locals - 2) as usize),
+                };
+                let variables: Vec<_> = variables
+                    .iter()
+                    .map(|(name, value)| {
+                        let mut variable = variable(value);
+                        variable["name"] = json!(name);
+                        variable
+                    })
+                    .collect();
+                connection.respond(request, json!({ "variables": variables }))?;
+            }
+            ("evaluate", Some(paused)) => {
+                let expression = arguments["expression"].as_str().unwrap_or_default();
+                let frame = arguments["frameId"].as_u64().unwrap_or(1).max(1) - 1;
+                // The expression may print, which goes out as events.
+                drop(connection);
+                let result = paused.eval(expression, frame as usize);
+                let mut connection = self.connection.borrow_mut();
+                match result {
+                    Ok(value) => {
+                        let mut body = variable(&value);
+                        body["result"] = body["value"].take();
+                        connection.respond(request, body)?;
+                    }
+                    Err(error) => connection.fail(request, &error.to_string())?,
+                }
+            }
+            (_, Some(_)) if step.is_some() => {
+                let body = json!({ "allThreadsContinued": true });
+                let body = if command == "continue" {
+                    body
+                } else {
+                    json!({})
+                };
+                connection.respond(request, body)?;
+                return Ok(Action::Resume(step.unwrap()));
+            }
+            ("launch" | "configurationDone", _) => {
+                connection.fail(request, "The script has already been launched.")?;
+            }
+            ("stackTrace" | "scopes" | "variables" | "evaluate", None) => {
+                connection.fail(request, "The script is not paused.")?;
+            }
+            (_, None) if step.is_some() => {
+                connection.fail(request, "The script is not paused.")?;
+            }
+            _ => {
+                let message = format!("Unsupported request '{}'.", command);
+                connection.fail(request, &message)?;
+            }
+        }
+        Ok(Action::None)
+    }
+
+    /// Tells the client the script stopped, and answers its requests until
+    /// one resumes it.
+    fn stop(&mut self, paused: &mut Paused<'_>, reason: &str) -> io::Result<()> {
+        let body = json!({ "reason": reason, "threadId": THREAD, "allThreadsStopped": true });
+        self.connection.borrow_mut().event("stopped", body)?;
+        loop {
+            let request = self.connection.borrow_mut().read()?;
+            let Some(request) = request else {
+                self.ended = true;
+                paused.stop();
+                return Ok(());
+            };
+            match self.handle(&request, Some(paused))? {
+                Action::Resume(step) => {
+                    self.stepper.resume(step, paused);
+                    return Ok(());
+                }
+                Action::Disconnect => {
+                    self.ended = true;
+                    paused.stop();
+                    return Ok(());
+                }
+                Action::None | Action::Run => {}
+            }
+        }
+    }
+}
+
+/// `value` as a DAP variable, without its name.
+fn variable(value: &Value) -> Json {
+    json!({
+        "value": describe(value),
+        "type": value.type_name(),
+        "variablesReference": 0,
+    })
+}
+
+struct Hook(Rc<RefCell<Session>>);
+
+impl DebugHook for Hook {
+    fn on_line(&mut self, paused: &mut Paused<'_>) {
+        let mut session = self.0.borrow_mut();
+        let Some(reason) = session.stepper.check(paused) else {
+            return;
+        };
+        let reason = match reason {
+            _ if mem::take(&mut session.entry) => "entry",
+            StopReason::Breakpoint => "breakpoint",
+            StopReason::Step => "step",
+        };
+        if let Err(error) = session.stop(paused, reason) {
+            session.failure = Some(error);
+            session.ended = true;
+            paused.stop();
+        }
+    }
+}
+
+/// Sends what the script writes to `category` as `output` events, a line
+/// at a time.
+struct Output {
+    connection: Rc<RefCell<Connection>>,
+    category: &'static str,
+    line: Vec<u8>,
+}
+
+impl Output {
+    fn new(connection: &Rc<RefCell<Connection>>, category: &'static str) -> Self {
+        Self {
+            connection: connection.clone(),
+            category,
+            line: vec![],
+        }
+    }
+
+    fn send(&mut self, text: Vec<u8>) -> io::Result<()> {
+        let body = json!({
+            "category": self.category,
+            "output": String::from_utf8_lossy(&text),
+        });
+        self.connection.borrow_mut().event("output", body)
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|&byte| byte == b'\n') {
+            let rest = self.line.split_off(end + 1);
+            let line = mem::replace(&mut self.line, rest);
+            self.send(line)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            let line = mem::take(&mut self.line);
+            // Nowhere left to report a failure to.
+            let _ = self.send(line);
+        }
+    }
+}
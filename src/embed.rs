@@ -0,0 +1,828 @@
+//! The embedding API: what a Rust program linking `nlox` uses to run
+//! scripts and trade values with them.
+//!
+//! `Vm` owns an interpreter and its heap. Values cross the boundary as
+//! `Value`, which copies numbers, booleans and strings, and holds anything
+//! else (functions, classes, instances) through an `Object` handle that
+//! keeps it alive for as long as the host holds on to it. Lists and maps
+//! are copied both ways; scripts see them as instances of the built-in
+//! `List` and `Map` classes, with a field per element or entry. A Rust type
+//! can instead be handed over by reference, as an instance of a class
+//! registered with a `UserClass`.
+
+use std::{collections::HashMap, fmt, io::Write, rc::Rc};
+
+use crate::{
+    compiler::CompilerOptions,
+    convert::{IntoArgs, IntoValue, NativeFunction, NativeMethod},
+    debug::DebugHook,
+    gc::{GcOptions, GcStats},
+    jit::JitOptions,
+    limits::{InterruptHandle, Limit, Limits},
+    program::Program,
+    shape::CacheStats,
+    userdata::UserClass,
+    value::{self, NativeFn, ObjNative},
+    vm::{InterpretResult, VM},
+};
+
+/// A value on the host side.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    List(Vec<Value>),
+    Map(HashMap<String, Value>),
+    /// A function, class, instance or bound method.
+    Object(Object),
+}
+
+impl Value {
+    /// Named as in conversion errors.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Object(object) => object.pin.kind,
+        }
+    }
+}
+
+/// Formats like the script's `print`.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(bool) => write!(f, "{}", bool),
+            Value::Number(number) => write!(f, "{}", number),
+            Value::String(string) => write!(f, "{}", string),
+            Value::List(list) => {
+                let elements: Vec<_> = list.iter().map(Value::to_string).collect();
+                write!(f, "[{}]", elements.join(", "))
+            }
+            Value::Map(map) => {
+                let mut entries: Vec<_> = map
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key, value))
+                    .collect();
+                entries.sort();
+                write!(f, "{{{}}}", entries.join(", "))
+            }
+            Value::Object(object) => write!(f, "{}", object),
+        }
+    }
+}
+
+/// A heap object of the `Vm` it came from, kept alive while any clone of
+/// the handle is. Equal handles refer to the same object.
+#[derive(Clone)]
+pub struct Object {
+    pub(crate) pin: Rc<Pin>,
+}
+
+/// What an `Object` holds; the VM marks every one still alive.
+pub struct Pin {
+    pub(crate) value: value::Value,
+    pub(crate) vm: u64,
+    /// "function", "class" or "instance".
+    pub(crate) kind: &'static str,
+    /// As `print` shows it, captured when the object crossed over.
+    pub(crate) display: String,
+}
+
+impl PartialEq for Object {
+    fn eq(&self, other: &Self) -> bool {
+        self.pin.vm == other.pin.vm && self.pin.value == other.pin.value
+    }
+}
+
+impl fmt::Debug for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Object({})", self.pin.display)
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.pin.display)
+    }
+}
+
+/// Why a `Vm` call failed. The compiler's diagnostics and runtime errors
+/// are also written to the `Vm`'s stderr sink as the script runs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// `script` did not compile; one line per diagnostic.
+    Compile {
+        script: String,
+        diagnostics: Vec<String>,
+    },
+    /// `script` failed at runtime. The trace lists the frames innermost
+    /// first.
+    Runtime {
+        script: String,
+        message: String,
+        trace: Vec<String>,
+    },
+    /// `script` ran into one of the `Vm`'s `Limits`.
+    Limit { script: String, limit: Limit },
+    /// `Vm::call` was given a name with no global behind it.
+    UndefinedGlobal(String),
+    /// An `Object` was handed to a `Vm` other than the one it came from.
+    ForeignObject,
+    /// `Vm::new_userdata` was given a Rust type no class was registered
+    /// for.
+    UnregisteredClass(&'static str),
+    /// A value was not of the Rust type it was converted to.
+    Conversion {
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// Raised by host code, such as a native; scripts see the message as a
+    /// runtime error.
+    Host(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Compile {
+                script,
+                diagnostics,
+            } => write!(f, "{}: {}", script, diagnostics.join("\n")),
+            Error::Runtime {
+                script,
+                message,
+                trace,
+            } => {
+                write!(f, "{}: {}", script, message)?;
+                for line in trace {
+                    write!(f, "\n{}", line)?;
+                }
+                Ok(())
+            }
+            Error::Limit { script, limit } => write!(f, "{}: {}", script, limit.message()),
+            Error::UndefinedGlobal(name) => write!(f, "Undefined variable '{}'.", name),
+            Error::ForeignObject => write!(f, "Object belongs to another VM."),
+            Error::UnregisteredClass(type_name) => {
+                write!(f, "No class is registered for {}.", type_name)
+            }
+            Error::Conversion { expected, found } => {
+                write!(f, "Expected {} but got {}.", expected, found)
+            }
+            Error::Host(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// An interpreter with its own heap and globals. Globals persist from one
+/// `eval` to the next.
+///
+/// Natives registered with `register_with_vm` get the `Vm` running them,
+/// and can call back into it while the script that called them waits: a
+/// runtime error in the inner call comes back to the native as an `Err`,
+/// and passing it on continues its stack trace in the outer script.
+#[repr(transparent)]
+pub struct Vm {
+    vm: VM,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self { vm: VM::new() }
+    }
+
+    /// The `Vm` around `vm`, for the natives it calls.
+    pub(crate) fn from_inner(vm: &mut VM) -> &mut Self {
+        // `Vm` is a transparent wrapper, so the two share a layout.
+        unsafe { &mut *(vm as *mut VM as *mut Self) }
+    }
+
+    /// Used by every later `eval`.
+    pub fn set_compiler_options(&mut self, options: CompilerOptions) {
+        self.vm.options = options;
+    }
+
+    pub fn set_gc_options(&mut self, options: GcOptions) {
+        self.vm.heap.options = options;
+    }
+
+    pub fn set_jit_options(&mut self, options: JitOptions) {
+        self.vm.jit = options;
+    }
+
+    /// Bounds every later `eval` and `call` separately.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.vm.limits = limits;
+    }
+
+    /// Sends what scripts `print` to `out` instead of the process's
+    /// stdout.
+    pub fn set_stdout(&mut self, out: impl Write + 'static) {
+        self.vm.stdout = Box::new(out);
+    }
+
+    /// Sends compiler diagnostics and runtime errors to `out` instead of
+    /// the process's stderr.
+    pub fn set_stderr(&mut self, out: impl Write + 'static) {
+        self.vm.stderr = Box::new(out);
+    }
+
+    /// Calls `hook` at each new line scripts reach, from now on.
+    pub fn set_debug_hook(&mut self, hook: impl DebugHook + 'static) {
+        self.vm.debug_hook = Some(Box::new(hook));
+    }
+
+    /// Stops calling the debug hook, and hands it back.
+    pub fn detach_debug_hook(&mut self) -> Option<Box<dyn DebugHook>> {
+        self.vm.debug_hook.take()
+    }
+
+    /// A handle other threads can use to cancel the running script.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.vm.interrupt_handle()
+    }
+
+    /// Compiles and runs `source`. `name` identifies the script in errors.
+    pub fn eval(&mut self, source: &str, name: &str) -> Result<()> {
+        let result = self.vm.interpret(source.to_string(), name);
+        self.check(result, name)
+    }
+
+    /// Runs `program`, which may have been compiled once for many `Vm`s.
+    pub fn run(&mut self, program: &Program) -> Result<()> {
+        let result = self.vm.run_program(program);
+        self.check(result, program.name())
+    }
+
+    /// Calls the global `function` with `args`, a tuple or a list of
+    /// values, and returns its result.
+    pub fn call(&mut self, function: &str, args: impl IntoArgs) -> Result<Value> {
+        let callee = self
+            .vm
+            .global(function)
+            .ok_or_else(|| Error::UndefinedGlobal(function.to_string()))?;
+        self.call_inner(callee, args, function)
+    }
+
+    /// Calls `callee`, a function, class or bound method the `Vm` handed
+    /// out, with `args`. Errors name the callee as `print` shows it.
+    pub fn call_value(&mut self, callee: &Value, args: impl IntoArgs) -> Result<Value> {
+        let name = callee.to_string();
+        let callee = self.vm.import(callee)?;
+        self.call_inner(callee, args, &name)
+    }
+
+    fn call_inner(
+        &mut self,
+        callee: value::Value,
+        args: impl IntoArgs,
+        name: &str,
+    ) -> Result<Value> {
+        let args = args
+            .into_args()
+            .iter()
+            .map(|arg| self.vm.import(arg))
+            .collect::<Result<Vec<_>>>()?;
+        self.call_imported(callee, args, name)
+    }
+
+    /// Calls `callee` with arguments already in the VM. None of them may be
+    /// collected before the call pushes them.
+    pub(crate) fn call_imported(
+        &mut self,
+        callee: value::Value,
+        args: Vec<value::Value>,
+        name: &str,
+    ) -> Result<Value> {
+        match self.vm.call_from_host(callee, args) {
+            Ok(result) => Ok(self.vm.export(&result)),
+            Err(result) => Err(self.check(result, name).unwrap_err()),
+        }
+    }
+
+    pub fn get_global(&mut self, name: &str) -> Option<Value> {
+        let value = self.vm.global(name)?;
+        Some(self.vm.export(&value))
+    }
+
+    /// Defines the global `name`, or overwrites it.
+    pub fn set_global(&mut self, name: &str, value: impl IntoValue) -> Result<()> {
+        let value = self.vm.import(&value.into_value())?;
+        self.vm.set_global(name, value);
+        Ok(())
+    }
+
+    /// Defines the global `name` as a native that runs `function`, which
+    /// can be any closure whose parameters are `FromValue` and which
+    /// returns `Result<impl IntoValue>`. An argument of the wrong type, or
+    /// an `Err` returned, is a runtime error in the script.
+    pub fn register<Args, F: NativeFunction<Args>>(&mut self, name: &str, function: F) {
+        self.register_untyped(name, F::ARITY, move |_, args| function.invoke(args));
+    }
+
+    /// `register` for a native that also gets the `Vm` as `&mut Vm` before
+    /// its arguments, to call script functions it was handed with
+    /// `call_value`, or anything else.
+    pub fn register_with_vm<Args, F: NativeMethod<Vm, Args>>(&mut self, name: &str, function: F) {
+        self.register_untyped(name, F::ARITY, move |vm, args| function.invoke(vm, args));
+    }
+
+    /// `register` for callers that only know the arity at runtime.
+    pub(crate) fn register_untyped(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&mut Vm, Vec<Value>) -> Result<Value> + 'static,
+    ) {
+        let native = ObjNative {
+            name: name.to_string(),
+            arity,
+            function: NativeFn::Function(Rc::new(function)),
+        };
+        self.vm.define_native(name, native);
+    }
+
+    /// Defines the host class `class` as a global.
+    pub fn register_class<T: 'static>(&mut self, class: UserClass<T>) {
+        self.vm.register_class(class);
+    }
+
+    /// Wraps `data` in an instance of the class registered for `T`, to
+    /// hand to scripts.
+    pub fn new_userdata<T: 'static>(&mut self, data: T) -> Result<Value> {
+        let instance = self.vm.new_userdata(data)?;
+        Ok(self.vm.export(&instance))
+    }
+
+    /// The `T` inside `object`, if it is an instance of this `Vm`'s class
+    /// for `T`.
+    pub fn userdata_mut<T: 'static>(&mut self, object: &Object) -> Option<&mut T> {
+        self.vm.userdata_mut(object)
+    }
+
+    /// Collects the whole heap now.
+    pub fn collect_garbage(&mut self) {
+        self.vm.collect_garbage();
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.vm.gc_stats()
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.vm.cache_stats()
+    }
+
+    /// Instructions dispatched since the `Vm` was created.
+    pub fn instructions_executed(&self) -> u64 {
+        self.vm.instructions_executed()
+    }
+
+    fn check(&mut self, result: InterpretResult, script: &str) -> Result<()> {
+        let mut report = std::mem::take(&mut self.vm.report);
+        let script = script.to_string();
+        match result {
+            InterpretResult::INTERPRET_OK => Ok(()),
+            InterpretResult::INTERPRET_COMPILE_ERROR => Err(Error::Compile {
+                script,
+                diagnostics: report,
+            }),
+            InterpretResult::INTERPRET_RUNTIME_ERROR => {
+                let message = report.remove(0);
+                Err(Error::Runtime {
+                    script,
+                    message,
+                    trace: report,
+                })
+            }
+            result => Err(Error::Limit {
+                script,
+                limit: Limit::from_result(result).unwrap(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        collections::HashMap,
+        io::{self, Write},
+        rc::Rc,
+    };
+
+    use super::{Error, Result, Value, Vm};
+    use crate::{
+        compiler::{Backend, CompilerOptions},
+        convert::FromValue,
+        gc::GcOptions,
+        limits::{Limit, Limits},
+    };
+
+    fn vm(backend: Backend) -> Vm {
+        let mut vm = Vm::new();
+        vm.set_compiler_options(CompilerOptions {
+            backend,
+            ..CompilerOptions::default()
+        });
+        vm
+    }
+
+    const BACKENDS: [Backend; 2] = [Backend::Stack, Backend::Register];
+
+    #[test]
+    fn calls_script_functions_and_shares_globals() {
+        for backend in BACKENDS {
+            let mut vm = vm(backend);
+            vm.eval(
+                "var base = 10; fun add(a, b) { return base + a + b; }",
+                "add",
+            )
+            .unwrap();
+            vm.set_global("base", 100).unwrap();
+            assert_eq!(vm.call("add", (1, 2)), Ok(Value::Number(103.0)));
+
+            vm.set_global("suffix", "!").unwrap();
+            vm.eval("var shout = \"hey\" + suffix; fun nothing() {}", "shout")
+                .unwrap();
+            assert_eq!(
+                vm.get_global("shout"),
+                Some(Value::String("hey!".to_string()))
+            );
+            assert_eq!(vm.call("nothing", ()), Ok(Value::Nil));
+            assert_eq!(vm.get_global("missing"), None);
+        }
+    }
+
+    #[test]
+    fn objects_survive_collection_while_held() {
+        for backend in BACKENDS {
+            let mut vm = vm(backend);
+            vm.eval(
+                "class Point { sum() { return this.x + this.y; } }
+                 fun make(x, y) { var p = Point(); p.x = x; p.y = y; return p; }
+                 fun blank() { return Point(); }
+                 fun sum(p) { return p.sum(); }",
+                "points",
+            )
+            .unwrap();
+            let point = vm.call("make", (1, 2)).unwrap();
+            assert_eq!(point.to_string(), "Point instance");
+            // A tail call to a class without `init` pushes no frame.
+            assert_eq!(vm.call("blank", ()).unwrap().to_string(), "Point instance");
+
+            vm.eval("make = nil; Point = nil;", "drop").unwrap();
+            vm.collect_garbage();
+            assert_eq!(vm.call("sum", (point.clone(),)), Ok(Value::Number(3.0)));
+
+            vm.set_global("p", point.clone()).unwrap();
+            assert_eq!(vm.get_global("p"), Some(point));
+        }
+    }
+
+    #[test]
+    fn errors_say_what_failed() {
+        let mut vm = vm(Backend::Stack);
+        assert!(matches!(
+            vm.eval("var = 1;", "bad.nox"),
+            Err(Error::Compile { script, diagnostics })
+                if script == "bad.nox" && diagnostics.len() == 1
+        ));
+
+        vm.eval("fun fail(x) { return -x; }", "fail.nox").unwrap();
+        assert_eq!(
+            vm.call("fail", (true,)),
+            Err(Error::Runtime {
+                script: "fail".to_string(),
+                message: "Operand must be a number.".to_string(),
+                trace: vec!["Line[1] in fail()".to_string()],
+            })
+        );
+        assert!(matches!(
+            vm.call("fail", ()),
+            Err(Error::Runtime { message, .. }) if message == "Expected 1 arguments but got 0."
+        ));
+        assert_eq!(
+            vm.call("missing", ()),
+            Err(Error::UndefinedGlobal("missing".to_string()))
+        );
+
+        vm.set_limits(Limits {
+            fuel: Some(100),
+            ..Limits::default()
+        });
+        assert_eq!(
+            vm.eval("while (true) {}", "spin"),
+            Err(Error::Limit {
+                script: "spin".to_string(),
+                limit: Limit::Fuel,
+            })
+        );
+
+        let mut other = Vm::new();
+        other.eval("fun f() {}", "other").unwrap();
+        let function = other.get_global("f").unwrap();
+        assert_eq!(vm.set_global("f", function), Err(Error::ForeignObject));
+    }
+
+    #[test]
+    fn natives_take_and_return_rust_types() {
+        for backend in BACKENDS {
+            let mut vm = vm(backend);
+            vm.register("repeat", |text: String, times: usize| -> Result<String> {
+                Ok(text.repeat(times))
+            });
+            vm.register("check", |ok: bool| {
+                if ok {
+                    Ok(())
+                } else {
+                    Err(Error::Host("Check failed.".to_string()))
+                }
+            });
+            vm.eval(
+                "fun twice(s) { return repeat(s, 2); }
+                 fun tail(s) { return repeat(s, 3); }
+                 var direct = repeat(\"ab\", 2) + twice(\"c\");",
+                "natives",
+            )
+            .unwrap();
+            assert_eq!(
+                vm.get_global("direct"),
+                Some(Value::String("ababcc".to_string()))
+            );
+            // `tail` becomes a tail call that replaces the host's frame.
+            assert_eq!(
+                vm.call("tail", ("x",)),
+                Ok(Value::String("xxx".to_string()))
+            );
+            assert_eq!(vm.call("check", (true,)), Ok(Value::Nil));
+
+            let failures = [
+                ("repeat(1, 2);", "Expected string but got number."),
+                ("repeat(\"a\", 1.5);", "Expected usize but got number."),
+                ("repeat(\"a\");", "Expected 2 arguments but got 1."),
+                ("check(false);", "Check failed."),
+            ];
+            for (source, expected) in failures {
+                assert!(matches!(
+                    vm.eval(source, "fail"),
+                    Err(Error::Runtime { message, .. }) if message == expected
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn natives_call_back_into_scripts() {
+        for backend in BACKENDS {
+            let mut vm = vm(backend);
+            vm.set_stderr(io::sink());
+            vm.register_with_vm(
+                "sort",
+                |vm: &mut Vm, mut list: Vec<Value>, before: Value| {
+                    for i in 1..list.len() {
+                        let mut j = i;
+                        while j > 0 {
+                            let pair = (list[j].clone(), list[j - 1].clone());
+                            if !bool::from_value(vm.call_value(&before, pair)?)? {
+                                break;
+                            }
+                            list.swap(j, j - 1);
+                            j -= 1;
+                        }
+                    }
+                    Ok(list)
+                },
+            );
+            vm.register_with_vm("attempt", |vm: &mut Vm, function: Value| {
+                Ok(match vm.call_value(&function, ()) {
+                    Ok(result) => result,
+                    Err(Error::Runtime { message, .. }) => Value::String(message),
+                    Err(error) => Value::String(error.to_string()),
+                })
+            });
+            vm.set_global("numbers", vec![2, 3, 1]).unwrap();
+            vm.eval(
+                "fun desc(a, b) { return a > b; }
+                 fun nested(depth) {
+                   fun inner() { return nested(depth - 1); }
+                   if (depth == 0) return sort(numbers, desc);
+                   return attempt(inner);
+                 }
+                 fun broken(a, b) { return a < nil; }
+                 fun sortBroken() { var sorted = sort(numbers, broken); return sorted; }
+                 fun fails() { return -nil; }
+                 var caught = attempt(fails);
+                 var sorted = nested(3);",
+                "callbacks",
+            )
+            .unwrap();
+            let sorted = Vec::<i32>::from_value(vm.get_global("sorted").unwrap());
+            assert_eq!(sorted, Ok(vec![3, 2, 1]));
+            assert_eq!(
+                vm.get_global("caught"),
+                Some(Value::String("Operand must be a number.".to_string()))
+            );
+
+            let desc = vm.get_global("desc").unwrap();
+            assert_eq!(vm.call_value(&desc, (2, 1)), Ok(Value::Bool(true)));
+            assert_eq!(
+                vm.call("sortBroken", ()),
+                Err(Error::Runtime {
+                    script: "sortBroken".to_string(),
+                    message: "Operands must be numbers.".to_string(),
+                    trace: vec![
+                        "Line[7] in broken()".to_string(),
+                        "Line[8] in sortBroken()".to_string(),
+                    ],
+                })
+            );
+            assert!(matches!(
+                vm.call_value(&desc, ()),
+                Err(Error::Runtime { script, .. }) if script == "<fn desc>"
+            ));
+
+            // A native cannot swallow a limit.
+            vm.set_limits(Limits {
+                fuel: Some(1000),
+                ..Limits::default()
+            });
+            assert_eq!(
+                vm.eval("fun spin() { while (true) {} } attempt(spin);", "spin"),
+                Err(Error::Limit {
+                    script: "spin".to_string(),
+                    limit: Limit::Fuel,
+                })
+            );
+            vm.set_limits(Limits::default());
+            assert_eq!(vm.call("nested", (2,)).map(|_| ()), Ok(()));
+        }
+    }
+
+    #[test]
+    fn lists_and_maps_cross_by_value() {
+        let mut vm = vm(Backend::Stack);
+        vm.register("sum", |numbers: Vec<f64>| Ok(numbers.iter().sum::<f64>()));
+        vm.register("port", |config: HashMap<String, Value>| {
+            Ok(config.get("port").cloned())
+        });
+        let config = HashMap::from([
+            ("host".to_string(), Value::String("localhost".to_string())),
+            ("port".to_string(), Value::Number(8080.0)),
+            ("retries".to_string(), Value::List(vec![Value::Number(1.0)])),
+        ]);
+        vm.set_global("config", Value::Map(config.clone())).unwrap();
+        vm.set_global("numbers", vec![1, 2, 3]).unwrap();
+        vm.eval(
+            "var total = sum(numbers); var host = config.host; var same = port(config);
+             config.port = 9090;",
+            "collections",
+        )
+        .unwrap();
+        assert_eq!(vm.get_global("total"), Some(Value::Number(6.0)));
+        assert_eq!(
+            vm.get_global("host"),
+            Some(Value::String("localhost".to_string()))
+        );
+        assert_eq!(vm.get_global("same"), Some(Value::Number(8080.0)));
+
+        let config = HashMap::<String, Value>::from_value(vm.get_global("config").unwrap());
+        assert_eq!(config.unwrap()["port"], Value::Number(9090.0));
+        let numbers = Vec::<i32>::from_value(vm.get_global("numbers").unwrap());
+        assert_eq!(numbers, Ok(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn big_maps_and_instances_do_not_grow_the_shapes() {
+        for (backend, stress) in [(Backend::Stack, false), (Backend::Register, true)] {
+            let mut vm = vm(backend);
+            // Collecting at every allocation checks the dictionaries keep
+            // their names alive.
+            vm.set_gc_options(GcOptions {
+                stress,
+                ..GcOptions::default()
+            });
+            vm.eval("class Bag {} var bag = Bag();", "setup").unwrap();
+            let shapes = vm.vm.shapes.count();
+
+            let map: HashMap<String, Value> = (0..1000)
+                .map(|i| (format!("k{}", i), Value::Number(i as f64)))
+                .collect();
+            vm.set_global("map", Value::Map(map)).unwrap();
+            vm.set_global("list", (0..1000).collect::<Vec<i32>>())
+                .unwrap();
+            // The instance passes the limit while the script fills it.
+            vm.eval(
+                "for (var i = 0; i < 100; i = i + 1) {
+                   bag.a = i; bag.b = i; bag.c = i; bag.d = i; bag.e = i;
+                   bag.f = i; bag.g = i; bag.h = i; bag.i = i; bag.j = i;
+                   bag.k = i; bag.l = i; bag.m = i; bag.n = i; bag.o = i;
+                   bag.p = i; bag.q = i; bag.r = i; bag.s = i; bag.t = i;
+                   bag.u = i; bag.v = i; bag.w = i; bag.x = i; bag.y = i;
+                   bag.z = i; bag.aa = i; bag.ab = i; bag.ac = i; bag.ad = i;
+                   bag.ae = i; bag.af = i; bag.ag = i; bag.ah = i; bag.ai = i;
+                 }
+                 map.k999 = map.k1 + map.k998;
+                 map.extra = 1;
+                 var sum = bag.a + bag.af + bag.ai + map.k999 + map.extra;",
+                "fill",
+            )
+            .unwrap();
+            vm.collect_garbage();
+            assert!(vm.vm.shapes.count() < shapes + 100);
+            assert_eq!(vm.get_global("sum"), Some(Value::Number(1297.0)));
+
+            let map = HashMap::<String, Value>::from_value(vm.get_global("map").unwrap());
+            let map = map.unwrap();
+            assert_eq!(map.len(), 1001);
+            assert_eq!(map["k999"], Value::Number(999.0));
+            assert_eq!(map["extra"], Value::Number(1.0));
+            let list = Vec::<i32>::from_value(vm.get_global("list").unwrap());
+            assert_eq!(list, Ok((0..1000).collect()));
+        }
+    }
+
+    /// A sink whose output the test can read back.
+    #[derive(Clone, Default)]
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Capture {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("closed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_goes_to_each_vms_sinks() {
+        for backend in BACKENDS {
+            let (mut first, mut second) = (vm(backend), vm(backend));
+            let (out, err) = (Capture::default(), Capture::default());
+            first.set_stdout(out.clone());
+            first.set_stderr(err.clone());
+            second.set_stdout(Capture::default());
+            second.set_stderr(Capture::default());
+
+            first
+                .eval("{ var unused = 1; } print(\"one\");", "one")
+                .unwrap();
+            second.eval("print(\"two\");", "two").unwrap();
+            assert!(first.eval("print(1 + nil);", "fail").is_err());
+            assert!(first.eval("print(;", "bad").is_err());
+            assert_eq!(out.text(), "one\n");
+            assert_eq!(
+                err.text(),
+                "[Line 1] Warning: unused variable 'unused' [unused-variable]\n\
+                 Operands must be two numbers or two strings.\n\
+                 Line[1] in script\n\
+                 [Line 1] Error at ';': Expected expression\n"
+            );
+
+            second.set_stdout(Broken);
+            assert!(matches!(
+                second.eval("print(2);", "broken"),
+                Err(Error::Runtime { message, .. }) if message == "Could not print: closed."
+            ));
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! The compiler's intermediate forms, printed for `--emit`.
+
+use std::io::{self, Write};
+
+use crate::{
+    ast,
+    compiler::{Backend, Compiler, CompilerOptions},
+    cst,
+    gc::Heap,
+    register::RegisterCode,
+};
+
+/// Which stage of the pipeline `emit` stops at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Emit {
+    /// The lossless syntax tree.
+    Cst,
+    /// The AST, as JSON.
+    Ast,
+    /// The disassembled code of whichever backend the options select.
+    Bytecode,
+}
+
+/// Writes `source` as it looks after the `emit` stage to `out`, and any
+/// errors to `diagnostics`.
+pub fn emit(
+    source: &str,
+    emit: Emit,
+    options: CompilerOptions,
+    out: &mut dyn Write,
+    diagnostics: &mut dyn Write,
+) -> io::Result<()> {
+    match emit {
+        Emit::Cst => {
+            let parse = cst::parse(source);
+            write!(out, "{}", parse.root.dump())?;
+            for error in parse.errors {
+                writeln!(
+                    diagnostics,
+                    "[Line {}] Error: {}",
+                    error.line, error.message
+                )?;
+            }
+        }
+        Emit::Ast => match ast::parse(source) {
+            Ok(program) => writeln!(out, "{}", serde_json::to_string_pretty(&program).unwrap())?,
+            Err(errors) => {
+                for error in errors {
+                    writeln!(
+                        diagnostics,
+                        "[Line {}] Error: {}",
+                        error.line, error.message
+                    )?;
+                }
+            }
+        },
+        Emit::Bytecode => {
+            let mut heap = Heap::new();
+            let mut compiler = Compiler::new(source.to_string(), options, &mut heap);
+            let function = compiler.compile();
+            for diagnostic in std::mem::take(&mut compiler.diagnostics) {
+                writeln!(diagnostics, "{}", diagnostic)?;
+            }
+            if let Some(function) = function {
+                match options.backend {
+                    Backend::Stack => function.chunk.disassembleChunk("script", &heap, out)?,
+                    Backend::Register => {
+                        RegisterCode::disassemble(&function, "script", &heap, out)?
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Emit, emit};
+    use crate::compiler::{Backend, CompilerOptions};
+
+    /// What `emit` writes to its output and its diagnostics.
+    fn capture(source: &str, stage: Emit, backend: Backend) -> (String, String) {
+        let options = CompilerOptions {
+            backend,
+            ..CompilerOptions::default()
+        };
+        let (mut out, mut diagnostics) = (vec![], vec![]);
+        emit(source, stage, options, &mut out, &mut diagnostics).unwrap();
+        (
+            String::from_utf8(out).unwrap(),
+            String::from_utf8(diagnostics).unwrap(),
+        )
+    }
+
+    #[test]
+    fn writes_to_the_given_sinks() {
+        let source = "fun f() { return 1; } print(f());";
+        let (cst, errors) = capture(source, Emit::Cst, Backend::Stack);
+        assert!(cst.starts_with("Program@0..33\n"));
+        assert_eq!(errors, "");
+        let (ast, _) = capture(source, Emit::Ast, Backend::Stack);
+        assert!(ast.contains("\"type\": \"Print\""));
+
+        let (stack, _) = capture(source, Emit::Bytecode, Backend::Stack);
+        assert!(stack.starts_with("== script ==\n"));
+        assert!(stack.contains("\n== <fn f> ==\n"));
+        let (register, _) = capture(source, Emit::Bytecode, Backend::Register);
+        assert!(register.starts_with("== script ("));
+
+        for stage in [Emit::Cst, Emit::Ast, Emit::Bytecode] {
+            let (_, errors) = capture("print(;", stage, Backend::Stack);
+            assert!(errors.starts_with("[Line 1] Error"), "{:?}", errors);
+        }
+    }
+}
@@ -0,0 +1,1128 @@
+//! VM-owned object heap with a generational, incremental collector.
+//!
+//! Strings, closures, upvalues, classes, instances, bound methods and
+//! natives live in a slot arena and are referred to by typed `Gc<T>`
+//! handles, so cycles such as a closure that captures itself are
+//! reclaimed. Strings are interned: there is at most one live object per
+//! distinct contents, so string equality is handle equality. Function
+//! prototypes stay in `Rc`s: they are immutable and only ever form a tree,
+//! but their constants are traced so the strings they hold stay alive.
+//!
+//! New objects start in the nursery. A minor collection traces only young
+//! objects, from the VM's stack roots plus the remembered set, and promotes
+//! whatever survives. Old objects are reclaimed by a major collection that
+//! marks incrementally, a bounded slice at a time, and then sweeps the same
+//! way, so no single pause has to walk the whole heap.
+//!
+//! Both schemes rely on the write barrier. Every store of a value into a heap
+//! object or into the globals table must go through `write_barrier` or
+//! `root_write_barrier`: young values stored into old holders are remembered
+//! for the next minor collection, and values stored into already-marked
+//! holders during marking are shaded so the collector cannot miss them. The
+//! stack is not barriered; it is rescanned once marking runs dry.
+//!
+//! The heap cannot see the VM's roots, so it never collects on its own.
+//! Allocation only records bytes; the VM checks `should_collect` at points
+//! where everything live is reachable, marks its roots and drives the
+//! collection from there.
+
+use std::{
+    collections::HashMap,
+    fmt, hash,
+    marker::PhantomData,
+    mem,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    embed::Pin,
+    shape::{Dictionary, ShapeId},
+    userdata::Tracer,
+    value::{
+        ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative, ObjString,
+        ObjUpvalue, Value, ValueType,
+    },
+};
+
+/// Old-generation size after a major collection is multiplied by this to get
+/// the next threshold.
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const FIRST_GC_BYTES: usize = 1024 * 1024;
+/// Incremental work is measured in objects traced or slots swept; the clock
+/// is only read once per this many units.
+const WORK_PER_CLOCK_CHECK: usize = 64;
+/// In stress mode a major cycle starts after this many minor collections.
+const STRESS_MINORS_PER_MAJOR: usize = 4;
+
+/// Collector tuning, set on `VM::heap.options`.
+#[derive(Debug, Clone, Copy)]
+pub struct GcOptions {
+    /// Target upper bound for one incremental slice of a major collection.
+    pub max_pause: Duration,
+    /// Young bytes that trigger a minor collection.
+    pub nursery_bytes: usize,
+    /// Collect at every safepoint that follows an allocation, and step major
+    /// cycles with the smallest possible slices.
+    pub stress: bool,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        Self {
+            max_pause: Duration::from_millis(1),
+            nursery_bytes: 256 * 1024,
+            stress: false,
+        }
+    }
+}
+
+/// Snapshot of collector activity, returned by `Heap::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GcStats {
+    pub minor_collections: usize,
+    pub major_collections: usize,
+    /// Number of times the collector stopped the VM, for any reason.
+    pub pauses: usize,
+    pub total_pause: Duration,
+    pub max_pause: Duration,
+    pub last_pause: Duration,
+    pub bytes_allocated: usize,
+    pub young_bytes: usize,
+    pub live_objects: usize,
+}
+
+/// Handle to an object of type `T` in a `Heap`.
+pub struct Gc<T> {
+    index: u32,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Gc<T> {
+    fn new(index: u32) -> Self {
+        Self {
+            index,
+            marker: PhantomData,
+        }
+    }
+
+    /// The slot index, for value encodings that pack handles into bits.
+    #[cfg(feature = "nan-boxing")]
+    pub fn to_raw(self) -> u32 {
+        self.index
+    }
+
+    /// Rebuilds a handle from `to_raw`. The slot must hold a `T`.
+    #[cfg(feature = "nan-boxing")]
+    pub fn from_raw(index: u32) -> Self {
+        Self::new(index)
+    }
+}
+
+impl<T> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Gc<T> {}
+
+impl<T> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl<T> Eq for Gc<T> {}
+
+impl<T> hash::Hash for Gc<T> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Gc({})", self.index)
+    }
+}
+
+#[derive(Debug)]
+pub enum Obj {
+    String(ObjString),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    Native(ObjNative),
+}
+
+/// Types that can be stored in the heap.
+pub trait HeapObject: Sized {
+    fn into_obj(self) -> Obj;
+    fn from_obj(obj: &Obj) -> &Self;
+    fn from_obj_mut(obj: &mut Obj) -> &mut Self;
+}
+
+macro_rules! heap_object {
+    ($ty:ident, $variant:ident) => {
+        impl HeapObject for $ty {
+            fn into_obj(self) -> Obj {
+                Obj::$variant(self)
+            }
+
+            fn from_obj(obj: &Obj) -> &Self {
+                match obj {
+                    Obj::$variant(object) => object,
+                    _ => unreachable!("handle points at a different object type"),
+                }
+            }
+
+            fn from_obj_mut(obj: &mut Obj) -> &mut Self {
+                match obj {
+                    Obj::$variant(object) => object,
+                    _ => unreachable!("handle points at a different object type"),
+                }
+            }
+        }
+    };
+}
+
+heap_object!(ObjString, String);
+heap_object!(ObjClosure, Closure);
+heap_object!(ObjUpvalue, Upvalue);
+heap_object!(ObjClass, Class);
+heap_object!(ObjInstance, Instance);
+heap_object!(ObjBoundMethod, BoundMethod);
+heap_object!(ObjNative, Native);
+
+struct Slot {
+    obj: Obj,
+    marked: bool,
+    /// Still in the nursery.
+    young: bool,
+    /// Already in `Heap::remembered`.
+    remembered: bool,
+    /// Bytes charged for this object when it was allocated.
+    size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Idle,
+    /// Between `begin_minor` and `finish_minor`; only young objects are
+    /// marked.
+    Minor,
+    /// A major cycle is tracing the gray stack.
+    Marking,
+    /// A major cycle is sweeping; slots below the cursor are done.
+    Sweeping(usize),
+}
+
+pub struct Heap {
+    slots: Vec<Option<Slot>>,
+    free: Vec<u32>,
+    bytes_allocated: usize,
+    young_bytes: usize,
+    next_gc: usize,
+    pub options: GcOptions,
+    phase: Phase,
+    allocated_since_collect: bool,
+    minors_since_major: usize,
+    /// Every young object, so a minor sweep never walks the old generation.
+    nursery: Vec<u32>,
+    /// Young objects stored into old holders or globals since the last
+    /// collection; roots for the next minor collection.
+    remembered: Vec<u32>,
+    gray: Vec<u32>,
+    /// Interned strings by cached hash. Weak: sweeping drops freed strings.
+    strings: HashMap<u32, Vec<Gc<ObjString>>>,
+    /// Shapes of the classes and instances the running major cycle has
+    /// traced, and of any it moved an instance to, for `Shapes::collect`.
+    live_shapes: Vec<ShapeId>,
+    stats: GcStats,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            slots: vec![],
+            free: vec![],
+            bytes_allocated: 0,
+            young_bytes: 0,
+            next_gc: FIRST_GC_BYTES,
+            options: GcOptions::default(),
+            phase: Phase::Idle,
+            allocated_since_collect: false,
+            minors_since_major: 0,
+            nursery: vec![],
+            remembered: vec![],
+            gray: vec![],
+            strings: HashMap::new(),
+            live_shapes: vec![],
+            stats: GcStats::default(),
+        }
+    }
+
+    pub fn alloc<T: HeapObject>(&mut self, object: T) -> Gc<T> {
+        let obj = object.into_obj();
+        let size = Self::size_of(&obj);
+        self.bytes_allocated += size;
+        self.young_bytes += size;
+        self.allocated_since_collect = true;
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(None);
+                (self.slots.len() - 1) as u32
+            }
+        };
+        // Objects born during marking are gray so whatever they point at
+        // gets traced; during sweeping they must not be freed by this cycle.
+        let marked = match self.phase {
+            Phase::Marking => {
+                self.gray.push(index);
+                true
+            }
+            Phase::Sweeping(cursor) => index as usize >= cursor,
+            Phase::Idle | Phase::Minor => false,
+        };
+        self.slots[index as usize] = Some(Slot {
+            obj,
+            marked,
+            young: true,
+            remembered: false,
+            size,
+        });
+        self.nursery.push(index);
+        Gc::new(index)
+    }
+
+    pub fn get<T: HeapObject>(&self, gc: Gc<T>) -> &T {
+        T::from_obj(&self.slot(gc.index).obj)
+    }
+
+    pub fn get_mut<T: HeapObject>(&mut self, gc: Gc<T>) -> &mut T {
+        T::from_obj_mut(&mut self.slot_mut(gc.index).obj)
+    }
+
+    pub fn alloc_string(&mut self, chars: String) -> Value {
+        Value::from(ValueType::VAL_STRING(self.intern(chars)))
+    }
+
+    /// Returns the string object holding `chars`, allocating it only if no
+    /// such string is live. All strings must be created through here.
+    pub fn intern(&mut self, chars: String) -> Gc<ObjString> {
+        let hash = hash_string(&chars);
+        let existing = self.strings.get(&hash).and_then(|bucket| {
+            bucket
+                .iter()
+                .copied()
+                .find(|string| self.get(*string).chars == chars)
+        });
+        if let Some(string) = existing {
+            self.revive(string.index);
+            return string;
+        }
+        let string = self.alloc(ObjString { chars, hash });
+        self.strings.entry(hash).or_default().push(string);
+        string
+    }
+
+    /// A running major cycle may have judged an interned string dead before
+    /// `intern` handed it out again; make sure it survives that cycle.
+    fn revive(&mut self, index: u32) {
+        match self.phase {
+            Phase::Marking => self.mark_index(index),
+            Phase::Sweeping(cursor) if index as usize >= cursor => {
+                self.slot_mut(index).marked = true;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn string(&self, gc: Gc<ObjString>) -> &str {
+        &self.get(gc).chars
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn live_objects(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            bytes_allocated: self.bytes_allocated,
+            young_bytes: self.young_bytes,
+            live_objects: self.live_objects(),
+            ..self.stats
+        }
+    }
+
+    pub fn record_pause(&mut self, pause: Duration) {
+        self.stats.pauses += 1;
+        self.stats.total_pause += pause;
+        self.stats.max_pause = self.stats.max_pause.max(pause);
+        self.stats.last_pause = pause;
+    }
+
+    /// Whether a minor collection is due. Never true while a major cycle is
+    /// in progress; that cycle has to be stepped to completion first.
+    pub fn should_collect(&self) -> bool {
+        self.phase == Phase::Idle
+            && (self.young_bytes > self.options.nursery_bytes
+                || self.bytes_allocated > self.next_gc
+                || (self.options.stress && self.allocated_since_collect))
+    }
+
+    /// Whether the old generation has outgrown its threshold.
+    pub fn wants_major(&self) -> bool {
+        self.bytes_allocated - self.young_bytes > self.next_gc
+            || (self.options.stress && self.minors_since_major >= STRESS_MINORS_PER_MAJOR)
+    }
+
+    /// A major cycle is marking or sweeping.
+    pub fn is_collecting(&self) -> bool {
+        matches!(self.phase, Phase::Marking | Phase::Sweeping(_))
+    }
+
+    pub fn mark_value(&mut self, value: &Value) {
+        let mut children = vec![];
+        Self::value_children(value, &mut children);
+        for index in children {
+            self.mark_index(index);
+        }
+    }
+
+    pub fn mark<T>(&mut self, gc: Gc<T>) {
+        self.mark_index(gc.index);
+    }
+
+    /// Must follow every store of `value` into the object `holder`.
+    pub fn write_barrier<T>(&mut self, holder: Gc<T>, value: &Value) {
+        let Slot { marked, young, .. } = *self.slot(holder.index);
+        self.barrier(marked, young, value);
+    }
+
+    /// Must follow every store of `value` into a root that is only scanned
+    /// when a collection begins, such as the globals table.
+    pub fn root_write_barrier(&mut self, value: &Value) {
+        self.barrier(true, false, value);
+    }
+
+    fn barrier(&mut self, holder_marked: bool, holder_young: bool, value: &Value) {
+        // Functions never leave constant tables, so only heap objects can be
+        // stored.
+        let Some(index) = Self::object_index(value) else {
+            return;
+        };
+        if holder_marked && self.phase == Phase::Marking {
+            self.mark_index(index);
+        }
+        let slot = self.slot_mut(index);
+        if !holder_young && slot.young && !slot.remembered {
+            slot.remembered = true;
+            self.remembered.push(index);
+        }
+    }
+
+    /// Starts a minor collection. The VM marks its stack roots next; the
+    /// remembered set stands in for the old generation and the globals.
+    pub fn begin_minor(&mut self) {
+        debug_assert_eq!(self.phase, Phase::Idle);
+        self.phase = Phase::Minor;
+        for index in mem::take(&mut self.remembered) {
+            self.slot_mut(index).remembered = false;
+            self.mark_index(index);
+        }
+    }
+
+    /// Traces the marked young objects, frees the unreachable ones and
+    /// promotes the rest.
+    pub fn finish_minor(&mut self) {
+        self.drain_gray();
+        for index in mem::take(&mut self.nursery) {
+            match &mut self.slots[index as usize] {
+                Some(slot) if slot.marked => {
+                    slot.marked = false;
+                    slot.young = false;
+                }
+                Some(_) => self.free_slot(index),
+                None => {}
+            }
+        }
+        self.young_bytes = 0;
+        self.phase = Phase::Idle;
+        self.allocated_since_collect = false;
+        self.minors_since_major += 1;
+        self.stats.minor_collections += 1;
+    }
+
+    /// Starts a major cycle. The VM marks all of its roots next, globals
+    /// included; after that only the stack is rescanned.
+    pub fn begin_major(&mut self) {
+        debug_assert_eq!(self.phase, Phase::Idle);
+        self.phase = Phase::Marking;
+        self.minors_since_major = 0;
+        self.live_shapes.clear();
+    }
+
+    /// Does major-cycle work until `deadline`, or until done if there is
+    /// none. Returns true when the gray stack has run dry, at which point the
+    /// VM must re-mark its stack roots and call `finish_marking`.
+    pub fn step(&mut self, deadline: Option<Instant>) -> bool {
+        let mut children = vec![];
+        let mut work = 0;
+        loop {
+            match self.phase {
+                Phase::Marking => match self.gray.pop() {
+                    Some(index) => self.trace(index, &mut children),
+                    None => return true,
+                },
+                Phase::Sweeping(cursor) if cursor == self.slots.len() => {
+                    self.finish_major();
+                    return false;
+                }
+                Phase::Sweeping(cursor) => {
+                    self.sweep_slot(cursor);
+                    self.phase = Phase::Sweeping(cursor + 1);
+                }
+                Phase::Idle | Phase::Minor => return false,
+            }
+            work += 1;
+            if work % WORK_PER_CLOCK_CHECK == 0 && deadline.is_some_and(|d| Instant::now() >= d) {
+                return false;
+            }
+        }
+    }
+
+    /// Must follow every move of an instance to another shape; one already
+    /// traced would otherwise keep a shape the cycle thinks is unused.
+    pub fn shape_barrier(&mut self, shape: ShapeId) {
+        if self.phase == Phase::Marking {
+            self.live_shapes.push(shape);
+        }
+    }
+
+    /// Traces everything marked so far. Once the rescanned roots are in,
+    /// the shapes in use are known.
+    pub fn drain_gray(&mut self) {
+        let mut children = vec![];
+        while let Some(index) = self.gray.pop() {
+            self.trace(index, &mut children);
+        }
+    }
+
+    /// The shapes the major cycle found in use, once marking has run dry.
+    pub fn take_live_shapes(&mut self) -> Vec<ShapeId> {
+        mem::take(&mut self.live_shapes)
+    }
+
+    /// Must precede every handing out of a userdata's Rust value for
+    /// writing: an instance already traced is traced again, in case the
+    /// objects it holds change.
+    pub fn userdata_barrier(&mut self, instance: Gc<ObjInstance>) {
+        if self.phase == Phase::Marking && self.slot(instance.index).marked {
+            self.gray.push(instance.index);
+        }
+    }
+
+    /// How many of the references to each `Object` pin, by address, come
+    /// from the Rust values of userdata whose class traces them. A pin
+    /// nothing else holds is not a root: its object lives only if one of
+    /// those instances does.
+    pub fn held_pins(&self) -> HashMap<*const Pin, usize> {
+        let mut held = HashMap::new();
+        for slot in self.slots.iter().flatten() {
+            if let Obj::Instance(instance) = &slot.obj {
+                for (pin, _) in self.userdata_objects(instance) {
+                    *held.entry(pin).or_insert(0) += 1;
+                }
+            }
+        }
+        held
+    }
+
+    /// The objects the Rust value of a userdata instance reports holding,
+    /// if its class has a trace hook.
+    fn userdata_objects(&self, instance: &ObjInstance) -> Vec<(*const Pin, Value)> {
+        let (Some(data), Some(host)) = (&instance.host, &self.get(instance.class).host) else {
+            return vec![];
+        };
+        let Some(trace) = &host.trace else {
+            return vec![];
+        };
+        let mut tracer = Tracer::new(host.vm);
+        trace(data.as_ref(), &mut tracer);
+        tracer.objects
+    }
+
+    /// Traces whatever the rescanned roots added and starts sweeping.
+    pub fn finish_marking(&mut self) {
+        debug_assert_eq!(self.phase, Phase::Marking);
+        self.drain_gray();
+        self.phase = Phase::Sweeping(0);
+    }
+
+    fn finish_major(&mut self) {
+        for index in mem::take(&mut self.nursery) {
+            if let Some(slot) = &mut self.slots[index as usize] {
+                slot.young = false;
+            }
+        }
+        for index in mem::take(&mut self.remembered) {
+            if let Some(slot) = &mut self.slots[index as usize] {
+                slot.remembered = false;
+            }
+        }
+        self.young_bytes = 0;
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(FIRST_GC_BYTES);
+        self.phase = Phase::Idle;
+        self.allocated_since_collect = false;
+        self.stats.major_collections += 1;
+    }
+
+    fn sweep_slot(&mut self, index: usize) {
+        match &mut self.slots[index] {
+            Some(slot) if slot.marked => slot.marked = false,
+            Some(_) => self.free_slot(index as u32),
+            None => {}
+        }
+    }
+
+    fn free_slot(&mut self, index: u32) {
+        let Some(slot) = self.slots[index as usize].take() else {
+            return;
+        };
+        self.bytes_allocated -= slot.size;
+        if slot.young {
+            self.young_bytes -= slot.size;
+        }
+        if let Obj::String(string) = &slot.obj
+            && let Some(bucket) = self.strings.get_mut(&string.hash)
+        {
+            bucket.retain(|interned| interned.index != index);
+            if bucket.is_empty() {
+                self.strings.remove(&string.hash);
+            }
+        }
+        self.free.push(index);
+    }
+
+    fn trace(&mut self, index: u32, children: &mut Vec<u32>) {
+        let obj = &self.slot(index).obj;
+        Self::obj_children(obj, children);
+        if let Obj::Instance(instance) = obj {
+            for (_, value) in self.userdata_objects(instance) {
+                Self::value_children(&value, children);
+            }
+        }
+        let shape = match obj {
+            Obj::Class(class) => Some(class.shape),
+            Obj::Instance(instance) => Some(instance.shape),
+            _ => None,
+        };
+        if let Some(shape) = shape.filter(|_| self.phase == Phase::Marking) {
+            self.live_shapes.push(shape);
+        }
+        for child in children.drain(..) {
+            self.mark_index(child);
+        }
+    }
+
+    fn mark_index(&mut self, index: u32) {
+        let minor = self.phase == Phase::Minor;
+        let slot = self.slot_mut(index);
+        // A minor collection leaves the old generation alone; its pointers
+        // into the nursery are covered by the remembered set.
+        if slot.marked || (minor && !slot.young) {
+            return;
+        }
+        slot.marked = true;
+        self.gray.push(index);
+    }
+
+    fn slot(&self, index: u32) -> &Slot {
+        match &self.slots[index as usize] {
+            Some(slot) => slot,
+            None => panic!("use of a collected object"),
+        }
+    }
+
+    fn slot_mut(&mut self, index: u32) -> &mut Slot {
+        match &mut self.slots[index as usize] {
+            Some(slot) => slot,
+            None => panic!("use of a collected object"),
+        }
+    }
+
+    /// Slot of the heap object `value` refers to, if any.
+    fn object_index(value: &Value) -> Option<u32> {
+        match value.type_v() {
+            ValueType::VAL_STRING(gc) => Some(gc.index),
+            ValueType::VAL_CLOSURE(gc) => Some(gc.index),
+            ValueType::VAL_CLASS(gc) => Some(gc.index),
+            ValueType::VAL_INSTANCE(gc) => Some(gc.index),
+            ValueType::VAL_BOUND_METHOD(gc) => Some(gc.index),
+            ValueType::VAL_NATIVE(gc) => Some(gc.index),
+            ValueType::VAL_FUNCTION(_)
+            | ValueType::VAL_BOOL(_)
+            | ValueType::VAL_NIL
+            | ValueType::VAL_NUMBER(_) => None,
+        }
+    }
+
+    fn value_children(value: &Value, out: &mut Vec<u32>) {
+        match value.type_v() {
+            ValueType::VAL_FUNCTION(function) => Self::function_children(&function, out),
+            _ => out.extend(Self::object_index(value)),
+        }
+    }
+
+    fn function_children(function: &Rc<ObjFunction>, out: &mut Vec<u32>) {
+        for constant in &function.chunk.constants.values {
+            Self::value_children(constant, out);
+        }
+    }
+
+    fn obj_children(obj: &Obj, out: &mut Vec<u32>) {
+        match obj {
+            Obj::String(_) => {}
+            Obj::Closure(closure) => {
+                Self::function_children(&closure.function, out);
+                out.extend(closure.upvalues.iter().map(|upvalue| upvalue.index));
+            }
+            Obj::Upvalue(ObjUpvalue::Closed(value)) => Self::value_children(value, out),
+            Obj::Upvalue(ObjUpvalue::Open(_)) => {}
+            Obj::Class(class) => {
+                out.push(class.name.index);
+                for (name, method) in &class.methods {
+                    out.push(name.index);
+                    out.push(method.index);
+                }
+                out.extend(class.initializer.map(|initializer| initializer.index));
+                if let Some(host) = &class.host {
+                    for (name, method) in &host.methods {
+                        out.push(name.index);
+                        out.push(method.index);
+                    }
+                    out.extend(host.getters.keys().map(|name| name.index));
+                    out.extend(host.setters.keys().map(|name| name.index));
+                }
+            }
+            Obj::Instance(instance) => {
+                out.push(instance.class.index);
+                for field in &instance.fields {
+                    Self::value_children(field, out);
+                }
+                if let Some(dictionary) = &instance.dictionary {
+                    out.extend(dictionary.names().iter().map(|name| name.index));
+                }
+            }
+            Obj::BoundMethod(bound) => {
+                Self::value_children(&bound.receiver, out);
+                Self::value_children(&bound.method, out);
+            }
+            Obj::Native(_) => {}
+        }
+    }
+
+    fn size_of(obj: &Obj) -> usize {
+        let payload = match obj {
+            Obj::String(string) => string.chars.capacity(),
+            Obj::Closure(closure) => closure.upvalues.capacity() * mem::size_of::<Gc<ObjUpvalue>>(),
+            Obj::Upvalue(_) => 0,
+            Obj::Class(class) => {
+                class.methods.capacity() * mem::size_of::<(Gc<ObjString>, Gc<ObjClosure>)>()
+            }
+            Obj::Instance(instance) => {
+                instance.fields.capacity() * mem::size_of::<Value>()
+                    + instance.host.as_deref().map_or(0, mem::size_of_val)
+                    + instance.dictionary.as_deref().map_or(0, Dictionary::size)
+            }
+            Obj::BoundMethod(_) => 0,
+            Obj::Native(native) => native.name.capacity(),
+        };
+        mem::size_of::<Slot>() + payload
+    }
+
+    /// Formats `value` the way `print` shows it.
+    pub fn display<'a>(&'a self, value: &'a Value) -> ValueDisplay<'a> {
+        ValueDisplay { heap: self, value }
+    }
+}
+
+/// FNV-1a, computed once per string and cached in `ObjString::hash`.
+pub fn hash_string(chars: &str) -> u32 {
+    let mut hash = 2166136261u32;
+    for byte in chars.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+pub struct ValueDisplay<'a> {
+    heap: &'a Heap,
+    value: &'a Value,
+}
+
+impl fmt::Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value.type_v() {
+            ValueType::VAL_NIL => write!(f, "nil"),
+            ValueType::VAL_BOOL(a) => write!(f, "{}", a),
+            ValueType::VAL_NUMBER(a) => write!(f, "{}", a),
+            ValueType::VAL_STRING(a) => write!(f, "{}", self.heap.string(a)),
+            ValueType::VAL_FUNCTION(a) => write!(f, "{}", a),
+            ValueType::VAL_CLOSURE(a) => write!(f, "{}", self.heap.get(a).function),
+            ValueType::VAL_CLASS(a) => write!(f, "{}", self.heap.string(self.heap.get(a).name)),
+            ValueType::VAL_INSTANCE(a) => {
+                let class = self.heap.get(self.heap.get(a).class);
+                write!(f, "{} instance", self.heap.string(class.name))
+            }
+            ValueType::VAL_BOUND_METHOD(a) => {
+                write!(f, "{}", self.heap.display(&self.heap.get(a).method))
+            }
+            ValueType::VAL_NATIVE(a) => write!(f, "<native fn {}>", self.heap.get(a).name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, rc::Rc};
+
+    use super::{GcOptions, Heap};
+    use crate::{
+        compiler::CompilerOptions,
+        embed::{self, Object, Pin},
+        value::{
+            HostClass, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjUpvalue, Value, ValueType,
+        },
+        vm::{InterpretResult, VM},
+    };
+
+    /// Runs a major cycle to the end; the roots were marked after
+    /// `begin_major` and there is no stack to rescan.
+    fn collect(heap: &mut Heap) {
+        while heap.is_collecting() {
+            if heap.step(None) {
+                heap.finish_marking();
+            }
+        }
+    }
+
+    #[test]
+    fn frees_unreachable_objects() {
+        let mut heap = Heap::new();
+        let kept = heap.alloc_string("kept".to_string());
+        heap.alloc_string("garbage".to_string());
+        let before = heap.bytes_allocated();
+
+        heap.begin_major();
+        heap.mark_value(&kept);
+        collect(&mut heap);
+        assert_eq!(heap.live_objects(), 1);
+        assert!(heap.bytes_allocated() < before);
+
+        // The freed slot is reused.
+        let reused = heap.alloc_string("again".to_string());
+        assert_eq!(heap.live_objects(), 2);
+        assert_eq!(heap.display(&reused).to_string(), "again");
+    }
+
+    #[test]
+    fn collects_cycles_through_upvalues() {
+        let mut heap = Heap::new();
+        let upvalue = heap.alloc(ObjUpvalue::Open(0));
+        let closure = heap.alloc(ObjClosure {
+            function: Rc::new(ObjFunction::new(None, 0)),
+            upvalues: vec![upvalue],
+        });
+        *heap.get_mut(upvalue) = ObjUpvalue::Closed(Value::from(ValueType::VAL_CLOSURE(closure)));
+
+        heap.begin_major();
+        heap.mark(closure);
+        collect(&mut heap);
+        assert_eq!(heap.live_objects(), 2);
+
+        heap.begin_major();
+        collect(&mut heap);
+        assert_eq!(heap.live_objects(), 0);
+        assert_eq!(heap.bytes_allocated(), 0);
+    }
+
+    #[test]
+    fn traces_function_constants() {
+        let mut heap = Heap::new();
+        let mut function = ObjFunction::new(None, 0);
+        let name = heap.alloc_string("name".to_string());
+        function.chunk.addConstant(name);
+        let closure = heap.alloc(ObjClosure {
+            function: Rc::new(function),
+            upvalues: vec![],
+        });
+        heap.begin_major();
+        heap.mark(closure);
+        collect(&mut heap);
+        assert_eq!(heap.live_objects(), 2);
+    }
+
+    #[test]
+    fn collects_shapes_nothing_uses() {
+        // Each block gives an instance a field no other has, named only by
+        // its script's constants.
+        let scripts: Vec<String> = (0..10)
+            .map(|script| {
+                (0..50)
+                    .map(|i| format!("{{ var bag = Bag(); bag.k{}_{} = 1; }}\n", script, i))
+                    .collect()
+            })
+            .collect();
+        for stress in [false, true] {
+            let mut vm = VM::new();
+            vm.heap.options.stress = stress;
+            let setup = "class Bag {} var kept = Bag(); kept.a = 1;";
+            vm.interpret(setup.to_string(), "test");
+            vm.collect_garbage();
+            let (live, shapes) = (vm.heap.live_objects(), vm.shapes.count());
+
+            for script in &scripts {
+                assert_eq!(
+                    vm.interpret(script.clone(), "test"),
+                    InterpretResult::INTERPRET_OK
+                );
+            }
+            assert!(vm.shapes.count() > shapes);
+            vm.collect_garbage();
+            assert_eq!(vm.heap.live_objects(), live);
+            assert_eq!(vm.shapes.count(), shapes);
+
+            // The shapes still in use work as before.
+            let check = "if (kept.a != 1) -nil; kept.b = 2; if (kept.b != 2) -nil;";
+            assert_eq!(
+                vm.interpret(check.to_string(), "test"),
+                InterpretResult::INTERPRET_OK
+            );
+        }
+    }
+
+    #[test]
+    fn stress_mode_keeps_live_values() {
+        let mut vm = VM::new();
+        vm.options = CompilerOptions {
+            opt_level: 0,
+            ..CompilerOptions::default()
+        };
+        vm.heap.options.stress = true;
+        let source = "
+            fun counter() {
+                var n = 0;
+                fun count() { n = n + 1; return n; }
+                return count;
+            }
+            var c = counter();
+            var s = \"\";
+            for (var i = 0; i < 50; i = i + 1) { s = s + \"ab\"; c(); }
+            { fun loop() { return loop; } loop(); }
+        ";
+        assert!(matches!(
+            vm.interpret(source.to_string(), "test"),
+            InterpretResult::INTERPRET_OK
+        ));
+        // A runtime error here would mean a live value was freed.
+        assert!(matches!(
+            vm.interpret("if (c() != 51 or s != s + \"\") -nil;".to_string(), "test"),
+            InterpretResult::INTERPRET_OK
+        ));
+
+        // Dropping the globals lets their objects go, including the
+        // self-referencing closure's cycle from the block above.
+        vm.collect_garbage();
+        let live = vm.heap.live_objects();
+        vm.interpret("s = nil; c = nil; counter = nil;".to_string(), "test");
+        vm.collect_garbage();
+        assert!(vm.heap.live_objects() < live);
+    }
+
+    #[test]
+    fn minor_collection_promotes_survivors() {
+        let mut heap = Heap::new();
+        let kept = heap.alloc_string("kept".to_string());
+        heap.alloc_string("garbage".to_string());
+
+        heap.begin_minor();
+        heap.mark_value(&kept);
+        heap.finish_minor();
+        assert_eq!(heap.live_objects(), 1);
+        assert_eq!(heap.stats().young_bytes, 0);
+
+        // Old objects are out of a minor collection's reach.
+        heap.begin_minor();
+        heap.finish_minor();
+        assert_eq!(heap.live_objects(), 1);
+        assert_eq!(heap.stats().minor_collections, 2);
+    }
+
+    #[test]
+    fn write_barrier_remembers_young_values() {
+        let mut heap = Heap::new();
+        let upvalue = heap.alloc(ObjUpvalue::Closed(Value::nil_value()));
+        heap.begin_minor();
+        heap.mark(upvalue);
+        heap.finish_minor();
+
+        // Only the old upvalue points at the new string, and minor
+        // collections never trace old objects.
+        let young = heap.alloc_string("young".to_string());
+        *heap.get_mut(upvalue) = ObjUpvalue::Closed(young.clone());
+        heap.write_barrier(upvalue, &young);
+        let global = heap.alloc_string("global".to_string());
+        heap.root_write_barrier(&global);
+        heap.begin_minor();
+        heap.finish_minor();
+        assert_eq!(heap.live_objects(), 3);
+        assert_eq!(heap.display(&young).to_string(), "young");
+    }
+
+    #[test]
+    fn write_barrier_shades_stores_during_marking() {
+        let mut heap = Heap::new();
+        let upvalue = heap.alloc(ObjUpvalue::Closed(Value::nil_value()));
+        let white = heap.alloc_string("white".to_string());
+
+        heap.begin_major();
+        heap.mark(upvalue);
+        // Trace the upvalue black before the string is stored into it.
+        assert!(heap.step(None));
+        *heap.get_mut(upvalue) = ObjUpvalue::Closed(white.clone());
+        heap.write_barrier(upvalue, &white);
+        collect(&mut heap);
+        assert_eq!(heap.live_objects(), 2);
+        assert_eq!(heap.display(&white).to_string(), "white");
+    }
+
+    #[test]
+    fn incremental_collection_reports_pauses() {
+        let mut vm = VM::new();
+        vm.heap.options = GcOptions {
+            nursery_bytes: 4096,
+            ..GcOptions::default()
+        };
+        let source = "
+            var keep = \"\";
+            for (var i = 0; i < 2000; i = i + 1) {
+                var garbage = \"x\" + \"y\";
+                if (i < 200) keep = keep + \"k\";
+            }
+            if (keep != keep + \"\") -nil;
+        ";
+        assert!(matches!(
+            vm.interpret(source.to_string(), "test"),
+            InterpretResult::INTERPRET_OK
+        ));
+        let stats = vm.gc_stats();
+        assert!(stats.minor_collections > 0);
+        assert!(stats.pauses >= stats.minor_collections);
+        assert!(stats.max_pause <= stats.total_pause);
+        assert!(stats.last_pause <= stats.max_pause);
+
+        vm.collect_garbage();
+        let stats = vm.gc_stats();
+        assert!(stats.major_collections > 0);
+        assert_eq!(stats.young_bytes, 0);
+    }
+
+    #[test]
+    fn interns_equal_strings() {
+        let mut heap = Heap::new();
+        let a = heap.alloc_string("ab".to_string());
+        let b = heap.alloc_string(format!("{}{}", "a", "b"));
+        assert_eq!(a.as_string(), b.as_string());
+        assert_eq!(heap.live_objects(), 1);
+        let string = heap.get(a.as_string());
+        assert_eq!(string.hash, super::hash_string("ab"));
+
+        // Collected strings leave the table, so a later copy is fresh.
+        heap.begin_major();
+        collect(&mut heap);
+        assert!(heap.strings.is_empty());
+        let c = heap.alloc_string("ab".to_string());
+        assert_eq!(heap.live_objects(), 1);
+        assert_eq!(heap.display(&c).to_string(), "ab");
+    }
+
+    #[test]
+    fn interning_revives_strings_during_a_cycle() {
+        let mut heap = Heap::new();
+        let dead = heap.alloc_string("dead".to_string());
+        heap.begin_major();
+        assert!(heap.step(None));
+        heap.finish_marking();
+
+        // Handed out again before the sweep reaches it.
+        let again = heap.alloc_string("dead".to_string());
+        assert_eq!(again.as_string(), dead.as_string());
+        collect(&mut heap);
+        assert_eq!(heap.display(&again).to_string(), "dead");
+    }
+
+    /// Userdata holding one object, if any.
+    struct Holder(Option<embed::Value>);
+
+    #[test]
+    fn userdata_changed_while_marking_is_traced_again() {
+        let mut heap = Heap::new();
+        let name = heap.intern("Holder".to_string());
+        let host = HostClass {
+            constructor: None,
+            methods: HashMap::new(),
+            getters: HashMap::new(),
+            setters: HashMap::new(),
+            trace: Some(Rc::new(|data, tracer| {
+                let holder: &Holder = data.downcast_ref().unwrap();
+                if let Some(value) = &holder.0 {
+                    tracer.value(value);
+                }
+            })),
+            vm: 0,
+        };
+        let class = heap.alloc(ObjClass {
+            name,
+            methods: HashMap::new(),
+            initializer: None,
+            shape: 0,
+            host: Some(Rc::new(host)),
+        });
+        let holder = heap.alloc(ObjInstance {
+            class,
+            shape: 0,
+            fields: vec![],
+            dictionary: None,
+            host: Some(Box::new(Holder(None))),
+        });
+        let held = heap.alloc_string("held".to_string());
+
+        // The holder is traced while still empty; then the string moves
+        // into it, and only it keeps the string.
+        heap.begin_major();
+        heap.mark(holder);
+        assert!(heap.step(None));
+        let pin = Rc::new(Pin {
+            value: held.clone(),
+            vm: 0,
+            kind: "string",
+            display: "held".to_string(),
+        });
+        heap.userdata_barrier(holder);
+        let data = heap.get_mut(holder).host.as_deref_mut().unwrap();
+        data.downcast_mut::<Holder>().unwrap().0 = Some(embed::Value::Object(Object { pin }));
+        collect(&mut heap);
+        assert_eq!(heap.display(&held).to_string(), "held");
+    }
+}
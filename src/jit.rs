@@ -0,0 +1,88 @@
+//! Baseline template JIT for the stack backend, behind the `jit` feature.
+//!
+//! A function that has been called `JitOptions::threshold` times is
+//! translated one instruction at a time: an opcode with a template gets that
+//! fixed machine-code sequence copied in, and every other opcode becomes an
+//! exit that hands the instruction back to `VM::run`. Templates only touch
+//! the stack and locals; globals, calls, upvalues, properties and anything
+//! that allocates stay in the interpreter, which re-enters the native code
+//! at the next instruction that has one.
+//!
+//! Native code reads and writes the NaN-boxed stack as plain words. Type
+//! guards run before a template changes anything, so a failed guard exits
+//! with the stack exactly as the interpreter expects and the interpreter
+//! reports the error. Only Linux on x86-64 has an assembler; elsewhere
+//! `compile` gives up and everything stays interpreted.
+
+/// Calls before a function is compiled.
+pub const DEFAULT_THRESHOLD: u32 = 100;
+
+/// Set on `VM::jit`; without the `jit` feature there is nothing to turn on.
+#[derive(Debug, Clone, Copy)]
+pub struct JitOptions {
+    /// Cleared by `--no-jit`.
+    pub enabled: bool,
+    pub threshold: u32,
+}
+
+impl Default for JitOptions {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+}
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+mod x86_64;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+pub use x86_64::{NativeCode, compile};
+
+/// The running frame as native code sees it. Templates address these fields
+/// by offset, so the layout is fixed.
+#[cfg(feature = "jit")]
+#[repr(C)]
+pub struct NativeFrame {
+    pub slots: *mut crate::value::Value,
+    /// The next free slot, in and out.
+    pub top: *mut crate::value::Value,
+    /// Bytecode offset to start at; on return, the instruction to
+    /// interpret next.
+    pub ip: usize,
+    /// Instructions executed natively, for `--instruction-count`.
+    pub instructions: u64,
+    /// Loop back-edges exit once `instructions` reaches this, so the
+    /// interpreter gets to its checkpoints.
+    pub budget: u64,
+    /// Machine address for `ip`, filled in by `NativeCode::run`.
+    pub entry: *const u8,
+}
+
+#[cfg(all(feature = "jit", not(all(target_arch = "x86_64", target_os = "linux"))))]
+#[derive(Debug)]
+pub enum NativeCode {}
+
+#[cfg(all(feature = "jit", not(all(target_arch = "x86_64", target_os = "linux"))))]
+impl NativeCode {
+    pub fn has_entry(&self, _ip: usize) -> bool {
+        match *self {}
+    }
+
+    pub fn bytecode_len(&self) -> usize {
+        match *self {}
+    }
+
+    pub fn max_stack(&self) -> usize {
+        match *self {}
+    }
+
+    pub unsafe fn run(&self, _frame: &mut NativeFrame) {
+        match *self {}
+    }
+}
+
+#[cfg(all(feature = "jit", not(all(target_arch = "x86_64", target_os = "linux"))))]
+pub fn compile(_function: &crate::value::ObjFunction) -> Option<NativeCode> {
+    None
+}
@@ -0,0 +1,693 @@
+//! The x86-64 assembler and templates.
+//!
+//! Native code keeps the frame in callee-saved registers for its whole run:
+//!
+//! ```text
+//! r12  &mut NativeFrame       rbx  slot zero
+//! r13  next free stack slot   r14  nil, written over popped slots
+//! r15  QNAN, for number guards
+//! ```
+//!
+//! The code starts with a shared prologue, which loads those and jumps to
+//! `NativeFrame::entry`, and a shared exit, which stores `r13` back. Every
+//! instruction's template follows in bytecode order so jumps map one to one,
+//! and the guard exits come last, off the straight-line path.
+
+use std::{mem::offset_of, ptr};
+
+use super::NativeFrame;
+use crate::{
+    chunk::OpCode,
+    optimizer::operand_count,
+    value::{ObjFunction, Value, ValueType},
+};
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RDI: u8 = 7;
+const R12: u8 = 12;
+const R13: u8 = 13;
+const R14: u8 = 14;
+const R15: u8 = 15;
+const XMM0: u8 = 0;
+const XMM1: u8 = 1;
+
+/// Condition codes, as added to the `jcc` and `setcc` opcodes.
+const AE: u8 = 0x3;
+const E: u8 = 0x4;
+const BE: u8 = 0x6;
+const A: u8 = 0x7;
+const NP: u8 = 0xB;
+
+/// What `f64::NAN` boxes to; arithmetic results are folded into it like
+/// `Value::from` does.
+const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+const SLOT: i32 = size_of::<Value>() as i32;
+
+const SLOTS: i32 = offset_of!(NativeFrame, slots) as i32;
+const TOP: i32 = offset_of!(NativeFrame, top) as i32;
+const IP: i32 = offset_of!(NativeFrame, ip) as i32;
+const INSTRUCTIONS: i32 = offset_of!(NativeFrame, instructions) as i32;
+const BUDGET: i32 = offset_of!(NativeFrame, budget) as i32;
+const ENTRY: i32 = offset_of!(NativeFrame, entry) as i32;
+
+/// A function's machine code in its own executable mapping.
+pub struct NativeCode {
+    memory: *mut u8,
+    len: usize,
+    /// Code offset of each bytecode offset whose instruction has a template.
+    entries: Vec<Option<u32>>,
+    /// The function's `Chunk::max_stack`: the code never pushes past this
+    /// many slots above the frame's slot zero.
+    max_stack: usize,
+}
+
+impl NativeCode {
+    /// Whether native code can start at bytecode offset `ip`.
+    pub fn has_entry(&self, ip: usize) -> bool {
+        self.entries[ip].is_some()
+    }
+
+    /// Bytes of bytecode this was compiled from, which bounds the
+    /// instructions run between two back-edges.
+    pub fn bytecode_len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Stack slots the frame can fill, from `NativeFrame::slots` up.
+    pub fn max_stack(&self) -> usize {
+        self.max_stack
+    }
+
+    /// Runs from `frame.ip`, which must have an entry, until an instruction
+    /// native code cannot execute, and leaves that one's offset in
+    /// `frame.ip`.
+    ///
+    /// # Safety
+    ///
+    /// `frame.slots` and `frame.top` must point into the stack of a running
+    /// frame of the function this was compiled from, with `max_stack` slots
+    /// of room from `frame.slots`.
+    pub unsafe fn run(&self, frame: &mut NativeFrame) {
+        let entry = self.entries[frame.ip].expect("no native entry at this offset");
+        unsafe {
+            frame.entry = self.memory.add(entry as usize);
+            let code: extern "sysv64" fn(*mut NativeFrame) = std::mem::transmute(self.memory);
+            code(frame);
+        }
+    }
+}
+
+impl Drop for NativeCode {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.memory.cast(), self.len) };
+    }
+}
+
+impl std::fmt::Debug for NativeCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native code, {} bytes>", self.len)
+    }
+}
+
+/// Translates a stack-backend function, or gives up with `None`.
+pub fn compile(function: &ObjFunction) -> Option<NativeCode> {
+    let code = &function.chunk.code;
+    if code.is_empty() || !function.registers.is_empty() {
+        return None;
+    }
+    let mut translator = Translator {
+        asm: Assembler::default(),
+        function,
+        exit: 0,
+        guards: vec![],
+        jumps: vec![],
+    };
+    translator.prologue();
+
+    let mut addresses = vec![None; code.len()];
+    let mut entries = vec![None; code.len()];
+    let mut ip = 0;
+    while ip < code.len() {
+        let start = translator.asm.len();
+        addresses[ip] = Some(start);
+        if translator.instruction(ip) {
+            entries[ip] = Some(start as u32);
+        } else {
+            translator.exit_to(ip);
+        }
+        ip += 1 + operand_count(&function.chunk, ip);
+    }
+
+    let Translator {
+        mut asm,
+        exit,
+        guards,
+        jumps,
+        ..
+    } = translator;
+    for (patch, target) in jumps {
+        asm.patch_rel32(patch, (*addresses.get(target)?)?);
+    }
+    // One stub per guarded instruction, shared by all of its guards.
+    let mut stubs: Vec<(usize, usize)> = vec![];
+    for (patch, ip) in guards {
+        let stub = match stubs.iter().find(|(stub_ip, _)| *stub_ip == ip) {
+            Some((_, stub)) => *stub,
+            None => {
+                let stub = asm.len();
+                asm.store_imm(R12, IP, ip as i32);
+                let jump = asm.jmp();
+                asm.patch_rel32(jump, exit);
+                stubs.push((ip, stub));
+                stub
+            }
+        };
+        asm.patch_rel32(patch, stub);
+    }
+
+    let (memory, len) = map_executable(&asm.code)?;
+    Some(NativeCode {
+        memory,
+        len,
+        entries,
+        max_stack: function.chunk.max_stack,
+    })
+}
+
+fn map_executable(code: &[u8]) -> Option<(*mut u8, usize)> {
+    let len = code.len();
+    unsafe {
+        let memory = libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if memory == libc::MAP_FAILED {
+            return None;
+        }
+        ptr::copy_nonoverlapping(code.as_ptr(), memory.cast(), len);
+        if libc::mprotect(memory, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+            libc::munmap(memory, len);
+            return None;
+        }
+        Some((memory.cast(), len))
+    }
+}
+
+struct Translator<'a> {
+    asm: Assembler,
+    function: &'a ObjFunction,
+    /// Code offset of the shared exit.
+    exit: usize,
+    /// `jcc` operands to point at the exit stub of a bytecode offset.
+    guards: Vec<(usize, usize)>,
+    /// `jmp`/`jcc` operands to point at the template of a bytecode offset.
+    jumps: Vec<(usize, usize)>,
+}
+
+impl Translator<'_> {
+    fn prologue(&mut self) {
+        let asm = &mut self.asm;
+        for reg in [RBX, R12, R13, R14, R15] {
+            asm.push(reg);
+        }
+        asm.mov(R12, RDI);
+        asm.load(RBX, R12, SLOTS);
+        asm.load(R13, R12, TOP);
+        asm.mov_imm(R14, Value::NIL_BITS);
+        asm.mov_imm(R15, Value::QNAN_BITS);
+        asm.jmp_mem(R12, ENTRY);
+
+        self.exit = asm.len();
+        asm.store(R12, TOP, R13);
+        for reg in [R15, R14, R13, R12, RBX] {
+            asm.pop(reg);
+        }
+        asm.ret();
+    }
+
+    /// Leaves native code before the instruction at `ip`.
+    fn exit_to(&mut self, ip: usize) {
+        self.asm.store_imm(R12, IP, ip as i32);
+        let jump = self.asm.jmp();
+        self.asm.patch_rel32(jump, self.exit);
+    }
+
+    /// Exits before the instruction at `ip` unless `reg` holds a number.
+    fn guard_number(&mut self, reg: u8, ip: usize) {
+        self.asm.mov(RDX, reg);
+        self.asm.and(RDX, R15);
+        self.asm.cmp(RDX, R15);
+        let patch = self.asm.jcc(E);
+        self.guards.push((patch, ip));
+    }
+
+    fn count(&mut self) {
+        self.asm.add_mem_imm8(R12, INSTRUCTIONS, 1);
+    }
+
+    fn push(&mut self, reg: u8) {
+        self.asm.store(R13, 0, reg);
+        self.asm.add_imm(R13, SLOT);
+    }
+
+    /// Drops the top slot, leaving nil behind like `VM::pop`.
+    fn pop(&mut self) {
+        self.asm.add_imm(R13, -SLOT);
+        self.asm.store(R13, 0, R14);
+    }
+
+    /// Replaces the two operands with `rax`.
+    fn replace_operands(&mut self) {
+        self.asm.store(R13, -2 * SLOT, RAX);
+        self.pop();
+    }
+
+    /// `rax` becomes the boolean for flag `al`.
+    fn box_bool(&mut self) {
+        const _: () = assert!(Value::TRUE_BITS == Value::FALSE_BITS + 1);
+        self.asm.movzx_eax_al();
+        self.asm.mov_imm(RDX, Value::FALSE_BITS);
+        self.asm.add(RAX, RDX);
+    }
+
+    /// Moves `xmm0` to `rax`, folding every NaN into the canonical one.
+    fn box_number(&mut self) {
+        let asm = &mut self.asm;
+        asm.movq_from_xmm(RAX, XMM0);
+        asm.ucomisd(XMM0, XMM0);
+        let ordered = asm.jcc8(NP);
+        asm.mov_imm(RAX, CANONICAL_NAN);
+        asm.patch_rel8(ordered);
+    }
+
+    /// Loads both operands of a binary number instruction into `xmm0` and
+    /// `xmm1`, leaving their bits in `rax` and `rcx`.
+    fn number_operands(&mut self, ip: usize) {
+        self.asm.load(RAX, R13, -2 * SLOT);
+        self.asm.load(RCX, R13, -SLOT);
+        self.guard_number(RAX, ip);
+        self.guard_number(RCX, ip);
+        self.asm.movq_to_xmm(XMM0, RAX);
+        self.asm.movq_to_xmm(XMM1, RCX);
+    }
+
+    fn jump_to(&mut self, target: usize) {
+        let patch = self.asm.jmp();
+        self.jumps.push((patch, target));
+    }
+
+    /// Emits the template for the instruction at `ip`; `false` if it has
+    /// none.
+    fn instruction(&mut self, ip: usize) -> bool {
+        let function = self.function;
+        let code = &function.chunk.code;
+        let operand = |n: usize| code[ip + n] as i32;
+        let jump = || u16::from_be_bytes([code[ip + 1], code[ip + 2]]) as usize;
+        match OpCode::try_from(code[ip]).unwrap() {
+            OpCode::OP_NIL => {
+                self.push(R14);
+            }
+            op @ (OpCode::OP_TRUE | OpCode::OP_FALSE) => {
+                let bits = Value::bool_value(op == OpCode::OP_TRUE).to_bits();
+                self.asm.mov_imm(RAX, bits);
+                self.push(RAX);
+            }
+            OpCode::Op_Constnats => {
+                let value = &function.chunk.constants.values[operand(1) as usize];
+                // Functions are reference counted and never pushed as they
+                // are; `OP_CLOSURE` wraps them.
+                if let ValueType::VAL_FUNCTION(_) = value.type_v() {
+                    return false;
+                }
+                self.asm.mov_imm(RAX, value.to_bits());
+                self.push(RAX);
+            }
+            OpCode::OP_POP => self.pop(),
+            OpCode::OP_GET_LOCAL => {
+                self.asm.load(RAX, RBX, operand(1) * SLOT);
+                self.push(RAX);
+            }
+            OpCode::OP_SET_LOCAL => {
+                self.asm.load(RAX, R13, -SLOT);
+                self.asm.store(RBX, operand(1) * SLOT, RAX);
+            }
+            op @ (OpCode::OP_ADD
+            | OpCode::OP_SUBTRACT
+            | OpCode::OP_MULTIPLY
+            | OpCode::OP_DIVIDE) => {
+                self.number_operands(ip);
+                let opcode = match op {
+                    OpCode::OP_ADD => 0x58,
+                    OpCode::OP_SUBTRACT => 0x5C,
+                    OpCode::OP_MULTIPLY => 0x59,
+                    _ => 0x5E,
+                };
+                self.asm.sse(opcode, XMM0, XMM1);
+                self.box_number();
+                self.replace_operands();
+            }
+            op @ (OpCode::OP_GREATER
+            | OpCode::OP_LESS
+            | OpCode::OP_GREATER_EQUAL
+            | OpCode::OP_LESS_EQUAL) => {
+                self.number_operands(ip);
+                // `seta` is false and `setbe` true when either side is NaN,
+                // matching `a > b` and `!(a < b)` in `VM::binar_op`.
+                let (left, right, condition) = match op {
+                    OpCode::OP_GREATER => (XMM0, XMM1, A),
+                    OpCode::OP_LESS => (XMM1, XMM0, A),
+                    OpCode::OP_GREATER_EQUAL => (XMM1, XMM0, BE),
+                    _ => (XMM0, XMM1, BE),
+                };
+                self.asm.ucomisd(left, right);
+                self.asm.setcc(condition, RAX);
+                self.box_bool();
+                self.replace_operands();
+            }
+            OpCode::OP_NEGATE => {
+                self.asm.load(RAX, R13, -SLOT);
+                self.guard_number(RAX, ip);
+                self.asm.btc(RAX, 63);
+                self.asm.movq_to_xmm(XMM0, RAX);
+                self.box_number();
+                self.asm.store(R13, -SLOT, RAX);
+            }
+            OpCode::OP_NOT => {
+                self.asm.load(RAX, R13, -SLOT);
+                self.asm.cmp(RAX, R14);
+                self.asm.setcc(E, RCX);
+                self.asm.mov_imm(RDX, Value::FALSE_BITS);
+                self.asm.cmp(RAX, RDX);
+                self.asm.setcc(E, RAX);
+                self.asm.or8(RAX, RCX);
+                self.box_bool();
+                self.asm.store(R13, -SLOT, RAX);
+            }
+            op @ (OpCode::OP_EQUAL | OpCode::OP_NOT_EQUAL) => {
+                // Numbers compare as floats, everything else by identity,
+                // like `PartialEq for Value`.
+                let asm = &mut self.asm;
+                asm.load(RAX, R13, -2 * SLOT);
+                asm.load(RCX, R13, -SLOT);
+                let mut not_numbers = vec![];
+                for reg in [RAX, RCX] {
+                    asm.mov(RDX, reg);
+                    asm.and(RDX, R15);
+                    asm.cmp(RDX, R15);
+                    not_numbers.push(asm.jcc8(E));
+                }
+                asm.movq_to_xmm(XMM0, RAX);
+                asm.movq_to_xmm(XMM1, RCX);
+                asm.ucomisd(XMM0, XMM1);
+                asm.setcc(E, RAX);
+                asm.setcc(NP, RCX);
+                asm.and8(RAX, RCX);
+                let done = asm.jmp8();
+                for patch in not_numbers {
+                    asm.patch_rel8(patch);
+                }
+                asm.cmp(RAX, RCX);
+                asm.setcc(E, RAX);
+                asm.patch_rel8(done);
+                if op == OpCode::OP_NOT_EQUAL {
+                    asm.xor_al(1);
+                }
+                self.box_bool();
+                self.replace_operands();
+            }
+            OpCode::OP_JUMP => {
+                self.count();
+                self.jump_to(ip + 3 + jump());
+                return true;
+            }
+            OpCode::OP_LOOP => {
+                self.asm.load(RAX, R12, INSTRUCTIONS);
+                self.asm.cmp_mem(RAX, R12, BUDGET);
+                let patch = self.asm.jcc(AE);
+                self.guards.push((patch, ip));
+                self.count();
+                self.jump_to(ip + 3 - jump());
+                return true;
+            }
+            OpCode::OP_JUMP_IF_FALSE => {
+                self.count();
+                let target = ip + 3 + jump();
+                self.asm.load(RAX, R13, -SLOT);
+                self.asm.cmp(RAX, R14);
+                let patch = self.asm.jcc(E);
+                self.jumps.push((patch, target));
+                self.asm.mov_imm(RDX, Value::FALSE_BITS);
+                self.asm.cmp(RAX, RDX);
+                let patch = self.asm.jcc(E);
+                self.jumps.push((patch, target));
+                return true;
+            }
+            _ => return false,
+        }
+        // Counted only once the guards have passed, so an exit leaves the
+        // instruction for the interpreter to count.
+        self.count();
+        true
+    }
+}
+
+/// Just enough of x86-64 for the templates. Memory operands always use a
+/// 32-bit displacement, which sidesteps the rbp/r13 special case.
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+}
+
+impl Assembler {
+    fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.code.push(byte);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// The REX prefix, left out when it would carry nothing.
+    fn rex(&mut self, wide: bool, reg: u8, rm: u8) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3 & 1) << 2 | rm >> 3 & 1;
+        if rex != 0x40 {
+            self.byte(rex);
+        }
+    }
+
+    fn modrm_reg(&mut self, reg: u8, rm: u8) {
+        self.byte(0xC0 | (reg & 7) << 3 | rm & 7);
+    }
+
+    fn modrm_mem(&mut self, reg: u8, base: u8, disp: i32) {
+        self.byte(0x80 | (reg & 7) << 3 | base & 7);
+        if base & 7 == 4 {
+            // rsp and r12 need a SIB byte with no index.
+            self.byte(0x24);
+        }
+        self.bytes(&disp.to_le_bytes());
+    }
+
+    /// `mov dst, [base + disp]`
+    fn load(&mut self, dst: u8, base: u8, disp: i32) {
+        self.rex(true, dst, base);
+        self.byte(0x8B);
+        self.modrm_mem(dst, base, disp);
+    }
+
+    /// `mov [base + disp], src`
+    fn store(&mut self, base: u8, disp: i32, src: u8) {
+        self.rex(true, src, base);
+        self.byte(0x89);
+        self.modrm_mem(src, base, disp);
+    }
+
+    /// `mov qword [base + disp], imm32`, sign-extended.
+    fn store_imm(&mut self, base: u8, disp: i32, imm: i32) {
+        self.rex(true, 0, base);
+        self.byte(0xC7);
+        self.modrm_mem(0, base, disp);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// `add qword [base + disp], imm8`
+    fn add_mem_imm8(&mut self, base: u8, disp: i32, imm: i8) {
+        self.rex(true, 0, base);
+        self.byte(0x83);
+        self.modrm_mem(0, base, disp);
+        self.byte(imm as u8);
+    }
+
+    fn mov_imm(&mut self, dst: u8, imm: u64) {
+        self.rex(true, 0, dst);
+        self.byte(0xB8 + (dst & 7));
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    fn alu(&mut self, opcode: u8, dst: u8, src: u8) {
+        self.rex(true, src, dst);
+        self.byte(opcode);
+        self.modrm_reg(src, dst);
+    }
+
+    fn mov(&mut self, dst: u8, src: u8) {
+        self.alu(0x89, dst, src);
+    }
+
+    fn add(&mut self, dst: u8, src: u8) {
+        self.alu(0x01, dst, src);
+    }
+
+    fn and(&mut self, dst: u8, src: u8) {
+        self.alu(0x21, dst, src);
+    }
+
+    /// Flags for `left - right`.
+    fn cmp(&mut self, left: u8, right: u8) {
+        self.alu(0x39, left, right);
+    }
+
+    /// `cmp left, [base + disp]`
+    fn cmp_mem(&mut self, left: u8, base: u8, disp: i32) {
+        self.rex(true, left, base);
+        self.byte(0x3B);
+        self.modrm_mem(left, base, disp);
+    }
+
+    /// `add dst, imm32`
+    fn add_imm(&mut self, dst: u8, imm: i32) {
+        self.rex(true, 0, dst);
+        self.byte(0x81);
+        self.modrm_reg(0, dst);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    fn btc(&mut self, dst: u8, bit: u8) {
+        self.rex(true, 0, dst);
+        self.bytes(&[0x0F, 0xBA]);
+        self.modrm_reg(7, dst);
+        self.byte(bit);
+    }
+
+    /// Byte registers al, cl and dl only; others would need a REX prefix.
+    fn setcc(&mut self, condition: u8, dst: u8) {
+        self.bytes(&[0x0F, 0x90 + condition]);
+        self.modrm_reg(0, dst);
+    }
+
+    fn and8(&mut self, dst: u8, src: u8) {
+        self.byte(0x20);
+        self.modrm_reg(src, dst);
+    }
+
+    fn or8(&mut self, dst: u8, src: u8) {
+        self.byte(0x08);
+        self.modrm_reg(src, dst);
+    }
+
+    fn xor_al(&mut self, imm: u8) {
+        self.bytes(&[0x34, imm]);
+    }
+
+    fn movzx_eax_al(&mut self) {
+        self.bytes(&[0x0F, 0xB6, 0xC0]);
+    }
+
+    /// `movq xmm, r64`
+    fn movq_to_xmm(&mut self, dst: u8, src: u8) {
+        self.byte(0x66);
+        self.rex(true, dst, src);
+        self.bytes(&[0x0F, 0x6E]);
+        self.modrm_reg(dst, src);
+    }
+
+    /// `movq r64, xmm`
+    fn movq_from_xmm(&mut self, dst: u8, src: u8) {
+        self.byte(0x66);
+        self.rex(true, src, dst);
+        self.bytes(&[0x0F, 0x7E]);
+        self.modrm_reg(src, dst);
+    }
+
+    /// A scalar double operation: `addsd`, `subsd`, `mulsd` or `divsd`.
+    fn sse(&mut self, opcode: u8, dst: u8, src: u8) {
+        self.bytes(&[0xF2, 0x0F, opcode]);
+        self.modrm_reg(dst, src);
+    }
+
+    fn ucomisd(&mut self, left: u8, right: u8) {
+        self.bytes(&[0x66, 0x0F, 0x2E]);
+        self.modrm_reg(left, right);
+    }
+
+    fn push(&mut self, reg: u8) {
+        self.rex(false, 0, reg);
+        self.byte(0x50 + (reg & 7));
+    }
+
+    fn pop(&mut self, reg: u8) {
+        self.rex(false, 0, reg);
+        self.byte(0x58 + (reg & 7));
+    }
+
+    fn ret(&mut self) {
+        self.byte(0xC3);
+    }
+
+    /// `jmp [base + disp]`
+    fn jmp_mem(&mut self, base: u8, disp: i32) {
+        self.rex(false, 0, base);
+        self.byte(0xFF);
+        self.modrm_mem(4, base, disp);
+    }
+
+    /// `jmp rel32`; returns the operand to patch.
+    fn jmp(&mut self) -> usize {
+        self.byte(0xE9);
+        self.rel32()
+    }
+
+    /// `jcc rel32`; returns the operand to patch.
+    fn jcc(&mut self, condition: u8) -> usize {
+        self.bytes(&[0x0F, 0x80 + condition]);
+        self.rel32()
+    }
+
+    fn rel32(&mut self) -> usize {
+        self.bytes(&[0; 4]);
+        self.len() - 4
+    }
+
+    fn patch_rel32(&mut self, patch: usize, target: usize) {
+        let rel = target as i32 - (patch + 4) as i32;
+        self.code[patch..patch + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    /// Short forward `jmp`, patched to the current offset later.
+    fn jmp8(&mut self) -> usize {
+        self.bytes(&[0xEB, 0]);
+        self.len() - 1
+    }
+
+    /// Short forward `jcc`, patched to the current offset later.
+    fn jcc8(&mut self, condition: u8) -> usize {
+        self.bytes(&[0x70 + condition, 0]);
+        self.len() - 1
+    }
+
+    fn patch_rel8(&mut self, patch: usize) {
+        let rel = self.len() - (patch + 1);
+        self.code[patch] = u8::try_from(rel).expect("short jump out of range");
+    }
+}
@@ -0,0 +1,59 @@
+//! An interpreter for nox, a small dynamically typed scripting language, for
+//! embedding in Rust programs. The `nlox` binary is a client of this crate.
+//!
+//! ```
+//! use nlox::{Value, Vm};
+//!
+//! let mut vm = Vm::new();
+//! vm.register("shout", |text: String| Ok(text.to_uppercase()));
+//! vm.eval("fun greet(name) { return shout(\"hello \") + name; }", "greet.nox")
+//!     .unwrap();
+//! let greeting = vm.call("greet", ("host",));
+//! assert_eq!(greeting, Ok(Value::String("HELLO host".to_string())));
+//! ```
+//!
+//! Tools such as formatters and linters can parse scripts without running
+//! them: `cst::parse` keeps every byte of the source, and `ast::parse`
+//! gives the typed tree the compiler works from.
+
+pub mod ast;
+mod capi;
+mod chunk;
+mod compiler;
+mod convert;
+pub mod cst;
+mod debug;
+mod embed;
+mod emit;
+mod gc;
+mod jit;
+mod limits;
+mod lint;
+mod optimizer;
+mod program;
+mod register;
+mod register_compiler;
+mod resolver;
+mod scanner;
+mod serialize;
+mod shape;
+mod token;
+mod userdata;
+mod value;
+mod vm;
+
+pub use compiler::{Backend, CompilerOptions};
+pub use convert::{Constructor, FromValue, IntoArgs, IntoValue, NativeFunction, NativeMethod};
+pub use debug::{
+    DebugConsole, DebugHook, Paused, StackFrame, Step, Stepper, StopReason, serve_dap,
+};
+pub use embed::{Error, Object, Result, Value, Vm};
+pub use emit::{Emit, emit};
+pub use gc::{GcOptions, GcStats};
+pub use jit::JitOptions;
+pub use limits::{InterruptHandle, Limit, Limits};
+pub use optimizer::MAX_LEVEL as MAX_OPT_LEVEL;
+pub use program::Program;
+pub use serialize::{Serde, from_value, to_value};
+pub use shape::{CacheCounter, CacheStats};
+pub use userdata::{Tracer, UserClass};
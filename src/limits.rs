@@ -0,0 +1,257 @@
+//! Hard limits for running untrusted scripts.
+//!
+//! `VM::limits` bounds every `interpret` call. A limit that trips ends the
+//! script the way a runtime error does, with a message and a stack trace,
+//! but returns its own `InterpretResult` so the host can tell which one it
+//! was. Fuel, the deadline and interrupts are checked at the dispatch
+//! loop's checkpoints; the other limits where they are exceeded.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::vm::InterpretResult;
+
+/// `None` leaves that resource unbounded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// Instructions executed, by either backend.
+    pub fuel: Option<u64>,
+    /// Bytes the heap may hold. Checked after each allocation, once a full
+    /// collection has failed to get back under it.
+    pub heap_bytes: Option<usize>,
+    /// Frames on the call stack, the script's own included. The VM never
+    /// goes past 64 either way.
+    pub call_depth: Option<usize>,
+    /// Bytes in a string built at runtime.
+    pub string_length: Option<usize>,
+    /// Wall-clock time from the start of `interpret`.
+    pub timeout: Option<Duration>,
+}
+
+impl Limits {
+    /// The settings of `--sandbox`: enough for ordinary snippets, small
+    /// enough that a hostile one ends quickly.
+    pub fn sandbox() -> Self {
+        Self {
+            fuel: Some(100_000_000),
+            heap_bytes: Some(64 << 20),
+            call_depth: Some(32),
+            string_length: Some(1 << 20),
+            timeout: Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+/// Which limit ended a script.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Fuel,
+    Heap,
+    CallDepth,
+    StringLength,
+    Deadline,
+    Interrupted,
+}
+
+impl Limit {
+    pub fn message(self) -> &'static str {
+        match self {
+            Limit::Fuel => "Instruction limit exceeded.",
+            Limit::Heap => "Heap limit exceeded.",
+            Limit::CallDepth => "Call depth limit exceeded.",
+            Limit::StringLength => "String length limit exceeded.",
+            Limit::Deadline => "Deadline exceeded.",
+            Limit::Interrupted => "Interrupted.",
+        }
+    }
+
+    pub fn result(self) -> InterpretResult {
+        match self {
+            Limit::Fuel => InterpretResult::INTERPRET_OUT_OF_FUEL,
+            Limit::Heap => InterpretResult::INTERPRET_HEAP_LIMIT,
+            Limit::CallDepth => InterpretResult::INTERPRET_DEPTH_LIMIT,
+            Limit::StringLength => InterpretResult::INTERPRET_STRING_LIMIT,
+            Limit::Deadline => InterpretResult::INTERPRET_DEADLINE,
+            Limit::Interrupted => InterpretResult::INTERPRET_INTERRUPTED,
+        }
+    }
+
+    /// The limit behind `result`, if a limit ended the script.
+    pub fn from_result(result: InterpretResult) -> Option<Limit> {
+        Some(match result {
+            InterpretResult::INTERPRET_OUT_OF_FUEL => Limit::Fuel,
+            InterpretResult::INTERPRET_HEAP_LIMIT => Limit::Heap,
+            InterpretResult::INTERPRET_DEPTH_LIMIT => Limit::CallDepth,
+            InterpretResult::INTERPRET_STRING_LIMIT => Limit::StringLength,
+            InterpretResult::INTERPRET_DEADLINE => Limit::Deadline,
+            InterpretResult::INTERPRET_INTERRUPTED => Limit::Interrupted,
+            _ => return None,
+        })
+    }
+}
+
+/// Cancels whatever its VM is running, from any thread. An interrupt that
+/// arrives while the VM is idle is dropped when the next script starts, so
+/// it only ever cancels the script it was meant for.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether an interrupt is pending; clears it.
+    pub(crate) fn take(&self) -> bool {
+        self.flag.swap(false, Ordering::Relaxed)
+    }
+
+    /// Drops an interrupt left over from before the script starting now.
+    pub(crate) fn clear(&self) {
+        self.flag.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::Limits;
+    use crate::{
+        compiler::{Backend, CompilerOptions},
+        vm::{InterpretResult, VM},
+    };
+
+    fn run(source: &str, backend: Backend, limits: Limits) -> (InterpretResult, VM) {
+        let mut vm = VM::new();
+        vm.options = CompilerOptions {
+            backend,
+            ..CompilerOptions::default()
+        };
+        vm.limits = limits;
+        #[cfg(feature = "jit")]
+        {
+            vm.jit.threshold = 1;
+        }
+        let result = vm.interpret(source.to_string(), "test");
+        (result, vm)
+    }
+
+    const BACKENDS: [Backend; 2] = [Backend::Stack, Backend::Register];
+
+    #[test]
+    fn fuel_is_exact() {
+        let source = "
+            fun spin(n) { var i = 0; while (i < n) i = i + 1; return i; }
+            for (var i = 0; i < 20; i = i + 1) spin(i * 10);
+        ";
+        for backend in BACKENDS {
+            let (result, vm) = run(source, backend, Limits::default());
+            assert_eq!(result, InterpretResult::INTERPRET_OK);
+            let needed = vm.instructions_executed();
+
+            let enough = Limits {
+                fuel: Some(needed),
+                ..Limits::default()
+            };
+            assert_eq!(
+                run(source, backend, enough).0,
+                InterpretResult::INTERPRET_OK
+            );
+            let short = Limits {
+                fuel: Some(needed - 1),
+                ..Limits::default()
+            };
+            let (result, vm) = run(source, backend, short);
+            assert_eq!(result, InterpretResult::INTERPRET_OUT_OF_FUEL);
+            assert_eq!(vm.instructions_executed(), needed - 1);
+        }
+    }
+
+    #[test]
+    fn each_limit_has_its_own_result() {
+        let cases = [
+            (
+                "var s = \"ab\"; while (true) s = s + s;",
+                Limits {
+                    string_length: Some(1000),
+                    ..Limits::default()
+                },
+                InterpretResult::INTERPRET_STRING_LIMIT,
+            ),
+            (
+                "class Node { init(next) { this.next = next; } }
+                 var list = nil; while (true) list = Node(list);",
+                Limits {
+                    heap_bytes: Some(1 << 20),
+                    ..Limits::default()
+                },
+                InterpretResult::INTERPRET_HEAP_LIMIT,
+            ),
+            (
+                "fun deep(n) { return 1 + deep(n + 1); } deep(0);",
+                Limits {
+                    call_depth: Some(10),
+                    ..Limits::default()
+                },
+                InterpretResult::INTERPRET_DEPTH_LIMIT,
+            ),
+            (
+                "while (true) {}",
+                Limits {
+                    timeout: Some(Duration::from_millis(20)),
+                    ..Limits::default()
+                },
+                InterpretResult::INTERPRET_DEADLINE,
+            ),
+        ];
+        for backend in BACKENDS {
+            for (source, limits, expected) in cases {
+                assert_eq!(run(source, backend, limits).0, expected, "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn interrupts_cancel_from_another_thread() {
+        for backend in BACKENDS {
+            let mut vm = VM::new();
+            vm.options.backend = backend;
+            #[cfg(feature = "jit")]
+            {
+                vm.jit.threshold = 1;
+            }
+            let handle = vm.interrupt_handle();
+            let interrupter = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                handle.interrupt();
+            });
+            let result = vm.interpret("fun spin() { while (true) {} } spin();".to_string(), "test");
+            interrupter.join().unwrap();
+            assert_eq!(result, InterpretResult::INTERPRET_INTERRUPTED);
+
+            // The interrupt is spent; the VM runs the next script normally.
+            assert_eq!(
+                vm.interpret("var x = 1;".to_string(), "test"),
+                InterpretResult::INTERPRET_OK
+            );
+        }
+    }
+
+    #[test]
+    fn idle_interrupts_do_not_cancel_later_scripts() {
+        let mut vm = VM::new();
+        vm.interrupt_handle().interrupt();
+        assert_eq!(
+            vm.interpret("var i = 0; while (i < 5000) i = i + 1;".to_string(), "test"),
+            InterpretResult::INTERPRET_OK
+        );
+    }
+}
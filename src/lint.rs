@@ -0,0 +1,546 @@
+//! Compile-time warnings.
+//!
+//! Warnings never stop compilation unless `--deny-warnings` is given. Each
+//! one belongs to a named lint that can be silenced with a comment:
+//!
+//! ```text
+//! var _x = 1;                  // leading `_` opts out of unused/shadowing
+//! print(a == "a"); // nlox-allow: literal-comparison
+//! // nlox-allow: unused-variable, shadowing
+//! var a = 1;                   // an own-line comment covers the next line
+//! // nlox-allow-file: all
+//! ```
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use crate::{
+    ast::{BinaryOp, Expr, Function, Identifier, Literal, NodeId, Program, Stmt},
+    cst::SyntaxNode,
+    resolver::{Resolution, is_falsey},
+    scanner::TriviaKind,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    ConfusableIdentifier,
+    UnusedVariable,
+    UnreachableCode,
+    ConstantCondition,
+    Shadowing,
+    LiteralComparison,
+}
+
+impl Lint {
+    const ALL: [Lint; 6] = [
+        Lint::ConfusableIdentifier,
+        Lint::UnusedVariable,
+        Lint::UnreachableCode,
+        Lint::ConstantCondition,
+        Lint::Shadowing,
+        Lint::LiteralComparison,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::ConfusableIdentifier => "confusable-identifier",
+            Lint::UnusedVariable => "unused-variable",
+            Lint::UnreachableCode => "unreachable-code",
+            Lint::ConstantCondition => "constant-condition",
+            Lint::Shadowing => "shadowing",
+            Lint::LiteralComparison => "literal-comparison",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Lint> {
+        Self::ALL.into_iter().find(|lint| lint.name() == name)
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub line: usize,
+    pub lint: Lint,
+    pub message: String,
+}
+
+/// Runs every AST lint over a resolved program.
+pub fn check(program: &Program, resolution: &Resolution) -> Vec<Warning> {
+    let mut linter = Linter {
+        resolution,
+        scopes: vec![],
+        warnings: vec![],
+    };
+    linter.stmts(&program.body);
+    linter.warnings
+}
+
+/// Lints silenced by `nlox-allow` comments, for the whole file or per line.
+#[derive(Debug, Default)]
+pub struct Suppressions {
+    file: HashSet<Lint>,
+    lines: HashMap<usize, HashSet<Lint>>,
+}
+
+impl Suppressions {
+    pub fn from_tree(root: &SyntaxNode, source: &str) -> Self {
+        let mut suppressions = Self::default();
+        for token in root.tokens() {
+            for trivia in &token.leading {
+                if trivia.kind != TriviaKind::Comment {
+                    continue;
+                }
+                let comment = trivia.text.trim_start_matches('/').trim();
+                let (names, file_wide) =
+                    if let Some(names) = comment.strip_prefix("nlox-allow-file:") {
+                        (names, true)
+                    } else if let Some(names) = comment.strip_prefix("nlox-allow:") {
+                        (names, false)
+                    } else {
+                        continue;
+                    };
+
+                let before = &source[..trivia.span.start];
+                let line = before.matches('\n').count() + 1;
+                let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+                // A comment alone on its line covers the line below it.
+                let own_line = source[line_start..trivia.span.start].trim().is_empty();
+                let target = if file_wide {
+                    &mut suppressions.file
+                } else {
+                    let line = if own_line { line + 1 } else { line };
+                    suppressions.lines.entry(line).or_default()
+                };
+                for name in names.split(',').map(str::trim) {
+                    if name == "all" {
+                        target.extend(Lint::ALL);
+                    } else if let Some(lint) = Lint::from_name(name) {
+                        target.insert(lint);
+                    }
+                }
+            }
+        }
+        suppressions
+    }
+
+    pub fn allows(&self, warning: &Warning) -> bool {
+        self.file.contains(&warning.lint)
+            || self
+                .lines
+                .get(&warning.line)
+                .is_some_and(|lints| lints.contains(&warning.lint))
+    }
+}
+
+struct Declared {
+    name: String,
+    line: usize,
+}
+
+struct Linter<'a> {
+    resolution: &'a Resolution,
+    /// Local scopes, outermost first. Function scopes nest like blocks so
+    /// shadowing a captured variable is caught too.
+    scopes: Vec<Vec<Declared>>,
+    warnings: Vec<Warning>,
+}
+
+impl Linter<'_> {
+    fn warn(&mut self, line: usize, lint: Lint, message: String) {
+        self.warnings.push(Warning {
+            line,
+            lint,
+            message,
+        });
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        let mut returned = false;
+        for stmt in stmts {
+            if returned {
+                self.warn(
+                    stmt_line(stmt),
+                    Lint::UnreachableCode,
+                    "unreachable code after 'return'".to_string(),
+                );
+                returned = false;
+            }
+            self.stmt(stmt);
+            if always_returns(stmt) {
+                returned = true;
+            }
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Var {
+                id,
+                name,
+                initializer,
+            } => {
+                if let Some(initializer) = initializer {
+                    self.expr(initializer);
+                }
+                self.declare(*id, name, "variable");
+            }
+            Stmt::Function(function) => {
+                self.declare(function.id, &function.name, "function");
+                self.function(function);
+            }
+            Stmt::Class(class) => {
+                self.declare(class.id, &class.name, "class");
+                for method in &class.methods {
+                    self.function(method);
+                }
+            }
+            Stmt::Expression { expr, .. } | Stmt::Print { expr, .. } => self.expr(expr),
+            Stmt::Block { body, .. } => {
+                self.scopes.push(vec![]);
+                self.stmts(body);
+                self.scopes.pop();
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                line,
+            } => {
+                self.condition(condition, *line, false);
+                self.stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.stmt(else_branch);
+                }
+            }
+            Stmt::While {
+                condition,
+                body,
+                line,
+            } => {
+                self.condition(condition, *line, true);
+                self.stmt(body);
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                line,
+            } => {
+                self.scopes.push(vec![]);
+                if let Some(initializer) = initializer {
+                    self.stmt(initializer);
+                }
+                if let Some(condition) = condition {
+                    self.condition(condition, *line, true);
+                }
+                if let Some(increment) = increment {
+                    self.expr(increment);
+                }
+                self.stmt(body);
+                self.scopes.pop();
+            }
+            Stmt::Return { value, .. } => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, function: &Function) {
+        self.scopes.push(vec![]);
+        for param in &function.params {
+            self.declare(param.id, &param.name, "parameter");
+        }
+        self.stmts(&function.body);
+        self.scopes.pop();
+    }
+
+    /// Only locals are checked: globals can be used by code compiled later,
+    /// and shadowing a global is how most programs name their locals.
+    fn declare(&mut self, id: NodeId, name: &Identifier, what: &str) {
+        if self.scopes.is_empty() || name.name.starts_with('_') {
+            return;
+        }
+        if !self.resolution.used.contains(&id) {
+            self.warn(
+                name.line,
+                Lint::UnusedVariable,
+                format!("unused {} '{}'", what, name.name),
+            );
+        }
+        let (current, outer) = self.scopes.split_last_mut().unwrap();
+        let shadowed = outer
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|declared| declared.name == name.name)
+            .map(|declared| declared.line);
+        current.push(Declared {
+            name: name.name.clone(),
+            line: name.line,
+        });
+        if let Some(line) = shadowed {
+            self.warn(
+                name.line,
+                Lint::Shadowing,
+                format!(
+                    "'{}' shadows the variable declared on line {}",
+                    name.name, line
+                ),
+            );
+        }
+    }
+
+    /// `while (true)` and `for (;;)` are deliberate, so literal `true` is
+    /// allowed as a loop condition.
+    fn condition(&mut self, condition: &Expr, line: usize, is_loop: bool) {
+        self.expr(condition);
+        let Some(value) = self.resolution.constant(condition) else {
+            return;
+        };
+        if is_loop
+            && matches!(
+                condition,
+                Expr::Literal {
+                    value: Literal::Bool(true),
+                    ..
+                }
+            )
+        {
+            return;
+        }
+        let outcome = if is_falsey(&value) { "false" } else { "true" };
+        self.warn(
+            line,
+            Lint::ConstantCondition,
+            format!("condition is always {}", outcome),
+        );
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal { .. } | Expr::Variable { .. } | Expr::This { .. } => {}
+            Expr::Grouping { expr } => self.expr(expr),
+            Expr::Unary { operand, .. } => self.expr(operand),
+            Expr::Binary {
+                op,
+                left,
+                right,
+                line,
+            } => {
+                self.expr(left);
+                self.expr(right);
+                if matches!(op, BinaryOp::Equal | BinaryOp::NotEqual) {
+                    self.literal_comparison(*op, left, right, *line);
+                }
+            }
+            Expr::Logical { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            }
+            Expr::Assign { value, .. } => self.expr(value),
+            Expr::Call { callee, args, .. } => {
+                self.expr(callee);
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            Expr::Get { object, .. } => self.expr(object),
+            Expr::Set { object, value, .. } => {
+                self.expr(object);
+                self.expr(value);
+            }
+        }
+    }
+
+    fn literal_comparison(&mut self, op: BinaryOp, left: &Expr, right: &Expr, line: usize) {
+        let (Some(left), Some(right)) = (literal(left), literal(right)) else {
+            return;
+        };
+        if type_name(left) == type_name(right) {
+            return;
+        }
+        let outcome = if op == BinaryOp::Equal {
+            "false"
+        } else {
+            "true"
+        };
+        self.warn(
+            line,
+            Lint::LiteralComparison,
+            format!(
+                "comparing a {} with a {} is always {}",
+                type_name(left),
+                type_name(right),
+                outcome
+            ),
+        );
+    }
+}
+
+fn literal(expr: &Expr) -> Option<&Literal> {
+    match expr {
+        Expr::Literal { value, .. } => Some(value),
+        Expr::Grouping { expr } => literal(expr),
+        _ => None,
+    }
+}
+
+fn type_name(literal: &Literal) -> &'static str {
+    match literal {
+        Literal::Number(_) => "number",
+        Literal::String(_) => "string",
+        Literal::Bool(_) => "boolean",
+        Literal::Nil => "nil",
+    }
+}
+
+fn always_returns(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Return { .. } => true,
+        Stmt::Block { body, .. } => body.iter().any(always_returns),
+        Stmt::If {
+            then_branch,
+            else_branch: Some(else_branch),
+            ..
+        } => always_returns(then_branch) && always_returns(else_branch),
+        _ => false,
+    }
+}
+
+fn stmt_line(stmt: &Stmt) -> usize {
+    match stmt {
+        Stmt::Var { name, .. } => name.line,
+        Stmt::Function(function) => function.name.line,
+        Stmt::Class(class) => class.name.line,
+        Stmt::Expression { line, .. }
+        | Stmt::Print { line, .. }
+        | Stmt::If { line, .. }
+        | Stmt::While { line, .. }
+        | Stmt::For { line, .. }
+        | Stmt::Return { line, .. } => *line,
+        Stmt::Block { body, end_line } => body.first().map_or(*end_line, stmt_line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lint, Suppressions, check};
+    use crate::{ast, cst, resolver};
+
+    fn lint(source: &str) -> Vec<(usize, Lint)> {
+        let parse = cst::parse(source);
+        assert!(parse.errors.is_empty());
+        let program = ast::lower(&parse.root);
+        let resolution = resolver::resolve(&program).unwrap();
+        let suppressions = Suppressions::from_tree(&parse.root, source);
+        check(&program, &resolution)
+            .into_iter()
+            .filter(|warning| !suppressions.allows(warning))
+            .map(|warning| (warning.line, warning.lint))
+            .collect()
+    }
+
+    #[test]
+    fn flags_unused_locals_and_params() {
+        let source = "var g = 1;
+                      fun f(a, b, _c) {
+                          var used = a;
+                          var unused = 2;
+                          var _ignored = 3;
+                          var assigned;
+                          assigned = 4;
+                          return used;
+                      }";
+        assert_eq!(
+            lint(source),
+            vec![
+                (2, Lint::UnusedVariable),
+                (4, Lint::UnusedVariable),
+                (6, Lint::UnusedVariable)
+            ]
+        );
+    }
+
+    #[test]
+    fn flags_code_after_return() {
+        let source = "fun f(a) {
+                          if (a) return 1; else { return 2; }
+                          print(a);
+                          print(a);
+                      }
+                      fun g(a) { if (a) return 1; print(a); }";
+        assert_eq!(lint(source), vec![(3, Lint::UnreachableCode)]);
+    }
+
+    #[test]
+    fn flags_constant_conditions() {
+        let source = "while (true) {}
+                      for (;;) {}
+                      if (1 < 2) {}
+                      while (false) {}
+                      { var limit = 3; if (limit > 2) {} }
+                      { var n = 0; while (n < 3) n = n + 1; }";
+        assert_eq!(
+            lint(source),
+            vec![
+                (3, Lint::ConstantCondition),
+                (4, Lint::ConstantCondition),
+                (5, Lint::ConstantCondition)
+            ]
+        );
+    }
+
+    #[test]
+    fn flags_shadowing_locals() {
+        let source = "var g = 1;
+                      fun f(a) {
+                          var g = a;
+                          { var a = g; print(a); }
+                          fun inner() { var g = 2; return g; }
+                          return inner;
+                      }";
+        assert_eq!(
+            lint(source),
+            vec![(4, Lint::Shadowing), (5, Lint::Shadowing)]
+        );
+    }
+
+    #[test]
+    fn flags_literal_comparisons_between_types() {
+        let source =
+            "print(1 == \"1\"); print((nil) != false); print(1 == 2); print(\"a\" == \"b\");";
+        assert_eq!(
+            lint(source),
+            vec![(1, Lint::LiteralComparison), (1, Lint::LiteralComparison)]
+        );
+    }
+
+    #[test]
+    fn honours_suppression_comments() {
+        let source = "print(1 == \"1\"); // nlox-allow: literal-comparison
+                      // nlox-allow: constant-condition
+                      if (true) {}
+                      if (false) {}
+                      fun f(a) {
+                          return 1; // nlox-allow: unused-variable
+                      }";
+        assert_eq!(
+            lint(source),
+            vec![(4, Lint::ConstantCondition), (5, Lint::UnusedVariable)]
+        );
+
+        let source = "// nlox-allow-file: all\nif (true) {} print(1 == nil);";
+        assert!(lint(source).is_empty());
+    }
+}
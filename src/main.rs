@@ -1,39 +1,160 @@
 use std::{
-    cell::RefCell,
-    env::args,
-    io::{Stdout, Write, stdin, stdout},
-    process::Stdio,
-    rc::Rc,
+    io::{Write, stderr, stdin, stdout},
+    time::Duration,
 };
 
-use chunk::{Chunk, OpCode};
-use vm::VM;
+use nlox::{
+    Backend, CompilerOptions, DebugConsole, Emit, Error, GcOptions, JitOptions, Limits,
+    MAX_OPT_LEVEL, Vm,
+};
+
+const USAGE: &str = "Usage : nlox [debug] [-O0|-O1|-O2] [--deny-warnings] [--stress-gc] \
+     [--gc-max-pause=<microseconds>] [--gc-stats] [--backend=stack|register] \
+     [--instruction-count] [--ic-stats] [--no-jit] [--jit-threshold=<calls>] [--sandbox] \
+     [--max-instructions=<n>] [--max-heap=<bytes>] [--max-depth=<frames>] \
+     [--max-string=<bytes>] [--timeout=<milliseconds>] [--emit=cst|ast|bytecode] [path]
+       nlox dap";
 
-mod chunk;
-mod compiler;
-mod scanner;
-mod token;
-mod value;
-mod vm;
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() == 2 {
-        run_file(&args[1]);
-    } else if args.len() == 1 {
-        repl();
-    } else {
-        eprintln!("Usage : nlox [path]");
+    // A debug adapter for editors, speaking over stdin and stdout.
+    if std::env::args().nth(1).as_deref() == Some("dap") {
+        if std::env::args().len() > 2 {
+            return eprintln!("{}", USAGE);
+        }
+        if let Err(error) = nlox::serve_dap(stdin().lock(), stdout()) {
+            eprintln!("nlox dap: {}", error);
+            std::process::exit(74);
+        }
+        return;
+    }
+    let mut emit = None;
+    let mut options = CompilerOptions::default();
+    let mut gc = GcOptions::default();
+    let mut jit = JitOptions::default();
+    let mut limits = Limits::default();
+    let mut gc_stats = false;
+    let mut instruction_count = false;
+    let mut ic_stats = false;
+    let mut path = None;
+    let mut debug = false;
+    for (index, arg) in std::env::args().skip(1).enumerate() {
+        if index == 0 && arg == "debug" {
+            debug = true;
+        } else if arg == "--deny-warnings" {
+            options.deny_warnings = true;
+        } else if arg == "--stress-gc" {
+            gc.stress = true;
+        } else if arg == "--gc-stats" {
+            gc_stats = true;
+        } else if arg == "--instruction-count" {
+            instruction_count = true;
+        } else if arg == "--ic-stats" {
+            ic_stats = true;
+        } else if arg == "--no-jit" {
+            jit.enabled = false;
+        } else if arg == "--sandbox" {
+            limits = Limits::sandbox();
+        } else if let Some((flag, value)) = arg.split_once('=')
+            && (flag.starts_with("--max-") || flag == "--timeout")
+        {
+            let Ok(value) = value.parse() else {
+                return eprintln!("{}", USAGE);
+            };
+            match flag {
+                "--max-instructions" => limits.fuel = Some(value),
+                "--max-heap" => limits.heap_bytes = Some(value as usize),
+                "--max-depth" => limits.call_depth = Some(value as usize),
+                "--max-string" => limits.string_length = Some(value as usize),
+                "--timeout" => limits.timeout = Some(Duration::from_millis(value)),
+                _ => return eprintln!("{}", USAGE),
+            }
+        } else if let Some(backend) = arg.strip_prefix("--backend=") {
+            options.backend = match backend {
+                "stack" => Backend::Stack,
+                "register" => Backend::Register,
+                _ => return eprintln!("{}", USAGE),
+            };
+        } else if let Some(micros) = arg.strip_prefix("--gc-max-pause=") {
+            match micros.parse() {
+                Ok(micros) => gc.max_pause = Duration::from_micros(micros),
+                _ => return eprintln!("{}", USAGE),
+            }
+        } else if let Some(calls) = arg.strip_prefix("--jit-threshold=") {
+            match calls.parse() {
+                Ok(calls) => jit.threshold = calls,
+                _ => return eprintln!("{}", USAGE),
+            }
+        } else if let Some(kind) = arg.strip_prefix("--emit=") {
+            emit = Some(kind.to_string());
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            match level.parse() {
+                Ok(level) if level <= MAX_OPT_LEVEL => options.opt_level = level,
+                _ => return eprintln!("{}", USAGE),
+            }
+        } else if path.is_none() && !arg.starts_with('-') {
+            path = Some(arg);
+        } else {
+            return eprintln!("{}", USAGE);
+        }
+    }
+
+    let mut vm = Vm::new();
+    vm.set_compiler_options(options);
+    vm.set_gc_options(gc);
+    vm.set_jit_options(jit);
+    vm.set_limits(limits);
+    let emit = match emit.as_deref() {
+        None => None,
+        Some("cst") => Some(Emit::Cst),
+        Some("ast") => Some(Emit::Ast),
+        Some("bytecode") => Some(Emit::Bytecode),
+        Some(_) => return eprintln!("{}", USAGE),
+    };
+    match (emit, path) {
+        (Some(emit), Some(path)) if !debug => {
+            let source = read_file(&path);
+            if let Err(error) = nlox::emit(&source, emit, options, &mut stdout(), &mut stderr()) {
+                eprintln!("nlox: {}", error);
+                std::process::exit(74);
+            }
+        }
+        (None, Some(path)) if debug => {
+            let source = read_file(&path);
+            vm.set_debug_hook(DebugConsole::new(&path, &source, stdin().lock(), stdout()));
+            run_file(&path, vm, gc_stats, instruction_count, ic_stats)
+        }
+        (None, Some(path)) => run_file(&path, vm, gc_stats, instruction_count, ic_stats),
+        (None, None) if !debug => repl(vm, gc_stats),
+        _ => eprintln!("{}", USAGE),
     }
 }
 
-fn run_file(path: &String) {
-    let source = std::fs::read_to_string(path).expect("Failed to read the file");
-    let mut vm = VM::new(source);
-    let chunk = Rc::new(RefCell::new(Chunk::new()));
-    vm.interpret(chunk);
+fn read_file(path: &String) -> String {
+    std::fs::read_to_string(path).expect("Failed to read the file")
 }
 
-fn repl() {
+fn run_file(path: &String, mut vm: Vm, gc_stats: bool, instruction_count: bool, ic_stats: bool) {
+    let result = vm.eval(&read_file(path), path);
+    if gc_stats {
+        print_gc_stats(&vm);
+    }
+    if instruction_count {
+        eprintln!("[vm] {} instructions", vm.instructions_executed());
+    }
+    if ic_stats {
+        print_ic_stats(&vm);
+    }
+    // sysexits codes, so scripts and CI can tell the failures apart.
+    match result {
+        Ok(()) => {}
+        Err(Error::Compile { .. }) => std::process::exit(65),
+        // Limits end the script like a runtime error does.
+        Err(_) => std::process::exit(70),
+    }
+}
+
+/// Takes one VM for the whole session so globals survive between lines.
+fn repl(mut vm: Vm, gc_stats: bool) {
     let mut data = String::new();
 
     loop {
@@ -51,9 +172,47 @@ fn repl() {
             continue;
         }
 
-        let input = data.trim_end().to_string(); // ✅ trim newline
-        let chunk = Rc::new(RefCell::new(Chunk::new()));
-        let mut vm = VM::new(input);
-        vm.interpret(chunk.clone());
+        let input = data.trim_end(); // ✅ trim newline
+        // The VM has already printed any error.
+        let _ = vm.eval(input, "repl");
+        if gc_stats {
+            print_gc_stats(&vm);
+        }
     }
 }
+
+fn print_ic_stats(vm: &Vm) {
+    let stats = vm.cache_stats();
+    let rates: Vec<_> = [
+        ("get", stats.get),
+        ("set", stats.set),
+        ("invoke", stats.invoke),
+    ]
+    .iter()
+    .map(|(name, counter)| {
+        format!(
+            "{} {:.1}% of {}",
+            name,
+            counter.hit_rate() * 100.0,
+            counter.hits + counter.misses
+        )
+    })
+    .collect();
+    eprintln!("[ic] hit rates: {}", rates.join(", "));
+}
+
+fn print_gc_stats(vm: &Vm) {
+    let stats = vm.gc_stats();
+    eprintln!(
+        "[gc] {} minor, {} major, {} pauses: total {:?}, max {:?}, last {:?}; {} objects, {} bytes ({} young)",
+        stats.minor_collections,
+        stats.major_collections,
+        stats.pauses,
+        stats.total_pause,
+        stats.max_pause,
+        stats.last_pause,
+        stats.live_objects,
+        stats.bytes_allocated,
+        stats.young_bytes
+    );
+}
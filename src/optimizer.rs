@@ -0,0 +1,574 @@
+//! Peephole optimizer over finished chunks.
+//!
+//! The chunk is decoded into a list of instructions with jumps pointing at
+//! instruction indices, rewritten, and encoded again with fresh offsets and
+//! one line entry per byte. Rewrites never merge an instruction into its
+//! predecessor when something jumps to it, so control flow is unchanged.
+//!
+//! Levels:
+//! - `0`: no changes.
+//! - `1`: fuse `OP_EQUAL, OP_NOT` style pairs and thread jump-to-jump chains.
+//! - `2`: also fold constant arithmetic, comparisons and concatenation.
+
+use std::sync::Arc;
+
+use crate::{
+    ast::{BinaryOp, Literal},
+    chunk::{Chunk, OpCode},
+    gc::Heap,
+    resolver::{fold_binary, is_falsey},
+    value::{Value, ValueType},
+};
+
+pub const MAX_LEVEL: u8 = 2;
+
+struct Instruction {
+    op: OpCode,
+    operands: Vec<u8>,
+    line: usize,
+    /// Instruction index a jump or loop lands on.
+    target: Option<usize>,
+}
+
+/// Optimizes `chunk` in place. Nested functions are optimized when their own
+/// chunk is finished, so only this chunk's code is touched.
+pub fn optimize(chunk: &mut Chunk, level: u8, heap: &mut Heap) {
+    if level == 0 {
+        return;
+    }
+    let instructions = decode(chunk);
+    let mut instructions = peephole(chunk, heap, instructions, level);
+    thread_jumps(&mut instructions);
+    // Threading can only lengthen a jump; if that no longer fits, keep the
+    // unthreaded but still valid code by giving up on this chunk entirely.
+    if let Some((code, lines)) = encode(&instructions) {
+        chunk.code = Arc::new(code);
+        chunk.lines = Arc::new(lines);
+    }
+}
+
+fn is_jump(op: OpCode) -> bool {
+    matches!(
+        op,
+        OpCode::OP_JUMP | OpCode::OP_JUMP_IF_FALSE | OpCode::OP_LOOP
+    )
+}
+
+pub fn operand_count(chunk: &Chunk, offset: usize) -> usize {
+    match OpCode::try_from(chunk.code[offset]).unwrap() {
+        OpCode::Op_Constnats
+        | OpCode::OP_DEFINE_GLOBAL
+        | OpCode::OP_GET_GLOBAL
+        | OpCode::OP_SET_GLOBAL
+        | OpCode::OP_GET_LOCAL
+        | OpCode::OP_SET_LOCAL
+        | OpCode::OP_GET_UPVALUE
+        | OpCode::OP_SET_UPVALUE
+        | OpCode::OP_CALL
+        | OpCode::OP_TAIL_CALL
+        | OpCode::OP_CLASS
+        | OpCode::OP_METHOD => 1,
+        OpCode::OP_JUMP | OpCode::OP_JUMP_IF_FALSE | OpCode::OP_LOOP => 2,
+        OpCode::OP_GET_PROPERTY | OpCode::OP_SET_PROPERTY => 3,
+        OpCode::OP_INVOKE | OpCode::OP_TAIL_INVOKE => 4,
+        OpCode::OP_CLOSURE => {
+            let constant = chunk.code[offset + 1] as usize;
+            let ValueType::VAL_FUNCTION(function) = chunk.constants.values[constant].type_v()
+            else {
+                unreachable!("OP_CLOSURE operand must be a function");
+            };
+            1 + 2 * function.upvalue_count
+        }
+        _ => 0,
+    }
+}
+
+/// The most stack slots a frame running `chunk` fills, counting the callee
+/// and the `arity` arguments it starts with. The depth before each
+/// instruction is the same along every path that reaches it.
+pub fn max_stack(chunk: &Chunk, arity: usize) -> usize {
+    let instructions = decode(chunk);
+    let mut depths = vec![None; instructions.len()];
+    let mut pending = vec![(0, arity + 1)];
+    let mut max = arity + 1;
+    while let Some((index, depth)) = pending.pop() {
+        if index >= instructions.len() || depths[index].is_some() {
+            continue;
+        }
+        depths[index] = Some(depth);
+        let instruction = &instructions[index];
+        let after = depth.checked_add_signed(stack_effect(instruction)).unwrap();
+        max = max.max(after);
+        if let Some(target) = instruction.target {
+            pending.push((target, after));
+        }
+        if !matches!(
+            instruction.op,
+            OpCode::Return
+                | OpCode::OP_JUMP
+                | OpCode::OP_LOOP
+                | OpCode::OP_TAIL_CALL
+                | OpCode::OP_TAIL_INVOKE
+        ) {
+            pending.push((index + 1, after));
+        }
+    }
+    max
+}
+
+/// How many values `instruction` leaves on the stack less what it takes.
+fn stack_effect(instruction: &Instruction) -> isize {
+    match instruction.op {
+        OpCode::OP_NIL
+        | OpCode::OP_TRUE
+        | OpCode::OP_FALSE
+        | OpCode::Op_Constnats
+        | OpCode::OP_GET_GLOBAL
+        | OpCode::OP_GET_LOCAL
+        | OpCode::OP_GET_UPVALUE
+        | OpCode::OP_CLOSURE
+        | OpCode::OP_CLASS => 1,
+        OpCode::OP_ADD
+        | OpCode::OP_SUBTRACT
+        | OpCode::OP_MULTIPLY
+        | OpCode::OP_DIVIDE
+        | OpCode::OP_EQUAL
+        | OpCode::OP_NOT_EQUAL
+        | OpCode::OP_GREATER
+        | OpCode::OP_LESS
+        | OpCode::OP_GREATER_EQUAL
+        | OpCode::OP_LESS_EQUAL
+        | OpCode::OP_PRINT
+        | OpCode::OP_POP
+        | OpCode::OP_DEFINE_GLOBAL
+        | OpCode::OP_CLOSE_UPVALUE
+        | OpCode::OP_METHOD
+        | OpCode::OP_SET_PROPERTY
+        | OpCode::Return => -1,
+        // The result replaces the callee, or the receiver.
+        OpCode::OP_CALL | OpCode::OP_TAIL_CALL => -(instruction.operands[0] as isize),
+        OpCode::OP_INVOKE | OpCode::OP_TAIL_INVOKE => -(instruction.operands[1] as isize),
+        OpCode::OP_NEGATE
+        | OpCode::OP_NOT
+        | OpCode::OP_SET_GLOBAL
+        | OpCode::OP_SET_LOCAL
+        | OpCode::OP_SET_UPVALUE
+        | OpCode::OP_GET_PROPERTY
+        | OpCode::OP_JUMP
+        | OpCode::OP_JUMP_IF_FALSE
+        | OpCode::OP_LOOP => 0,
+    }
+}
+
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    // Instruction index for every byte offset that starts an instruction.
+    let mut index_at = vec![usize::MAX; chunk.code.len() + 1];
+    let mut targets = vec![];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = OpCode::try_from(chunk.code[offset]).unwrap();
+        let end = offset + 1 + operand_count(chunk, offset);
+        let operands = chunk.code[offset + 1..end].to_vec();
+        if is_jump(op) {
+            let jump = u16::from_be_bytes([operands[0], operands[1]]) as usize;
+            targets.push(match op {
+                OpCode::OP_LOOP => end - jump,
+                _ => end + jump,
+            });
+        } else {
+            targets.push(usize::MAX);
+        }
+        index_at[offset] = instructions.len();
+        instructions.push(Instruction {
+            op,
+            operands,
+            line: chunk.lines[offset],
+            target: None,
+        });
+        offset = end;
+    }
+    index_at[chunk.code.len()] = instructions.len();
+    for (instruction, target) in instructions.iter_mut().zip(targets) {
+        if target != usize::MAX {
+            instruction.target = Some(index_at[target]);
+        }
+    }
+    instructions
+}
+
+/// Rewrites the instruction stream left to right, folding patterns at the tail
+/// of the output. `barrier` is the output index of the latest jump target;
+/// nothing before it may be combined with what follows.
+fn peephole(
+    chunk: &mut Chunk,
+    heap: &mut Heap,
+    input: Vec<Instruction>,
+    level: u8,
+) -> Vec<Instruction> {
+    let mut is_target = vec![false; input.len() + 1];
+    for instruction in &input {
+        if let Some(target) = instruction.target {
+            is_target[target] = true;
+        }
+    }
+
+    let mut new_index = vec![0; input.len() + 1];
+    let mut out: Vec<Instruction> = Vec::with_capacity(input.len());
+    let mut barrier = 0;
+    for (index, instruction) in input.into_iter().enumerate() {
+        if is_target[index] {
+            barrier = out.len();
+        }
+        new_index[index] = out.len();
+        out.push(instruction);
+        while rewrite_tail(chunk, heap, &mut out, barrier, level) {}
+    }
+    new_index[is_target.len() - 1] = out.len();
+
+    for instruction in &mut out {
+        if let Some(target) = instruction.target {
+            instruction.target = Some(new_index[target]);
+        }
+    }
+    out
+}
+
+fn rewrite_tail(
+    chunk: &mut Chunk,
+    heap: &mut Heap,
+    out: &mut Vec<Instruction>,
+    barrier: usize,
+    level: u8,
+) -> bool {
+    let len = out.len();
+    let window = len - barrier;
+    let Instruction { op: last, line, .. } = out[len - 1];
+
+    if window >= 2 && last == OpCode::OP_NOT {
+        let fused = match out[len - 2].op {
+            OpCode::OP_EQUAL => Some(OpCode::OP_NOT_EQUAL),
+            OpCode::OP_GREATER => Some(OpCode::OP_LESS_EQUAL),
+            OpCode::OP_LESS => Some(OpCode::OP_GREATER_EQUAL),
+            _ => None,
+        };
+        if let Some(op) = fused {
+            out.pop();
+            out[len - 2].op = op;
+            out[len - 2].line = line;
+            return true;
+        }
+    }
+    if level < 2 {
+        return false;
+    }
+
+    if window >= 2 {
+        let folded = match (last, literal(chunk, heap, &out[len - 2])) {
+            (OpCode::OP_NEGATE, Some(Literal::Number(n))) => Some(Literal::Number(-n)),
+            (OpCode::OP_NOT, Some(value)) => Some(Literal::Bool(is_falsey(&value))),
+            _ => None,
+        };
+        if let Some(value) = folded
+            && replace(chunk, heap, out, 2, value, line)
+        {
+            return true;
+        }
+    }
+
+    if window >= 3
+        && let Some(op) = binary_op(last)
+        && let Some(left) = literal(chunk, heap, &out[len - 3])
+        && let Some(right) = literal(chunk, heap, &out[len - 2])
+        && let Some(value) = fold_binary(op, left, right)
+    {
+        return replace(chunk, heap, out, 3, value, line);
+    }
+    false
+}
+
+/// Replaces the last `count` instructions with a single push of `value`.
+fn replace(
+    chunk: &mut Chunk,
+    heap: &mut Heap,
+    out: &mut Vec<Instruction>,
+    count: usize,
+    value: Literal,
+    line: usize,
+) -> bool {
+    let (op, operands) = match value {
+        Literal::Bool(true) => (OpCode::OP_TRUE, vec![]),
+        Literal::Bool(false) => (OpCode::OP_FALSE, vec![]),
+        Literal::Nil => (OpCode::OP_NIL, vec![]),
+        value => match constant(chunk, heap, value) {
+            Some(index) => (OpCode::Op_Constnats, vec![index]),
+            None => return false,
+        },
+    };
+    out.truncate(out.len() - count);
+    out.push(Instruction {
+        op,
+        operands,
+        line,
+        target: None,
+    });
+    true
+}
+
+/// Index of a number or string constant, reusing an equal entry. `None` when
+/// the pool is full, in which case the fold is skipped.
+fn constant(chunk: &mut Chunk, heap: &mut Heap, value: Literal) -> Option<u8> {
+    let existing =
+        chunk
+            .constants
+            .values
+            .iter()
+            .position(|constant| match (constant.type_v(), &value) {
+                (ValueType::VAL_NUMBER(a), Literal::Number(b)) => a.to_bits() == b.to_bits(),
+                (ValueType::VAL_STRING(a), Literal::String(b)) => heap.string(a) == b,
+                _ => false,
+            });
+    let index = match existing {
+        Some(index) => index,
+        None if chunk.constants.values.len() <= u8::MAX as usize => {
+            let value = match value {
+                Literal::Number(n) => Value::from(n),
+                Literal::String(s) => heap.alloc_string(s),
+                _ => unreachable!("only numbers and strings live in the pool"),
+            };
+            chunk.addConstant(value)
+        }
+        None => return None,
+    };
+    u8::try_from(index).ok()
+}
+
+fn literal(chunk: &Chunk, heap: &Heap, instruction: &Instruction) -> Option<Literal> {
+    match instruction.op {
+        OpCode::OP_TRUE => Some(Literal::Bool(true)),
+        OpCode::OP_FALSE => Some(Literal::Bool(false)),
+        OpCode::OP_NIL => Some(Literal::Nil),
+        OpCode::Op_Constnats => {
+            match &chunk.constants.values[instruction.operands[0] as usize].type_v() {
+                ValueType::VAL_NUMBER(n) => Some(Literal::Number(*n)),
+                ValueType::VAL_STRING(s) => Some(Literal::String(heap.string(*s).to_string())),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn binary_op(op: OpCode) -> Option<BinaryOp> {
+    let op = match op {
+        OpCode::OP_ADD => BinaryOp::Add,
+        OpCode::OP_SUBTRACT => BinaryOp::Subtract,
+        OpCode::OP_MULTIPLY => BinaryOp::Multiply,
+        OpCode::OP_DIVIDE => BinaryOp::Divide,
+        OpCode::OP_EQUAL => BinaryOp::Equal,
+        OpCode::OP_NOT_EQUAL => BinaryOp::NotEqual,
+        OpCode::OP_GREATER => BinaryOp::Greater,
+        OpCode::OP_LESS => BinaryOp::Less,
+        OpCode::OP_GREATER_EQUAL => BinaryOp::GreaterEqual,
+        OpCode::OP_LESS_EQUAL => BinaryOp::LessEqual,
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// Points every jump straight at the end of the chain it would follow.
+///
+/// `OP_JUMP_IF_FALSE` leaves its condition on the stack, so landing on
+/// another one means that one is taken too. An `OP_JUMP` that lands on an
+/// `OP_LOOP` becomes that loop.
+fn thread_jumps(instructions: &mut [Instruction]) {
+    for index in 0..instructions.len() {
+        let op = instructions[index].op;
+        if !matches!(op, OpCode::OP_JUMP | OpCode::OP_JUMP_IF_FALSE) {
+            continue;
+        }
+        let mut target = instructions[index].target.unwrap();
+        // Every hop moves strictly forward, so the chain ends.
+        while let Some(next) = instructions.get(target) {
+            let follows = next.op == OpCode::OP_JUMP
+                || (op == OpCode::OP_JUMP_IF_FALSE && next.op == OpCode::OP_JUMP_IF_FALSE);
+            if !follows {
+                break;
+            }
+            target = next.target.unwrap();
+        }
+        if op == OpCode::OP_JUMP
+            && let Some(next) = instructions.get(target)
+            && next.op == OpCode::OP_LOOP
+        {
+            target = next.target.unwrap();
+            instructions[index].op = OpCode::OP_LOOP;
+        }
+        instructions[index].target = Some(target);
+    }
+}
+
+fn encode(instructions: &[Instruction]) -> Option<(Vec<u8>, Vec<usize>)> {
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for instruction in instructions {
+        offsets.push(offset);
+        offset += 1 + instruction.operands.len();
+    }
+    offsets.push(offset);
+
+    let mut code = Vec::with_capacity(offset);
+    let mut lines = Vec::with_capacity(offset);
+    for (index, instruction) in instructions.iter().enumerate() {
+        code.push(instruction.op as u8);
+        match instruction.target {
+            Some(target) => {
+                let end = offsets[index + 1];
+                let jump = match instruction.op {
+                    OpCode::OP_LOOP => end - offsets[target],
+                    _ => offsets[target] - end,
+                };
+                let [high, low] = u16::try_from(jump).ok()?.to_be_bytes();
+                code.extend([high, low]);
+            }
+            None => code.extend(&instruction.operands),
+        }
+        lines.extend(std::iter::repeat_n(
+            instruction.line,
+            1 + instruction.operands.len(),
+        ));
+    }
+    Some((code, lines))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{decode, max_stack};
+    use crate::{
+        chunk::{Chunk, OpCode},
+        compiler::{Compiler, CompilerOptions},
+        gc::Heap,
+        value::{ObjFunction, ValueType},
+    };
+
+    fn compile_in(heap: &mut Heap, source: &str, level: u8) -> Rc<ObjFunction> {
+        let options = CompilerOptions {
+            opt_level: level,
+            ..CompilerOptions::default()
+        };
+        Compiler::new(source.to_string(), options, heap)
+            .compile()
+            .unwrap()
+    }
+
+    fn compile(source: &str, level: u8) -> Rc<ObjFunction> {
+        compile_in(&mut Heap::new(), source, level)
+    }
+
+    fn ops(chunk: &Chunk) -> Vec<OpCode> {
+        decode(chunk).into_iter().map(|ins| ins.op).collect()
+    }
+
+    #[test]
+    fn folds_constant_expressions() {
+        let mut heap = Heap::new();
+        let function = compile_in(
+            &mut heap,
+            "print(5 + 5 * 2); print(\"a\" + \"b\"); print(!(1 <= 2));",
+            2,
+        );
+        let chunk = &function.chunk;
+        assert_eq!(
+            ops(chunk),
+            vec![
+                OpCode::Op_Constnats,
+                OpCode::OP_PRINT,
+                OpCode::Op_Constnats,
+                OpCode::OP_PRINT,
+                OpCode::OP_FALSE,
+                OpCode::OP_PRINT,
+                OpCode::OP_NIL,
+                OpCode::Return,
+            ]
+        );
+        let constant = |offset: usize| chunk.constants.values[chunk.code[offset] as usize].clone();
+        assert_eq!(constant(1).type_v(), ValueType::VAL_NUMBER(15.0));
+        assert_eq!(heap.display(&constant(4)).to_string(), "ab");
+    }
+
+    #[test]
+    fn leaves_runtime_errors_unfolded() {
+        let function = compile("print(1 + \"a\"); print(-nil);", 2);
+        assert!(ops(&function.chunk).contains(&OpCode::OP_ADD));
+        assert!(ops(&function.chunk).contains(&OpCode::OP_NEGATE));
+    }
+
+    #[test]
+    fn fuses_negated_comparisons() {
+        let source = "fun f(a, b) { print(a != b); print(a <= b); print(a >= b); }";
+        for (level, fused) in [(0, false), (1, true)] {
+            let function = compile(source, level);
+            let ValueType::VAL_FUNCTION(f) = function.chunk.constants.values[0].type_v() else {
+                panic!("expected a function constant");
+            };
+            let ops = ops(&f.chunk);
+            assert_eq!(ops.contains(&OpCode::OP_NOT), !fused);
+            assert_eq!(ops.contains(&OpCode::OP_LESS_EQUAL), fused);
+            assert_eq!(ops.contains(&OpCode::OP_GREATER_EQUAL), fused);
+            assert_eq!(ops.contains(&OpCode::OP_NOT_EQUAL), fused);
+        }
+    }
+
+    #[test]
+    fn threads_jump_chains() {
+        // The inner if's else-jump lands on the outer if's else-jump.
+        let source = "var a = 1; if (a) { if (a) print(1); } else print(2);";
+        let function = compile(source, 1);
+        let instructions = decode(&function.chunk);
+        for instruction in &instructions {
+            if instruction.op == OpCode::OP_JUMP {
+                let target = instruction.target.unwrap();
+                assert_ne!(instructions[target].op, OpCode::OP_JUMP);
+            }
+        }
+    }
+
+    #[test]
+    fn measures_stack_height() {
+        let source = "fun f(a) { var b = a; if (a) print(a + (b + a)); return b; }";
+        for level in [0, 2] {
+            let function = compile(source, level);
+            let ValueType::VAL_FUNCTION(f) = function.chunk.constants.values[0].type_v() else {
+                panic!("expected a function constant");
+            };
+            // The callee, `a` and `b`, then three operands.
+            assert_eq!(f.chunk.max_stack, 6);
+            assert_eq!(max_stack(&f.chunk, f.arity), 6);
+        }
+    }
+
+    #[test]
+    fn keeps_lines_per_byte() {
+        let source = "var a = 1;\nprint(a\n  + 2 * 3);\nwhile (a < 3)\n  a = a + 1;\n";
+        let unoptimized = compile(source, 0);
+        let optimized = compile(source, 2);
+        let chunk = &optimized.chunk;
+        assert_eq!(chunk.code.len(), chunk.lines.len());
+        assert!(chunk.code.len() < unoptimized.chunk.code.len());
+
+        // Every instruction keeps the line it had before optimizing; the
+        // folded `2 * 3` takes the line of its operator.
+        let lines = |chunk: &Chunk| {
+            decode(chunk)
+                .into_iter()
+                .filter(|ins| ins.op != OpCode::Op_Constnats)
+                .map(|ins| (ins.op, ins.line))
+                .collect::<Vec<_>>()
+        };
+        let mut before = lines(&unoptimized.chunk);
+        before.retain(|(op, _)| *op != OpCode::OP_MULTIPLY);
+        assert_eq!(lines(chunk), before);
+    }
+}
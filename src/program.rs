@@ -0,0 +1,274 @@
+//! Compiled scripts that outlive the heap they were compiled in.
+//!
+//! A function's constant strings live in the heap of the VM running it,
+//! and its inline caches and JIT counters belong to that VM, so none of
+//! them can be shared. A `Program` spells the constants out instead and
+//! keeps the code behind `Arc`s: immutable, `Send + Sync`, and cheap to
+//! clone. Each VM that runs one links it first, into functions of its own
+//! heap that share the code and hold only the VM's own constants, caches
+//! and counters. The VM keeps them by prototype, so running the program
+//! again reuses them, and any number of VMs on any threads can run one
+//! `Program` without compiling or copying it.
+
+use std::{cell::Cell, rc::Rc, sync::Arc};
+
+use crate::{
+    chunk::Chunk,
+    compiler::{Compiler, CompilerOptions},
+    embed::{Error, Result},
+    gc::Heap,
+    register::RegisterCode,
+    value::{LocalName, ObjFunction, Value, ValueArray, ValueType},
+};
+
+/// A compiled script, ready to run in any `Vm`.
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub(crate) script: Arc<Prototype>,
+    name: Arc<str>,
+    warnings: Arc<[String]>,
+}
+
+/// A function's compiled code, free of any heap.
+#[derive(Debug)]
+pub(crate) struct Prototype {
+    name: Option<String>,
+    script: String,
+    arity: usize,
+    upvalue_count: usize,
+    code: Arc<Vec<u8>>,
+    lines: Arc<Vec<usize>>,
+    max_stack: usize,
+    registers: Arc<RegisterCode>,
+    constants: Vec<Constant>,
+    caches: usize,
+    locals: Arc<[LocalName]>,
+    upvalue_names: Arc<[String]>,
+}
+
+#[derive(Debug)]
+enum Constant {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Function(Arc<Prototype>),
+}
+
+impl Constant {
+    fn freeze(constant: &Value, heap: &Heap) -> Self {
+        match constant.type_v() {
+            ValueType::VAL_NIL => Constant::Nil,
+            ValueType::VAL_BOOL(bool) => Constant::Bool(bool),
+            ValueType::VAL_NUMBER(number) => Constant::Number(number),
+            ValueType::VAL_STRING(string) => Constant::String(heap.string(string).to_string()),
+            ValueType::VAL_FUNCTION(function) => {
+                Constant::Function(Arc::new(Prototype::freeze(&function, heap)))
+            }
+            _ => unreachable!("constants are strings, functions or primitives"),
+        }
+    }
+}
+
+impl Program {
+    /// Compiles `source` with `options`. `name` identifies the script in
+    /// errors, as for `Vm::eval`.
+    pub fn compile(source: &str, name: &str, options: CompilerOptions) -> Result<Self> {
+        let mut heap = Heap::new();
+        let mut compiler = Compiler::new(source.to_string(), options, &mut heap);
+        compiler.script = name.into();
+        let function = compiler.compile();
+        let (errors, diagnostics) = (compiler.errors, compiler.diagnostics);
+        let Some(function) = function else {
+            return Err(Error::Compile {
+                script: name.to_string(),
+                diagnostics: errors,
+            });
+        };
+        Ok(Self {
+            script: Arc::new(Prototype::freeze(&function, &heap)),
+            name: name.into(),
+            warnings: diagnostics.into(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The compiler's warnings, which running the program does not repeat.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+}
+
+impl Prototype {
+    fn freeze(function: &ObjFunction, heap: &Heap) -> Self {
+        let constants = &function.chunk.constants.values;
+        Self {
+            name: function.name.as_ref().map(|name| name.to_string()),
+            script: function.script.to_string(),
+            arity: function.arity,
+            upvalue_count: function.upvalue_count,
+            code: function.chunk.code.clone(),
+            lines: function.chunk.lines.clone(),
+            max_stack: function.chunk.max_stack,
+            registers: function.registers.clone(),
+            constants: constants
+                .iter()
+                .map(|constant| Constant::freeze(constant, heap))
+                .collect(),
+            caches: function.caches.len(),
+            locals: function.locals.clone(),
+            upvalue_names: function.upvalue_names.clone(),
+        }
+    }
+
+    /// A function of `heap` sharing this code. Its strings are interned
+    /// but not yet reachable, so the caller must root the function before
+    /// the next collection.
+    pub(crate) fn link(&self, heap: &mut Heap) -> Rc<ObjFunction> {
+        let mut function = ObjFunction::new(self.name.clone().map(Rc::new), self.arity);
+        function.script = self.script.as_str().into();
+        function.upvalue_count = self.upvalue_count;
+        function.chunk = Chunk {
+            code: self.code.clone(),
+            lines: self.lines.clone(),
+            constants: ValueArray::new(),
+            max_stack: self.max_stack,
+        };
+        function.registers = self.registers.clone();
+        function.caches = (0..self.caches).map(|_| Cell::default()).collect();
+        function.locals = self.locals.clone();
+        function.upvalue_names = self.upvalue_names.clone();
+        for constant in &self.constants {
+            let value = match constant {
+                Constant::Nil => Value::nil_value(),
+                Constant::Bool(bool) => Value::bool_value(*bool),
+                Constant::Number(number) => Value::number_value(*number),
+                Constant::String(string) => {
+                    Value::from(ValueType::VAL_STRING(heap.intern(string.clone())))
+                }
+                Constant::Function(prototype) => {
+                    Value::from(ValueType::VAL_FUNCTION(prototype.link(heap)))
+                }
+            };
+            function.chunk.constants.values.push(value);
+        }
+        Rc::new(function)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, sync::Arc, thread};
+
+    use super::Program;
+    use crate::{
+        compiler::{Backend, CompilerOptions},
+        embed::{Error, Vm},
+        value::{ObjFunction, ValueType},
+        vm::{InterpretResult, VM},
+    };
+
+    #[test]
+    fn one_program_runs_in_vms_on_many_threads() {
+        fn shareable<T: Send + Sync>(_: &T) {}
+
+        for backend in [Backend::Stack, Backend::Register] {
+            let options = CompilerOptions {
+                backend,
+                ..CompilerOptions::default()
+            };
+            let program = Program::compile(
+                "var hits = 0;
+                 class Greeter { greet(name) { hits = hits + 1; return \"hi \" + name; } }
+                 fun handle(name) { var greeter = Greeter(); greeter.greet(name); return greeter.greet(name); }",
+                "handler.nox",
+                options,
+            )
+            .unwrap();
+            shareable(&program);
+
+            let handlers: Vec<_> = (0..4)
+                .map(|worker| {
+                    let program = program.clone();
+                    thread::spawn(move || {
+                        let mut vm = Vm::new();
+                        vm.run(&program).unwrap();
+                        // Host values stay on their VM's thread; send text.
+                        let greeting = vm.call("handle", (worker.to_string(),)).unwrap();
+                        format!("{} after {}", greeting, vm.get_global("hits").unwrap())
+                    })
+                })
+                .collect();
+            for (worker, handler) in handlers.into_iter().enumerate() {
+                let expected = format!("hi {} after 2", worker);
+                assert_eq!(handler.join().unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_errors_like_eval() {
+        let options = CompilerOptions::default();
+        assert!(matches!(
+            Program::compile("var = 1;", "bad.nox", options),
+            Err(Error::Compile { script, diagnostics })
+                if script == "bad.nox" && diagnostics.len() == 1
+        ));
+
+        let program =
+            Program::compile("{ var unused; } print(-nil);", "fail.nox", options).unwrap();
+        assert_eq!(program.warnings().len(), 1);
+        let mut vm = Vm::new();
+        vm.set_stderr(std::io::sink());
+        assert_eq!(
+            vm.run(&program),
+            Err(Error::Runtime {
+                script: "fail.nox".to_string(),
+                message: "Operand must be a number.".to_string(),
+                trace: vec!["Line[1] in script".to_string()],
+            })
+        );
+    }
+
+    #[test]
+    fn vms_share_the_code_but_not_their_functions() {
+        let program = Program::compile(
+            "fun get(point) { return point.x; }",
+            "get.nox",
+            CompilerOptions::default(),
+        )
+        .unwrap();
+        let get = |vm: &mut VM| -> Rc<ObjFunction> {
+            assert_eq!(vm.run_program(&program), InterpretResult::INTERPRET_OK);
+            let ValueType::VAL_CLOSURE(closure) = vm.global("get").unwrap().type_v() else {
+                panic!("get is a closure");
+            };
+            vm.heap.get(closure).function.clone()
+        };
+        let (mut first, mut second) = (VM::new(), VM::new());
+        let (get_first, get_second) = (get(&mut first), get(&mut second));
+        assert!(Arc::ptr_eq(&get_first.chunk.code, &get_second.chunk.code));
+        // Each VM has its own caches; running the program again reuses them.
+        assert!(!Rc::ptr_eq(&get_first, &get_second));
+        assert!(Rc::ptr_eq(&get_first, &get(&mut first)));
+    }
+
+    #[test]
+    fn linked_constants_survive_collections() {
+        let program = Program::compile(
+            "var word = \"ke\" + \"pt\";",
+            "word.nox",
+            CompilerOptions::default(),
+        )
+        .unwrap();
+        let mut vm = Vm::new();
+        vm.run(&program).unwrap();
+        // Only the linked script holds its two strings now.
+        vm.collect_garbage();
+        vm.run(&program).unwrap();
+        assert_eq!(vm.get_global("word").unwrap().to_string(), "kept");
+    }
+}
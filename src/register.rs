@@ -0,0 +1,368 @@
+//! Register-based instruction set, the experimental alternative to the stack
+//! `OpCode`s.
+//!
+//! Every function gets a window of up to 256 registers on the VM stack.
+//! Register 0 holds the closure being called and the parameters follow it,
+//! the same layout as the stack machine's call frame, so locals keep the
+//! slots the resolver gave them. Temporaries are allocated above the live
+//! locals. Constants stay in the function's chunk so the collector traces
+//! them the same way for both backends.
+
+use std::io::{self, Write};
+
+use crate::{
+    chunk::Chunk,
+    gc::Heap,
+    resolver::Upvalue,
+    value::{ObjFunction, ValueType},
+};
+
+pub type Reg = u8;
+
+/// One three-address instruction. Jump targets are absolute instruction
+/// indices.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    LoadNil {
+        dst: Reg,
+    },
+    LoadBool {
+        dst: Reg,
+        value: bool,
+    },
+    LoadConstant {
+        dst: Reg,
+        constant: u16,
+    },
+    Move {
+        dst: Reg,
+        src: Reg,
+    },
+    Negate {
+        dst: Reg,
+        src: Reg,
+    },
+    Not {
+        dst: Reg,
+        src: Reg,
+    },
+    Add {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    Subtract {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    Multiply {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    Divide {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    Equal {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    NotEqual {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    Greater {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    GreaterEqual {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    Less {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    LessEqual {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    DefineGlobal {
+        src: Reg,
+        name: u16,
+    },
+    GetGlobal {
+        dst: Reg,
+        name: u16,
+    },
+    SetGlobal {
+        src: Reg,
+        name: u16,
+    },
+    GetUpvalue {
+        dst: Reg,
+        index: u8,
+    },
+    SetUpvalue {
+        src: Reg,
+        index: u8,
+    },
+    Jump {
+        target: u32,
+    },
+    JumpIfFalse {
+        cond: Reg,
+        target: u32,
+    },
+    JumpIfTrue {
+        cond: Reg,
+        target: u32,
+    },
+    /// Calls the closure in `base` with the `args` registers after it; the
+    /// result replaces the callee in `base`.
+    Call {
+        base: Reg,
+        args: u8,
+    },
+    /// Captures are listed in the prototype's `RegisterCode::captures`.
+    Closure {
+        dst: Reg,
+        function: u16,
+    },
+    /// Closes every open upvalue at or above `from`.
+    CloseUpvalues {
+        from: Reg,
+    },
+    Class {
+        dst: Reg,
+        name: u16,
+    },
+    /// Adds the closure in `method` to the class in `class`.
+    Method {
+        class: Reg,
+        method: Reg,
+        name: u16,
+    },
+    /// `cache` indexes the function's `ObjFunction::caches`.
+    GetProperty {
+        dst: Reg,
+        object: Reg,
+        name: u16,
+        cache: u16,
+    },
+    SetProperty {
+        object: Reg,
+        src: Reg,
+        name: u16,
+        cache: u16,
+    },
+    /// Calls the method `name` on the receiver in `base`, with the `args`
+    /// registers after it; the result replaces the receiver.
+    Invoke {
+        base: Reg,
+        args: u8,
+        name: u16,
+        cache: u16,
+    },
+    /// `Call` in place of the running frame, which it returns from.
+    TailCall {
+        base: Reg,
+        args: u8,
+    },
+    /// `Invoke` in place of the running frame, which it returns from.
+    TailInvoke {
+        base: Reg,
+        args: u8,
+        name: u16,
+        cache: u16,
+    },
+    Print {
+        src: Reg,
+    },
+    Return {
+        src: Reg,
+    },
+}
+
+/// A function body compiled for the register backend.
+#[derive(Debug, Clone, Default)]
+pub struct RegisterCode {
+    pub code: Vec<Instruction>,
+    pub lines: Vec<usize>,
+    /// Registers the frame needs, including the callee and parameters.
+    pub max_registers: usize,
+    pub captures: Vec<Upvalue>,
+}
+
+impl RegisterCode {
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    pub fn disassemble(
+        function: &ObjFunction,
+        name: &str,
+        heap: &Heap,
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        let registers = &function.registers;
+        writeln!(
+            out,
+            "== {} ({} registers) ==",
+            name, registers.max_registers
+        )?;
+        for (offset, instruction) in registers.code.iter().enumerate() {
+            write!(out, "{:04} ", offset)?;
+            if offset > 0 && registers.lines[offset] == registers.lines[offset - 1] {
+                write!(out, "   | ")?;
+            } else {
+                write!(out, "{:04} ", registers.lines[offset])?;
+            }
+            writeln!(out, "{}", Self::format(instruction, &function.chunk, heap))?;
+        }
+
+        for constant in &function.chunk.constants.values {
+            if let ValueType::VAL_FUNCTION(inner) = constant.type_v() {
+                writeln!(out)?;
+                Self::disassemble(&inner, &inner.to_string(), heap, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn format(instruction: &Instruction, chunk: &Chunk, heap: &Heap) -> String {
+        use Instruction::*;
+        let constant = |index: u16| {
+            heap.display(&chunk.constants.values[index as usize])
+                .to_string()
+        };
+        let binary = |name: &str, dst: &Reg, lhs: &Reg, rhs: &Reg| {
+            format!("{:<16} r{}, r{}, r{}", name, dst, lhs, rhs)
+        };
+        match instruction {
+            LoadNil { dst } => format!("{:<16} r{}", "LOAD_NIL", dst),
+            LoadBool { dst, value } => format!("{:<16} r{}, {}", "LOAD_BOOL", dst, value),
+            LoadConstant { dst, constant: k } => {
+                format!(
+                    "{:<16} r{}, k{} '{}'",
+                    "LOAD_CONSTANT",
+                    dst,
+                    k,
+                    constant(*k)
+                )
+            }
+            Move { dst, src } => format!("{:<16} r{}, r{}", "MOVE", dst, src),
+            Negate { dst, src } => format!("{:<16} r{}, r{}", "NEGATE", dst, src),
+            Not { dst, src } => format!("{:<16} r{}, r{}", "NOT", dst, src),
+            Add { dst, lhs, rhs } => binary("ADD", dst, lhs, rhs),
+            Subtract { dst, lhs, rhs } => binary("SUBTRACT", dst, lhs, rhs),
+            Multiply { dst, lhs, rhs } => binary("MULTIPLY", dst, lhs, rhs),
+            Divide { dst, lhs, rhs } => binary("DIVIDE", dst, lhs, rhs),
+            Equal { dst, lhs, rhs } => binary("EQUAL", dst, lhs, rhs),
+            NotEqual { dst, lhs, rhs } => binary("NOT_EQUAL", dst, lhs, rhs),
+            Greater { dst, lhs, rhs } => binary("GREATER", dst, lhs, rhs),
+            GreaterEqual { dst, lhs, rhs } => binary("GREATER_EQUAL", dst, lhs, rhs),
+            Less { dst, lhs, rhs } => binary("LESS", dst, lhs, rhs),
+            LessEqual { dst, lhs, rhs } => binary("LESS_EQUAL", dst, lhs, rhs),
+            DefineGlobal { src, name } => {
+                format!("{:<16} '{}', r{}", "DEFINE_GLOBAL", constant(*name), src)
+            }
+            GetGlobal { dst, name } => {
+                format!("{:<16} r{}, '{}'", "GET_GLOBAL", dst, constant(*name))
+            }
+            SetGlobal { src, name } => {
+                format!("{:<16} '{}', r{}", "SET_GLOBAL", constant(*name), src)
+            }
+            GetUpvalue { dst, index } => format!("{:<16} r{}, u{}", "GET_UPVALUE", dst, index),
+            SetUpvalue { src, index } => format!("{:<16} u{}, r{}", "SET_UPVALUE", index, src),
+            Jump { target } => format!("{:<16} -> {}", "JUMP", target),
+            JumpIfFalse { cond, target } => {
+                format!("{:<16} r{} -> {}", "JUMP_IF_FALSE", cond, target)
+            }
+            JumpIfTrue { cond, target } => {
+                format!("{:<16} r{} -> {}", "JUMP_IF_TRUE", cond, target)
+            }
+            Call { base, args } => format!("{:<16} r{}, {}", "CALL", base, args),
+            Closure { dst, function } => {
+                format!("{:<16} r{}, {}", "CLOSURE", dst, constant(*function))
+            }
+            CloseUpvalues { from } => format!("{:<16} r{}", "CLOSE_UPVALUES", from),
+            Class { dst, name } => format!("{:<16} r{}, '{}'", "CLASS", dst, constant(*name)),
+            Method {
+                class,
+                method,
+                name,
+            } => format!(
+                "{:<16} r{}.'{}', r{}",
+                "METHOD",
+                class,
+                constant(*name),
+                method
+            ),
+            GetProperty {
+                dst,
+                object,
+                name,
+                cache,
+            } => format!(
+                "{:<16} r{}, r{}.'{}' ic {}",
+                "GET_PROPERTY",
+                dst,
+                object,
+                constant(*name),
+                cache
+            ),
+            SetProperty {
+                object,
+                src,
+                name,
+                cache,
+            } => format!(
+                "{:<16} r{}.'{}', r{} ic {}",
+                "SET_PROPERTY",
+                object,
+                constant(*name),
+                src,
+                cache
+            ),
+            Invoke {
+                base,
+                args,
+                name,
+                cache,
+            } => format!(
+                "{:<16} r{}.'{}', {} ic {}",
+                "INVOKE",
+                base,
+                constant(*name),
+                args,
+                cache
+            ),
+            TailCall { base, args } => format!("{:<16} r{}, {}", "TAIL_CALL", base, args),
+            TailInvoke {
+                base,
+                args,
+                name,
+                cache,
+            } => format!(
+                "{:<16} r{}.'{}', {} ic {}",
+                "TAIL_INVOKE",
+                base,
+                constant(*name),
+                args,
+                cache
+            ),
+            Print { src } => format!("{:<16} r{}", "PRINT", src),
+            Return { src } => format!("{:<16} r{}", "RETURN", src),
+        }
+    }
+}
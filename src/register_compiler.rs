@@ -0,0 +1,715 @@
+use std::{rc::Rc, sync::Arc};
+
+use crate::{
+    ast::{BinaryOp, Expr, Function, Identifier, Literal, LogicalOp, Program, Stmt, UnaryOp},
+    compiler::CompilerOptions,
+    gc::Heap,
+    register::{Instruction, Reg},
+    resolver::{Binding, FunctionKind, Resolution},
+    value::{ObjFunction, Value, ValueType},
+};
+
+/// Register-machine code generator. Walks the same resolved AST as the
+/// stack `Compiler`, so locals keep the slots the resolver assigned and
+/// temporaries are handed out above them in stack order.
+pub struct RegisterCompiler<'a> {
+    heap: &'a mut Heap,
+    resolution: &'a Resolution,
+    options: CompilerOptions,
+    /// Name of the script, recorded in each function.
+    script: Rc<str>,
+    /// Functions being compiled, innermost last.
+    functions: Vec<FunctionState>,
+    scope_depth: usize,
+    line: usize,
+    errors: Vec<(usize, String)>,
+}
+
+struct FunctionState {
+    function: ObjFunction,
+    kind: FunctionKind,
+    /// First free register; everything below is a live local or temporary.
+    next: usize,
+}
+
+impl<'a> RegisterCompiler<'a> {
+    pub fn new(
+        heap: &'a mut Heap,
+        resolution: &'a Resolution,
+        options: CompilerOptions,
+        script: Rc<str>,
+    ) -> Self {
+        Self {
+            heap,
+            resolution,
+            options,
+            script,
+            functions: vec![],
+            scope_depth: 0,
+            line: 0,
+            errors: vec![],
+        }
+    }
+
+    /// Compiles the script, or returns the `(line, message)` of every limit
+    /// it ran into.
+    pub fn compile(mut self, program: &Program) -> Result<ObjFunction, Vec<(usize, String)>> {
+        self.begin_function(ObjFunction::new(None, 0), FunctionKind::Function);
+        for stmt in &program.body {
+            self.statement(stmt);
+        }
+        let function = self.end_function();
+        if self.errors.is_empty() {
+            Ok(function)
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Var {
+                name, initializer, ..
+            } => {
+                let dst = self.alloc();
+                match initializer {
+                    Some(initializer) => self.expr_to(initializer, dst),
+                    None => {
+                        self.line = name.line;
+                        self.emit(Instruction::LoadNil { dst });
+                    }
+                }
+                self.define_variable(name, dst);
+            }
+            Stmt::Function(function) => {
+                let dst = self.alloc();
+                self.function(function, FunctionKind::Function, dst);
+                self.define_variable(&function.name, dst);
+            }
+            Stmt::Class(class) => {
+                let dst = self.alloc();
+                self.line = class.name.line;
+                let name = self.identifier_constant(&class.name);
+                self.emit(Instruction::Class { dst, name });
+                for method in &class.methods {
+                    let saved = self.next();
+                    let closure = self.alloc();
+                    self.function(method, FunctionKind::of_method(method), closure);
+                    let name = self.identifier_constant(&method.name);
+                    self.emit(Instruction::Method {
+                        class: dst,
+                        method: closure,
+                        name,
+                    });
+                    self.set_next(saved);
+                }
+                self.define_variable(&class.name, dst);
+            }
+            Stmt::Expression { expr, .. } => {
+                let saved = self.next();
+                self.expr_any(expr);
+                self.set_next(saved);
+            }
+            Stmt::Print { expr, line } => {
+                let saved = self.next();
+                let src = self.expr_any(expr);
+                self.line = *line;
+                self.emit(Instruction::Print { src });
+                self.set_next(saved);
+            }
+            Stmt::Block { body, end_line } => {
+                self.scope_depth += 1;
+                let start = self.next();
+                for stmt in body {
+                    self.statement(stmt);
+                }
+                self.line = *end_line;
+                self.end_scope(body, start);
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                line,
+            } => {
+                let cond = self.condition(condition);
+                self.line = *line;
+                let then_jump = self.emit(Instruction::JumpIfFalse { cond, target: 0 });
+                self.statement(then_branch);
+                match else_branch {
+                    Some(else_branch) => {
+                        let else_jump = self.emit(Instruction::Jump { target: 0 });
+                        self.patch_jump(then_jump);
+                        self.statement(else_branch);
+                        self.patch_jump(else_jump);
+                    }
+                    None => self.patch_jump(then_jump),
+                }
+            }
+            Stmt::While {
+                condition,
+                body,
+                line,
+            } => {
+                let loop_start = self.code_len();
+                let cond = self.condition(condition);
+                self.line = *line;
+                let exit_jump = self.emit(Instruction::JumpIfFalse { cond, target: 0 });
+                self.statement(body);
+                self.line = *line;
+                self.emit(Instruction::Jump { target: loop_start });
+                self.patch_jump(exit_jump);
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                line,
+            } => {
+                self.scope_depth += 1;
+                let start = self.next();
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+                let loop_start = self.code_len();
+                let exit_jump = condition.as_ref().map(|condition| {
+                    let cond = self.condition(condition);
+                    self.line = *line;
+                    self.emit(Instruction::JumpIfFalse { cond, target: 0 })
+                });
+                self.statement(body);
+                if let Some(increment) = increment {
+                    let saved = self.next();
+                    self.expr_any(increment);
+                    self.set_next(saved);
+                }
+                self.line = *line;
+                self.emit(Instruction::Jump { target: loop_start });
+                if let Some(exit_jump) = exit_jump {
+                    self.patch_jump(exit_jump);
+                }
+                match initializer {
+                    Some(initializer) => self.end_scope(std::slice::from_ref(initializer), start),
+                    None => self.end_scope(&[], start),
+                }
+            }
+            Stmt::Return {
+                value: Some(Expr::Call { callee, args, line }),
+                ..
+            } => {
+                let saved = self.next();
+                let dst = self.alloc();
+                self.call(callee, args, *line, dst, true);
+                self.set_next(saved);
+            }
+            Stmt::Return { value, line } => {
+                let saved = self.next();
+                let src = match value {
+                    Some(value) => self.expr_any(value),
+                    // An initializer's receiver lives in register zero.
+                    None if self.kind() == FunctionKind::Initializer => 0,
+                    None => {
+                        let dst = self.alloc();
+                        self.line = *line;
+                        self.emit(Instruction::LoadNil { dst });
+                        dst
+                    }
+                };
+                self.line = *line;
+                self.emit(Instruction::Return { src });
+                self.set_next(saved);
+            }
+        }
+    }
+
+    /// Evaluates a branch condition into a register that is free again by
+    /// the time the jump reads it; nothing is allocated in between.
+    fn condition(&mut self, condition: &Expr) -> Reg {
+        let saved = self.next();
+        let cond = self.expr_any(condition);
+        self.set_next(saved);
+        cond
+    }
+
+    /// Globals are stored from the register the value was built in; locals
+    /// simply keep it.
+    fn define_variable(&mut self, name: &Identifier, src: Reg) {
+        if self.scope_depth > 0 {
+            return;
+        }
+        self.line = name.line;
+        let name = self.identifier_constant(name);
+        self.emit(Instruction::DefineGlobal { src, name });
+        self.set_next(src as usize);
+    }
+
+    /// Frees the registers of the locals declared since `start`, closing
+    /// them first if a closure captured any.
+    fn end_scope(&mut self, body: &[Stmt], start: usize) {
+        self.scope_depth -= 1;
+        let captured = body.iter().any(|stmt| {
+            let declaration = match stmt {
+                Stmt::Var { id, .. } => *id,
+                Stmt::Function(function) => function.id,
+                Stmt::Class(class) => class.id,
+                _ => return false,
+            };
+            self.resolution.captured.contains(&declaration)
+        });
+        if captured {
+            self.emit(Instruction::CloseUpvalues { from: start as Reg });
+        }
+        self.set_next(start);
+    }
+
+    fn function(&mut self, function: &Function, kind: FunctionKind, dst: Reg) {
+        let name = Rc::new(function.name.name.clone());
+        self.begin_function(ObjFunction::new(Some(name), function.params.len()), kind);
+        self.scope_depth += 1;
+        for stmt in &function.body {
+            self.statement(stmt);
+        }
+        self.line = function.end_line;
+        self.scope_depth -= 1;
+        let mut compiled = self.end_function();
+
+        let captures = self.resolution.upvalues[&function.id].clone();
+        compiled.upvalue_count = captures.len();
+        compiled.locals = self.resolution.locals[&Some(function.id)].as_slice().into();
+        compiled.upvalue_names = self.resolution.upvalue_names[&function.id]
+            .as_slice()
+            .into();
+        Arc::make_mut(&mut compiled.registers).captures = captures;
+        self.line = function.name.line;
+        let function = self.constant(Value::from(ValueType::VAL_FUNCTION(Rc::new(compiled))));
+        self.emit(Instruction::Closure { dst, function });
+    }
+
+    fn begin_function(&mut self, mut function: ObjFunction, kind: FunctionKind) {
+        function.script = self.script.clone();
+        // The callee and its parameters are already in place when the frame
+        // starts.
+        let next = function.arity + 1;
+        self.functions.push(FunctionState {
+            function,
+            kind,
+            next,
+        });
+        self.set_next(next);
+    }
+
+    fn end_function(&mut self) -> ObjFunction {
+        if self.kind() == FunctionKind::Initializer {
+            self.emit(Instruction::Return { src: 0 });
+        } else {
+            let dst = self.alloc();
+            self.emit(Instruction::LoadNil { dst });
+            self.emit(Instruction::Return { src: dst });
+        }
+        self.functions.pop().unwrap().function
+    }
+
+    /// Evaluates `expr` and returns the register holding its value: a local's
+    /// own register when it can be read in place, otherwise a new temporary.
+    fn expr_any(&mut self, expr: &Expr) -> Reg {
+        match expr {
+            Expr::Grouping { expr } => self.expr_any(expr),
+            Expr::Variable { id, .. } | Expr::This { id, .. } => {
+                match self.resolution.bindings[id] {
+                    Binding::Local(slot) => slot,
+                    _ => self.expr_temp(expr),
+                }
+            }
+            Expr::Assign { id, value, .. } => match self.resolution.bindings[id] {
+                Binding::Local(slot) => {
+                    self.assign_local(value, slot);
+                    slot
+                }
+                _ => self.expr_temp(expr),
+            },
+            _ => self.expr_temp(expr),
+        }
+    }
+
+    fn expr_temp(&mut self, expr: &Expr) -> Reg {
+        let dst = self.alloc();
+        self.expr_to(expr, dst);
+        dst
+    }
+
+    /// Evaluates `expr` into `dst`.
+    fn expr_to(&mut self, expr: &Expr, dst: Reg) {
+        if self.options.opt_level >= 2
+            && matches!(expr, Expr::Unary { .. } | Expr::Binary { .. })
+            && let Some(value) = self.resolution.constant(expr)
+        {
+            self.line = expr.line();
+            return self.load_literal(value, dst);
+        }
+        match expr {
+            Expr::Literal { value, line } => {
+                self.line = *line;
+                self.load_literal(value.clone(), dst);
+            }
+            Expr::Grouping { expr } => self.expr_to(expr, dst),
+            Expr::Unary { op, operand, line } => {
+                let saved = self.next();
+                let src = self.expr_any(operand);
+                self.set_next(saved);
+                self.line = *line;
+                match op {
+                    UnaryOp::Negate => self.emit(Instruction::Negate { dst, src }),
+                    UnaryOp::Not => self.emit(Instruction::Not { dst, src }),
+                };
+            }
+            Expr::Binary {
+                op,
+                left,
+                right,
+                line,
+            } => {
+                let saved = self.next();
+                // A local read in place could change under the right operand.
+                let lhs = if has_side_effects(right) {
+                    self.expr_temp(left)
+                } else {
+                    self.expr_any(left)
+                };
+                let rhs = self.expr_any(right);
+                self.set_next(saved);
+                self.line = *line;
+                self.emit(binary(*op, dst, lhs, rhs));
+            }
+            Expr::Logical {
+                op,
+                left,
+                right,
+                line,
+            } => {
+                self.expr_to(left, dst);
+                self.line = *line;
+                let end_jump = match op {
+                    LogicalOp::And => self.emit(Instruction::JumpIfFalse {
+                        cond: dst,
+                        target: 0,
+                    }),
+                    LogicalOp::Or => self.emit(Instruction::JumpIfTrue {
+                        cond: dst,
+                        target: 0,
+                    }),
+                };
+                self.expr_to(right, dst);
+                self.patch_jump(end_jump);
+            }
+            Expr::Variable { id, name } => {
+                self.line = name.line;
+                match self.resolution.bindings[id] {
+                    Binding::Local(src) if src == dst => {}
+                    Binding::Local(src) => {
+                        self.emit(Instruction::Move { dst, src });
+                    }
+                    Binding::Upvalue(index) => {
+                        self.emit(Instruction::GetUpvalue { dst, index });
+                    }
+                    Binding::Global => {
+                        let name = self.identifier_constant(name);
+                        self.emit(Instruction::GetGlobal { dst, name });
+                    }
+                }
+            }
+            Expr::Assign { id, name, value } => match self.resolution.bindings[id] {
+                Binding::Local(slot) => {
+                    self.assign_local(value, slot);
+                    if slot != dst {
+                        self.line = name.line;
+                        self.emit(Instruction::Move { dst, src: slot });
+                    }
+                }
+                Binding::Upvalue(index) => {
+                    self.expr_to(value, dst);
+                    self.line = name.line;
+                    self.emit(Instruction::SetUpvalue { src: dst, index });
+                }
+                Binding::Global => {
+                    self.expr_to(value, dst);
+                    self.line = name.line;
+                    let name = self.identifier_constant(name);
+                    self.emit(Instruction::SetGlobal { src: dst, name });
+                }
+            },
+            Expr::Call { callee, args, line } => self.call(callee, args, *line, dst, false),
+            Expr::Get { object, name } => {
+                let saved = self.next();
+                let object = self.expr_any(object);
+                self.set_next(saved);
+                self.line = name.line;
+                let name = self.identifier_constant(name);
+                let cache = self.inline_cache();
+                self.emit(Instruction::GetProperty {
+                    dst,
+                    object,
+                    name,
+                    cache,
+                });
+            }
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => {
+                let saved = self.next();
+                let object = if has_side_effects(value) {
+                    self.expr_temp(object)
+                } else {
+                    self.expr_any(object)
+                };
+                self.expr_to(value, dst);
+                self.set_next(saved);
+                self.line = name.line;
+                let name = self.identifier_constant(name);
+                let cache = self.inline_cache();
+                self.emit(Instruction::SetProperty {
+                    object,
+                    src: dst,
+                    name,
+                    cache,
+                });
+            }
+            Expr::This { id, line } => {
+                self.line = *line;
+                match self.resolution.bindings[id] {
+                    Binding::Local(src) if src == dst => {}
+                    Binding::Local(src) => {
+                        self.emit(Instruction::Move { dst, src });
+                    }
+                    Binding::Upvalue(index) => {
+                        self.emit(Instruction::GetUpvalue { dst, index });
+                    }
+                    Binding::Global => unreachable!("'this' is only bound inside methods"),
+                }
+            }
+        }
+    }
+
+    /// A call leaving its result in `dst`, or with `tail` one that replaces
+    /// the running frame and returns the result from it.
+    fn call(&mut self, callee: &Expr, args: &[Expr], line: usize, dst: Reg, tail: bool) {
+        let saved = self.next();
+        // Reuse `dst` as the call base when nothing lives above it.
+        let base = if dst as usize + 1 == saved {
+            dst
+        } else {
+            self.alloc()
+        };
+        // `object.name(args)` calls the method without binding it.
+        let method = match callee {
+            Expr::Get { object, name } => {
+                self.expr_to(object, base);
+                Some(name)
+            }
+            callee => {
+                self.expr_to(callee, base);
+                None
+            }
+        };
+        for arg in args {
+            let register = self.alloc();
+            self.expr_to(arg, register);
+        }
+        self.line = line;
+        let args = args.len() as u8;
+        match method {
+            Some(name) => {
+                let name = self.identifier_constant(name);
+                let cache = self.inline_cache();
+                self.emit(if tail {
+                    Instruction::TailInvoke {
+                        base,
+                        args,
+                        name,
+                        cache,
+                    }
+                } else {
+                    Instruction::Invoke {
+                        base,
+                        args,
+                        name,
+                        cache,
+                    }
+                })
+            }
+            None if tail => self.emit(Instruction::TailCall { base, args }),
+            None => self.emit(Instruction::Call { base, args }),
+        };
+        if base != dst && !tail {
+            self.emit(Instruction::Move { dst, src: base });
+        }
+        self.set_next(saved);
+    }
+
+    /// Stores `value` into the local in `slot`. Only expressions that write
+    /// their destination once, after reading every operand, may target the
+    /// local directly; the rest could clobber it while still reading it.
+    fn assign_local(&mut self, value: &Expr, slot: Reg) {
+        if writes_once(value) {
+            return self.expr_to(value, slot);
+        }
+        let saved = self.next();
+        let src = self.expr_temp(value);
+        self.emit(Instruction::Move { dst: slot, src });
+        self.set_next(saved);
+    }
+
+    fn load_literal(&mut self, value: Literal, dst: Reg) {
+        let instruction = match value {
+            Literal::Number(n) => Instruction::LoadConstant {
+                dst,
+                constant: self.constant(Value::from(n)),
+            },
+            Literal::String(s) => {
+                let value = self.heap.alloc_string(s);
+                Instruction::LoadConstant {
+                    dst,
+                    constant: self.constant(value),
+                }
+            }
+            Literal::Bool(value) => Instruction::LoadBool { dst, value },
+            Literal::Nil => Instruction::LoadNil { dst },
+        };
+        self.emit(instruction);
+    }
+
+    fn identifier_constant(&mut self, name: &Identifier) -> u16 {
+        let value = self.heap.alloc_string(name.name.clone());
+        self.constant(value)
+    }
+
+    fn constant(&mut self, value: Value) -> u16 {
+        let constants = &mut self.current().chunk.constants.values;
+        // Interned strings (mostly global names) are worth sharing; numbers
+        // are not, since `0 == -0` would merge two different constants.
+        if value.is_string()
+            && let Some(index) = constants.iter().position(|constant| *constant == value)
+        {
+            return index as u16;
+        }
+        if constants.len() > u16::MAX as usize {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+        constants.push(value);
+        (constants.len() - 1) as u16
+    }
+
+    /// Gives the next property instruction its own slot in the function's
+    /// caches.
+    fn inline_cache(&mut self) -> u16 {
+        let caches = &mut self.current().caches;
+        caches.push(Default::default());
+        if caches.len() > u16::MAX as usize + 1 {
+            self.error("Too many property accesses in one function.");
+            return 0;
+        }
+        (caches.len() - 1) as u16
+    }
+
+    fn alloc(&mut self) -> Reg {
+        let register = self.next();
+        if register > Reg::MAX as usize {
+            self.error("Too many registers in function.");
+            return Reg::MAX;
+        }
+        self.set_next(register + 1);
+        register as Reg
+    }
+
+    fn next(&self) -> usize {
+        self.functions.last().unwrap().next
+    }
+
+    fn set_next(&mut self, next: usize) {
+        let state = self.functions.last_mut().unwrap();
+        state.next = next;
+        let registers = Arc::make_mut(&mut state.function.registers);
+        registers.max_registers = registers.max_registers.max(next);
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        let line = self.line;
+        let registers = Arc::make_mut(&mut self.current().registers);
+        registers.code.push(instruction);
+        registers.lines.push(line);
+        registers.code.len() - 1
+    }
+
+    fn code_len(&mut self) -> u32 {
+        self.current().registers.code.len() as u32
+    }
+
+    fn patch_jump(&mut self, jump: usize) {
+        let here = self.code_len();
+        match &mut Arc::make_mut(&mut self.current().registers).code[jump] {
+            Instruction::Jump { target }
+            | Instruction::JumpIfFalse { target, .. }
+            | Instruction::JumpIfTrue { target, .. } => *target = here,
+            instruction => unreachable!("{:?} is not a jump", instruction),
+        }
+    }
+
+    fn kind(&self) -> FunctionKind {
+        self.functions.last().unwrap().kind
+    }
+
+    fn current(&mut self) -> &mut ObjFunction {
+        &mut self.functions.last_mut().unwrap().function
+    }
+
+    fn error(&mut self, message: &str) {
+        self.errors.push((self.line, message.to_string()));
+    }
+}
+
+fn binary(op: BinaryOp, dst: Reg, lhs: Reg, rhs: Reg) -> Instruction {
+    match op {
+        BinaryOp::Add => Instruction::Add { dst, lhs, rhs },
+        BinaryOp::Subtract => Instruction::Subtract { dst, lhs, rhs },
+        BinaryOp::Multiply => Instruction::Multiply { dst, lhs, rhs },
+        BinaryOp::Divide => Instruction::Divide { dst, lhs, rhs },
+        BinaryOp::Equal => Instruction::Equal { dst, lhs, rhs },
+        BinaryOp::NotEqual => Instruction::NotEqual { dst, lhs, rhs },
+        BinaryOp::Greater => Instruction::Greater { dst, lhs, rhs },
+        BinaryOp::GreaterEqual => Instruction::GreaterEqual { dst, lhs, rhs },
+        BinaryOp::Less => Instruction::Less { dst, lhs, rhs },
+        BinaryOp::LessEqual => Instruction::LessEqual { dst, lhs, rhs },
+    }
+}
+
+fn writes_once(expr: &Expr) -> bool {
+    match expr {
+        Expr::Grouping { expr } => writes_once(expr),
+        Expr::Literal { .. }
+        | Expr::Unary { .. }
+        | Expr::Binary { .. }
+        | Expr::Variable { .. }
+        | Expr::Get { .. }
+        | Expr::This { .. } => true,
+        Expr::Logical { .. } | Expr::Assign { .. } | Expr::Call { .. } | Expr::Set { .. } => false,
+    }
+}
+
+/// Whether evaluating `expr` can write a variable: any assignment, or a call
+/// whose closure might assign a captured local. Field stores only write the
+/// instance.
+fn has_side_effects(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal { .. } | Expr::Variable { .. } | Expr::This { .. } => false,
+        Expr::Grouping { expr } | Expr::Get { object: expr, .. } => has_side_effects(expr),
+        Expr::Set { object, value, .. } => has_side_effects(object) || has_side_effects(value),
+        Expr::Unary { operand, .. } => has_side_effects(operand),
+        Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+            has_side_effects(left) || has_side_effects(right)
+        }
+        Expr::Assign { .. } | Expr::Call { .. } => true,
+    }
+}
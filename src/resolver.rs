@@ -0,0 +1,743 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    ast::{BinaryOp, Expr, Function, Identifier, Literal, NodeId, Program, Stmt, UnaryOp},
+    value::LocalName,
+};
+
+/// Where a variable reference finds its value at runtime.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Binding {
+    Local(u8),
+    Upvalue(u8),
+    Global,
+}
+
+/// What a function body is compiled as. Methods find their receiver in slot
+/// zero under the name `this`; initializers also return it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FunctionKind {
+    Function,
+    Method,
+    Initializer,
+}
+
+impl FunctionKind {
+    pub fn of_method(method: &Function) -> Self {
+        if method.name.name == "init" {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        }
+    }
+}
+
+/// One captured variable of a closure: a slot of the enclosing function when
+/// `is_local`, otherwise one of the enclosing function's own upvalues.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Upvalue {
+    pub index: u8,
+    pub is_local: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ResolveError {
+    pub line: usize,
+    pub name: String,
+    pub message: String,
+}
+
+/// Side tables produced by the resolver, keyed by AST node ids.
+#[derive(Debug, Default)]
+pub struct Resolution {
+    /// Binding of every `Variable` and `Assign` expression.
+    pub bindings: HashMap<NodeId, Binding>,
+    /// Upvalues captured by each function declaration.
+    pub upvalues: HashMap<NodeId, Vec<Upvalue>>,
+    /// Local declarations (variables and parameters) captured by a closure;
+    /// these have to be closed over rather than popped when their scope ends.
+    pub captured: HashSet<NodeId>,
+    /// Local declarations that are assigned after being initialized.
+    pub reassigned: HashSet<NodeId>,
+    /// Local declarations that are read at least once.
+    pub used: HashSet<NodeId>,
+    /// Locals of each function declaration, or of the script under `None`,
+    /// for debuggers.
+    pub locals: HashMap<Option<NodeId>, Vec<LocalName>>,
+    /// Names of each function declaration's upvalues, in index order, for
+    /// debuggers.
+    pub upvalue_names: HashMap<NodeId, Vec<String>>,
+    /// Declaration each local or upvalue reference resolved to.
+    declarations: HashMap<NodeId, NodeId>,
+    /// Initializer of each declared variable. Whether it is constant is only
+    /// decided on lookup, once every assignment has been seen.
+    initializers: HashMap<NodeId, Expr>,
+}
+
+impl Resolution {
+    /// Value of `expr` if it can be computed at compile time: literals,
+    /// operators over constants, and locals that are initialized with a
+    /// constant and never reassigned.
+    pub fn constant(&self, expr: &Expr) -> Option<Literal> {
+        match expr {
+            Expr::Literal { value, .. } => Some(value.clone()),
+            Expr::Grouping { expr } => self.constant(expr),
+            Expr::Unary { op, operand, .. } => {
+                let operand = self.constant(operand)?;
+                match (op, operand) {
+                    (UnaryOp::Negate, Literal::Number(n)) => Some(Literal::Number(-n)),
+                    (UnaryOp::Negate, _) => None,
+                    (UnaryOp::Not, value) => Some(Literal::Bool(is_falsey(&value))),
+                }
+            }
+            Expr::Binary {
+                op, left, right, ..
+            } => fold_binary(*op, self.constant(left)?, self.constant(right)?),
+            Expr::Variable { id, .. } => {
+                let declaration = self.declarations.get(id)?;
+                if self.reassigned.contains(declaration) {
+                    return None;
+                }
+                self.constant(self.initializers.get(declaration)?)
+            }
+            _ => None,
+        }
+    }
+}
+
+pub fn is_falsey(value: &Literal) -> bool {
+    matches!(value, Literal::Nil | Literal::Bool(false))
+}
+
+/// Applies `op` the way the VM would, or gives up where the VM would raise a
+/// runtime error. `>=` and `<=` compile to a negated `<` / `>`, so they are
+/// folded the same way to keep NaN comparisons consistent.
+#[allow(clippy::neg_cmp_op_on_partial_ord)]
+pub fn fold_binary(op: BinaryOp, left: Literal, right: Literal) -> Option<Literal> {
+    use Literal::*;
+    let value = match (op, left, right) {
+        (BinaryOp::Equal, a, b) => Bool(a == b),
+        (BinaryOp::NotEqual, a, b) => Bool(a != b),
+        (BinaryOp::Add, String(a), String(b)) => String(a + &b),
+        (BinaryOp::Add, Number(a), Number(b)) => Number(a + b),
+        (BinaryOp::Subtract, Number(a), Number(b)) => Number(a - b),
+        (BinaryOp::Multiply, Number(a), Number(b)) => Number(a * b),
+        (BinaryOp::Divide, Number(a), Number(b)) => Number(a / b),
+        (BinaryOp::Greater, Number(a), Number(b)) => Bool(a > b),
+        (BinaryOp::GreaterEqual, Number(a), Number(b)) => Bool(!(a < b)),
+        (BinaryOp::Less, Number(a), Number(b)) => Bool(a < b),
+        (BinaryOp::LessEqual, Number(a), Number(b)) => Bool(!(a > b)),
+        _ => return None,
+    };
+    Some(value)
+}
+
+struct Local {
+    name: String,
+    declaration: NodeId,
+    /// `None` while the initializer is being resolved.
+    depth: Option<usize>,
+    /// Index of its entry in `FunctionScope::names`.
+    debug: usize,
+}
+
+struct FunctionScope {
+    locals: Vec<Local>,
+    /// Every local declared so far, for `Resolution::locals`.
+    names: Vec<LocalName>,
+    upvalues: Vec<Upvalue>,
+    upvalue_names: Vec<String>,
+    scope_depth: usize,
+    kind: FunctionKind,
+}
+
+impl FunctionScope {
+    fn new(scope_depth: usize, kind: FunctionKind, line: usize) -> Self {
+        // Slot zero holds the function being called, or the receiver.
+        let (name, names) = match kind {
+            FunctionKind::Function => ("", vec![]),
+            FunctionKind::Method | FunctionKind::Initializer => (
+                "this",
+                vec![LocalName {
+                    name: "this".to_string(),
+                    slot: 0,
+                    first_line: line,
+                    last_line: usize::MAX,
+                }],
+            ),
+        };
+        Self {
+            locals: vec![Local {
+                name: name.to_string(),
+                declaration: NodeId(0),
+                depth: Some(0),
+                debug: 0,
+            }],
+            names,
+            upvalues: vec![],
+            upvalue_names: vec![],
+            scope_depth,
+            kind,
+        }
+    }
+}
+
+pub struct Resolver {
+    functions: Vec<FunctionScope>,
+    /// Class declarations enclosing the current code.
+    classes: usize,
+    resolution: Resolution,
+    errors: Vec<ResolveError>,
+}
+
+pub fn resolve(program: &Program) -> Result<Resolution, Vec<ResolveError>> {
+    let mut resolver = Resolver {
+        functions: vec![FunctionScope::new(0, FunctionKind::Function, 1)],
+        classes: 0,
+        resolution: Resolution::default(),
+        errors: vec![],
+    };
+    for stmt in &program.body {
+        resolver.stmt(stmt);
+    }
+    let script = resolver.functions.pop().unwrap();
+    resolver.resolution.locals.insert(None, script.names);
+    if resolver.errors.is_empty() {
+        Ok(resolver.resolution)
+    } else {
+        Err(resolver.errors)
+    }
+}
+
+impl Resolver {
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Var {
+                id,
+                name,
+                initializer,
+            } => {
+                self.declare(*id, name);
+                let initializer = match initializer {
+                    Some(initializer) => {
+                        self.expr(initializer);
+                        initializer.clone()
+                    }
+                    None => Expr::Literal {
+                        value: Literal::Nil,
+                        line: name.line,
+                    },
+                };
+                self.resolution.initializers.insert(*id, initializer);
+                self.mark_initialized();
+            }
+            Stmt::Function(function) => {
+                // Mark the name usable before the body so it can recurse.
+                self.declare(function.id, &function.name);
+                self.mark_initialized();
+                self.function(function, FunctionKind::Function);
+            }
+            Stmt::Class(class) => {
+                self.declare(class.id, &class.name);
+                self.mark_initialized();
+                self.classes += 1;
+                for method in &class.methods {
+                    self.function(method, FunctionKind::of_method(method));
+                }
+                self.classes -= 1;
+            }
+            Stmt::Expression { expr, .. } | Stmt::Print { expr, .. } => self.expr(expr),
+            Stmt::Block { body, end_line } => {
+                self.begin_scope();
+                for stmt in body {
+                    self.stmt(stmt);
+                }
+                self.end_scope(*end_line);
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.expr(condition);
+                self.stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.stmt(else_branch);
+                }
+            }
+            Stmt::While {
+                condition, body, ..
+            } => {
+                self.expr(condition);
+                self.stmt(body);
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                line,
+            } => {
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.stmt(initializer);
+                }
+                if let Some(condition) = condition {
+                    self.expr(condition);
+                }
+                if let Some(increment) = increment {
+                    self.expr(increment);
+                }
+                self.stmt(body);
+                let end_line = match &**body {
+                    Stmt::Block { end_line, .. } => *end_line,
+                    _ => *line,
+                };
+                self.end_scope(end_line);
+            }
+            Stmt::Return { value, line } => {
+                if self.functions.len() == 1 {
+                    self.error(*line, "return", "Can't return from top-level code.");
+                }
+                if let Some(value) = value {
+                    if self.scope().kind == FunctionKind::Initializer {
+                        self.error(*line, "return", "Can't return a value from an initializer.");
+                    }
+                    self.expr(value);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, function: &Function, kind: FunctionKind) {
+        let depth = self.scope().scope_depth + 1;
+        let line = function.name.line;
+        self.functions.push(FunctionScope::new(depth, kind, line));
+        for param in &function.params {
+            self.declare(param.id, &param.name);
+            self.mark_initialized();
+        }
+        for stmt in &function.body {
+            self.stmt(stmt);
+        }
+        let mut scope = self.functions.pop().unwrap();
+        for name in &mut scope.names {
+            name.last_line = name.last_line.min(function.end_line);
+        }
+        self.resolution
+            .locals
+            .insert(Some(function.id), scope.names);
+        self.resolution.upvalues.insert(function.id, scope.upvalues);
+        self.resolution
+            .upvalue_names
+            .insert(function.id, scope.upvalue_names);
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal { .. } => {}
+            Expr::Grouping { expr } => self.expr(expr),
+            Expr::Unary { operand, .. } => self.expr(operand),
+            Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            }
+            Expr::Variable { id, name } => {
+                let top = self.functions.len() - 1;
+                let uninitialized = self.functions[top]
+                    .locals
+                    .iter()
+                    .rev()
+                    .find(|local| local.name == name.name)
+                    .is_some_and(|local| local.depth.is_none());
+                if uninitialized {
+                    self.error(
+                        name.line,
+                        &name.name,
+                        "Can't read local variable in its own initializer.",
+                    );
+                }
+                self.bind(*id, name, false);
+            }
+            Expr::Assign { id, name, value } => {
+                self.expr(value);
+                self.bind(*id, name, true);
+            }
+            Expr::Call { callee, args, .. } => {
+                self.expr(callee);
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            Expr::Get { object, .. } => self.expr(object),
+            Expr::Set { object, value, .. } => {
+                self.expr(object);
+                self.expr(value);
+            }
+            Expr::This { id, line } => {
+                if self.classes == 0 {
+                    self.error(*line, "this", "Can't use 'this' outside of a class.");
+                    return;
+                }
+                let name = Identifier {
+                    name: "this".to_string(),
+                    line: *line,
+                };
+                self.bind(*id, &name, false);
+            }
+        }
+    }
+
+    fn bind(&mut self, id: NodeId, name: &Identifier, assign: bool) {
+        let top = self.functions.len() - 1;
+        let binding = match self.resolve_local(top, &name.name) {
+            Some((slot, declaration)) => {
+                self.use_declaration(id, declaration, assign);
+                Binding::Local(slot)
+            }
+            None => match self.resolve_upvalue(top, &name.name, name.line) {
+                Some((index, declaration)) => {
+                    self.use_declaration(id, declaration, assign);
+                    Binding::Upvalue(index)
+                }
+                None => Binding::Global,
+            },
+        };
+        self.resolution.bindings.insert(id, binding);
+    }
+
+    fn use_declaration(&mut self, id: NodeId, declaration: NodeId, assign: bool) {
+        self.resolution.declarations.insert(id, declaration);
+        if assign {
+            self.resolution.reassigned.insert(declaration);
+        } else {
+            self.resolution.used.insert(declaration);
+        }
+    }
+
+    fn resolve_local(&self, function: usize, name: &str) -> Option<(u8, NodeId)> {
+        self.functions[function]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)
+            .map(|(slot, local)| (slot as u8, local.declaration))
+    }
+
+    fn resolve_upvalue(
+        &mut self,
+        function: usize,
+        name: &str,
+        line: usize,
+    ) -> Option<(u8, NodeId)> {
+        if function == 0 {
+            return None;
+        }
+        if let Some((slot, declaration)) = self.resolve_local(function - 1, name) {
+            self.resolution.captured.insert(declaration);
+            let index = self.add_upvalue(function, slot, true, name, line);
+            return Some((index, declaration));
+        }
+        let (index, declaration) = self.resolve_upvalue(function - 1, name, line)?;
+        Some((
+            self.add_upvalue(function, index, false, name, line),
+            declaration,
+        ))
+    }
+
+    fn add_upvalue(
+        &mut self,
+        function: usize,
+        index: u8,
+        is_local: bool,
+        name: &str,
+        line: usize,
+    ) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        let scope = &mut self.functions[function];
+        if let Some(existing) = scope.upvalues.iter().position(|u| *u == upvalue) {
+            return existing as u8;
+        }
+        if scope.upvalues.len() == u8::MAX as usize + 1 {
+            self.error(line, "", "Too many closure variables in function.");
+            return 0;
+        }
+        scope.upvalues.push(upvalue);
+        scope.upvalue_names.push(name.to_string());
+        (scope.upvalues.len() - 1) as u8
+    }
+
+    fn declare(&mut self, declaration: NodeId, name: &Identifier) {
+        let scope = self.scope();
+        if scope.scope_depth == 0 {
+            return;
+        }
+        let depth = scope.scope_depth;
+        let duplicate = scope
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|d| d >= depth))
+            .any(|local| local.name == name.name);
+        if duplicate {
+            self.error(
+                name.line,
+                &name.name,
+                "Already a variable with this name in this scope.",
+            );
+        }
+        if self.scope().locals.len() == u8::MAX as usize + 1 {
+            self.error(
+                name.line,
+                &name.name,
+                "Too many local variables in function.",
+            );
+            return;
+        }
+        let scope = self.scope_mut();
+        scope.names.push(LocalName {
+            name: name.name.clone(),
+            slot: scope.locals.len() as u8,
+            first_line: name.line,
+            last_line: usize::MAX,
+        });
+        scope.locals.push(Local {
+            name: name.name.clone(),
+            declaration,
+            depth: None,
+            debug: scope.names.len() - 1,
+        });
+    }
+
+    fn mark_initialized(&mut self) {
+        let scope = self.scope_mut();
+        let depth = scope.scope_depth;
+        if depth == 0 {
+            return;
+        }
+        if let Some(local) = scope.locals.last_mut() {
+            local.depth = Some(depth);
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope_mut().scope_depth += 1;
+    }
+
+    /// Ends the innermost block scope, whose last line is `end_line`.
+    fn end_scope(&mut self, end_line: usize) {
+        let scope = self.scope_mut();
+        scope.scope_depth -= 1;
+        let depth = scope.scope_depth;
+        while scope
+            .locals
+            .last()
+            .is_some_and(|local| local.depth.is_none_or(|d| d > depth))
+        {
+            let local = scope.locals.pop().unwrap();
+            scope.names[local.debug].last_line = end_line;
+        }
+    }
+
+    fn scope(&self) -> &FunctionScope {
+        self.functions.last().unwrap()
+    }
+
+    fn scope_mut(&mut self) -> &mut FunctionScope {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn error(&mut self, line: usize, name: &str, message: &str) {
+        self.errors.push(ResolveError {
+            line,
+            name: name.to_string(),
+            message: message.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Binding, Upvalue, resolve};
+    use crate::ast::{self, Expr, Literal, Stmt};
+
+    fn print_exprs(stmts: &[Stmt], out: &mut Vec<Expr>) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Print { expr, .. } => out.push(expr.clone()),
+                Stmt::Block { body, .. } => print_exprs(body, out),
+                Stmt::Function(function) => print_exprs(&function.body, out),
+                _ => {}
+            }
+        }
+    }
+
+    fn binding_of(expr: &Expr, resolution: &super::Resolution) -> Binding {
+        let Expr::Variable { id, .. } = expr else {
+            panic!("expected a variable");
+        };
+        resolution.bindings[id]
+    }
+
+    #[test]
+    fn binds_globals_locals_and_upvalues() {
+        let program = ast::parse(
+            "var g = 1;
+             fun outer(a) {
+                 var b = 2;
+                 fun inner() { print(a); print(b); print(g); }
+                 print(b);
+             }",
+        )
+        .unwrap();
+        let resolution = resolve(&program).unwrap();
+        let mut prints = vec![];
+        print_exprs(&program.body, &mut prints);
+
+        assert_eq!(binding_of(&prints[0], &resolution), Binding::Upvalue(0));
+        assert_eq!(binding_of(&prints[1], &resolution), Binding::Upvalue(1));
+        assert_eq!(binding_of(&prints[2], &resolution), Binding::Global);
+        assert_eq!(binding_of(&prints[3], &resolution), Binding::Local(2));
+
+        let Stmt::Function(outer) = &program.body[1] else {
+            panic!("expected a function");
+        };
+        let Stmt::Function(inner) = &outer.body[1] else {
+            panic!("expected a function");
+        };
+        assert_eq!(
+            resolution.upvalues[&inner.id],
+            vec![
+                Upvalue {
+                    index: 1,
+                    is_local: true
+                },
+                Upvalue {
+                    index: 2,
+                    is_local: true
+                }
+            ]
+        );
+        assert!(resolution.captured.contains(&outer.params[0].id));
+    }
+
+    #[test]
+    fn tracks_constant_locals() {
+        let program = ast::parse(
+            "{
+                 var a = 2 * 3;
+                 var b = a;
+                 var c = 1;
+                 c = 2;
+                 print(a + 1);
+                 print(b == 6);
+                 print(c);
+             }",
+        )
+        .unwrap();
+        let resolution = resolve(&program).unwrap();
+        let mut prints = vec![];
+        print_exprs(&program.body, &mut prints);
+
+        assert_eq!(resolution.constant(&prints[0]), Some(Literal::Number(7.0)));
+        assert_eq!(resolution.constant(&prints[1]), Some(Literal::Bool(true)));
+        assert_eq!(resolution.constant(&prints[2]), None);
+    }
+
+    #[test]
+    fn loop_assignments_are_not_constant() {
+        let program = ast::parse(
+            "{
+                 var a = 1;
+                 while (true) {
+                     var b = a;
+                     print(b);
+                     a = a + 1;
+                 }
+             }",
+        )
+        .unwrap();
+        let resolution = resolve(&program).unwrap();
+        let Stmt::Block { body, .. } = &program.body[0] else {
+            panic!("expected a block");
+        };
+        let Stmt::While { body, .. } = &body[1] else {
+            panic!("expected a loop");
+        };
+        let mut prints = vec![];
+        print_exprs(std::slice::from_ref(body), &mut prints);
+        assert_eq!(resolution.constant(&prints[0]), None);
+    }
+
+    #[test]
+    fn reports_scope_errors() {
+        let program = ast::parse("{ var a = 1; var a = 2; } { var b = b; } return;").unwrap();
+        let errors = resolve(&program).unwrap_err();
+        let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Already a variable with this name in this scope.",
+                "Can't read local variable in its own initializer.",
+                "Can't return from top-level code.",
+            ]
+        );
+    }
+
+    #[test]
+    fn binds_this_to_the_receiver_slot() {
+        let program = ast::parse(
+            "class A {
+                 m() {
+                     fun f() { return this; }
+                     return this;
+                 }
+             }",
+        )
+        .unwrap();
+        let resolution = resolve(&program).unwrap();
+        let Stmt::Class(class) = &program.body[0] else {
+            panic!("expected a class");
+        };
+        let method = &class.methods[0];
+        let this_of = |stmt: &Stmt| {
+            let Stmt::Return {
+                value: Some(Expr::This { id, .. }),
+                ..
+            } = stmt
+            else {
+                panic!("expected 'return this;'");
+            };
+            resolution.bindings[id]
+        };
+        let Stmt::Function(f) = &method.body[0] else {
+            panic!("expected a function");
+        };
+        assert_eq!(this_of(&method.body[1]), Binding::Local(0));
+        assert_eq!(this_of(&f.body[0]), Binding::Upvalue(0));
+        assert_eq!(
+            resolution.upvalues[&f.id],
+            vec![Upvalue {
+                index: 0,
+                is_local: true
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_class_errors() {
+        let program =
+            ast::parse("print(this); class A { init() { return 1; } m() { return 2; } }").unwrap();
+        let errors = resolve(&program).unwrap_err();
+        let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Can't use 'this' outside of a class.",
+                "Can't return a value from an initializer.",
+            ]
+        );
+    }
+}
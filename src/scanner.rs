@@ -1,9 +1,33 @@
-use crate::token::{Kind, Token};
-use std::{collections::VecDeque, rc::Rc};
+use crate::{
+    lint::{Lint, Warning},
+    token::{Kind, Token},
+};
+use std::{collections::VecDeque, ops::Range, rc::Rc};
+use unicode_ident::{is_xid_continue, is_xid_start};
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{MixedScript, skeleton};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriviaKind {
+    Whitespace,
+    Comment,
+}
+
+/// Source skipped between tokens; kept so the CST can reproduce it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub span: Range<usize>,
+}
+
 pub struct Scanner {
     characters: VecDeque<char>,
     line: usize,
+    /// Byte offset of the next unread character.
     index: usize,
+    pub warnings: Vec<Warning>,
+    /// Trivia that preceded the token last returned by `next`.
+    pub trivia: Vec<Trivia>,
 }
 
 impl Scanner {
@@ -12,6 +36,8 @@ impl Scanner {
             characters: source.chars().collect(),
             line: 1,
             index: 0,
+            warnings: vec![],
+            trivia: vec![],
         }
     }
 
@@ -39,8 +65,8 @@ impl Scanner {
             Some('<') if self.peek(1) == Some(&'=') => self.make_token(Kind::LessEqual, 2),
             Some('<') => self.make_token(Kind::Less, 1),
 
-            Some('a'..='z') | Some('A'..='Z') | Some('_') => self.indentifier_literal(),
             Some('0'..='9') => self.number_literal(),
+            Some(&c) if c == '_' || is_xid_start(c) => self.indentifier_literal(),
             Some('"') => self.string_literal(),
             Some(_) => self.make_token(Kind::Error, 1),
             _ => self.make_token(Kind::Eof, 0),
@@ -69,11 +95,13 @@ impl Scanner {
             }
         }
 
-        if self.peek(len) == Some(&'.') && self.peek(len + 1).unwrap().is_ascii_digit() {
+        if self.peek(len) == Some(&'.') && self.peek(len + 1).is_some_and(char::is_ascii_digit) {
             len += 2;
             while let Some(ch) = self.peek(len) {
                 if ch.is_ascii_digit() {
                     len += 1;
+                } else {
+                    break;
                 }
             }
         }
@@ -84,7 +112,7 @@ impl Scanner {
     fn indentifier_literal(&mut self) -> Token {
         let mut length = 1;
         while let Some(ch) = self.peek(length) {
-            if ch.is_ascii_digit() || ch.is_alphanumeric() || ch == &'_' {
+            if is_xid_continue(*ch) {
                 length += 1;
             } else {
                 break;
@@ -119,7 +147,36 @@ impl Scanner {
             _ => Kind::IdentifierLiteral,
         };
 
-        self.make_token(kind, length)
+        // Keywords are matched on the raw ASCII spelling above; only plain
+        // identifiers are normalized so `größe` typed either precomposed or
+        // with combining marks names the same variable.
+        let mut token = self.make_token(kind, length);
+        if token.kind == Kind::IdentifierLiteral && !token.string.is_ascii() {
+            token.string = Rc::new(token.string.nfc().collect());
+            self.check_confusable(&token);
+        }
+        token
+    }
+
+    fn check_confusable(&mut self, token: &Token) {
+        let name = token.string.as_str();
+        let message = if !name.is_single_script() {
+            format!(
+                "identifier '{}' mixes scripts and may be confused with another name",
+                name
+            )
+        } else {
+            let look_alike: String = skeleton(name).collect();
+            if !look_alike.is_ascii() {
+                return;
+            }
+            format!("identifier '{}' looks like '{}'", name, look_alike)
+        };
+        self.warnings.push(Warning {
+            line: token.line,
+            lint: Lint::ConfusableIdentifier,
+            message,
+        });
     }
 
     fn starts_with(&self, prefix: &str) -> bool {
@@ -128,56 +185,72 @@ impl Scanner {
                 return false;
             }
         }
-        return true;
+        true
     }
 
     fn make_token(&mut self, kind: Kind, count: usize) -> Token {
+        let start = self.index;
+        let string = Rc::new(self.read_front(count));
         Token {
             kind,
             line: self.line,
-            index_in_source: self.index,
-            string: Rc::new(self.read_front(count)),
+            span: start..self.index,
+            string,
         }
     }
 
     fn read_front(&mut self, count: usize) -> String {
         let mut string = String::new();
-        for i in 0..count {
+        for _ in 0..count {
             if let Some(ch) = self.advance() {
                 string.push(ch);
             }
         }
-        string.replace("\"", "")
+        string
     }
     pub fn advance(&mut self) -> Option<char> {
-        self.index += 1;
-        self.characters.pop_front()
+        let ch = self.characters.pop_front()?;
+        self.index += ch.len_utf8();
+        Some(ch)
     }
     pub fn peek(&self, count: usize) -> Option<&char> {
         self.characters.get(count)
     }
 
     pub fn consume_whitespaces(&mut self) {
+        self.trivia.clear();
         loop {
-            match self.peek(0) {
+            let start = self.index;
+            let kind = match self.peek(0) {
                 Some(c) => match c {
                     ' ' | '\t' | '\r' => {
                         self.advance();
-                        continue;
+                        TriviaKind::Whitespace
                     }
                     '\n' => {
                         self.advance();
                         self.line += 1;
-                        continue;
+                        TriviaKind::Whitespace
                     }
                     '/' if self.peek(1) == Some(&'/') => {
-                        while self.advance() != Some('\n') {}
-                        continue;
+                        while self.peek(0).is_some_and(|c| *c != '\n') {
+                            self.advance();
+                        }
+                        TriviaKind::Comment
                     }
                     _ => return,
                 },
                 None => return,
             };
+            match self.trivia.last_mut() {
+                Some(last) if kind == TriviaKind::Whitespace && last.kind == kind => {
+                    last.span.end = self.index
+                }
+                _ => self.trivia.push(Trivia {
+                    kind,
+                    span: start..self.index,
+                }),
+            }
         }
     }
 }
@@ -211,6 +284,45 @@ mod tests {
         single_token_test(String::from("_abc123"), Kind::IdentifierLiteral);
     }
 
+    #[test]
+    fn unicode_identifiers() {
+        single_token_test(String::from("größe"), Kind::IdentifierLiteral);
+        single_token_test(String::from("変数"), Kind::IdentifierLiteral);
+        single_token_test(String::from("_δ2"), Kind::IdentifierLiteral);
+        // Fullwidth letters are identifier characters, never keywords.
+        single_token_test(String::from("ｗｈｉｌｅ"), Kind::IdentifierLiteral);
+
+        let mut scanner = scanner::Scanner::new(String::from("gro\u{308}ße"));
+        let token = scanner.next();
+        assert_eq!(token.kind, Kind::IdentifierLiteral);
+        assert_eq!(token.string.as_str(), "größe");
+        assert_eq!(scanner.next().kind, Kind::Eof);
+        assert!(scanner.warnings.is_empty());
+    }
+
+    #[test]
+    fn confusable_identifiers() {
+        for source in ["раураl", "pаypal", "ѕсоре"] {
+            let mut scanner = scanner::Scanner::new(String::from(source));
+            assert_eq!(scanner.next().kind, Kind::IdentifierLiteral);
+            assert_eq!(
+                scanner.warnings.len(),
+                1,
+                "expected a warning for {}",
+                source
+            );
+        }
+        for source in ["größe", "変数", "paypal"] {
+            let mut scanner = scanner::Scanner::new(String::from(source));
+            scanner.next();
+            assert!(
+                scanner.warnings.is_empty(),
+                "unexpected warning for {}",
+                source
+            );
+        }
+    }
+
     #[test]
     fn keywords() {
         single_token_test(String::from("and"), Kind::And);
@@ -0,0 +1,670 @@
+//! Serde support for host values, so Rust data crosses into scripts
+//! without hand-written conversions.
+//!
+//! `Value` is `Serialize` and `Deserialize` as the data model's unit,
+//! bool, number, string, sequence and map. `to_value` turns any
+//! `T: Serialize` into a `Value` and `from_value` reads one back into a
+//! `T: Deserialize`. Structs become maps of their fields; enum variants
+//! follow serde's externally tagged form, a unit variant being its name
+//! and any other a one-entry map from the name to its contents. Numbers
+//! are `f64`s, so integers read back must be whole and in range. Objects
+//! have no serialized form.
+//!
+//! Wrapping a type in `Serde` lets a native take it as an argument; one
+//! can return `to_value`'s result for its own.
+
+use std::{collections::HashMap, fmt};
+
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{
+        self, DeserializeOwned, IntoDeserializer, MapAccess, SeqAccess, Visitor,
+        value::{MapAccessDeserializer, MapDeserializer, SeqDeserializer},
+    },
+    ser::{self, SerializeMap, SerializeSeq},
+};
+
+use crate::{
+    convert::FromValue,
+    embed::{Error, Result, Value},
+};
+
+/// Converts `value` with its `Serialize` implementation.
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value> {
+    value.serialize(ValueSerializer)
+}
+
+/// Builds a `T` from `value` with its `Deserialize` implementation.
+pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T> {
+    T::deserialize(value)
+}
+
+/// A native's argument converted through serde.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Serde<T>(pub T);
+
+impl<T: DeserializeOwned> FromValue for Serde<T> {
+    fn from_value(value: Value) -> Result<Self> {
+        from_value(value).map(Serde)
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error::Host(message.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error::Host(message.to_string())
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Value::Nil => serializer.serialize_unit(),
+            Value::Bool(bool) => serializer.serialize_bool(*bool),
+            Value::Number(number) => serializer.serialize_f64(*number),
+            Value::String(string) => serializer.serialize_str(string),
+            Value::List(list) => {
+                let mut seq = serializer.serialize_seq(Some(list.len()))?;
+                for element in list {
+                    seq.serialize_element(element)?;
+                }
+                seq.end()
+            }
+            Value::Map(map) => {
+                // Sorted, so the output does not depend on hashing.
+                let mut entries: Vec<_> = map.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                let mut out = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    out.serialize_entry(key, value)?;
+                }
+                out.end()
+            }
+            Value::Object(object) => {
+                Err(ser::Error::custom(format!("Cannot serialize {}.", object)))
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "nil, a bool, number, string, list or map")
+    }
+
+    fn visit_unit<E>(self) -> std::result::Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_none<E>(self) -> std::result::Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, inner: D) -> std::result::Result<Value, D::Error> {
+        inner.deserialize_any(self)
+    }
+
+    fn visit_bool<E>(self, bool: bool) -> std::result::Result<Value, E> {
+        Ok(Value::Bool(bool))
+    }
+
+    fn visit_i64<E>(self, number: i64) -> std::result::Result<Value, E> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn visit_u64<E>(self, number: u64) -> std::result::Result<Value, E> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn visit_f64<E>(self, number: f64) -> std::result::Result<Value, E> {
+        Ok(Value::Number(number))
+    }
+
+    fn visit_str<E>(self, string: &str) -> std::result::Result<Value, E> {
+        Ok(Value::String(string.to_string()))
+    }
+
+    fn visit_string<E>(self, string: String) -> std::result::Result<Value, E> {
+        Ok(Value::String(string))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Value, A::Error> {
+        let mut list = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(element) = seq.next_element()? {
+            list.push(element);
+        }
+        Ok(Value::List(list))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> std::result::Result<Value, A::Error> {
+        let mut map = HashMap::with_capacity(access.size_hint().unwrap_or(0));
+        while let Some((key, value)) = access.next_entry()? {
+            map.insert(key, value);
+        }
+        Ok(Value::Map(map))
+    }
+}
+
+/// Reads a `Value` as serde data, for `from_value`.
+impl<'de> Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self {
+            Value::Nil => visitor.visit_unit(),
+            Value::Bool(bool) => visitor.visit_bool(bool),
+            // Whole numbers go as integers, which integer fields accept.
+            Value::Number(number)
+                if number.fract() == 0.0
+                    && number >= i64::MIN as f64
+                    && number < i64::MAX as f64 =>
+            {
+                visitor.visit_i64(number as i64)
+            }
+            Value::Number(number) => visitor.visit_f64(number),
+            Value::String(string) => visitor.visit_string(string),
+            Value::List(list) => {
+                let mut seq = SeqDeserializer::<_, Error>::new(list.into_iter());
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            Value::Map(map) => {
+                let mut map = MapDeserializer::<_, Error>::new(map.into_iter());
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+            Value::Object(object) => {
+                Err(de::Error::custom(format!("Cannot deserialize {}.", object)))
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self {
+            Value::Nil => visitor.visit_none(),
+            value => visitor.visit_some(value),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self {
+            Value::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+            Value::Map(map) if map.len() == 1 => visitor.visit_enum(MapAccessDeserializer::new(
+                MapDeserializer::new(map.into_iter()),
+            )),
+            value => Err(Error::Conversion {
+                expected: name,
+                found: value.type_name(),
+            }),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+impl IntoDeserializer<'_, Error> for Value {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+/// Builds a `Value` from serde data, for `to_value`.
+struct ValueSerializer;
+
+impl Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = VariantSerializer<ListSerializer>;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = MapSerializer;
+    type SerializeStructVariant = VariantSerializer<MapSerializer>;
+
+    fn serialize_bool(self, bool: bool) -> Result<Value> {
+        Ok(Value::Bool(bool))
+    }
+
+    fn serialize_i8(self, number: i8) -> Result<Value> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn serialize_i16(self, number: i16) -> Result<Value> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn serialize_i32(self, number: i32) -> Result<Value> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn serialize_i64(self, number: i64) -> Result<Value> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn serialize_u8(self, number: u8) -> Result<Value> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn serialize_u16(self, number: u16) -> Result<Value> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn serialize_u32(self, number: u32) -> Result<Value> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn serialize_u64(self, number: u64) -> Result<Value> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn serialize_f32(self, number: f32) -> Result<Value> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn serialize_f64(self, number: f64) -> Result<Value> {
+        Ok(Value::Number(number))
+    }
+
+    fn serialize_char(self, char: char) -> Result<Value> {
+        Ok(Value::String(char.to_string()))
+    }
+
+    fn serialize_str(self, string: &str) -> Result<Value> {
+        Ok(Value::String(string.to_string()))
+    }
+
+    fn serialize_bytes(self, bytes: &[u8]) -> Result<Value> {
+        Ok(Value::List(
+            bytes
+                .iter()
+                .map(|byte| Value::Number(*byte as f64))
+                .collect(),
+        ))
+    }
+
+    fn serialize_none(self) -> Result<Value> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Value> {
+        Ok(Value::String(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value> {
+        Ok(self::variant(variant, value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<ListSerializer> {
+        Ok(ListSerializer(Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ListSerializer> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<ListSerializer> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<VariantSerializer<ListSerializer>> {
+        Ok(VariantSerializer {
+            variant,
+            inner: self.serialize_seq(Some(len))?,
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<MapSerializer> {
+        Ok(MapSerializer {
+            map: HashMap::with_capacity(len.unwrap_or(0)),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<MapSerializer> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<VariantSerializer<MapSerializer>> {
+        Ok(VariantSerializer {
+            variant,
+            inner: self.serialize_map(Some(len))?,
+        })
+    }
+}
+
+struct ListSerializer(Vec<Value>);
+
+impl SerializeSeq for ListSerializer {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.0.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value> {
+        Ok(Value::List(self.0))
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        SerializeSeq::end(self)
+    }
+}
+
+struct MapSerializer {
+    map: HashMap<String, Value>,
+    /// Set between `serialize_key` and `serialize_value`.
+    key: Option<String>,
+}
+
+impl SerializeMap for MapSerializer {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        // Keys that are numbers or bools keep their printed form.
+        self.key = Some(match to_value(key)? {
+            Value::String(key) => key,
+            key @ (Value::Number(_) | Value::Bool(_)) => key.to_string(),
+            key => {
+                return Err(Error::Conversion {
+                    expected: "string key",
+                    found: key.type_name(),
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self.key.take().expect("serialize_key comes first");
+        self.map.insert(key, to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value> {
+        Ok(Value::Map(self.map))
+    }
+}
+
+impl ser::SerializeStruct for MapSerializer {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.map.insert(key.to_string(), to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value> {
+        SerializeMap::end(self)
+    }
+}
+
+/// Wraps what `inner` builds in a one-entry map keyed by the variant.
+struct VariantSerializer<S> {
+    variant: &'static str,
+    inner: S,
+}
+
+/// An enum variant with contents, externally tagged.
+fn variant(name: &str, contents: Value) -> Value {
+    Value::Map(HashMap::from([(name.to_string(), contents)]))
+}
+
+impl ser::SerializeTupleVariant for VariantSerializer<ListSerializer> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        SerializeSeq::serialize_element(&mut self.inner, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        Ok(variant(self.variant, SerializeSeq::end(self.inner)?))
+    }
+}
+
+impl ser::SerializeStructVariant for VariantSerializer<MapSerializer> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        ser::SerializeStruct::serialize_field(&mut self.inner, key, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        Ok(variant(self.variant, SerializeMap::end(self.inner)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{Serde, from_value, to_value};
+    use crate::embed::{Error, Value, Vm};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Method {
+        Get,
+        Post { body: String },
+        Redirect(String, u16),
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Request {
+        path: String,
+        method: Method,
+        retries: u8,
+        timeout: Option<f64>,
+        headers: HashMap<String, String>,
+    }
+
+    fn string(string: &str) -> Value {
+        Value::String(string.to_string())
+    }
+
+    #[test]
+    fn round_trips_rust_data() {
+        let request = Request {
+            path: "/".to_string(),
+            method: Method::Post {
+                body: "{}".to_string(),
+            },
+            retries: 3,
+            timeout: None,
+            headers: HashMap::from([("accept".to_string(), "*/*".to_string())]),
+        };
+        let value = to_value(&request).unwrap();
+        let Value::Map(map) = &value else {
+            panic!("expected a map");
+        };
+        assert_eq!(map["retries"], Value::Number(3.0));
+        assert_eq!(map["timeout"], Value::Nil);
+        assert_eq!(
+            map["method"],
+            Value::Map(HashMap::from([(
+                "Post".to_string(),
+                Value::Map(HashMap::from([("body".to_string(), string("{}"))]))
+            )]))
+        );
+        assert_eq!(from_value::<Request>(value), Ok(request));
+
+        for method in [Method::Get, Method::Redirect("/b".to_string(), 301)] {
+            assert_eq!(from_value(to_value(&method).unwrap()), Ok(method));
+        }
+        assert_eq!(to_value(&Method::Get), Ok(string("Get")));
+        assert!(from_value::<u8>(Value::Number(256.0)).is_err());
+        assert!(from_value::<u8>(Value::Number(1.5)).is_err());
+        assert!(matches!(
+            from_value::<Method>(Value::Number(1.0)),
+            Err(Error::Conversion {
+                expected: "Method",
+                found: "number"
+            })
+        ));
+    }
+
+    #[test]
+    fn values_work_with_other_formats() {
+        let json = r#"{"list":[1,2.5,true,null],"name":"x"}"#;
+        let value: Value = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            value,
+            Value::Map(HashMap::from([
+                (
+                    "list".to_string(),
+                    Value::List(vec![
+                        Value::Number(1.0),
+                        Value::Number(2.5),
+                        Value::Bool(true),
+                        Value::Nil
+                    ])
+                ),
+                ("name".to_string(), string("x")),
+            ]))
+        );
+        assert_eq!(
+            serde_json::to_string(&value).unwrap(),
+            r#"{"list":[1.0,2.5,true,null],"name":"x"}"#
+        );
+    }
+
+    #[test]
+    fn natives_take_and_return_serde_types() {
+        let mut vm = Vm::new();
+        vm.register("route", |request: Serde<Request>| {
+            let method = match request.0.method {
+                Method::Redirect(to, status) => Method::Redirect(to + "/", status + 1),
+                method => method,
+            };
+            to_value(&method)
+        });
+        let request = Request {
+            path: "/a".to_string(),
+            method: Method::Redirect("/b".to_string(), 301),
+            retries: 0,
+            timeout: Some(1.5),
+            headers: HashMap::new(),
+        };
+        vm.set_global("request", to_value(&request).unwrap())
+            .unwrap();
+        vm.eval("var routed = route(request);", "route").unwrap();
+        let routed = from_value::<Method>(vm.get_global("routed").unwrap());
+        assert_eq!(routed, Ok(Method::Redirect("/b/".to_string(), 302)));
+    }
+}
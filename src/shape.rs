@@ -0,0 +1,436 @@
+//! Hidden classes and inline caches for property access.
+//!
+//! Instances do not carry a field table of their own. Each one points at a
+//! `Shape`, which lists its field names in slot order, and keeps only the
+//! values. Adding a field moves the instance along a transition to the shape
+//! with that name appended, so instances that gain the same fields in the
+//! same order end up sharing one shape. Every class starts its own tree from
+//! an empty root, which makes a shape identify the class too: a cache keyed
+//! by shape can remember methods as well as field slots.
+//!
+//! A major collection frees the shapes that no live instance or class
+//! uses, so a class declared in a loop or instances with ever new fields
+//! do not grow the table for good. Freed ids are reused; caches remember
+//! the epoch they were filled in, and ignore their entries once shapes
+//! have been freed since.
+//!
+//! An instance that gains more than `DICTIONARY_FIELDS` fields, such as a
+//! big map or list the host passed in, leaves its tree for dictionary
+//! mode: it keeps its own `Dictionary` of field names, and accesses to it
+//! are never cached. A long chain of shapes would cost a name per field
+//! per shape, and a slot search per miss.
+//!
+//! Every property opcode owns an `InlineCache` in its function's
+//! `ObjFunction::caches`. It remembers what the access resolved to for the
+//! last few shapes it saw, so a hit skips the field-name search and the
+//! method table. Sites that see more shapes than fit give up and go
+//! megamorphic.
+
+use std::{collections::HashMap, mem};
+
+use crate::{
+    gc::Gc,
+    value::{ObjClosure, ObjNative, ObjString},
+};
+
+pub type ShapeId = u32;
+
+/// Shapes a site remembers before it stops caching.
+const CACHE_ENTRIES: usize = 4;
+
+/// Fields an instance can have before it goes to dictionary mode.
+pub const DICTIONARY_FIELDS: usize = 32;
+
+#[derive(Debug, Default)]
+struct Shape {
+    fields: Vec<Gc<ObjString>>,
+    transitions: HashMap<Gc<ObjString>, ShapeId>,
+    /// The shape this one is a transition of; `None` for a root.
+    parent: Option<ShapeId>,
+}
+
+/// Every shape the VM has created, indexed by `ShapeId`.
+#[derive(Debug, Default)]
+pub struct Shapes {
+    /// `None` where a shape was freed.
+    shapes: Vec<Option<Shape>>,
+    free: Vec<ShapeId>,
+    /// Collections that have freed shapes so far.
+    epoch: u32,
+}
+
+impl Shapes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A fresh empty shape, the root of one class's tree.
+    pub fn root(&mut self) -> ShapeId {
+        self.add(Shape::default())
+    }
+
+    fn add(&mut self, shape: Shape) -> ShapeId {
+        match self.free.pop() {
+            Some(id) => {
+                self.shapes[id as usize] = Some(shape);
+                id
+            }
+            None => {
+                self.shapes.push(Some(shape));
+                (self.shapes.len() - 1) as ShapeId
+            }
+        }
+    }
+
+    fn get(&self, shape: ShapeId) -> &Shape {
+        self.shapes[shape as usize]
+            .as_ref()
+            .expect("use of a freed shape")
+    }
+
+    /// Slot of the field `name` in instances of `shape`.
+    pub fn slot(&self, shape: ShapeId, name: Gc<ObjString>) -> Option<usize> {
+        self.get(shape)
+            .fields
+            .iter()
+            .position(|field| *field == name)
+    }
+
+    /// Field names of instances of `shape`, in slot order.
+    pub fn fields(&self, shape: ShapeId) -> &[Gc<ObjString>] {
+        &self.get(shape).fields
+    }
+
+    /// The shape an instance of `shape` moves to when it gains the field
+    /// `name`, which goes in the next slot.
+    pub fn transition(&mut self, shape: ShapeId, name: Gc<ObjString>) -> ShapeId {
+        if let Some(next) = self.get(shape).transitions.get(&name) {
+            return *next;
+        }
+        let mut fields = self.get(shape).fields.clone();
+        fields.push(name);
+        let next = self.add(Shape {
+            fields,
+            transitions: HashMap::new(),
+            parent: Some(shape),
+        });
+        let parent = self.shapes[shape as usize].as_mut().unwrap();
+        parent.transitions.insert(name, next);
+        next
+    }
+
+    /// The epoch caches must have been filled in to be trusted.
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Frees every shape that is neither `live` nor on the way to one, and
+    /// returns the names the rest are keyed by. The tables compare names by
+    /// identity, so the collector must keep those strings.
+    pub fn collect(&mut self, live: impl IntoIterator<Item = ShapeId>) -> Vec<Gc<ObjString>> {
+        let mut kept = vec![false; self.shapes.len()];
+        for shape in live {
+            let mut shape = Some(shape);
+            while let Some(id) = shape.filter(|id| !kept[*id as usize]) {
+                kept[id as usize] = true;
+                shape = self.get(id).parent;
+            }
+        }
+        let mut names = vec![];
+        let mut freed = false;
+        for id in 0..self.shapes.len() {
+            let Some(shape) = &self.shapes[id] else {
+                continue;
+            };
+            let name = shape.fields.last().copied();
+            if kept[id] {
+                names.extend(name);
+                continue;
+            }
+            if let Some(parent) = shape.parent.filter(|parent| kept[*parent as usize]) {
+                let parent = self.shapes[parent as usize].as_mut().unwrap();
+                parent.transitions.remove(&name.unwrap());
+            }
+            self.shapes[id] = None;
+            self.free.push(id as ShapeId);
+            freed = true;
+        }
+        if freed {
+            self.epoch += 1;
+        }
+        names
+    }
+
+    /// Shapes not freed, for tests.
+    #[cfg(test)]
+    pub fn count(&self) -> usize {
+        self.shapes.len() - self.free.len()
+    }
+}
+
+/// The field names of an instance in dictionary mode, in slot order.
+#[derive(Debug, Default)]
+pub struct Dictionary {
+    names: Vec<Gc<ObjString>>,
+    slots: HashMap<Gc<ObjString>, usize>,
+}
+
+impl Dictionary {
+    pub fn new(names: &[Gc<ObjString>]) -> Self {
+        let mut dictionary = Self::default();
+        for name in names {
+            dictionary.insert(*name);
+        }
+        dictionary
+    }
+
+    pub fn slot(&self, name: Gc<ObjString>) -> Option<usize> {
+        self.slots.get(&name).copied()
+    }
+
+    /// Adds the field `name` in the next slot.
+    pub fn insert(&mut self, name: Gc<ObjString>) {
+        self.slots.insert(name, self.names.len());
+        self.names.push(name);
+    }
+
+    pub fn names(&self) -> &[Gc<ObjString>] {
+        &self.names
+    }
+
+    /// Bytes the dictionary holds on the side, for the collector's books.
+    pub fn size(&self) -> usize {
+        let entry = mem::size_of::<(Gc<ObjString>, usize)>();
+        mem::size_of::<Self>()
+            + self.names.capacity() * mem::size_of::<Gc<ObjString>>()
+            + self.slots.capacity() * entry
+    }
+}
+
+/// What a property access resolved to for one shape.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheTarget {
+    /// Read or overwrite the field in this slot.
+    Field(usize),
+    /// Store into a new last slot and move the instance to this shape.
+    Transition(ShapeId),
+    /// Store into a new last slot of an instance in, or going to,
+    /// dictionary mode. Never cached.
+    Insert,
+    /// The class method of that name.
+    Method(Gc<ObjClosure>),
+    /// The method of that name of a host class.
+    Native(Gc<ObjNative>),
+    /// A getter or setter of a host class, looked up on each access.
+    Accessor,
+}
+
+/// A per-site cache: monomorphic with one entry, polymorphic up to
+/// `CACHE_ENTRIES`, then megamorphic.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InlineCache {
+    entries: [Option<(ShapeId, CacheTarget)>; CACHE_ENTRIES],
+    megamorphic: bool,
+    /// `Shapes::epoch` when the entries were filled in.
+    epoch: u32,
+}
+
+impl InlineCache {
+    /// What `shape` resolved to, if it was remembered in `epoch`.
+    pub fn lookup(&self, shape: ShapeId, epoch: u32) -> Option<CacheTarget> {
+        if self.epoch != epoch {
+            return None;
+        }
+        self.entries
+            .iter()
+            .map_while(|entry| *entry)
+            .find(|(cached, _)| *cached == shape)
+            .map(|(_, target)| target)
+    }
+
+    /// Remembers `target` for `shape` in `epoch`, forgetting the entries
+    /// of an earlier one, or goes megamorphic when full.
+    pub fn insert(&mut self, shape: ShapeId, target: CacheTarget, epoch: u32) {
+        if self.epoch != epoch {
+            *self = Self {
+                epoch,
+                ..Self::default()
+            };
+        }
+        if self.megamorphic {
+            return;
+        }
+        match self.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => *entry = Some((shape, target)),
+            None => {
+                self.entries = Default::default();
+                self.megamorphic = true;
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_megamorphic(&self) -> bool {
+        self.megamorphic
+    }
+}
+
+/// The kind of property opcode a cache serves.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Get,
+    Set,
+    Invoke,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheCounter {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheCounter {
+    /// Fraction of lookups answered by the cache; 0 before the first one.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// Inline cache hits and misses by opcode, returned by `VM::cache_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub get: CacheCounter,
+    pub set: CacheCounter,
+    pub invoke: CacheCounter,
+}
+
+impl CacheStats {
+    pub fn counter(&mut self, access: Access) -> &mut CacheCounter {
+        match access {
+            Access::Get => &mut self.get,
+            Access::Set => &mut self.set,
+            Access::Invoke => &mut self.invoke,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheTarget, InlineCache, Shapes};
+    use crate::{
+        compiler::{Backend, CompilerOptions},
+        gc::Heap,
+        vm::{InterpretResult, VM},
+    };
+
+    #[test]
+    fn instances_with_the_same_fields_share_a_shape() {
+        let mut heap = Heap::new();
+        let (x, y) = (heap.intern("x".to_string()), heap.intern("y".to_string()));
+        let mut shapes = Shapes::new();
+        let root = shapes.root();
+        let with_x = shapes.transition(root, x);
+        let xy = shapes.transition(with_x, y);
+        assert_eq!(shapes.transition(root, x), with_x);
+        assert_eq!(shapes.transition(with_x, y), xy);
+        assert_eq!(shapes.slot(xy, x), Some(0));
+        assert_eq!(shapes.slot(xy, y), Some(1));
+
+        // Another order is another layout.
+        let with_y = shapes.transition(root, y);
+        let yx = shapes.transition(with_y, x);
+        assert_ne!(yx, xy);
+        assert_eq!(shapes.slot(yx, x), Some(1));
+
+        // Another class never shares the first one's shapes.
+        let other = shapes.root();
+        assert_ne!(shapes.transition(other, x), with_x);
+    }
+
+    #[test]
+    fn caches_go_megamorphic_past_their_entries() {
+        let mut cache = InlineCache::default();
+        assert_eq!(cache.lookup(0, 0), None);
+        for shape in 0..4 {
+            cache.insert(shape, CacheTarget::Field(shape as usize), 0);
+        }
+        assert_eq!(cache.lookup(0, 0), Some(CacheTarget::Field(0)));
+        assert_eq!(cache.lookup(3, 0), Some(CacheTarget::Field(3)));
+        assert!(!cache.is_megamorphic());
+
+        cache.insert(4, CacheTarget::Field(4), 0);
+        assert!(cache.is_megamorphic());
+        assert_eq!(cache.lookup(0, 0), None);
+        cache.insert(0, CacheTarget::Field(0), 0);
+        assert_eq!(cache.lookup(0, 0), None);
+
+        // Once shapes have been freed the cache starts over.
+        cache.insert(0, CacheTarget::Field(1), 1);
+        assert!(!cache.is_megamorphic());
+        assert_eq!(cache.lookup(0, 1), Some(CacheTarget::Field(1)));
+        assert_eq!(cache.lookup(0, 0), None);
+    }
+
+    #[test]
+    fn collecting_frees_shapes_nothing_uses() {
+        let mut heap = Heap::new();
+        let (x, y) = (heap.intern("x".to_string()), heap.intern("y".to_string()));
+        let mut shapes = Shapes::new();
+        let root = shapes.root();
+        let with_x = shapes.transition(root, x);
+        let xy = shapes.transition(with_x, y);
+        shapes.transition(root, y);
+        let other = shapes.root();
+        shapes.transition(other, x);
+
+        // `xy` keeps the shapes on its way; the other tree goes entirely.
+        assert_eq!(shapes.collect([xy]), [x, y]);
+        assert_eq!(shapes.count(), 3);
+        assert_eq!(shapes.epoch(), 1);
+        assert_eq!(shapes.transition(root, x), with_x);
+        assert_eq!(shapes.slot(xy, y), Some(1));
+        // Freed ids are reused, the last one first.
+        let new_y = shapes.transition(root, y);
+        assert_eq!(shapes.fields(new_y), [y]);
+        assert_eq!(new_y, 5);
+
+        // Nothing freed, nothing invalidated.
+        shapes.collect([xy, new_y]);
+        assert_eq!(shapes.epoch(), 1);
+    }
+
+    #[test]
+    fn monomorphic_sites_hit_after_the_first_access() {
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut vm = VM::new();
+            vm.options = CompilerOptions {
+                backend,
+                ..CompilerOptions::default()
+            };
+            let source = "
+                class P {
+                    init(x) { this.x = x; }
+                    get() { return this.x; }
+                }
+                var sum = 0;
+                for (var i = 0; i < 100; i = i + 1) sum = sum + P(i).get();
+            ";
+            assert!(matches!(
+                vm.interpret(source.to_string(), "test"),
+                InterpretResult::INTERPRET_OK
+            ));
+            let stats = vm.cache_stats();
+            // One miss per site: `this.x =`, `this.x` and `.get()`.
+            assert_eq!(stats.set.misses, 1);
+            assert_eq!(stats.get.misses, 1);
+            assert_eq!(stats.invoke.misses, 1);
+            assert_eq!(stats.get.hits, 99);
+            assert!(stats.invoke.hit_rate() > 0.98);
+        }
+    }
+}
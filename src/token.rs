@@ -1,4 +1,4 @@
-use std::rc::Rc;
+use std::{ops::Range, rc::Rc};
 
 #[derive(Debug, PartialEq, Clone, Default)]
 pub enum Kind {
@@ -53,6 +53,7 @@ pub enum Kind {
 pub struct Token {
     pub kind: Kind,
     pub line: usize,
-    pub index_in_source: usize,
+    /// Byte range of the lexeme in the source.
+    pub span: Range<usize>,
     pub string: Rc<String>,
 }
@@ -0,0 +1,336 @@
+//! Rust types exposed to scripts as classes.
+//!
+//! A `UserClass<T>` describes a class whose instances each own a `T`: how
+//! a script constructs one, and the methods, getters and setters it has,
+//! all implemented in Rust. `Vm::register_class` defines it as a global.
+//! The instance keeps its `T` as a `Box<dyn Any>`, dropped when the
+//! instance is collected, or with the `Vm`. A `T` that holds script values
+//! does so through `Object`s. Those keep their objects alive like any other
+//! host handle, unless the class has a trace hook reporting them: the
+//! collector then reaches them through the instance, so an object that
+//! points back at the instance holding it can be freed with it.
+
+use std::{any::Any, marker::PhantomData, rc::Rc};
+
+use crate::{
+    convert::{Constructor, FromValue, IntoValue, NativeMethod},
+    embed::{Object, Pin, Result, Value},
+    value::{self, Getter, NativeFn, ObjNative, Setter, Trace},
+};
+
+/// The description of a host class, built up before registering it.
+pub struct UserClass<T> {
+    pub(crate) name: String,
+    pub(crate) constructor: Option<(usize, value::Constructor)>,
+    pub(crate) methods: Vec<ObjNative>,
+    pub(crate) getters: Vec<(String, Getter)>,
+    pub(crate) setters: Vec<(String, Setter)>,
+    pub(crate) trace: Option<Trace>,
+    marker: PhantomData<fn() -> T>,
+}
+
+/// What a trace hook reports the objects its `T` holds to.
+pub struct Tracer {
+    vm: u64,
+    /// Each reported object of the collecting VM, by its pin's address.
+    pub(crate) objects: Vec<(*const Pin, value::Value)>,
+}
+
+impl Tracer {
+    pub(crate) fn new(vm: u64) -> Self {
+        Self {
+            vm,
+            objects: vec![],
+        }
+    }
+
+    /// Reports an object the `T` holds. Objects of another `Vm` are not
+    /// this collector's business.
+    pub fn object(&mut self, object: &Object) {
+        if object.pin.vm == self.vm {
+            let pin = Rc::as_ptr(&object.pin);
+            self.objects.push((pin, object.pin.value.clone()));
+        }
+    }
+
+    /// Reports every object in `value`, including inside lists and maps.
+    pub fn value(&mut self, value: &Value) {
+        match value {
+            Value::Object(object) => self.object(object),
+            Value::List(list) => list.iter().for_each(|value| self.value(value)),
+            Value::Map(map) => map.values().for_each(|value| self.value(value)),
+            _ => {}
+        }
+    }
+}
+
+/// The `T` of an instance of `T`'s class; the VM never hands a member
+/// anything else.
+fn data<T: 'static>(data: &dyn Any) -> &T {
+    data.downcast_ref()
+        .expect("host members only see their own class")
+}
+
+fn data_mut<T: 'static>(data: &mut dyn Any) -> &mut T {
+    data.downcast_mut()
+        .expect("host members only see their own class")
+}
+
+impl<T: 'static> UserClass<T> {
+    /// A class without members, which scripts cannot construct: its
+    /// instances come from `Vm::new_userdata`.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            constructor: None,
+            methods: vec![],
+            getters: vec![],
+            setters: vec![],
+            trace: None,
+            marker: PhantomData,
+        }
+    }
+
+    /// Lets scripts call the class, with arguments as for a native, to
+    /// create an instance around the `T` that `constructor` returns.
+    pub fn constructor<Args, F: Constructor<T, Args>>(mut self, constructor: F) -> Self {
+        let construct = move |args| Ok(Box::new(constructor.construct(args)?) as Box<dyn Any>);
+        self.constructor = Some((F::ARITY, Rc::new(construct)));
+        self
+    }
+
+    /// Adds the method `name`, which gets the instance's `T` before the
+    /// script's arguments.
+    pub fn method<Args, F: NativeMethod<T, Args>>(mut self, name: &str, method: F) -> Self {
+        let function = move |receiver: &mut dyn Any, args| method.invoke(data_mut(receiver), args);
+        self.methods.push(ObjNative {
+            name: name.to_string(),
+            arity: F::ARITY,
+            function: NativeFn::Method(Rc::new(function)),
+        });
+        self
+    }
+
+    /// Makes reading the property `name` call `getter`.
+    pub fn getter<R: IntoValue>(
+        mut self,
+        name: &str,
+        getter: impl Fn(&T) -> Result<R> + 'static,
+    ) -> Self {
+        let getter = move |receiver: &dyn Any| getter(data(receiver)).map(R::into_value);
+        self.getters.push((name.to_string(), Rc::new(getter)));
+        self
+    }
+
+    /// Makes assigning the property `name` call `setter`. Properties
+    /// without one cannot be assigned.
+    pub fn setter<V: FromValue>(
+        mut self,
+        name: &str,
+        setter: impl Fn(&mut T, V) -> Result<()> + 'static,
+    ) -> Self {
+        let setter =
+            move |receiver: &mut dyn Any, value| setter(data_mut(receiver), V::from_value(value)?);
+        self.setters.push((name.to_string(), Rc::new(setter)));
+        self
+    }
+
+    /// Makes the collector look inside the `T`: `trace` must report every
+    /// `Object` it holds to the `Tracer`. Objects it leaves out stay alive
+    /// for as long as the `T` holds them, as without a hook.
+    pub fn trace(mut self, trace: impl Fn(&T, &mut Tracer) + 'static) -> Self {
+        let trace = move |data: &dyn Any, tracer: &mut Tracer| trace(self::data(data), tracer);
+        self.trace = Some(Rc::new(trace));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::UserClass;
+    use crate::{
+        compiler::{Backend, CompilerOptions},
+        embed::{Error, Value, Vm},
+        gc::GcOptions,
+    };
+
+    struct Counter {
+        count: f64,
+        step: f64,
+    }
+
+    fn counter_class() -> UserClass<Counter> {
+        UserClass::new("Counter")
+            .constructor(|step: f64| Ok(Counter { count: 0.0, step }))
+            .method("bump", |counter: &mut Counter| {
+                counter.count += counter.step;
+                Ok(counter.count)
+            })
+            .method("add", |counter: &mut Counter, amount: f64| {
+                counter.count += amount;
+                Ok(())
+            })
+            .getter("count", |counter: &Counter| Ok(counter.count))
+            .getter("step", |counter: &Counter| Ok(counter.step))
+            .setter("step", |counter: &mut Counter, step: f64| {
+                if step <= 0.0 {
+                    return Err(Error::Host("Step must be positive.".to_string()));
+                }
+                counter.step = step;
+                Ok(())
+            })
+    }
+
+    #[test]
+    fn scripts_use_rust_members() {
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut vm = Vm::new();
+            vm.set_compiler_options(CompilerOptions {
+                backend,
+                ..CompilerOptions::default()
+            });
+            vm.register_class(counter_class());
+            vm.eval(
+                "var c = Counter(2);
+                 c.bump(); c.add(3); c.step = 10;
+                 var bump = c.bump;
+                 var total = bump() + c.count;
+                 fun tail(c) { return c.bump(); }",
+                "counter",
+            )
+            .unwrap();
+            assert_eq!(vm.get_global("total"), Some(Value::Number(30.0)));
+            let Some(Value::Object(counter)) = vm.get_global("c") else {
+                panic!("expected an object");
+            };
+            assert_eq!(counter.to_string(), "Counter instance");
+            vm.userdata_mut::<Counter>(&counter).unwrap().step = 1.0;
+            assert_eq!(vm.call("tail", (counter,)), Ok(Value::Number(16.0)));
+
+            let failures = [
+                ("c.step = -1;", "Step must be positive."),
+                ("c.count = 1;", "Undefined property 'count'."),
+                ("c.missing;", "Undefined property 'missing'."),
+                ("c.add(\"x\");", "Expected number but got string."),
+                ("Counter();", "Expected 1 arguments but got 0."),
+            ];
+            for (source, expected) in failures {
+                assert!(matches!(
+                    vm.eval(source, "fail"),
+                    Err(Error::Runtime { message, .. }) if message == expected
+                ));
+            }
+        }
+    }
+
+    struct Handle(Rc<Cell<u32>>);
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn data_is_dropped_with_its_instance() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vm = Vm::new();
+        vm.register_class(UserClass::<Handle>::new("Handle"));
+        assert_eq!(vm.new_userdata(0), Err(Error::UnregisteredClass("i32")));
+        assert!(matches!(
+            vm.eval("Handle();", "construct"),
+            Err(Error::Runtime { message, .. })
+                if message == "Handle instances can only be created by the host."
+        ));
+
+        let kept = vm.new_userdata(Handle(drops.clone())).unwrap();
+        vm.set_global("kept", kept).unwrap();
+        let held = vm.new_userdata(Handle(drops.clone())).unwrap();
+        vm.new_userdata(Handle(drops.clone())).unwrap();
+        vm.collect_garbage();
+        assert_eq!(drops.get(), 1);
+
+        drop(held);
+        vm.collect_garbage();
+        assert_eq!(drops.get(), 2);
+        drop(vm);
+        assert_eq!(drops.get(), 3);
+    }
+
+    /// Holds a script value, which may point back at its own instance.
+    struct Link {
+        other: Option<Value>,
+        _handle: Handle,
+    }
+
+    #[test]
+    fn traced_cycles_through_userdata_are_freed() {
+        for (backend, stress) in [(Backend::Stack, false), (Backend::Register, true)] {
+            let drops = Rc::new(Cell::new(0));
+            let counter = drops.clone();
+            let mut vm = Vm::new();
+            vm.set_compiler_options(CompilerOptions {
+                backend,
+                ..CompilerOptions::default()
+            });
+            vm.set_gc_options(GcOptions {
+                stress,
+                ..GcOptions::default()
+            });
+            vm.register_class(
+                UserClass::new("Link")
+                    .constructor(move || {
+                        Ok(Link {
+                            other: None,
+                            _handle: Handle(counter.clone()),
+                        })
+                    })
+                    .getter("other", |link: &Link| Ok(link.other.clone()))
+                    .setter("other", |link: &mut Link, other: Value| {
+                        link.other = Some(other);
+                        Ok(())
+                    })
+                    .trace(|link, tracer| {
+                        if let Some(other) = &link.other {
+                            tracer.value(other);
+                        }
+                    }),
+            );
+            vm.eval(
+                "class Box {}
+                 fun cycle() {
+                   var link = Link();
+                   var box = Box();
+                   box.link = link;
+                   link.other = box;
+                   return link;
+                 }
+                 for (var i = 0; i < 10; i = i + 1) cycle();
+                 var kept = cycle();",
+                "cycles",
+            )
+            .unwrap();
+            vm.collect_garbage();
+            assert_eq!(drops.get(), 10);
+
+            // The kept cycle is whole; taking its object out of the `T`
+            // keeps it alive once the instance is gone.
+            vm.eval("var same = kept.other.link == kept;", "kept")
+                .unwrap();
+            assert_eq!(vm.get_global("same"), Some(Value::Bool(true)));
+            let Some(Value::Object(kept)) = vm.get_global("kept") else {
+                panic!("expected an object");
+            };
+            let other = vm.userdata_mut::<Link>(&kept).unwrap().other.clone();
+            drop(kept);
+            vm.eval("kept = nil;", "drop").unwrap();
+            vm.collect_garbage();
+            assert_eq!(drops.get(), 10);
+            drop(other);
+            vm.collect_garbage();
+            assert_eq!(drops.get(), 11);
+        }
+    }
+}
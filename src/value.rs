@@ -1,15 +1,238 @@
 use std::{
-    ops::{Add, Mul, Neg, Sub},
+    any::Any,
+    cell::Cell,
+    collections::HashMap,
+    fmt,
+    ops::{Add, Div, Mul, Neg, Sub},
     panic,
     rc::Rc,
+    sync::Arc,
 };
 
-#[derive(Debug, Clone, PartialEq)]
+use crate::{
+    chunk::Chunk,
+    embed,
+    gc::Gc,
+    register::RegisterCode,
+    shape::{Dictionary, InlineCache, ShapeId},
+    userdata::Tracer,
+};
+
+#[cfg(feature = "nan-boxing")]
+mod nanbox;
+
+#[derive(Debug, Clone)]
+#[allow(non_camel_case_types)]
 pub enum ValueType {
     VAL_BOOL(bool),
     VAL_NIL,
     VAL_NUMBER(f64),
-    VAL_STRING(Rc<String>),
+    VAL_STRING(Gc<ObjString>),
+    VAL_FUNCTION(Rc<ObjFunction>),
+    VAL_CLOSURE(Gc<ObjClosure>),
+    VAL_CLASS(Gc<ObjClass>),
+    VAL_INSTANCE(Gc<ObjInstance>),
+    VAL_BOUND_METHOD(Gc<ObjBoundMethod>),
+    VAL_NATIVE(Gc<ObjNative>),
+}
+
+/// Handles compare by identity. Strings are interned, so this is also the
+/// language's `==`.
+impl PartialEq for ValueType {
+    fn eq(&self, other: &Self) -> bool {
+        use ValueType::*;
+        match (self, other) {
+            (VAL_BOOL(a), VAL_BOOL(b)) => a == b,
+            (VAL_NIL, VAL_NIL) => true,
+            (VAL_NUMBER(a), VAL_NUMBER(b)) => a == b,
+            (VAL_STRING(a), VAL_STRING(b)) => a == b,
+            (VAL_FUNCTION(a), VAL_FUNCTION(b)) => Rc::ptr_eq(a, b),
+            (VAL_CLOSURE(a), VAL_CLOSURE(b)) => a == b,
+            (VAL_CLASS(a), VAL_CLASS(b)) => a == b,
+            (VAL_INSTANCE(a), VAL_INSTANCE(b)) => a == b,
+            (VAL_BOUND_METHOD(a), VAL_BOUND_METHOD(b)) => a == b,
+            (VAL_NATIVE(a), VAL_NATIVE(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    /// `None` for the top-level script.
+    pub name: Option<Rc<String>>,
+    /// Name of the script the function was compiled from, for debuggers.
+    pub script: Rc<str>,
+    /// Body for the register backend; empty when compiled to `chunk.code`.
+    /// Shared like `chunk.code`.
+    pub registers: Arc<RegisterCode>,
+    /// One inline cache per property opcode, indexed by its cache operand.
+    pub caches: Vec<Cell<InlineCache>>,
+    /// Names of the locals, for debuggers.
+    pub locals: Arc<[LocalName]>,
+    /// Names of the upvalues, in index order, for debuggers.
+    pub upvalue_names: Arc<[String]>,
+    /// Calls so far, counted toward `JitOptions::threshold`.
+    #[cfg(feature = "jit")]
+    pub calls: Cell<u32>,
+    /// Set once the function turns hot; `None` inside when it could not be
+    /// compiled.
+    #[cfg(feature = "jit")]
+    pub native: std::cell::OnceCell<Option<crate::jit::NativeCode>>,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<Rc<String>>, arity: usize) -> Self {
+        Self {
+            arity,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name,
+            script: Rc::from(""),
+            registers: Arc::default(),
+            caches: vec![],
+            locals: Arc::default(),
+            upvalue_names: Arc::default(),
+            #[cfg(feature = "jit")]
+            calls: Cell::new(0),
+            #[cfg(feature = "jit")]
+            native: std::cell::OnceCell::new(),
+        }
+    }
+
+    /// Stack slots a call fills, from the callee's slot up, with either
+    /// backend.
+    pub fn frame_size(&self) -> usize {
+        if self.registers.is_empty() {
+            self.chunk.max_stack
+        } else {
+            self.registers.max_registers
+        }
+    }
+}
+
+/// A local variable as a debugger sees it: the stack slot, or register,
+/// it lives in on the lines its scope covers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalName {
+    pub name: String,
+    pub slot: u8,
+    pub first_line: usize,
+    pub last_line: usize,
+}
+
+impl fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjString {
+    pub chars: String,
+    pub hash: u32,
+}
+
+/// A captured variable. It points at its stack slot while the declaring
+/// frame is live and takes ownership of the value once that slot goes away.
+#[derive(Debug)]
+pub enum ObjUpvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct ObjClosure {
+    pub function: Rc<ObjFunction>,
+    pub upvalues: Vec<Gc<ObjUpvalue>>,
+}
+
+#[derive(Debug)]
+pub struct ObjClass {
+    pub name: Gc<ObjString>,
+    pub methods: HashMap<Gc<ObjString>, Gc<ObjClosure>>,
+    /// The `init` method, looked up once when the class is built.
+    pub initializer: Option<Gc<ObjClosure>>,
+    /// Empty root shape shared by the class's new instances.
+    pub shape: ShapeId,
+    /// Members implemented in Rust, for a class the host registered.
+    pub host: Option<Rc<HostClass>>,
+}
+
+/// Field values in the slot order of `shape`, or of `dictionary` in
+/// dictionary mode.
+#[derive(Debug)]
+pub struct ObjInstance {
+    pub class: Gc<ObjClass>,
+    /// The class's root shape in dictionary mode.
+    pub shape: ShapeId,
+    pub fields: Vec<Value>,
+    pub dictionary: Option<Box<Dictionary>>,
+    /// The Rust value behind an instance of a host class. Dropped when the
+    /// instance is collected.
+    pub host: Option<Box<dyn Any>>,
+}
+
+/// A method read off an instance, remembering its receiver. The method is
+/// a closure, or a native for a host class.
+#[derive(Debug)]
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: Value,
+}
+/// A Rust function registered by the host. It sees its arguments as host
+/// values, already checked against `arity`.
+pub struct ObjNative {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+#[derive(Clone)]
+pub enum NativeFn {
+    /// A free function, also handed the `Vm` so it can call back into
+    /// scripts.
+    Function(Function),
+    /// A method of a host class, also handed the receiver's Rust value.
+    Method(Method),
+}
+
+/// The Rust side of a class registered through `userdata::UserClass`.
+pub struct HostClass {
+    pub constructor: Option<(usize, Constructor)>,
+    pub methods: HashMap<Gc<ObjString>, Gc<ObjNative>>,
+    pub getters: HashMap<Gc<ObjString>, Getter>,
+    pub setters: HashMap<Gc<ObjString>, Setter>,
+    pub trace: Option<Trace>,
+    /// The VM the class was registered with, whose objects `trace` reports.
+    pub vm: u64,
+}
+
+pub type Function = Rc<dyn Fn(&mut embed::Vm, Vec<embed::Value>) -> embed::Result<embed::Value>>;
+pub type Method = Rc<dyn Fn(&mut dyn Any, Vec<embed::Value>) -> embed::Result<embed::Value>>;
+pub type Constructor = Rc<dyn Fn(Vec<embed::Value>) -> embed::Result<Box<dyn Any>>>;
+pub type Getter = Rc<dyn Fn(&dyn Any) -> embed::Result<embed::Value>>;
+pub type Setter = Rc<dyn Fn(&mut dyn Any, embed::Value) -> embed::Result<()>>;
+pub type Trace = Rc<dyn Fn(&dyn Any, &mut Tracer)>;
+
+impl fmt::Debug for HostClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HostClass")
+            .field("methods", &self.methods)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for ObjNative {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
 }
 /*
 impl Clone for Value{
@@ -21,11 +244,19 @@ impl Clone for Value{
        }
     }
 }*/
-#[derive(Debug, Clone)]
+/// A Lox value: an enum by default, or a NaN-boxed word with the
+/// `nan-boxing` feature. Both expose the same API; `type_v` unpacks either
+/// into a `ValueType` for matching.
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Value {
-    pub type_v: ValueType,
+    type_v: ValueType,
 }
 
+#[cfg(feature = "nan-boxing")]
+pub use nanbox::Value;
+
+#[cfg(not(feature = "nan-boxing"))]
 impl From<f64> for Value {
     fn from(value: f64) -> Self {
         Self {
@@ -34,24 +265,17 @@ impl From<f64> for Value {
     }
 }
 
+#[cfg(not(feature = "nan-boxing"))]
 impl From<ValueType> for Value {
     fn from(value: ValueType) -> Self {
         Self { type_v: value }
     }
 }
 
+#[cfg(not(feature = "nan-boxing"))]
 impl Value {
-    pub fn bool_value(value: bool) -> Self {
-        Self::from(ValueType::VAL_BOOL(value))
-    }
-    pub fn nil_value() -> Self {
-        Self::from(ValueType::VAL_NIL)
-    }
-    pub fn number_value(value: f64) -> Self {
-        Self::from(ValueType::VAL_NUMBER(value))
-    }
-    pub fn obj_value(str: &str) -> Self {
-        Self::from(ValueType::VAL_STRING(Rc::new(str.to_string())))
+    pub fn type_v(&self) -> ValueType {
+        self.type_v.clone()
     }
 
     pub fn as_bool(&self) -> bool {
@@ -70,17 +294,17 @@ impl Value {
         }
     }
 
-    pub fn as_obj(&self) -> Rc<String> {
-        if let ValueType::VAL_STRING(str) = self.type_v.clone() {
-            str.clone()
+    pub fn as_string(&self) -> Gc<ObjString> {
+        if let ValueType::VAL_STRING(string) = self.type_v {
+            string
         } else {
-            panic!("Tried to extract object from non obj value");
+            panic!("Tried to extract string from non-string value");
         }
     }
     pub fn is_bool(&self) -> bool {
         matches!(self.type_v, ValueType::VAL_BOOL(_))
     }
-    pub fn is_obj(&self) -> bool {
+    pub fn is_string(&self) -> bool {
         matches!(self.type_v, ValueType::VAL_STRING(_))
     }
 
@@ -93,6 +317,18 @@ impl Value {
     }
 }
 
+impl Value {
+    pub fn bool_value(value: bool) -> Self {
+        Self::from(ValueType::VAL_BOOL(value))
+    }
+    pub fn nil_value() -> Self {
+        Self::from(ValueType::VAL_NIL)
+    }
+    pub fn number_value(value: f64) -> Self {
+        Self::from(value)
+    }
+}
+
 #[derive(Debug)]
 pub struct ValueArray {
     pub values: Vec<Value>,
@@ -107,52 +343,28 @@ impl ValueArray {
 impl Neg for Value {
     type Output = Self;
     fn neg(self) -> Self::Output {
-        match self.type_v {
-            ValueType::VAL_NUMBER(n) => Value::from(-n),
-            a => panic!("-{:?} is not valid", a),
-        }
-    }
-}
-
-impl std::ops::Div for Value {
-    type Output = Self;
-    fn div(self, rhs: Self) -> Self::Output {
-        match (self.type_v, rhs.type_v) {
-            (ValueType::VAL_NUMBER(n), ValueType::VAL_NUMBER(n2)) => Value::from(n / n2),
-            a => panic!("{:?}/{:?} is not valid", a.0, a.1),
-        }
-    }
-}
-
-impl Add for Value {
-    type Output = Self;
-    fn add(self, rhs: Self) -> Self::Output {
-        match (self.type_v, rhs.type_v) {
-            (ValueType::VAL_NUMBER(n), ValueType::VAL_NUMBER(n2)) => Value::from(n + n2),
-            (ValueType::VAL_STRING(a), ValueType::VAL_STRING(b)) => Value::from(
-                ValueType::VAL_STRING(Rc::new(format!("{}{}", a.as_str(), b.as_str()))),
-            ),
-            a => panic!("{:?}+{:?} is not valid", a.0, a.1),
+        if !self.is_number() {
+            panic!("-{:?} is not valid", self);
         }
+        Value::from(-self.as_number())
     }
 }
 
-impl Sub for Value {
-    type Output = Self;
-    fn sub(self, rhs: Self) -> Self::Output {
-        match (self.type_v, rhs.type_v) {
-            (ValueType::VAL_NUMBER(n), ValueType::VAL_NUMBER(n2)) => Value::from(n - n2),
-            a => panic!("{:?}-{:?} is not valid", a.0, a.1),
+macro_rules! number_op {
+    ($trait:ident, $method:ident, $op:tt) => {
+        impl $trait for Value {
+            type Output = Self;
+            fn $method(self, rhs: Self) -> Self::Output {
+                if !self.is_number() || !rhs.is_number() {
+                    panic!("{:?}{}{:?} is not valid", self, stringify!($op), rhs);
+                }
+                Value::from(self.as_number() $op rhs.as_number())
+            }
         }
-    }
+    };
 }
 
-impl Mul for Value {
-    type Output = Self;
-    fn mul(self, rhs: Self) -> Self::Output {
-        match (self.type_v, rhs.type_v) {
-            (ValueType::VAL_NUMBER(n), ValueType::VAL_NUMBER(n2)) => Value::from(n * n2),
-            a => panic!("{:?}*{:?} is not valid", a.0, a.1),
-        }
-    }
-}
+number_op!(Div, div, /);
+number_op!(Add, add, +);
+number_op!(Sub, sub, -);
+number_op!(Mul, mul, *);
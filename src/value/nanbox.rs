@@ -0,0 +1,278 @@
+//! NaN-boxed `Value`: every value fits in one `u64`.
+//!
+//! Numbers are stored as their own bits. Anything else hides in the payload
+//! of a quiet NaN, which no arithmetic produces once NaNs are canonicalized
+//! on the way in:
+//!
+//! ```text
+//! nil, false, true   0 11111111111 11 00..0 | 1, 2, 3
+//! heap object        1 11111111111 11 index << 3 | kind
+//! function           1 11111111111 11 Rc pointer | kind
+//! ```
+//!
+//! Heap objects are `Gc` slot indices. Functions are `Rc` pointers, 8-byte
+//! aligned so the low three bits are free for the kind; `Clone` and `Drop`
+//! keep their reference count right.
+
+use std::{fmt, marker::PhantomData, mem::ManuallyDrop, rc::Rc};
+
+use super::{ObjFunction, ValueType};
+use crate::gc::Gc;
+
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const OBJ: u64 = SIGN_BIT | QNAN;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+const KIND_MASK: u64 = 0b111;
+const KIND_STRING: u64 = 1;
+const KIND_CLOSURE: u64 = 2;
+const KIND_FUNCTION: u64 = 3;
+const KIND_CLASS: u64 = 4;
+const KIND_INSTANCE: u64 = 5;
+const KIND_BOUND_METHOD: u64 = 6;
+const KIND_NATIVE: u64 = 7;
+
+const NIL: u64 = QNAN | TAG_NIL;
+const FALSE: u64 = QNAN | TAG_FALSE;
+const TRUE: u64 = QNAN | TAG_TRUE;
+
+#[repr(transparent)]
+pub struct Value {
+    bits: u64,
+    /// May own an `Rc`, so stays `!Send` like the enum representation.
+    marker: PhantomData<Rc<ObjFunction>>,
+}
+
+impl Value {
+    fn from_bits(bits: u64) -> Self {
+        Self {
+            bits,
+            marker: PhantomData,
+        }
+    }
+
+    fn is_obj(&self) -> bool {
+        self.bits & OBJ == OBJ
+    }
+
+    fn kind(&self) -> u64 {
+        if self.is_obj() {
+            self.bits & KIND_MASK
+        } else {
+            0
+        }
+    }
+
+    fn payload(&self) -> u64 {
+        self.bits & !OBJ & !KIND_MASK
+    }
+
+    fn function_ptr(&self) -> *const ObjFunction {
+        self.payload() as usize as *const ObjFunction
+    }
+
+    fn gc<T>(&self) -> Gc<T> {
+        Gc::from_raw((self.payload() >> 3) as u32)
+    }
+
+    fn obj<T>(gc: Gc<T>, kind: u64) -> Self {
+        Self::from_bits(OBJ | (gc.to_raw() as u64) << 3 | kind)
+    }
+
+    pub fn type_v(&self) -> ValueType {
+        match self.bits {
+            NIL => return ValueType::VAL_NIL,
+            FALSE => return ValueType::VAL_BOOL(false),
+            TRUE => return ValueType::VAL_BOOL(true),
+            _ if self.is_number() => return ValueType::VAL_NUMBER(self.as_number()),
+            _ => {}
+        }
+        match self.kind() {
+            KIND_STRING => ValueType::VAL_STRING(self.gc()),
+            KIND_CLOSURE => ValueType::VAL_CLOSURE(self.gc()),
+            KIND_CLASS => ValueType::VAL_CLASS(self.gc()),
+            KIND_INSTANCE => ValueType::VAL_INSTANCE(self.gc()),
+            KIND_BOUND_METHOD => ValueType::VAL_BOUND_METHOD(self.gc()),
+            KIND_NATIVE => ValueType::VAL_NATIVE(self.gc()),
+            KIND_FUNCTION => {
+                // The returned `Rc` is a new reference; this value keeps its own.
+                let function = ManuallyDrop::new(unsafe { Rc::from_raw(self.function_ptr()) });
+                ValueType::VAL_FUNCTION(Rc::clone(&function))
+            }
+            _ => unreachable!("corrupt value bits {:#x}", self.bits),
+        }
+    }
+
+    pub fn as_bool(&self) -> bool {
+        if self.is_bool() {
+            self.bits == TRUE
+        } else {
+            panic!("Tried to extract bool from non-bool Value: {:?}", self);
+        }
+    }
+
+    pub fn as_number(&self) -> f64 {
+        if self.is_number() {
+            f64::from_bits(self.bits)
+        } else {
+            panic!("Tried to extract number from non-number Value: {:?}", self);
+        }
+    }
+
+    pub fn as_string(&self) -> Gc<super::ObjString> {
+        if self.is_string() {
+            self.gc()
+        } else {
+            panic!("Tried to extract string from non-string value");
+        }
+    }
+    pub fn is_bool(&self) -> bool {
+        self.bits == TRUE || self.bits == FALSE
+    }
+    pub fn is_string(&self) -> bool {
+        self.kind() == KIND_STRING
+    }
+
+    pub fn is_number(&self) -> bool {
+        self.bits & QNAN != QNAN
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.bits == NIL
+    }
+}
+
+/// Raw encodings for the JIT, whose templates read and write stack slots
+/// as plain words.
+#[cfg(feature = "jit")]
+impl Value {
+    /// Set in every non-number; a word is a number unless all of these are.
+    pub const QNAN_BITS: u64 = QNAN;
+    pub const NIL_BITS: u64 = NIL;
+    pub const FALSE_BITS: u64 = FALSE;
+    pub const TRUE_BITS: u64 = TRUE;
+
+    pub fn to_bits(&self) -> u64 {
+        self.bits
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        // Arithmetic can produce NaNs with arbitrary payloads; fold them into
+        // the one NaN that cannot be mistaken for a boxed value.
+        let value = if value.is_nan() { f64::NAN } else { value };
+        Self::from_bits(value.to_bits())
+    }
+}
+
+impl From<ValueType> for Value {
+    fn from(value: ValueType) -> Self {
+        match value {
+            ValueType::VAL_NIL => Self::from_bits(NIL),
+            ValueType::VAL_BOOL(b) => Self::from_bits(if b { TRUE } else { FALSE }),
+            ValueType::VAL_NUMBER(n) => Self::from(n),
+            ValueType::VAL_STRING(gc) => Self::obj(gc, KIND_STRING),
+            ValueType::VAL_CLOSURE(gc) => Self::obj(gc, KIND_CLOSURE),
+            ValueType::VAL_CLASS(gc) => Self::obj(gc, KIND_CLASS),
+            ValueType::VAL_INSTANCE(gc) => Self::obj(gc, KIND_INSTANCE),
+            ValueType::VAL_BOUND_METHOD(gc) => Self::obj(gc, KIND_BOUND_METHOD),
+            ValueType::VAL_NATIVE(gc) => Self::obj(gc, KIND_NATIVE),
+            ValueType::VAL_FUNCTION(function) => {
+                let ptr = Rc::into_raw(function) as usize as u64;
+                debug_assert_eq!(ptr & (OBJ | KIND_MASK), 0, "pointer does not fit a NaN box");
+                Self::from_bits(OBJ | ptr | KIND_FUNCTION)
+            }
+        }
+    }
+}
+
+impl Clone for Value {
+    fn clone(&self) -> Self {
+        if self.kind() == KIND_FUNCTION {
+            unsafe { Rc::increment_strong_count(self.function_ptr()) };
+        }
+        Self::from_bits(self.bits)
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        if self.kind() == KIND_FUNCTION {
+            unsafe { Rc::decrement_strong_count(self.function_ptr()) };
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        if self.is_number() && other.is_number() {
+            self.as_number() == other.as_number()
+        } else {
+            self.bits == other.bits
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.type_v())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::Value;
+    use crate::{
+        gc::Gc,
+        value::{ObjFunction, ValueType},
+    };
+
+    #[test]
+    fn fits_in_eight_bytes() {
+        assert_eq!(std::mem::size_of::<Value>(), 8);
+    }
+
+    #[test]
+    fn round_trips_every_kind() {
+        for value in [
+            ValueType::VAL_NIL,
+            ValueType::VAL_BOOL(true),
+            ValueType::VAL_BOOL(false),
+            ValueType::VAL_NUMBER(-0.5),
+            ValueType::VAL_NUMBER(f64::INFINITY),
+            ValueType::VAL_STRING(Gc::from_raw(u32::MAX)),
+            ValueType::VAL_CLOSURE(Gc::from_raw(7)),
+            ValueType::VAL_CLASS(Gc::from_raw(8)),
+            ValueType::VAL_INSTANCE(Gc::from_raw(9)),
+            ValueType::VAL_BOUND_METHOD(Gc::from_raw(10)),
+            ValueType::VAL_NATIVE(Gc::from_raw(11)),
+        ] {
+            assert_eq!(Value::from(value.clone()).type_v(), value);
+        }
+        let nan = Value::from(f64::from_bits(0x7fff_ffff_ffff_ffff));
+        assert!(nan.is_number() && nan.as_number().is_nan());
+        assert_ne!(nan, nan.clone());
+        assert_eq!(Value::from(0.0), Value::from(-0.0));
+    }
+
+    #[test]
+    fn counts_function_references() {
+        let function = Rc::new(ObjFunction::new(None, 0));
+        let value = Value::from(ValueType::VAL_FUNCTION(function.clone()));
+        let copy = value.clone();
+        assert_eq!(Rc::strong_count(&function), 3);
+        let ValueType::VAL_FUNCTION(unpacked) = copy.type_v() else {
+            panic!("expected a function");
+        };
+        assert!(Rc::ptr_eq(&unpacked, &function));
+        drop((value, copy, unpacked));
+        assert_eq!(Rc::strong_count(&function), 1);
+    }
+}
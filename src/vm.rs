@@ -1,218 +1,1315 @@
 use std::{
-    cell::RefCell,
-    collections::{HashMap, VecDeque},
-    env::set_var,
-    hash::Hash,
-    mem::offset_of,
-    rc::Rc,
-    usize, vec,
+    any::TypeId,
+    cell::Cell,
+    collections::HashMap,
+    io::{self, Write},
+    rc::{Rc, Weak},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    chunk::{Chunk, OpCode},
-    compiler::Compiler,
-    value::{self, Value, ValueType},
+    chunk::OpCode,
+    compiler::{Compiler, CompilerOptions},
+    debug::DebugHook,
+    embed::Pin,
+    gc::{Gc, GcStats, Heap},
+    jit::JitOptions,
+    limits::{InterruptHandle, Limit, Limits},
+    program::{Program, Prototype},
+    shape::{Access, CacheStats, CacheTarget, DICTIONARY_FIELDS, Dictionary, InlineCache, Shapes},
+    value::{
+        ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjString, ObjUpvalue,
+        Value, ValueType,
+    },
 };
 
+mod debug;
+mod host;
+#[cfg(feature = "jit")]
+mod jit;
+mod register;
+
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
+/// Instructions between checkpoints: incremental steps of a running major
+/// collection, and checks of the deadline and interrupts.
+const GC_SLICE: u64 = 1000;
+
+pub struct CallFrame {
+    closure: Gc<ObjClosure>,
+    /// The closure's function, kept here so reading code skips the heap.
+    function: Rc<ObjFunction>,
+    ip: usize,
+    /// Stack index of the frame's slot zero.
+    slots: usize,
+    /// Frames this one took over through tail calls, for stack traces.
+    elided: usize,
+}
+
+impl CallFrame {
+    /// The source line of the instruction at `ip`, in either backend's
+    /// code.
+    fn line_at(&self, ip: usize) -> usize {
+        if self.function.registers.is_empty() {
+            self.function.chunk.lines[ip]
+        } else {
+            self.function.registers.lines[ip]
+        }
+    }
+}
+
 pub struct VM {
-    chunk: Option<Rc<RefCell<Chunk>>>,
-    pub ip: usize,
-    pub stack: VecDeque<Value>,
-    compiler: Compiler,
-    table: HashMap<String, Value>,
+    frames: Vec<CallFrame>,
+    /// Allocated once; only `stack[..stack_top]` is live.
+    stack: Box<[Value]>,
+    stack_top: usize,
+    /// Globals keyed by interned name, so lookups never touch the chars.
+    table: HashMap<Gc<ObjString>, Value>,
+    /// Upvalues still pointing into the stack.
+    open_upvalues: Vec<Gc<ObjUpvalue>>,
+    pub heap: Heap,
+    /// Options used for every `interpret` call.
+    pub options: CompilerOptions,
+    /// Instructions dispatched so far, by either backend.
+    instructions: u64,
+    /// Hidden classes of every instance.
+    pub(crate) shapes: Shapes,
+    cache_stats: CacheStats,
+    /// Only read when built with the `jit` feature.
+    pub jit: JitOptions,
+    /// Applied to every `interpret` call.
+    pub limits: Limits,
+    interrupt: InterruptHandle,
+    /// The limit that is ending the script, picked up by `runtime_Error`.
+    fault: Option<Limit>,
+    /// Instruction count at which the loops stop for `reach_checkpoint`;
+    /// zeroed to stop at the next instruction.
+    checkpoint: u64,
+    next_slice: u64,
+    /// Instruction count the current script's fuel runs out at.
+    fuel_end: u64,
+    deadline: Option<Instant>,
+    /// Lines printed for the last error: the compiler's diagnostics, or a
+    /// runtime error's message and then its stack trace.
+    pub report: Vec<String>,
+    /// Where `print` writes.
+    pub stdout: Box<dyn Write>,
+    /// Where compiler diagnostics and runtime errors go.
+    pub stderr: Box<dyn Write>,
+    /// Values held by the host through `embed::Object`; dead ones are
+    /// pruned as they are found.
+    pub pins: Vec<Weak<Pin>>,
+    /// Identifies this VM's pins.
+    pub id: u64,
+    /// Classes of the lists and maps the host has passed in.
+    list_class: Option<Gc<ObjClass>>,
+    map_class: Option<Gc<ObjClass>>,
+    /// Classes registered by the host, by the Rust type they wrap.
+    host_classes: HashMap<TypeId, Gc<ObjClass>>,
+    /// Host calls under way: one for the script or function the host ran,
+    /// and one more for each call a native made back into the VM.
+    host_calls: usize,
+    /// Frames below this one belong to an outer host call; the innermost
+    /// `run` returns once it is back down to it.
+    base: usize,
+    /// Stack trace lines of an error a nested host call raised and a native
+    /// passed on, which the error's own trace continues.
+    inner_trace: Vec<String>,
+    /// Called at each new line while attached; see `debug`.
+    pub debug_hook: Option<Box<dyn DebugHook>>,
+    /// Frame depth and line the hook was last called for.
+    debug_position: (usize, usize),
+    /// The script functions of each `Program` run here, by the address of
+    /// its prototype, which the entry keeps alive. They hold this VM's
+    /// constants, inline caches and JIT counters for the shared code, so
+    /// running a program again picks up where it left off.
+    linked: HashMap<usize, (Arc<Prototype>, Rc<ObjFunction>)>,
 }
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types)]
 pub enum InterpretResult {
     INTERPRET_OK,
     INTERPRET_COMPILE_ERROR,
     INTERPRET_RUNTIME_ERROR,
+    /// Ended by `Limits::fuel`.
+    INTERPRET_OUT_OF_FUEL,
+    INTERPRET_HEAP_LIMIT,
+    INTERPRET_DEPTH_LIMIT,
+    INTERPRET_STRING_LIMIT,
+    /// Ended by `Limits::timeout`.
+    INTERPRET_DEADLINE,
+    /// Cancelled through an `InterruptHandle`.
+    INTERPRET_INTERRUPTED,
 }
 
-#[allow(non_camel_case_types, non_snake_case)]
+#[allow(non_snake_case)]
 impl VM {
-    pub fn new(source: String) -> Self {
+    pub fn new() -> Self {
         Self {
-            chunk: None,
-            ip: 0,
-            stack: VecDeque::with_capacity(256),
-            compiler: Compiler::new(source),
+            frames: Vec::with_capacity(FRAMES_MAX),
+            stack: vec![Value::nil_value(); STACK_MAX].into_boxed_slice(),
+            stack_top: 0,
             table: HashMap::new(),
+            open_upvalues: vec![],
+            heap: Heap::new(),
+            options: CompilerOptions::default(),
+            instructions: 0,
+            shapes: Shapes::new(),
+            cache_stats: CacheStats::default(),
+            jit: JitOptions::default(),
+            limits: Limits::default(),
+            interrupt: InterruptHandle::default(),
+            fault: None,
+            checkpoint: 0,
+            next_slice: GC_SLICE,
+            fuel_end: u64::MAX,
+            deadline: None,
+            report: vec![],
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            pins: vec![],
+            id: host::next_id(),
+            list_class: None,
+            map_class: None,
+            host_classes: HashMap::new(),
+            host_calls: 0,
+            base: 0,
+            inner_trace: vec![],
+            debug_hook: None,
+            debug_position: (0, 0),
+            linked: HashMap::new(),
         }
     }
 
-    pub fn interpret(&mut self, chunk: Rc<RefCell<Chunk>>) -> InterpretResult {
-        if !self.compiler.compile(chunk.clone()) {
+    /// Compiles and runs `source` under the name `script`, which debuggers
+    /// see the script's functions by.
+    pub fn interpret(&mut self, source: String, script: &str) -> InterpretResult {
+        let mut compiler = Compiler::new(source, self.options, &mut self.heap);
+        compiler.script = script.into();
+        let function = compiler.compile();
+        let errors = std::mem::take(&mut compiler.errors);
+        for diagnostic in std::mem::take(&mut compiler.diagnostics) {
+            let _ = writeln!(self.stderr, "{}", diagnostic);
+        }
+        let Some(function) = function else {
+            self.report = errors;
             return InterpretResult::INTERPRET_COMPILE_ERROR;
+        };
+        self.run_script(function)
+    }
+
+    /// Runs `program`, linking its code into this VM's heap the first
+    /// time.
+    pub fn run_program(&mut self, program: &Program) -> InterpretResult {
+        let key = Arc::as_ptr(&program.script) as usize;
+        let function = match self.linked.get(&key) {
+            Some((_, function)) => function.clone(),
+            None => {
+                let function = program.script.link(&mut self.heap);
+                let entry = (program.script.clone(), function.clone());
+                self.linked.insert(key, entry);
+                function
+            }
+        };
+        self.run_script(function)
+    }
+
+    fn run_script(&mut self, function: Rc<ObjFunction>) -> InterpretResult {
+        let result = self.host_call(|vm| {
+            if vm.stack_top == STACK_MAX {
+                return Err("Stack overflow.".to_string());
+            }
+            let closure = vm.heap.alloc(ObjClosure {
+                function,
+                upvalues: vec![],
+            });
+            vm.push(Value::from(ValueType::VAL_CLOSURE(closure)));
+            vm.maybe_collect();
+            vm.call(closure, 0)
+        });
+        result.err().unwrap_or(InterpretResult::INTERPRET_OK)
+    }
+
+    /// Calls `callee` with `args` and runs it to completion. A native can
+    /// do this while a script is running.
+    pub fn call_from_host(
+        &mut self,
+        callee: Value,
+        args: Vec<Value>,
+    ) -> Result<Value, InterpretResult> {
+        self.host_call(|vm| {
+            if args.len() > u8::MAX as usize {
+                return Err("Can't have more than 255 arguments.".to_string());
+            }
+            // The callee's own frame is checked when it is called.
+            if vm.stack_top + args.len() >= STACK_MAX {
+                return Err("Stack overflow.".to_string());
+            }
+            let arg_count = args.len();
+            vm.push(callee.clone());
+            for arg in args {
+                vm.push(arg);
+            }
+            vm.call_value(callee, arg_count)
+        })
+    }
+
+    /// Runs a call for the host: `start` pushes the callee and its
+    /// arguments on top of the stack and calls it, and the frames that
+    /// pushes then run to completion, leaving its result. Host calls nest
+    /// when a native calls back into the VM: the inner one gets the frames
+    /// above those already running, and on an error unwinds only those,
+    /// leaving the outer call to carry on or fail in turn. Limits count
+    /// from the outermost call.
+    fn host_call(
+        &mut self,
+        start: impl FnOnce(&mut Self) -> Result<(), String>,
+    ) -> Result<Value, InterpretResult> {
+        if self.host_calls == 0 {
+            self.begin();
+        }
+        let (outer_base, slot) = (self.base, self.stack_top);
+        self.base = self.frames.len();
+        self.host_calls += 1;
+        let started = if self.host_calls > FRAMES_MAX {
+            // Natives calling natives push no frames to run out of.
+            Err("Stack overflow.".to_string())
+        } else {
+            start(self)
+        };
+        let result = match started {
+            Err(message) => self.runtime_Error(&message),
+            Ok(()) if self.frames.len() > self.base => self.run_frame(),
+            Ok(()) => InterpretResult::INTERPRET_OK,
+        };
+        self.host_calls -= 1;
+        self.base = outer_base;
+        if result != InterpretResult::INTERPRET_OK {
+            if self.host_calls > 0 {
+                self.close_upvalues(slot);
+                self.stack[slot..self.stack_top].fill(Value::nil_value());
+                self.stack_top = slot;
+                // A native cannot catch a limit; the outer call ends too.
+                if let Some(limit) = Limit::from_result(result) {
+                    self.trip(limit);
+                }
+            }
+            return Err(result);
+        }
+        let value = self.pop();
+        self.stack[slot..self.stack_top].fill(Value::nil_value());
+        self.stack_top = slot;
+        Ok(value)
+    }
+
+    /// Resets the per-call state `limits` are measured from.
+    fn begin(&mut self) {
+        self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+        self.fuel_end = match self.limits.fuel {
+            Some(fuel) => self.instructions.saturating_add(fuel),
+            None => u64::MAX,
+        };
+        self.fault = None;
+        self.interrupt.clear();
+        self.checkpoint = 0;
+        self.debug_position = (0, 0);
+        self.report.clear();
+    }
+
+    /// Runs the frame just pushed, and everything it calls, with the
+    /// backend its function was compiled for.
+    fn run_frame(&mut self) -> InterpretResult {
+        if self.frames.last().unwrap().function.registers.is_empty() {
+            return self.run();
+        }
+        if let Err(message) = self.enter_register_frame() {
+            return self.runtime_Error(&message);
         }
-        self.chunk = Some(chunk.clone());
-        self.run().unwrap()
+        self.run_registers()
+    }
+
+    /// The global `name`, if it is defined.
+    pub fn global(&mut self, name: &str) -> Option<Value> {
+        let name = self.heap.intern(name.to_string());
+        self.table.get(&name).cloned()
+    }
+
+    /// Defines or overwrites the global `name`.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        let name = self.heap.alloc_string(name.to_string());
+        self.heap.root_write_barrier(&name);
+        self.heap.root_write_barrier(&value);
+        self.table.insert(name.as_string(), value);
+    }
+
+    /// A handle other threads can use to cancel the running script.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache_stats
     }
 
-    pub fn run(&mut self) -> Option<InterpretResult> {
+    pub fn run(&mut self) -> InterpretResult {
+        // The running frame is cached in locals so the loop never goes back
+        // through `self.frames`; `ip` is written back before calls and
+        // errors, the only places that read it from the frame.
+        let mut function;
+        let mut closure;
+        let mut ip;
+        let mut slots;
+        macro_rules! load_frame {
+            () => {{
+                let frame = self.frames.last().unwrap();
+                function = frame.function.clone();
+                closure = frame.closure;
+                ip = frame.ip;
+                slots = frame.slots;
+            }};
+        }
+        macro_rules! save_ip {
+            () => {
+                self.frames.last_mut().unwrap().ip = ip
+            };
+        }
+        macro_rules! read_byte {
+            () => {{
+                let byte = function.chunk.code[ip];
+                ip += 1;
+                byte
+            }};
+        }
+        macro_rules! read_short {
+            () => {{
+                let high = read_byte!();
+                let low = read_byte!();
+                u16::from_be_bytes([high, low]) as usize
+            }};
+        }
+        macro_rules! read_constant {
+            () => {
+                function.chunk.constants.values[read_byte!() as usize].clone()
+            };
+        }
+        macro_rules! runtime_error {
+            ($message:expr) => {{
+                save_ip!();
+                return self.runtime_Error($message);
+            }};
+        }
+
+        load_frame!();
         loop {
-            //print!("          ");
-            // println!(" stack {:?}", self.stack);
-            // self.chunk
-            //   .as_ref()
-            // .unwrap()
-            //.borrow_mut()
-            //.disassembleInstruction(self.ip);
-            let instruction = self.read_byte();
-            match OpCode::try_from(instruction).unwrap() {
+            #[cfg(feature = "jit")]
+            if let Some(Some(native)) = function.native.get() {
+                match self.run_native(native, ip, slots) {
+                    Ok(next) => ip = next,
+                    Err(message) => runtime_error!(message),
+                }
+            }
+            if self.instructions >= self.checkpoint {
+                save_ip!();
+                if let Some(limit) = self.reach_checkpoint() {
+                    runtime_error!(limit.message());
+                }
+            }
+            self.instructions += 1;
+            match OpCode::try_from(read_byte!()).unwrap() {
                 OpCode::Return => {
-                    // let val = self.stack.pop_back().unwrap();
-                    //match val.type_v  {
-                    //  ValueType::VAL_NUMBER(a)=>println!("{}",a),
-                    // ValueType::VAL_BOOL(a)=>println!("{}",a),
-                    //ValueType::VAL_NIL=>println!("nil"),
-                    //ValueType::VAL_STRING(a)=>println!("{}",a)
-                    //}
-                    return Some(InterpretResult::INTERPRET_OK);
-                }
-                OpCode::OP_NIL => self.stack.push_back(Value::nil_value()),
-                OpCode::OP_FALSE => self
-                    .stack
-                    .push_back(Value::from(ValueType::VAL_BOOL(false))),
-                OpCode::OP_TRUE => self.stack.push_back(Value::from(ValueType::VAL_BOOL(true))),
-                OpCode::Op_Constnats => {
-                    let value = self.read_constant();
-                    if let ValueType::VAL_NUMBER(_) = value.type_v {
-                        //println!("{:>4?}", value);
-                        self.stack.push_back(Value::from(value));
-                    } else if let ValueType::VAL_STRING(_) = value.type_v {
-                        //println!("{:?}", value);
-                        self.stack.push_back(Value::from(value));
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    self.stack_top = frame.slots;
+                    self.push(result);
+                    if self.frames.len() == self.base {
+                        return InterpretResult::INTERPRET_OK;
                     }
+                    load_frame!();
+                }
+                OpCode::OP_NIL => self.push(Value::nil_value()),
+                OpCode::OP_FALSE => self.push(Value::bool_value(false)),
+                OpCode::OP_TRUE => self.push(Value::bool_value(true)),
+                OpCode::Op_Constnats => {
+                    let value = read_constant!();
+                    self.push(value);
                 }
                 OpCode::OP_NEGATE => {
-                    let value = self.stack.pop_back().unwrap();
                     if !self.peek(0).is_number() {
-                        self.runtime_Error("Operand must be a number");
-                        return Some(InterpretResult::INTERPRET_RUNTIME_ERROR);
+                        runtime_error!("Operand must be a number.");
+                    }
+                    let value = self.pop();
+                    self.push(-value);
+                }
+                a @ (OpCode::OP_ADD
+                | OpCode::OP_DIVIDE
+                | OpCode::OP_SUBTRACT
+                | OpCode::OP_MULTIPLY
+                | OpCode::OP_GREATER
+                | OpCode::OP_LESS
+                | OpCode::OP_GREATER_EQUAL
+                | OpCode::OP_LESS_EQUAL) => {
+                    if let Err(message) = self.binar_op(a) {
+                        runtime_error!(message);
                     }
-                    self.stack.push_back(-value);
                 }
-                a @ OpCode::OP_ADD => self.binar_op(a),
-                a @ OpCode::OP_DIVIDE => self.binar_op(a),
-                a @ OpCode::OP_SUBTRACT => self.binar_op(a),
-                a @ OpCode::OP_MULTIPLY => self.binar_op(a),
                 OpCode::OP_NOT => {
-                    let val = self.stack.pop_back().unwrap();
-                    self.stack
-                        .push_back(Value::from(ValueType::VAL_BOOL(Self::is_falsely(val))));
+                    let val = self.pop();
+                    self.push(Value::bool_value(Self::is_falsely(&val)));
                 }
                 OpCode::OP_EQUAL => {
-                    let val1 = self.stack.pop_back().unwrap();
-                    let val2 = self.stack.pop_back().unwrap();
-                    self.stack
-                        .push_back(Value::from(ValueType::VAL_BOOL(Self::valueEqual(
-                            val1, val2,
-                        ))));
+                    let val1 = self.pop();
+                    let val2 = self.pop();
+                    self.push(Value::bool_value(self.valueEqual(val1, val2)));
+                }
+                OpCode::OP_NOT_EQUAL => {
+                    let val1 = self.pop();
+                    let val2 = self.pop();
+                    self.push(Value::bool_value(!self.valueEqual(val1, val2)));
                 }
-
-                a @ OpCode::OP_GREATER => self.binar_op(a),
-                a @ OpCode::OP_LESS => self.binar_op(a),
                 OpCode::OP_PRINT => {
-                    self.chunk
-                        .as_ref()
-                        .unwrap()
-                        .borrow_mut()
-                        .printValue(&self.stack.pop_back().unwrap());
+                    let value = self.pop();
+                    if let Err(error) =
+                        function
+                            .chunk
+                            .printValue(&value, &self.heap, &mut self.stdout)
+                    {
+                        save_ip!();
+                        return self.runtime_Error(&format!("Could not print: {}.", error));
+                    }
+                }
+                OpCode::OP_POP => {
+                    self.pop();
+                }
+                OpCode::OP_DEFINE_GLOBAL => {
+                    let name = read_constant!();
+                    let value = self.pop();
+                    self.heap.root_write_barrier(&name);
+                    self.heap.root_write_barrier(&value);
+                    self.table.insert(name.as_string(), value);
+                }
+                OpCode::OP_GET_GLOBAL => {
+                    let name = read_constant!().as_string();
+                    match self.table.get(&name) {
+                        Some(value) => self.push(value.clone()),
+                        None => {
+                            save_ip!();
+                            return self.undefined_variable(name);
+                        }
+                    }
+                }
+                OpCode::OP_SET_GLOBAL => {
+                    let name = read_constant!().as_string();
+                    let value = self.peek(0).clone();
+                    self.heap.root_write_barrier(&value);
+                    match self.table.get_mut(&name) {
+                        Some(slot) => *slot = value,
+                        None => {
+                            save_ip!();
+                            return self.undefined_variable(name);
+                        }
+                    }
+                }
+                OpCode::OP_GET_LOCAL => {
+                    let slot = slots + read_byte!() as usize;
+                    self.push(self.stack[slot].clone());
+                }
+                OpCode::OP_SET_LOCAL => {
+                    let slot = slots + read_byte!() as usize;
+                    self.stack[slot] = self.peek(0).clone();
+                }
+                OpCode::OP_GET_UPVALUE => {
+                    let index = read_byte!() as usize;
+                    let upvalue = self.heap.get(closure).upvalues[index];
+                    let value = match self.heap.get(upvalue) {
+                        ObjUpvalue::Open(slot) => self.stack[*slot].clone(),
+                        ObjUpvalue::Closed(value) => value.clone(),
+                    };
+                    self.push(value);
+                }
+                OpCode::OP_SET_UPVALUE => {
+                    let index = read_byte!() as usize;
+                    let upvalue = self.heap.get(closure).upvalues[index];
+                    let value = self.peek(0).clone();
+                    match self.heap.get_mut(upvalue) {
+                        ObjUpvalue::Open(slot) => self.stack[*slot] = value,
+                        ObjUpvalue::Closed(closed) => {
+                            *closed = value.clone();
+                            self.heap.write_barrier(upvalue, &value);
+                        }
+                    }
+                }
+                OpCode::OP_JUMP => {
+                    let offset = read_short!();
+                    ip += offset;
+                }
+                OpCode::OP_JUMP_IF_FALSE => {
+                    let offset = read_short!();
+                    if Self::is_falsely(self.peek(0)) {
+                        ip += offset;
+                    }
+                }
+                OpCode::OP_LOOP => {
+                    let offset = read_short!();
+                    ip -= offset;
+                    // A loop that goes round on one line reaches it again.
+                    self.debug_position = (0, 0);
+                }
+                OpCode::OP_CALL => {
+                    let arg_count = read_byte!() as usize;
+                    let callee = self.peek(arg_count).clone();
+                    save_ip!();
+                    if let Err(message) = self.call_value(callee, arg_count) {
+                        return self.runtime_Error(&message);
+                    }
+                    load_frame!();
+                }
+                OpCode::OP_TAIL_CALL => {
+                    let arg_count = read_byte!() as usize;
+                    let callee = self.stack_top - arg_count - 1;
+                    save_ip!();
+                    if let Err(message) = self.tail_call(callee, arg_count, |vm| {
+                        vm.call_value(vm.peek(arg_count).clone(), arg_count)
+                    }) {
+                        return self.runtime_Error(&message);
+                    }
+                    // The host called a function that tail-called one that
+                    // pushed no frame.
+                    if self.frames.len() == self.base {
+                        return InterpretResult::INTERPRET_OK;
+                    }
+                    load_frame!();
+                }
+                OpCode::OP_CLOSURE => {
+                    let ValueType::VAL_FUNCTION(inner) = read_constant!().type_v() else {
+                        unreachable!("OP_CLOSURE operand must be a function");
+                    };
+                    let mut upvalues = Vec::with_capacity(inner.upvalue_count);
+                    for _ in 0..inner.upvalue_count {
+                        let is_local = read_byte!() == 1;
+                        let index = read_byte!() as usize;
+                        if is_local {
+                            upvalues.push(self.capture_upvalue(slots + index));
+                        } else {
+                            upvalues.push(self.heap.get(closure).upvalues[index]);
+                        }
+                    }
+                    let closure = self.heap.alloc(ObjClosure {
+                        function: inner,
+                        upvalues,
+                    });
+                    self.push(Value::from(ValueType::VAL_CLOSURE(closure)));
+                    self.maybe_collect();
+                }
+                OpCode::OP_CLOSE_UPVALUE => {
+                    self.close_upvalues(self.stack_top - 1);
+                    self.pop();
+                }
+                OpCode::OP_CLASS => {
+                    let name = read_constant!().as_string();
+                    let class = self.new_class(name);
+                    self.push(class);
+                    self.maybe_collect();
+                }
+                OpCode::OP_METHOD => {
+                    let name = read_constant!().as_string();
+                    let method = self.pop();
+                    self.define_method(self.peek(0).clone(), name, method);
+                }
+                OpCode::OP_GET_PROPERTY => {
+                    let name = read_constant!().as_string();
+                    let cache = &function.caches[read_short!()];
+                    let object = self.stack_top - 1;
+                    if let Err(message) = self.get_property(cache, name, object, object) {
+                        runtime_error!(&message);
+                    }
+                }
+                OpCode::OP_SET_PROPERTY => {
+                    let name = read_constant!().as_string();
+                    let cache = &function.caches[read_short!()];
+                    let value = self.pop();
+                    let object = self.pop();
+                    if let Err(message) = self.set_property(cache, name, object, value.clone()) {
+                        runtime_error!(&message);
+                    }
+                    self.push(value);
+                }
+                OpCode::OP_INVOKE => {
+                    let name = read_constant!().as_string();
+                    let arg_count = read_byte!() as usize;
+                    let cache = &function.caches[read_short!()];
+                    save_ip!();
+                    if let Err(message) = self.invoke(cache, name, arg_count) {
+                        return self.runtime_Error(&message);
+                    }
+                    load_frame!();
+                }
+                OpCode::OP_TAIL_INVOKE => {
+                    let name = read_constant!().as_string();
+                    let arg_count = read_byte!() as usize;
+                    let cache = &function.caches[read_short!()];
+                    let receiver = self.stack_top - arg_count - 1;
+                    save_ip!();
+                    if let Err(message) =
+                        self.tail_call(receiver, arg_count, |vm| vm.invoke(cache, name, arg_count))
+                    {
+                        return self.runtime_Error(&message);
+                    }
+                    if self.frames.len() == self.base {
+                        return InterpretResult::INTERPRET_OK;
+                    }
+                    load_frame!();
+                }
+            }
+        }
+    }
+
+    fn new_class(&mut self, name: Gc<ObjString>) -> Value {
+        let class = self.heap.alloc(ObjClass {
+            name,
+            methods: HashMap::new(),
+            initializer: None,
+            shape: self.shapes.root(),
+            host: None,
+        });
+        Value::from(ValueType::VAL_CLASS(class))
+    }
+
+    fn define_method(&mut self, class: Value, name: Gc<ObjString>, method: Value) {
+        let ValueType::VAL_CLASS(class) = class.type_v() else {
+            unreachable!("methods are only added to the class being declared");
+        };
+        let ValueType::VAL_CLOSURE(closure) = method.type_v() else {
+            unreachable!("a method must be a closure");
+        };
+        let is_initializer = self.heap.string(name) == "init";
+        let class_obj = self.heap.get_mut(class);
+        class_obj.methods.insert(name, closure);
+        if is_initializer {
+            class_obj.initializer = Some(closure);
+        }
+        self.heap
+            .write_barrier(class, &Value::from(ValueType::VAL_STRING(name)));
+        self.heap.write_barrier(class, &method);
+    }
+
+    /// Resolves `name` on `instance` through `cache`, filling the cache on a
+    /// miss. Sets that add a field resolve to the shape transition, or to
+    /// an insert past `DICTIONARY_FIELDS`. Getters and setters of a host
+    /// class, and instances in dictionary mode, are never cached.
+    fn find_property(
+        &mut self,
+        cache: &Cell<InlineCache>,
+        access: Access,
+        instance: Gc<ObjInstance>,
+        name: Gc<ObjString>,
+    ) -> Option<CacheTarget> {
+        let instance = self.heap.get(instance);
+        let (shape, class) = (instance.shape, instance.class);
+        if let Some(dictionary) = &instance.dictionary {
+            return match dictionary.slot(name) {
+                Some(slot) => Some(CacheTarget::Field(slot)),
+                None if access == Access::Set => Some(CacheTarget::Insert),
+                None => Some(CacheTarget::Method(
+                    *self.heap.get(class).methods.get(&name)?,
+                )),
+            };
+        }
+        let counter = self.cache_stats.counter(access);
+        let epoch = self.shapes.epoch();
+        if let Some(target) = cache.get().lookup(shape, epoch) {
+            counter.hits += 1;
+            return Some(target);
+        }
+        counter.misses += 1;
+        let target = match self.shapes.slot(shape, name) {
+            Some(slot) => CacheTarget::Field(slot),
+            None if self.heap.get(class).host.is_some() => {
+                match self.host_member(class, access, name)? {
+                    CacheTarget::Accessor => return Some(CacheTarget::Accessor),
+                    target => target,
+                }
+            }
+            None if access == Access::Set
+                && self.shapes.fields(shape).len() == DICTIONARY_FIELDS =>
+            {
+                return Some(CacheTarget::Insert);
+            }
+            None if access == Access::Set => {
+                // Shapes are keyed by the name's identity; keep it alive.
+                self.heap
+                    .root_write_barrier(&Value::from(ValueType::VAL_STRING(name)));
+                CacheTarget::Transition(self.shapes.transition(shape, name))
+            }
+            None => CacheTarget::Method(*self.heap.get(class).methods.get(&name)?),
+        };
+        let mut updated = cache.get();
+        updated.insert(shape, target, epoch);
+        cache.set(updated);
+        Some(target)
+    }
+
+    /// Reads `name` off the instance in stack slot `object` into slot `dst`;
+    /// a method comes back bound to the instance.
+    fn get_property(
+        &mut self,
+        cache: &Cell<InlineCache>,
+        name: Gc<ObjString>,
+        object: usize,
+        dst: usize,
+    ) -> Result<(), String> {
+        let receiver = self.stack[object].clone();
+        let ValueType::VAL_INSTANCE(instance) = receiver.type_v() else {
+            return Err("Only instances have properties.".to_string());
+        };
+        match self.find_property(cache, Access::Get, instance, name) {
+            Some(CacheTarget::Field(slot)) => {
+                self.stack[dst] = self.heap.get(instance).fields[slot].clone();
+            }
+            Some(CacheTarget::Method(method)) => {
+                let method = Value::from(ValueType::VAL_CLOSURE(method));
+                let bound = self.heap.alloc(ObjBoundMethod { receiver, method });
+                self.stack[dst] = Value::from(ValueType::VAL_BOUND_METHOD(bound));
+                self.maybe_collect();
+            }
+            Some(CacheTarget::Native(method)) => {
+                let method = Value::from(ValueType::VAL_NATIVE(method));
+                let bound = self.heap.alloc(ObjBoundMethod { receiver, method });
+                self.stack[dst] = Value::from(ValueType::VAL_BOUND_METHOD(bound));
+                self.maybe_collect();
+            }
+            Some(CacheTarget::Accessor) => self.stack[dst] = self.call_getter(instance, name)?,
+            _ => return Err(self.undefined_property(name)),
+        }
+        Ok(())
+    }
+
+    fn set_property(
+        &mut self,
+        cache: &Cell<InlineCache>,
+        name: Gc<ObjString>,
+        object: Value,
+        value: Value,
+    ) -> Result<(), String> {
+        let ValueType::VAL_INSTANCE(instance) = object.type_v() else {
+            return Err("Only instances have fields.".to_string());
+        };
+        let target = self.find_property(cache, Access::Set, instance, name);
+        let instance_obj = self.heap.get_mut(instance);
+        match target {
+            Some(CacheTarget::Field(slot)) => instance_obj.fields[slot] = value.clone(),
+            Some(CacheTarget::Transition(shape)) => {
+                instance_obj.shape = shape;
+                instance_obj.fields.push(value.clone());
+                self.heap.shape_barrier(shape);
+            }
+            Some(CacheTarget::Insert) => {
+                self.insert_field(instance, name, value);
+                return Ok(());
+            }
+            Some(CacheTarget::Accessor) => return self.call_setter(instance, name, &value),
+            // Instances of host classes have no fields of their own.
+            _ => return Err(self.undefined_property(name)),
+        }
+        self.heap.write_barrier(instance, &value);
+        Ok(())
+    }
+
+    /// Adds the field `name` to `instance`, moving it to dictionary mode
+    /// first if it is not there yet.
+    fn insert_field(&mut self, instance: Gc<ObjInstance>, name: Gc<ObjString>, value: Value) {
+        let class = self.heap.get(instance).class;
+        let root = self.heap.get(class).shape;
+        let instance_obj = self.heap.get_mut(instance);
+        if instance_obj.dictionary.is_none() {
+            let names = self.shapes.fields(instance_obj.shape);
+            instance_obj.dictionary = Some(Box::new(Dictionary::new(names)));
+            instance_obj.shape = root;
+        }
+        instance_obj.dictionary.as_mut().unwrap().insert(name);
+        instance_obj.fields.push(value.clone());
+        self.heap
+            .write_barrier(instance, &Value::from(ValueType::VAL_STRING(name)));
+        self.heap.write_barrier(instance, &value);
+    }
+
+    /// Calls the method `name` on the receiver below the `arg_count`
+    /// arguments. A field holding something callable is called instead.
+    fn invoke(
+        &mut self,
+        cache: &Cell<InlineCache>,
+        name: Gc<ObjString>,
+        arg_count: usize,
+    ) -> Result<(), String> {
+        let ValueType::VAL_INSTANCE(instance) = self.peek(arg_count).type_v() else {
+            return Err("Only instances have methods.".to_string());
+        };
+        match self.find_property(cache, Access::Invoke, instance, name) {
+            Some(CacheTarget::Field(slot)) => {
+                let callee = self.heap.get(instance).fields[slot].clone();
+                self.stack[self.stack_top - arg_count - 1] = callee.clone();
+                self.call_value(callee, arg_count)
+            }
+            Some(CacheTarget::Method(method)) => self.call(method, arg_count),
+            Some(CacheTarget::Native(method)) => self.call_native(method, arg_count),
+            Some(CacheTarget::Accessor) => {
+                let callee = self.call_getter(instance, name)?;
+                self.stack[self.stack_top - arg_count - 1] = callee.clone();
+                self.call_value(callee, arg_count)
+            }
+            _ => Err(self.undefined_property(name)),
+        }
+    }
+
+    fn undefined_property(&self, name: Gc<ObjString>) -> String {
+        format!("Undefined property '{}'.", self.heap.string(name))
+    }
+
+    /// Calls `callee`, which sits below its `arg_count` arguments. Pushes a
+    /// frame unless it is a native, a host class or a class without `init`,
+    /// whose result simply replaces it.
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), String> {
+        let slot = self.stack_top - arg_count - 1;
+        match callee.type_v() {
+            ValueType::VAL_CLOSURE(closure) => self.call(closure, arg_count),
+            ValueType::VAL_BOUND_METHOD(bound) => {
+                let bound = self.heap.get(bound);
+                let method = bound.method.clone();
+                self.stack[slot] = bound.receiver.clone();
+                self.call_value(method, arg_count)
+            }
+            ValueType::VAL_CLASS(class) if self.heap.get(class).host.is_some() => {
+                self.construct(class, arg_count)
+            }
+            ValueType::VAL_CLASS(class) => {
+                let class_obj = self.heap.get(class);
+                let (shape, initializer) = (class_obj.shape, class_obj.initializer);
+                let instance = self.heap.alloc(ObjInstance {
+                    class,
+                    shape,
+                    fields: vec![],
+                    dictionary: None,
+                    host: None,
+                });
+                self.stack[slot] = Value::from(ValueType::VAL_INSTANCE(instance));
+                self.maybe_collect();
+                match initializer {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => {
+                        Err(format!("Expected 0 arguments but got {}.", arg_count))
+                    }
+                    None => Ok(()),
                 }
-                _ => todo!(),
+            }
+            ValueType::VAL_NATIVE(native) => self.call_native(native, arg_count),
+            _ => Err("Can only call functions and classes.".to_string()),
+        }
+    }
+
+    /// Makes the call `call` with the callee in stack slot `callee` in
+    /// place of the running frame. The callee and its arguments move down
+    /// over the frame's slots first, so tail recursion never deepens the
+    /// frame stack. A callee that pushes no frame leaves its result where
+    /// the frame began, just as `Return` would. On error the frame is put
+    /// back so the stack trace still shows it.
+    fn tail_call(
+        &mut self,
+        callee: usize,
+        arg_count: usize,
+        call: impl FnOnce(&mut Self) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let frame = self.frames.pop().unwrap();
+        self.close_upvalues(frame.slots);
+        for offset in 0..=arg_count {
+            let value = std::mem::replace(&mut self.stack[callee + offset], Value::nil_value());
+            self.stack[frame.slots + offset] = value;
+        }
+        let top = frame.slots + arg_count + 1;
+        self.stack[top..self.stack_top].fill(Value::nil_value());
+        self.stack_top = top;
+
+        let depth = self.frames.len();
+        if let Err(message) = call(self) {
+            self.frames.push(frame);
+            return Err(message);
+        }
+        if self.frames.len() > depth {
+            self.frames.last_mut().unwrap().elided = frame.elided + 1;
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, closure: Gc<ObjClosure>, arg_count: usize) -> Result<(), String> {
+        let function = self.heap.get(closure).function.clone();
+        if arg_count != function.arity {
+            return Err(format!(
+                "Expected {} arguments but got {}.",
+                function.arity, arg_count
+            ));
+        }
+        if self
+            .limits
+            .call_depth
+            .is_some_and(|depth| self.frames.len() >= depth)
+        {
+            self.trip(Limit::CallDepth);
+            return Err(Limit::CallDepth.message().to_string());
+        }
+        let slots = self.stack_top - arg_count - 1;
+        if self.frames.len() == FRAMES_MAX || slots + function.frame_size() > STACK_MAX {
+            return Err("Stack overflow.".to_string());
+        }
+        #[cfg(feature = "jit")]
+        self.count_call(&function);
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots,
+            elided: 0,
+        });
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Gc<ObjUpvalue> {
+        let existing = self.open_upvalues.iter().find(
+            |upvalue| matches!(self.heap.get(**upvalue), ObjUpvalue::Open(open) if *open == slot),
+        );
+        if let Some(upvalue) = existing {
+            return *upvalue;
+        }
+        let upvalue = self.heap.alloc(ObjUpvalue::Open(slot));
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    /// Moves every captured stack slot at or above `last` into its upvalue.
+    fn close_upvalues(&mut self, last: usize) {
+        let (stack, heap) = (&self.stack, &mut self.heap);
+        self.open_upvalues.retain(|gc| {
+            let upvalue = heap.get_mut(*gc);
+            let slot = match upvalue {
+                ObjUpvalue::Open(slot) => *slot,
+                ObjUpvalue::Closed(_) => return false,
+            };
+            if slot < last {
+                return true;
+            }
+            *upvalue = ObjUpvalue::Closed(stack[slot].clone());
+            heap.write_barrier(*gc, &stack[slot]);
+            false
+        });
+    }
+
+    /// Runs a minor collection, and starts a major cycle if the old
+    /// generation has grown, once the nursery is full; steps the major cycle
+    /// instead while one is running. Only called right after a new object
+    /// has been stored where the roots can see it.
+    fn maybe_collect(&mut self) {
+        if self.heap.is_collecting() {
+            self.gc_step();
+        } else if self.heap.should_collect() {
+            let start = Instant::now();
+            self.heap.begin_minor();
+            self.mark_stack_roots();
+            self.heap.finish_minor();
+            if self.heap.wants_major() {
+                self.heap.begin_major();
+                self.mark_globals();
+                self.mark_stack_roots();
+            }
+            self.heap.record_pause(start.elapsed());
+        }
+        if let Some(limit) = self.limits.heap_bytes
+            && self.heap.bytes_allocated() > limit
+        {
+            self.collect_garbage();
+            if self.heap.bytes_allocated() > limit {
+                self.trip(Limit::Heap);
             }
         }
     }
 
-    fn valueEqual(val1: Value, val2: Value) -> bool {
-        if val1.type_v != val2.type_v {
-            return false;
+    /// Steps the collector when a slice is due, checks the limits that are
+    /// not enforced where they happen, and calls any debug hook. Returns the
+    /// limit that ends the script, left in `fault` for `runtime_Error`. The
+    /// running frame's `ip` must be saved.
+    fn reach_checkpoint(&mut self) -> Option<Limit> {
+        if self.fault.is_none() {
+            if self.instructions >= self.fuel_end {
+                self.fault = Some(Limit::Fuel);
+            } else if self.interrupt.take() {
+                self.fault = Some(Limit::Interrupted);
+            } else if self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                self.fault = Some(Limit::Deadline);
+            }
         }
-        match val1.type_v {
-            ValueType::VAL_BOOL(_) => val1.as_bool() == val2.as_bool(),
-            ValueType::VAL_NIL => true,
-            ValueType::VAL_NUMBER(_) => val1.as_number() == val2.as_number(),
-            ValueType::VAL_STRING(_) => val1.as_obj().as_str() == val2.as_obj().as_str(),
+        if self.fault.is_some() {
+            return self.fault;
+        }
+        if self.instructions >= self.next_slice {
+            self.next_slice = self.instructions + GC_SLICE;
+            if self.heap.is_collecting() {
+                self.gc_step();
+            }
         }
+        if self.debug_hook.is_some() {
+            // Every instruction is a checkpoint while a hook is attached.
+            self.checkpoint = 0;
+            self.call_debug_hook();
+            return self.fault;
+        }
+        self.checkpoint = self.next_slice.min(self.fuel_end);
+        None
+    }
+
+    /// Ends the script at the next checkpoint, or with the error that
+    /// reports it, because `limit` was exceeded.
+    fn trip(&mut self, limit: Limit) {
+        self.fault = Some(limit);
+        self.checkpoint = 0;
     }
 
-    fn is_falsely(value: Value) -> bool {
+    /// One slice of the running major cycle, bounded by the heap's
+    /// `max_pause`.
+    fn gc_step(&mut self) {
+        let start = Instant::now();
+        let budget = if self.heap.options.stress {
+            Duration::ZERO
+        } else {
+            self.heap.options.max_pause
+        };
+        if self.heap.step(Some(start + budget)) {
+            self.finish_marking();
+        }
+        self.heap.record_pause(start.elapsed());
+    }
+
+    /// Finishes any running cycle, then collects the whole heap in one pause.
+    pub fn collect_garbage(&mut self) {
+        let start = Instant::now();
+        self.finish_cycle();
+        self.heap.begin_major();
+        self.mark_globals();
+        self.mark_stack_roots();
+        self.finish_cycle();
+        self.heap.record_pause(start.elapsed());
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    fn finish_cycle(&mut self) {
+        while self.heap.is_collecting() {
+            if self.heap.step(None) {
+                self.finish_marking();
+            }
+        }
+    }
+
+    /// Rescans the stack and, with everything live marked, frees the shapes
+    /// nothing uses before the sweep; the names the rest are keyed by stay.
+    fn finish_marking(&mut self) {
+        self.mark_stack_roots();
+        self.heap.drain_gray();
+        let live = self.heap.take_live_shapes();
+        for name in self.shapes.collect(live) {
+            self.heap.mark(name);
+        }
+        self.heap.finish_marking();
+    }
+
+    /// Roots that change too often to barrier: rescanned at the end of
+    /// marking and used by every minor collection.
+    fn mark_stack_roots(&mut self) {
+        for value in &self.stack[..self.stack_top] {
+            self.heap.mark_value(value);
+        }
+        for frame in &self.frames {
+            self.heap.mark(frame.closure);
+        }
+        for upvalue in &self.open_upvalues {
+            self.heap.mark(*upvalue);
+        }
+        for class in [self.list_class, self.map_class].into_iter().flatten() {
+            self.heap.mark(class);
+        }
+        for class in self.host_classes.values() {
+            self.heap.mark(*class);
+        }
+        // Their constants are young right after linking, and never stored
+        // through a barrier.
+        for (_, function) in self.linked.values() {
+            self.heap
+                .mark_value(&Value::from(ValueType::VAL_FUNCTION(function.clone())));
+        }
+        // A major cycle reaches the objects only traced userdata holds
+        // through that userdata, so cycles through it can be freed.
+        let traced = self.host_classes.values().any(|class| {
+            let host = self.heap.get(*class).host.as_ref();
+            host.is_some_and(|host| host.trace.is_some())
+        });
+        let held = if self.heap.is_collecting() && traced {
+            self.heap.held_pins()
+        } else {
+            HashMap::new()
+        };
+        self.pins.retain(|pin| pin.strong_count() > 0);
+        for pin in &self.pins {
+            let count = pin.strong_count();
+            if held.get(&pin.as_ptr()).is_some_and(|held| count <= *held) {
+                continue;
+            }
+            if let Some(pin) = pin.upgrade() {
+                self.heap.mark_value(&pin.value);
+            }
+        }
+    }
+
+    fn mark_globals(&mut self) {
+        for (name, value) in &self.table {
+            self.heap.mark(*name);
+            self.heap.mark_value(value);
+        }
+    }
+
+    fn undefined_variable(&mut self, name: Gc<ObjString>) -> InterpretResult {
+        let message = format!("Undefined variable '{}'.", self.heap.string(name));
+        self.runtime_Error(&message)
+    }
+
+    fn valueEqual(&self, val1: Value, val2: Value) -> bool {
+        val1 == val2
+    }
+
+    fn is_falsely(value: &Value) -> bool {
         value.is_nil() || value.is_bool() && !value.as_bool()
     }
-    pub fn runtime_Error(&mut self, msg: &str) {
-        eprintln!("{}", msg);
-        if self.ip > 0 {
-            let ins = self.ip - 1;
-            let chunk = self.chunk.as_ref().unwrap().clone();
-            if ins < chunk.borrow_mut().lines.len() {
-                let line = chunk.borrow_mut().lines[ins];
-                eprintln!("Line[{}] in script", line);
-            } else {
-                eprintln!("Line[uknown] in script");
+
+    /// Reports `msg` with a stack trace of the innermost host call, and
+    /// unwinds that call's frames. Only the outermost call writes the
+    /// report to `stderr`; an inner one hands it to the native that made
+    /// it instead.
+    pub fn runtime_Error(&mut self, msg: &str) -> InterpretResult {
+        self.report = vec![msg.to_string()];
+        self.report.append(&mut self.inner_trace);
+        for frame in self.frames[self.base..].iter().rev() {
+            let line = frame.line_at(frame.ip.saturating_sub(1));
+            match &frame.function.name {
+                Some(name) => self.report.push(format!("Line[{}] in {}()", line, name)),
+                None => self.report.push(format!("Line[{}] in script", line)),
+            }
+            match frame.elided {
+                0 => {}
+                1 => self
+                    .report
+                    .push("... 1 frame elided by tail calls".to_string()),
+                elided => self
+                    .report
+                    .push(format!("... {} frames elided by tail calls", elided)),
+            }
+        }
+        if self.host_calls > 1 {
+            if let Some(frame) = self.frames.get(self.base) {
+                self.close_upvalues(frame.slots);
             }
+            self.frames.truncate(self.base);
+            return match self.fault.take() {
+                Some(limit) => limit.result(),
+                None => InterpretResult::INTERPRET_RUNTIME_ERROR,
+            };
+        }
+        for line in &self.report {
+            // Nowhere left to report a failure to.
+            let _ = writeln!(self.stderr, "{}", line);
+        }
+        self.stack_top = 0;
+        self.frames.clear();
+        self.open_upvalues.clear();
+        match self.fault.take() {
+            Some(limit) => limit.result(),
+            None => InterpretResult::INTERPRET_RUNTIME_ERROR,
         }
-        self.stack.clear();
     }
     #[inline]
-    fn binar_op(&mut self, bi_op: OpCode) {
-        match bi_op {
-            OpCode::OP_ADD => {
-                let value_1 = self.stack.pop_back().unwrap();
-                let value_2 = self.stack.pop_back().unwrap();
-                self.stack.push_back(value_2 + value_1);
-            }
-            OpCode::OP_DIVIDE => {
-                let value_1 = self.stack.pop_back().unwrap();
-                let value_2 = self.stack.pop_back().unwrap();
-                self.stack.push_back(value_2 / value_1);
-            }
-            OpCode::OP_SUBTRACT => {
-                let value_1 = self.stack.pop_back().unwrap();
-                let value_2 = self.stack.pop_back().unwrap();
-                self.stack.push_back(value_2 - value_1);
-            }
-            OpCode::OP_MULTIPLY => {
-                let value_1 = self.stack.pop_back().unwrap();
-                let value_2 = self.stack.pop_back().unwrap();
-                self.stack.push_back(value_2 * value_1);
-            }
-            OpCode::OP_GREATER => {
-                let val1 = self.stack.pop_back().unwrap();
-                let val2 = self.stack.pop_back().unwrap();
-                if !val1.is_number() || !val2.is_number() {
-                    self.runtime_Error("LHS != RHS");
-                }
-                self.stack.push_back(Value::from(ValueType::VAL_BOOL(
-                    val1.as_number() < val2.as_number(),
-                )));
-            }
-            OpCode::OP_LESS => {
-                let val1 = self.stack.pop_back().unwrap();
-                let val2 = self.stack.pop_back().unwrap();
-                if !val1.is_number() || !val2.is_number() {
-                    self.runtime_Error("LHS != RHS");
-                }
-                self.stack.push_back(Value::from(ValueType::VAL_BOOL(
-                    val1.as_number() > val2.as_number(),
-                )));
-            }
-            a => panic!("Unable to parse the Binary operation"),
-        }
-    }
-    pub fn peek(&self, index: usize) -> Value {
-        let len = self.stack.len();
-        self.stack
-            .get(len - 1 - index)
-            .cloned()
-            .unwrap_or_else(|| panic!("stack under flow"))
-    }
-    pub fn read_byte(&mut self) -> u8 {
-        let byte = self.chunk.as_ref().unwrap().borrow_mut().code[self.ip];
-        self.ip += 1;
-        byte
-    }
-
-    pub fn read_constant(&mut self) -> Value {
-        let byte = self.read_byte();
-        self.chunk.as_ref().unwrap().borrow_mut().constants.values[byte as usize].clone()
+    #[allow(clippy::neg_cmp_op_on_partial_ord)]
+    fn binar_op(&mut self, bi_op: OpCode) -> Result<(), &'static str> {
+        let both_numbers = self.peek(0).is_number() && self.peek(1).is_number();
+        if let OpCode::OP_ADD = bi_op {
+            let both_strings = self.peek(0).is_string() && self.peek(1).is_string();
+            if !both_numbers && !both_strings {
+                return Err("Operands must be two numbers or two strings.");
+            }
+        } else if !both_numbers {
+            return Err("Operands must be numbers.");
+        }
+
+        if !both_numbers {
+            let value = self.concatenate(self.peek(1).as_string(), self.peek(0).as_string())?;
+            self.pop();
+            self.pop();
+            self.push(value);
+            self.maybe_collect();
+            return Ok(());
+        }
+
+        let b = self.pop().as_number();
+        let a = self.pop().as_number();
+        let result = match bi_op {
+            OpCode::OP_ADD => Value::from(a + b),
+            OpCode::OP_DIVIDE => Value::from(a / b),
+            OpCode::OP_SUBTRACT => Value::from(a - b),
+            OpCode::OP_MULTIPLY => Value::from(a * b),
+            OpCode::OP_GREATER => Value::bool_value(a > b),
+            OpCode::OP_LESS => Value::bool_value(a < b),
+            // Fused `OP_LESS, OP_NOT` and `OP_GREATER, OP_NOT`; written as the
+            // negation so NaN compares the same as the unfused pair.
+            OpCode::OP_GREATER_EQUAL => Value::bool_value(!(a < b)),
+            OpCode::OP_LESS_EQUAL => Value::bool_value(!(a > b)),
+            _ => panic!("Unable to parse the Binary operation"),
+        };
+        self.push(result);
+        Ok(())
+    }
+
+    /// A new string of `a` followed by `b`, within `Limits::string_length`.
+    fn concatenate(&mut self, a: Gc<ObjString>, b: Gc<ObjString>) -> Result<Value, &'static str> {
+        let (a, b) = (self.heap.string(a), self.heap.string(b));
+        if self
+            .limits
+            .string_length
+            .is_some_and(|limit| a.len() + b.len() > limit)
+        {
+            self.trip(Limit::StringLength);
+            return Err(Limit::StringLength.message());
+        }
+        let chars = format!("{}{}", a, b);
+        Ok(self.heap.alloc_string(chars))
+    }
+
+    #[inline]
+    fn push(&mut self, value: Value) {
+        self.stack[self.stack_top] = value;
+        self.stack_top += 1;
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Value {
+        self.stack_top -= 1;
+        std::mem::replace(&mut self.stack[self.stack_top], Value::nil_value())
+    }
+
+    #[inline]
+    pub fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack_top - 1 - distance]
     }
 }
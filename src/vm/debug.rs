@@ -0,0 +1,202 @@
+//! The VM's side of `debug`: calling the hook, and what it sees of the
+//! paused script.
+
+use super::VM;
+use crate::{
+    compiler::{Compiler, CompilerOptions},
+    debug::{Paused, StackFrame},
+    embed::{self, Error},
+    limits::Limit,
+    value::{LocalName, ObjBoundMethod, ObjClosure, ObjUpvalue, Value, ValueType},
+};
+
+impl VM {
+    /// Calls the hook if the running frame has reached a new line since
+    /// the last call. The hook is taken out while it runs, so code it
+    /// evaluates runs without it.
+    pub(super) fn call_debug_hook(&mut self) {
+        let position = (self.frames.len(), self.debug_line(0));
+        if position == self.debug_position {
+            return;
+        }
+        self.debug_position = position;
+        let Some(mut hook) = self.debug_hook.take() else {
+            return;
+        };
+        hook.on_line(&mut Paused { vm: self });
+        self.debug_hook = Some(hook);
+        self.checkpoint = 0;
+    }
+
+    /// The line `frame` is at, counting from the innermost: the next to
+    /// run in the paused frame, the call in the others.
+    pub(crate) fn debug_line(&self, frame: usize) -> usize {
+        let Some(index) = self.frames.len().checked_sub(frame + 1) else {
+            return 0;
+        };
+        let call = &self.frames[index];
+        match frame {
+            0 => call.line_at(call.ip),
+            _ => call.line_at(call.ip - 1),
+        }
+    }
+
+    /// The name of the script the paused frame's function is from.
+    pub(crate) fn debug_script(&self) -> &str {
+        self.frames.last().map_or("", |call| &call.function.script)
+    }
+
+    pub(crate) fn debug_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub(crate) fn debug_stack(&self) -> Vec<StackFrame> {
+        (0..self.frames.len())
+            .map(|frame| {
+                let function = &self.frames[self.frames.len() - frame - 1].function;
+                StackFrame {
+                    function: function
+                        .name
+                        .as_ref()
+                        .map_or("script".to_string(), |name| name.to_string()),
+                    script: function.script.to_string(),
+                    line: self.debug_line(frame),
+                }
+            })
+            .collect()
+    }
+
+    /// The variables `frame` sees: the upvalues its closure captured, then
+    /// the locals in scope, in slot order. Locals of a scope the frame has
+    /// left, or a declaration it has yet to reach, are hidden, and a slot
+    /// shared by sibling scopes goes to the latest declaration.
+    pub(crate) fn debug_locals(&self, frame: usize) -> Vec<(String, Value)> {
+        let Some(index) = self.frames.len().checked_sub(frame + 1) else {
+            return vec![];
+        };
+        let call = &self.frames[index];
+        // The next frame's callee sits above this one's locals.
+        let top = self
+            .frames
+            .get(index + 1)
+            .map_or(self.stack_top, |callee| callee.slots);
+        let line = self.debug_line(frame);
+        let mut visible: Vec<&LocalName> = vec![];
+        for local in call.function.locals.iter() {
+            if !(local.first_line..=local.last_line).contains(&line)
+                || call.slots + local.slot as usize >= top
+            {
+                continue;
+            }
+            match visible.iter_mut().find(|seen| seen.slot == local.slot) {
+                Some(seen) if seen.first_line <= local.first_line => *seen = local,
+                Some(_) => {}
+                None => visible.push(local),
+            }
+        }
+        visible.sort_by_key(|local| local.slot);
+        let upvalues = self.heap.get(call.closure).upvalues.iter();
+        let upvalues = call
+            .function
+            .upvalue_names
+            .iter()
+            .zip(upvalues)
+            .map(|(name, upvalue)| {
+                let value = match self.heap.get(*upvalue) {
+                    ObjUpvalue::Open(slot) => self.stack[*slot].clone(),
+                    ObjUpvalue::Closed(value) => value.clone(),
+                };
+                (name.clone(), value)
+            });
+        let locals = visible.iter().map(|local| {
+            let value = self.stack[call.slots + local.slot as usize].clone();
+            (local.name.clone(), value)
+        });
+        upvalues.chain(locals).collect()
+    }
+
+    pub(crate) fn debug_globals(&mut self) -> Vec<(String, embed::Value)> {
+        let globals: Vec<_> = self
+            .table
+            .iter()
+            .map(|(name, value)| (*name, value.clone()))
+            .collect();
+        let mut globals: Vec<_> = globals
+            .into_iter()
+            .map(|(name, value)| (self.heap.string(name).to_string(), self.export(&value)))
+            .collect();
+        globals.sort_by(|a, b| a.0.cmp(&b.0));
+        globals
+    }
+
+    /// Compiles `expression` into a function taking the variables `frame`
+    /// sees, and calls it with their values. Where the frame has a
+    /// receiver, the function is a method bound to it, so `this` works.
+    pub(crate) fn debug_eval(
+        &mut self,
+        expression: &str,
+        frame: usize,
+    ) -> embed::Result<embed::Value> {
+        // A local shadows an upvalue, and an inner local an outer one, of
+        // the same name.
+        let mut params: Vec<(String, Value)> = vec![];
+        let mut receiver = None;
+        for (name, value) in self.debug_locals(frame).into_iter().rev() {
+            if name == "this" {
+                receiver.get_or_insert(value);
+            } else if !params.iter().any(|(seen, _)| *seen == name) {
+                params.push((name, value));
+            }
+        }
+        params.reverse();
+        let names: Vec<_> = params.iter().map(|(name, _)| name.as_str()).collect();
+        let function = format!("eval({}) {{ return {}; }}", names.join(", "), expression);
+        let source = match receiver {
+            Some(_) => format!("class eval {{ {} }}", function),
+            None => format!("fun {}", function),
+        };
+        // Parameters the expression leaves unused are not worth failing over.
+        let options = CompilerOptions {
+            deny_warnings: false,
+            ..self.options
+        };
+        let mut compiler = Compiler::new(source, options, &mut self.heap);
+        compiler.script = "eval".into();
+        let script = compiler.compile();
+        let errors = std::mem::take(&mut compiler.errors);
+        let Some(script) = script else {
+            return Err(Error::Compile {
+                script: "eval".to_string(),
+                diagnostics: errors,
+            });
+        };
+        let function = script
+            .chunk
+            .constants
+            .values
+            .iter()
+            .find_map(|constant| match constant.type_v() {
+                ValueType::VAL_FUNCTION(function) => Some(function),
+                _ => None,
+            })
+            .expect("the script declares one function");
+        let closure = self.heap.alloc(ObjClosure {
+            function,
+            upvalues: vec![],
+        });
+        let args = params.into_iter().map(|(_, value)| value).collect();
+        let mut callee = Value::from(ValueType::VAL_CLOSURE(closure));
+        if let Some(receiver) = receiver {
+            let method = self.heap.alloc(ObjBoundMethod {
+                receiver,
+                method: callee,
+            });
+            callee = Value::from(ValueType::VAL_BOUND_METHOD(method));
+        }
+        embed::Vm::from_inner(self).call_imported(callee, args, "eval")
+    }
+
+    pub(crate) fn debug_stop(&mut self, limit: Limit) {
+        self.trip(limit);
+    }
+}
@@ -0,0 +1,410 @@
+//! Moving values between the VM and the host, and calling natives.
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    rc::Rc,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use super::VM;
+use crate::{
+    embed::{self, Error, Object, Pin},
+    gc::Gc,
+    shape::{Access, CacheTarget, DICTIONARY_FIELDS, Dictionary},
+    userdata::UserClass,
+    value::{HostClass, NativeFn, ObjClass, ObjInstance, ObjNative, ObjString, Value, ValueType},
+};
+
+/// Tells the objects of one VM from another's.
+static NEXT_VM: AtomicU64 = AtomicU64::new(0);
+
+pub(super) fn next_id() -> u64 {
+    NEXT_VM.fetch_add(1, Ordering::Relaxed)
+}
+
+impl VM {
+    /// Converts `value` for the host. Instances of the built-in list and map
+    /// classes are copied out, unless they contain themselves; any other
+    /// object is pinned behind an `Object`.
+    pub fn export(&mut self, value: &Value) -> embed::Value {
+        self.export_within(value, &mut vec![])
+    }
+
+    fn export_within(&mut self, value: &Value, open: &mut Vec<Gc<ObjInstance>>) -> embed::Value {
+        match value.type_v() {
+            ValueType::VAL_NIL => return embed::Value::Nil,
+            ValueType::VAL_BOOL(bool) => return embed::Value::Bool(bool),
+            ValueType::VAL_NUMBER(number) => return embed::Value::Number(number),
+            ValueType::VAL_STRING(string) => {
+                return embed::Value::String(self.heap.string(string).to_string());
+            }
+            ValueType::VAL_INSTANCE(instance) if !open.contains(&instance) => {
+                let class = Some(self.heap.get(instance).class);
+                if class == self.list_class || class == self.map_class {
+                    open.push(instance);
+                    let copy = self.export_collection(instance, open);
+                    open.pop();
+                    return copy;
+                }
+            }
+            _ => {}
+        }
+        let kind = match value.type_v() {
+            ValueType::VAL_CLASS(_) => "class",
+            ValueType::VAL_INSTANCE(_) => "instance",
+            _ => "function",
+        };
+        let pin = Rc::new(Pin {
+            value: value.clone(),
+            vm: self.id,
+            kind,
+            display: self.heap.display(value).to_string(),
+        });
+        if self.pins.len() == self.pins.capacity() {
+            self.pins.retain(|pin| pin.strong_count() > 0);
+        }
+        self.pins.push(Rc::downgrade(&pin));
+        embed::Value::Object(Object { pin })
+    }
+
+    fn export_collection(
+        &mut self,
+        instance: Gc<ObjInstance>,
+        open: &mut Vec<Gc<ObjInstance>>,
+    ) -> embed::Value {
+        let instance_obj = self.heap.get(instance);
+        let is_list = Some(instance_obj.class) == self.list_class;
+        let names = match &instance_obj.dictionary {
+            Some(dictionary) => dictionary.names().to_vec(),
+            None => self.shapes.fields(instance_obj.shape).to_vec(),
+        };
+        let fields = instance_obj.fields.clone();
+        let values: Vec<_> = fields
+            .iter()
+            .map(|field| self.export_within(field, open))
+            .collect();
+        if is_list {
+            return embed::Value::List(values);
+        }
+        let keys = names.iter().map(|name| self.heap.string(*name).to_string());
+        embed::Value::Map(keys.zip(values).collect::<HashMap<_, _>>())
+    }
+
+    /// Converts a host value for this VM. Lists and maps become new
+    /// instances of the built-in classes, whose fields are the elements,
+    /// named by index, or the entries.
+    pub fn import(&mut self, value: &embed::Value) -> embed::Result<Value> {
+        Ok(match value {
+            embed::Value::Nil => Value::nil_value(),
+            embed::Value::Bool(bool) => Value::bool_value(*bool),
+            embed::Value::Number(number) => Value::number_value(*number),
+            embed::Value::String(string) => self.heap.alloc_string(string.clone()),
+            embed::Value::List(list) => {
+                let entries = list
+                    .iter()
+                    .enumerate()
+                    .map(|(index, element)| (index.to_string(), element));
+                let class = self.builtin_class("List");
+                self.import_collection(class, entries.collect())?
+            }
+            embed::Value::Map(map) => {
+                // Sorted, so maps with the same keys share a shape.
+                let mut entries: Vec<_> = map
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value))
+                    .collect();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                let class = self.builtin_class("Map");
+                self.import_collection(class, entries)?
+            }
+            embed::Value::Object(object) if object.pin.vm == self.id => object.pin.value.clone(),
+            embed::Value::Object(_) => return Err(Error::ForeignObject),
+        })
+    }
+
+    fn import_collection(
+        &mut self,
+        class: Gc<ObjClass>,
+        entries: Vec<(String, &embed::Value)>,
+    ) -> embed::Result<Value> {
+        let mut shape = self.heap.get(class).shape;
+        // Past the limit the names go straight into a dictionary, with no
+        // shapes to build on the way.
+        let dictionary = entries.len() > DICTIONARY_FIELDS;
+        let mut names = Vec::with_capacity(entries.len());
+        let mut fields = Vec::with_capacity(entries.len());
+        for (name, value) in entries {
+            let name = self.heap.alloc_string(name).as_string();
+            if !dictionary {
+                // Shapes are keyed by the name's identity; keep it alive.
+                self.heap
+                    .root_write_barrier(&Value::from(ValueType::VAL_STRING(name)));
+                shape = self.shapes.transition(shape, name);
+            }
+            names.push(name);
+            fields.push(self.import(value)?);
+        }
+        let instance = self.heap.alloc(ObjInstance {
+            class,
+            shape,
+            fields,
+            dictionary: dictionary.then(|| Box::new(Dictionary::new(&names))),
+            host: None,
+        });
+        Ok(Value::from(ValueType::VAL_INSTANCE(instance)))
+    }
+
+    /// The class of imported lists or maps, created the first time one
+    /// crosses over. Neither is a global.
+    fn builtin_class(&mut self, name: &str) -> Gc<ObjClass> {
+        let existing = match name {
+            "List" => self.list_class,
+            _ => self.map_class,
+        };
+        if let Some(class) = existing {
+            return class;
+        }
+        let name_string = self.heap.intern(name.to_string());
+        let ValueType::VAL_CLASS(class) = self.new_class(name_string).type_v() else {
+            unreachable!("new_class makes a class");
+        };
+        match name {
+            "List" => self.list_class = Some(class),
+            _ => self.map_class = Some(class),
+        }
+        class
+    }
+
+    /// Defines the global `name` as a native.
+    pub fn define_native(&mut self, name: &str, native: ObjNative) {
+        let native = self.heap.alloc(native);
+        self.set_global(name, Value::from(ValueType::VAL_NATIVE(native)));
+        self.maybe_collect();
+    }
+
+    /// Calls the native below the `arg_count` arguments, whose result then
+    /// replaces it, as if it had returned from a frame.
+    pub(super) fn call_native(
+        &mut self,
+        native: Gc<ObjNative>,
+        arg_count: usize,
+    ) -> Result<(), String> {
+        let native = self.heap.get(native);
+        if arg_count != native.arity {
+            return Err(format!(
+                "Expected {} arguments but got {}.",
+                native.arity, arg_count
+            ));
+        }
+        let function = native.function.clone();
+        let slot = self.stack_top - arg_count - 1;
+        let args = (slot + 1..self.stack_top)
+            .map(|arg| self.export(&self.stack[arg].clone()))
+            .collect();
+        let result = match function {
+            NativeFn::Function(function) => function(embed::Vm::from_inner(self), args),
+            NativeFn::Method(method) => {
+                let ValueType::VAL_INSTANCE(receiver) = self.stack[slot].type_v() else {
+                    unreachable!("host methods are only bound to instances");
+                };
+                method(self.host_data(receiver), args)
+            }
+        };
+        let result = result
+            .and_then(|result| self.import(&result))
+            .map_err(|error| self.raise(error))?;
+        self.stack[slot + 1..self.stack_top].fill(Value::nil_value());
+        self.stack[slot] = result;
+        self.stack_top = slot + 1;
+        self.maybe_collect();
+        Ok(())
+    }
+}
+
+impl VM {
+    /// The message of the runtime error a native's `error` becomes. An
+    /// error from a script the native called keeps its stack trace, which
+    /// the new error's continues, and a limit it ran into ends this script
+    /// too.
+    fn raise(&mut self, error: Error) -> String {
+        match error {
+            Error::Runtime { message, trace, .. } => {
+                self.inner_trace = trace;
+                message
+            }
+            Error::Limit { limit, .. } => {
+                self.trip(limit);
+                limit.message().to_string()
+            }
+            error => error.to_string(),
+        }
+    }
+
+    /// Defines `class` as a global, and as the class `new_userdata` wraps
+    /// a `T` in.
+    pub fn register_class<T: 'static>(&mut self, class: UserClass<T>) {
+        let name = self.heap.intern(class.name.clone());
+        let class_value = self.new_class(name);
+        let ValueType::VAL_CLASS(class_ref) = class_value.type_v() else {
+            unreachable!("new_class makes a class");
+        };
+        self.set_global(&class.name, class_value);
+        self.host_classes.insert(TypeId::of::<T>(), class_ref);
+        // Nothing collects before `maybe_collect`, so the members can be
+        // barriered as they are made and attached afterwards.
+        let mut host = HostClass {
+            constructor: class.constructor,
+            methods: HashMap::new(),
+            getters: HashMap::new(),
+            setters: HashMap::new(),
+            trace: class.trace,
+            vm: self.id,
+        };
+        let member = |vm: &mut Self, name: &str| {
+            let name = vm.heap.intern(name.to_string());
+            vm.heap
+                .write_barrier(class_ref, &Value::from(ValueType::VAL_STRING(name)));
+            name
+        };
+        for method in class.methods {
+            let name = member(self, &method.name);
+            let method = self.heap.alloc(method);
+            self.heap
+                .write_barrier(class_ref, &Value::from(ValueType::VAL_NATIVE(method)));
+            host.methods.insert(name, method);
+        }
+        for (name, getter) in class.getters {
+            host.getters.insert(member(self, &name), getter);
+        }
+        for (name, setter) in class.setters {
+            host.setters.insert(member(self, &name), setter);
+        }
+        self.heap.get_mut(class_ref).host = Some(Rc::new(host));
+        self.maybe_collect();
+    }
+
+    /// A new instance of the class registered for `T`, owning `data`.
+    pub fn new_userdata<T: 'static>(&mut self, data: T) -> embed::Result<Value> {
+        let class = *self
+            .host_classes
+            .get(&TypeId::of::<T>())
+            .ok_or(Error::UnregisteredClass(std::any::type_name::<T>()))?;
+        Ok(self.host_instance(class, Box::new(data)))
+    }
+
+    /// The `T` inside `object`, if it is an instance of `T`'s class.
+    pub fn userdata_mut<T: 'static>(&mut self, object: &Object) -> Option<&mut T> {
+        if object.pin.vm != self.id {
+            return None;
+        }
+        let ValueType::VAL_INSTANCE(instance) = object.pin.value.type_v() else {
+            return None;
+        };
+        self.heap.userdata_barrier(instance);
+        self.heap
+            .get_mut(instance)
+            .host
+            .as_deref_mut()?
+            .downcast_mut()
+    }
+
+    fn host_instance(&mut self, class: Gc<ObjClass>, data: Box<dyn Any>) -> Value {
+        let instance = self.heap.alloc(ObjInstance {
+            class,
+            shape: self.heap.get(class).shape,
+            fields: vec![],
+            dictionary: None,
+            host: Some(data),
+        });
+        Value::from(ValueType::VAL_INSTANCE(instance))
+    }
+
+    fn host_data(&mut self, instance: Gc<ObjInstance>) -> &mut dyn Any {
+        self.heap.userdata_barrier(instance);
+        let data = self.heap.get_mut(instance).host.as_deref_mut();
+        data.expect("instances of host classes own their data")
+    }
+
+    fn host_class(&self, class: Gc<ObjClass>) -> Rc<HostClass> {
+        let host = self.heap.get(class).host.clone();
+        host.expect("only called for host classes")
+    }
+
+    /// Calls the host class below the `arg_count` arguments, which leaves
+    /// the new instance in its place.
+    pub(super) fn construct(
+        &mut self,
+        class: Gc<ObjClass>,
+        arg_count: usize,
+    ) -> Result<(), String> {
+        let host = self.host_class(class);
+        let Some((arity, constructor)) = &host.constructor else {
+            let name = self.heap.string(self.heap.get(class).name);
+            return Err(format!(
+                "{} instances can only be created by the host.",
+                name
+            ));
+        };
+        if arg_count != *arity {
+            return Err(format!(
+                "Expected {} arguments but got {}.",
+                arity, arg_count
+            ));
+        }
+        let slot = self.stack_top - arg_count - 1;
+        let args = (slot + 1..self.stack_top)
+            .map(|arg| self.export(&self.stack[arg].clone()))
+            .collect();
+        let data = constructor(args).map_err(|error| error.to_string())?;
+        let instance = self.host_instance(class, data);
+        self.stack[slot + 1..self.stack_top].fill(Value::nil_value());
+        self.stack[slot] = instance;
+        self.stack_top = slot + 1;
+        self.maybe_collect();
+        Ok(())
+    }
+
+    /// What `name` is on instances of the host class `class`: methods are
+    /// cached like script methods, accessors are looked up each time.
+    pub(super) fn host_member(
+        &self,
+        class: Gc<ObjClass>,
+        access: Access,
+        name: Gc<ObjString>,
+    ) -> Option<CacheTarget> {
+        let host = self.heap.get(class).host.as_ref()?;
+        match access {
+            Access::Set => host
+                .setters
+                .contains_key(&name)
+                .then_some(CacheTarget::Accessor),
+            _ if host.getters.contains_key(&name) => Some(CacheTarget::Accessor),
+            _ => host.methods.get(&name).copied().map(CacheTarget::Native),
+        }
+    }
+
+    pub(super) fn call_getter(
+        &mut self,
+        instance: Gc<ObjInstance>,
+        name: Gc<ObjString>,
+    ) -> Result<Value, String> {
+        let host = self.host_class(self.heap.get(instance).class);
+        let getter = &host.getters[&name];
+        getter(self.host_data(instance))
+            .and_then(|value| self.import(&value))
+            .map_err(|error| error.to_string())
+    }
+
+    pub(super) fn call_setter(
+        &mut self,
+        instance: Gc<ObjInstance>,
+        name: Gc<ObjString>,
+        value: &Value,
+    ) -> Result<(), String> {
+        let host = self.host_class(self.heap.get(instance).class);
+        let setter = &host.setters[&name];
+        let value = self.export(value);
+        setter(self.host_data(instance), value).map_err(|error| error.to_string())
+    }
+}
@@ -0,0 +1,97 @@
+//! Handing hot functions to `jit` and running their native code from the
+//! stack machine's loop.
+
+use super::{STACK_MAX, VM};
+use crate::{
+    jit::{self, NativeCode, NativeFrame},
+    value::ObjFunction,
+};
+
+impl VM {
+    /// Counts a call of `function` and compiles it on reaching the
+    /// threshold.
+    pub(super) fn count_call(&self, function: &ObjFunction) {
+        if !self.jit.enabled || function.native.get().is_some() {
+            return;
+        }
+        let calls = function.calls.get() + 1;
+        function.calls.set(calls);
+        if calls >= self.jit.threshold {
+            let _ = function.native.set(jit::compile(function));
+        }
+    }
+
+    /// Runs native code from `ip` in the frame at `slots`, if it has an
+    /// entry there and the next checkpoint leaves room, and returns the
+    /// offset to interpret next. Fails if the frame's stack window would
+    /// not fit, as native code writes to it unchecked.
+    pub(super) fn run_native(
+        &mut self,
+        native: &NativeCode,
+        ip: usize,
+        slots: usize,
+    ) -> Result<usize, &'static str> {
+        // Back-edges check the budget, and no more than the whole function
+        // runs between two of them; holding that much fuel back keeps
+        // `Limits::fuel` exact.
+        let fuel = self.fuel_end.saturating_sub(native.bytecode_len() as u64);
+        let budget = self.checkpoint.min(fuel).saturating_sub(self.instructions);
+        if budget == 0 || !native.has_entry(ip) {
+            return Ok(ip);
+        }
+        if slots + native.max_stack() > STACK_MAX {
+            return Err("Stack overflow.");
+        }
+        let stack = self.stack.as_mut_ptr();
+        let mut frame = unsafe {
+            NativeFrame {
+                slots: stack.add(slots),
+                top: stack.add(self.stack_top),
+                ip,
+                instructions: 0,
+                budget,
+                entry: std::ptr::null(),
+            }
+        };
+        // The frame is the running one for this function's code, and its
+        // window of `max_stack` slots was just checked to fit the stack.
+        unsafe { native.run(&mut frame) };
+        self.stack_top = unsafe { frame.top.offset_from(stack) } as usize;
+        self.instructions += frame.instructions;
+        Ok(frame.ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{InterpretResult, VM};
+
+    fn run(source: &str, enabled: bool) -> u64 {
+        let mut vm = VM::new();
+        vm.jit.enabled = enabled;
+        vm.jit.threshold = 1;
+        assert!(matches!(
+            vm.interpret(source.to_string(), "test"),
+            InterpretResult::INTERPRET_OK
+        ));
+        vm.instructions_executed()
+    }
+
+    #[test]
+    fn native_code_counts_every_instruction() {
+        let source = "
+            fun sum(n) {
+                var total = 0;
+                var i = 0;
+                while (i < n) {
+                    if (i != 3 and !(i >= 7)) total = total + i * 2 - 1 / 2;
+                    i = i + 1;
+                }
+                return -total;
+            }
+            var result = 0;
+            for (var i = 0; i < 10; i = i + 1) result = sum(i);
+        ";
+        assert_eq!(run(source, true), run(source, false));
+    }
+}
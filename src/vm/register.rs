@@ -0,0 +1,424 @@
+//! Interpreter loop for the register backend. Frames use the same call
+//! stack and `CallFrame`s as the stack machine; a frame's registers are the
+//! stack slots starting at its `slots`, and `stack_top` always covers every
+//! register of the running frame so the collector sees them as roots.
+
+use super::{InterpretResult, STACK_MAX, VM};
+use crate::{
+    register::Instruction,
+    value::{ObjClosure, ObjUpvalue, Value, ValueType},
+};
+
+#[allow(non_snake_case)]
+impl VM {
+    pub(super) fn run_registers(&mut self) -> InterpretResult {
+        let mut function;
+        let mut closure;
+        let mut ip;
+        let mut base;
+        macro_rules! load_frame {
+            () => {{
+                let frame = self.frames.last().unwrap();
+                function = frame.function.clone();
+                closure = frame.closure;
+                ip = frame.ip;
+                base = frame.slots;
+            }};
+        }
+        macro_rules! save_ip {
+            () => {
+                self.frames.last_mut().unwrap().ip = ip
+            };
+        }
+        macro_rules! runtime_error {
+            ($message:expr) => {{
+                save_ip!();
+                return self.runtime_Error($message);
+            }};
+        }
+        macro_rules! reg {
+            ($register:expr) => {
+                self.stack[base + $register as usize]
+            };
+        }
+        macro_rules! constant {
+            ($index:expr) => {
+                function.chunk.constants.values[$index as usize]
+            };
+        }
+        macro_rules! number_op {
+            ($dst:expr, $lhs:expr, $rhs:expr, |$a:ident, $b:ident| $result:expr) => {{
+                let (lhs, rhs) = (&reg!($lhs), &reg!($rhs));
+                if !lhs.is_number() || !rhs.is_number() {
+                    runtime_error!("Operands must be numbers.");
+                }
+                let ($a, $b) = (lhs.as_number(), rhs.as_number());
+                reg!($dst) = $result;
+            }};
+        }
+
+        load_frame!();
+        loop {
+            if self.instructions >= self.checkpoint {
+                save_ip!();
+                if let Some(limit) = self.reach_checkpoint() {
+                    runtime_error!(limit.message());
+                }
+            }
+            self.instructions += 1;
+            let instruction = function.registers.code[ip];
+            ip += 1;
+            match instruction {
+                Instruction::LoadNil { dst } => reg!(dst) = Value::nil_value(),
+                Instruction::LoadBool { dst, value } => reg!(dst) = Value::bool_value(value),
+                Instruction::LoadConstant { dst, constant } => {
+                    reg!(dst) = constant!(constant).clone();
+                }
+                Instruction::Move { dst, src } => reg!(dst) = reg!(src).clone(),
+                Instruction::Negate { dst, src } => {
+                    if !reg!(src).is_number() {
+                        runtime_error!("Operand must be a number.");
+                    }
+                    reg!(dst) = Value::from(-reg!(src).as_number());
+                }
+                Instruction::Not { dst, src } => {
+                    reg!(dst) = Value::bool_value(Self::is_falsely(&reg!(src)));
+                }
+                Instruction::Add { dst, lhs, rhs } => {
+                    let (a, b) = (&reg!(lhs), &reg!(rhs));
+                    if a.is_number() && b.is_number() {
+                        reg!(dst) = Value::from(a.as_number() + b.as_number());
+                    } else if a.is_string() && b.is_string() {
+                        match self.concatenate(a.as_string(), b.as_string()) {
+                            Ok(value) => reg!(dst) = value,
+                            Err(message) => runtime_error!(message),
+                        }
+                        self.maybe_collect();
+                    } else {
+                        runtime_error!("Operands must be two numbers or two strings.");
+                    }
+                }
+                Instruction::Subtract { dst, lhs, rhs } => {
+                    number_op!(dst, lhs, rhs, |a, b| Value::from(a - b))
+                }
+                Instruction::Multiply { dst, lhs, rhs } => {
+                    number_op!(dst, lhs, rhs, |a, b| Value::from(a * b))
+                }
+                Instruction::Divide { dst, lhs, rhs } => {
+                    number_op!(dst, lhs, rhs, |a, b| Value::from(a / b))
+                }
+                Instruction::Greater { dst, lhs, rhs } => {
+                    number_op!(dst, lhs, rhs, |a, b| Value::bool_value(a > b))
+                }
+                Instruction::Less { dst, lhs, rhs } => {
+                    number_op!(dst, lhs, rhs, |a, b| Value::bool_value(a < b))
+                }
+                // Same NaN behaviour as the stack machine's fused opcodes.
+                #[allow(clippy::neg_cmp_op_on_partial_ord)]
+                Instruction::GreaterEqual { dst, lhs, rhs } => {
+                    number_op!(dst, lhs, rhs, |a, b| Value::bool_value(!(a < b)))
+                }
+                #[allow(clippy::neg_cmp_op_on_partial_ord)]
+                Instruction::LessEqual { dst, lhs, rhs } => {
+                    number_op!(dst, lhs, rhs, |a, b| Value::bool_value(!(a > b)))
+                }
+                Instruction::Equal { dst, lhs, rhs } => {
+                    reg!(dst) = Value::bool_value(reg!(lhs) == reg!(rhs));
+                }
+                Instruction::NotEqual { dst, lhs, rhs } => {
+                    reg!(dst) = Value::bool_value(reg!(lhs) != reg!(rhs));
+                }
+                Instruction::DefineGlobal { src, name } => {
+                    let name = constant!(name).clone();
+                    let value = reg!(src).clone();
+                    self.heap.root_write_barrier(&name);
+                    self.heap.root_write_barrier(&value);
+                    self.table.insert(name.as_string(), value);
+                }
+                Instruction::GetGlobal { dst, name } => {
+                    let name = constant!(name).as_string();
+                    match self.table.get(&name) {
+                        Some(value) => reg!(dst) = value.clone(),
+                        None => {
+                            save_ip!();
+                            return self.undefined_variable(name);
+                        }
+                    }
+                }
+                Instruction::SetGlobal { src, name } => {
+                    let name = constant!(name).as_string();
+                    let value = reg!(src).clone();
+                    self.heap.root_write_barrier(&value);
+                    match self.table.get_mut(&name) {
+                        Some(slot) => *slot = value,
+                        None => {
+                            save_ip!();
+                            return self.undefined_variable(name);
+                        }
+                    }
+                }
+                Instruction::GetUpvalue { dst, index } => {
+                    let upvalue = self.heap.get(closure).upvalues[index as usize];
+                    reg!(dst) = match self.heap.get(upvalue) {
+                        ObjUpvalue::Open(slot) => self.stack[*slot].clone(),
+                        ObjUpvalue::Closed(value) => value.clone(),
+                    };
+                }
+                Instruction::SetUpvalue { src, index } => {
+                    let upvalue = self.heap.get(closure).upvalues[index as usize];
+                    let value = reg!(src).clone();
+                    match self.heap.get_mut(upvalue) {
+                        ObjUpvalue::Open(slot) => self.stack[*slot] = value,
+                        ObjUpvalue::Closed(closed) => {
+                            *closed = value.clone();
+                            self.heap.write_barrier(upvalue, &value);
+                        }
+                    }
+                }
+                Instruction::Jump { target } => {
+                    // Only loops jump back; one on a single line reaches it
+                    // again.
+                    if (target as usize) < ip {
+                        self.debug_position = (0, 0);
+                    }
+                    ip = target as usize;
+                }
+                Instruction::JumpIfFalse { cond, target } => {
+                    if Self::is_falsely(&reg!(cond)) {
+                        ip = target as usize;
+                    }
+                }
+                Instruction::JumpIfTrue { cond, target } => {
+                    if !Self::is_falsely(&reg!(cond)) {
+                        ip = target as usize;
+                    }
+                }
+                Instruction::Call { base: callee, args } => {
+                    let callee = base + callee as usize;
+                    save_ip!();
+                    // `call` finds the new frame's slots from `stack_top`.
+                    self.stack_top = callee + args as usize + 1;
+                    let depth = self.frames.len();
+                    let top = base + function.registers.max_registers;
+                    if let Err(message) = self
+                        .call_value(self.stack[callee].clone(), args as usize)
+                        .and_then(|()| self.finish_register_call(depth, callee, top))
+                    {
+                        return self.runtime_Error(&message);
+                    }
+                    load_frame!();
+                }
+                Instruction::Invoke {
+                    base: receiver,
+                    args,
+                    name,
+                    cache,
+                } => {
+                    let receiver = base + receiver as usize;
+                    let name = constant!(name).as_string();
+                    save_ip!();
+                    self.stack_top = receiver + args as usize + 1;
+                    let depth = self.frames.len();
+                    let top = base + function.registers.max_registers;
+                    if let Err(message) = self
+                        .invoke(&function.caches[cache as usize], name, args as usize)
+                        .and_then(|()| self.finish_register_call(depth, receiver, top))
+                    {
+                        return self.runtime_Error(&message);
+                    }
+                    load_frame!();
+                }
+                Instruction::TailCall { base: callee, args } => {
+                    let (callee, args) = (base + callee as usize, args as usize);
+                    save_ip!();
+                    let depth = self.frames.len();
+                    if let Err(message) = self
+                        .tail_call(callee, args, |vm| {
+                            vm.call_value(vm.peek(args).clone(), args)
+                        })
+                        .and_then(|()| self.finish_register_tail_call(depth))
+                    {
+                        return self.runtime_Error(&message);
+                    }
+                    // The host called a function that tail-called one that
+                    // pushed no frame.
+                    if self.frames.len() == self.base {
+                        return InterpretResult::INTERPRET_OK;
+                    }
+                    load_frame!();
+                }
+                Instruction::TailInvoke {
+                    base: receiver,
+                    args,
+                    name,
+                    cache,
+                } => {
+                    let (receiver, args) = (base + receiver as usize, args as usize);
+                    let name = constant!(name).as_string();
+                    let cache = &function.caches[cache as usize];
+                    save_ip!();
+                    let depth = self.frames.len();
+                    if let Err(message) = self
+                        .tail_call(receiver, args, |vm| vm.invoke(cache, name, args))
+                        .and_then(|()| self.finish_register_tail_call(depth))
+                    {
+                        return self.runtime_Error(&message);
+                    }
+                    if self.frames.len() == self.base {
+                        return InterpretResult::INTERPRET_OK;
+                    }
+                    load_frame!();
+                }
+                Instruction::Class { dst, name } => {
+                    let name = constant!(name).as_string();
+                    reg!(dst) = self.new_class(name);
+                    self.maybe_collect();
+                }
+                Instruction::Method {
+                    class,
+                    method,
+                    name,
+                } => {
+                    let name = constant!(name).as_string();
+                    self.define_method(reg!(class).clone(), name, reg!(method).clone());
+                }
+                Instruction::GetProperty {
+                    dst,
+                    object,
+                    name,
+                    cache,
+                } => {
+                    let name = constant!(name).as_string();
+                    let cache = &function.caches[cache as usize];
+                    let (object, dst) = (base + object as usize, base + dst as usize);
+                    if let Err(message) = self.get_property(cache, name, object, dst) {
+                        runtime_error!(&message);
+                    }
+                }
+                Instruction::SetProperty {
+                    object,
+                    src,
+                    name,
+                    cache,
+                } => {
+                    let name = constant!(name).as_string();
+                    let cache = &function.caches[cache as usize];
+                    let (object, value) = (reg!(object).clone(), reg!(src).clone());
+                    if let Err(message) = self.set_property(cache, name, object, value) {
+                        runtime_error!(&message);
+                    }
+                }
+                Instruction::Closure {
+                    dst,
+                    function: index,
+                } => {
+                    let ValueType::VAL_FUNCTION(inner) = constant!(index).type_v() else {
+                        unreachable!("Closure operand must be a function");
+                    };
+                    let mut upvalues = Vec::with_capacity(inner.upvalue_count);
+                    for capture in &inner.registers.captures {
+                        let index = capture.index as usize;
+                        if capture.is_local {
+                            upvalues.push(self.capture_upvalue(base + index));
+                        } else {
+                            upvalues.push(self.heap.get(closure).upvalues[index]);
+                        }
+                    }
+                    let closure = self.heap.alloc(ObjClosure {
+                        function: inner,
+                        upvalues,
+                    });
+                    reg!(dst) = Value::from(ValueType::VAL_CLOSURE(closure));
+                    self.maybe_collect();
+                }
+                Instruction::CloseUpvalues { from } => self.close_upvalues(base + from as usize),
+                Instruction::Print { src } => {
+                    if let Err(error) =
+                        function
+                            .chunk
+                            .printValue(&reg!(src), &self.heap, &mut self.stdout)
+                    {
+                        runtime_error!(&format!("Could not print: {}.", error));
+                    }
+                }
+                Instruction::Return { src } => {
+                    let result = reg!(src).clone();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    if self.frames.len() == self.base {
+                        self.stack_top = frame.slots;
+                        self.push(result);
+                        return InterpretResult::INTERPRET_OK;
+                    }
+                    // The result replaces the callee in the caller's register.
+                    self.stack[frame.slots] = result;
+                    load_frame!();
+                    // Caller temporaries above the callee's window went
+                    // unscanned while it ran; they are dead, so drop them
+                    // before they count as roots again.
+                    let top = base + function.registers.max_registers;
+                    let callee_top = frame.slots + frame.function.registers.max_registers;
+                    if callee_top < top {
+                        self.stack[callee_top..top].fill(Value::nil_value());
+                    }
+                    self.stack_top = top;
+                }
+            }
+        }
+    }
+
+    /// Completes a call made with the stack depth at `depth`. A class without
+    /// `init` leaves its instance in the `callee` register without pushing a
+    /// frame; the caller's registers are restored around it instead, with
+    /// the dead temporaries above cleared since the collector did not see
+    /// them while `stack_top` was lowered.
+    fn finish_register_call(
+        &mut self,
+        depth: usize,
+        callee: usize,
+        top: usize,
+    ) -> Result<(), String> {
+        if self.frames.len() > depth {
+            return self.enter_register_frame();
+        }
+        self.stack[callee + 1..top].fill(Value::nil_value());
+        self.stack_top = top;
+        Ok(())
+    }
+
+    /// Completes a tail call made with the stack depth at `depth`. A callee
+    /// that pushed no frame left its result in the register the replaced
+    /// frame started at, and the caller's registers come back as after
+    /// `Return`.
+    fn finish_register_tail_call(&mut self, depth: usize) -> Result<(), String> {
+        if self.frames.len() == depth {
+            return self.enter_register_frame();
+        }
+        if self.frames.len() == self.base {
+            return Ok(());
+        }
+        let caller = self.frames.last().unwrap();
+        let result = self.stack_top - 1;
+        let top = caller.slots + caller.function.registers.max_registers;
+        self.stack[result + 1..top].fill(Value::nil_value());
+        self.stack_top = top;
+        Ok(())
+    }
+
+    /// Sizes the frame `call` just pushed for its registers, clearing any
+    /// stale values above the arguments so they are not kept alive.
+    pub(super) fn enter_register_frame(&mut self) -> Result<(), String> {
+        let frame = self.frames.last().unwrap();
+        if frame.function.registers.is_empty() {
+            return Err("Can only call functions compiled for the register backend.".to_string());
+        }
+        let top = frame.slots + frame.function.frame_size();
+        if top > STACK_MAX {
+            return Err("Stack overflow.".to_string());
+        }
+        let start = self.stack_top.min(top);
+        self.stack[start..top].fill(Value::nil_value());
+        self.stack_top = top;
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+//! Checks the C API from C: `include/nlox.h` must match what cbindgen
+//! generates from `src/capi.rs`, and `examples/c/embed.c` must build
+//! against it with the system `cc` and print what it expects.
+//!
+//! Run with `NLOX_BLESS=1` to rewrite the header after changing the API.
+
+use std::{env, fs, path::Path, process::Command};
+
+const HEADER: &str = "include/nlox.h";
+
+fn generate_header() -> String {
+    let config = cbindgen::Config {
+        language: cbindgen::Language::C,
+        cpp_compat: true,
+        usize_is_size_t: true,
+        include_guard: Some("NLOX_H".to_string()),
+        header: Some(
+            "/* The C API of the nlox interpreter. Generated from src/capi.rs; do not edit. */"
+                .to_string(),
+        ),
+        style: cbindgen::Style::Both,
+        enumeration: cbindgen::EnumConfig {
+            rename_variants: cbindgen::RenameRule::ScreamingSnakeCase,
+            prefix_with_name: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut header = vec![];
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(Path::new(env!("CARGO_MANIFEST_DIR")).join("src/capi.rs"))
+        .generate()
+        .unwrap()
+        .write(&mut header);
+    String::from_utf8(header).unwrap()
+}
+
+#[test]
+fn header_is_current() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(HEADER);
+    let generated = generate_header();
+    if env::var_os("NLOX_BLESS").is_some() {
+        fs::write(&path, generated).unwrap();
+        return;
+    }
+    let current = fs::read_to_string(&path).unwrap_or_default();
+    assert!(
+        current == generated,
+        "{} is out of date; rerun with NLOX_BLESS=1",
+        HEADER
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn c_example_runs() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // Tests link against the library, so its `cdylib` is among the
+    // binary's dependencies.
+    let lib_dir = Path::new(env!("CARGO_BIN_EXE_nlox"))
+        .parent()
+        .unwrap()
+        .join("deps");
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let example = out_dir.join("embed");
+    let status = Command::new("cc")
+        .arg(root.join("examples/c/embed.c"))
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg("-L")
+        .arg(&lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .args(["-lnlox", "-Wall", "-Werror", "-o"])
+        .arg(&example)
+        .status()
+        .unwrap();
+    assert!(status.success(), "cc failed");
+
+    let output = Command::new(&example).output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "greeting: hello C\n\
+         sum: 3\n\
+         runtime error: bad: add takes two numbers.\n\
+         Line[1] in script\n\
+         calls: 2\n"
+    );
+}
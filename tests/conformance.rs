@@ -0,0 +1,137 @@
+//! Runs every script in `tests/conformance` under each backend and checks
+//! its output against the `// expect: <line>` comments, in order. A script
+//! may end with `// expect runtime error: <message>`, which must be the
+//! first line on stderr and exit the interpreter with status 70. A
+//! `// backends: <name> ...` comment limits the script to those backends.
+
+use std::{fs, path::Path, process::Command};
+
+const BACKENDS: [&str; 2] = ["stack", "register"];
+
+struct Expectation {
+    output: Vec<String>,
+    runtime_error: Option<String>,
+    backends: Vec<String>,
+}
+
+fn expectation(source: &str) -> Expectation {
+    let mut expected = Expectation {
+        output: vec![],
+        runtime_error: None,
+        backends: BACKENDS.map(String::from).to_vec(),
+    };
+    for line in source.lines() {
+        if let Some((_, output)) = line.split_once("// expect: ") {
+            expected.output.push(output.to_string());
+        } else if let Some((_, message)) = line.split_once("// expect runtime error: ") {
+            expected.runtime_error = Some(message.to_string());
+        } else if let Some((_, backends)) = line.split_once("// backends: ") {
+            expected.backends = backends.split_whitespace().map(String::from).collect();
+        }
+    }
+    expected
+}
+
+fn run(path: &Path, args: &[&str]) -> Result<(), String> {
+    let source = fs::read_to_string(path).unwrap();
+    let expected = expectation(&source);
+    let output = Command::new(env!("CARGO_BIN_EXE_nlox"))
+        .args(args)
+        .arg(path)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let actual: Vec<&str> = stdout.lines().collect();
+    if actual != expected.output {
+        return Err(format!("expected {:?}, got {:?}", expected.output, actual));
+    }
+    match expected.runtime_error {
+        Some(message) => {
+            if stderr.lines().next() != Some(message.as_str()) {
+                return Err(format!("expected error {:?}, got {:?}", message, stderr));
+            }
+            if output.status.code() != Some(70) {
+                return Err(format!("expected exit status 70, got {}", output.status));
+            }
+        }
+        None if !output.status.success() => {
+            return Err(format!("exited with {}: {}", output.status, stderr));
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+fn scripts() -> Vec<std::path::PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/conformance");
+    let mut scripts: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "nox"))
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+fn check(extra: &[&str]) {
+    let mut failures = vec![];
+    for script in scripts() {
+        let backends = expectation(&fs::read_to_string(&script).unwrap()).backends;
+        for backend in backends {
+            let flag = format!("--backend={}", backend);
+            let mut args = vec![flag.as_str()];
+            args.extend(extra);
+            if let Err(message) = run(&script, &args) {
+                failures.push(format!("{} {:?}: {}", script.display(), args, message));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn conformance() {
+    check(&[]);
+}
+
+#[test]
+fn conformance_unoptimized() {
+    check(&["-O0"]);
+}
+
+#[test]
+fn conformance_fully_optimized() {
+    check(&["-O2"]);
+}
+
+#[test]
+fn conformance_under_stress_gc() {
+    check(&["--stress-gc"]);
+}
+
+#[test]
+fn tail_call_traces_mark_elided_frames() {
+    let script =
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/conformance/tail_call_error.nox");
+    for backend in BACKENDS {
+        let output = Command::new(env!("CARGO_BIN_EXE_nlox"))
+            .arg(format!("--backend={}", backend))
+            .arg(&script)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let trace: Vec<&str> = stderr.lines().collect();
+        assert_eq!(
+            trace,
+            [
+                "Undefined variable 'missing'.",
+                "Line[2] in down()",
+                "... 3 frames elided by tail calls",
+                "Line[5] in script",
+            ],
+            "{}",
+            backend
+        );
+    }
+}
@@ -0,0 +1,373 @@
+//! Drives `nlox dap` the way an editor would: every request of a session
+//! is written up front, then the responses and events are read back.
+
+use std::{
+    fs,
+    io::{Read, Write},
+    process::{Command, Stdio},
+};
+
+use serde_json::{Value, json};
+
+const SCRIPT: &str = "fun add(a, b) {
+  var sum = a + b;
+  return sum;
+}
+var total = 0;
+for (var i = 0; i < 3; i = i + 1) {
+  total = add(total, i);
+}
+print(total);
+";
+
+/// Runs a session made of `requests`, and returns what the server sent.
+fn session(requests: &[Value]) -> Vec<Value> {
+    let bodies: Vec<_> = requests
+        .iter()
+        .enumerate()
+        .map(|(seq, request)| {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            request.to_string()
+        })
+        .collect();
+    raw_session(&bodies)
+}
+
+/// Runs a session made of messages with the given `bodies`, which need not
+/// be well formed.
+fn raw_session(bodies: &[String]) -> Vec<Value> {
+    let wire: String = bodies
+        .iter()
+        .map(|body| format!("Content-Length: {}\r\n\r\n{}", body.len(), body))
+        .collect();
+    wire_session(&wire)
+}
+
+/// Writes `wire` to the server as is, framing included.
+fn wire_session(wire: &str) -> Vec<Value> {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_nlox"))
+        .arg("dap")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut input = server.stdin.take().unwrap();
+    input.write_all(wire.as_bytes()).unwrap();
+    drop(input);
+    let mut output = String::new();
+    server
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut output)
+        .unwrap();
+    assert!(server.wait().unwrap().success());
+
+    let mut messages = vec![];
+    let mut rest = output.as_str();
+    while let Some(header) = rest.strip_prefix("Content-Length: ") {
+        let (length, body) = header.split_once("\r\n\r\n").unwrap();
+        let (message, next) = body.split_at(length.parse().unwrap());
+        messages.push(serde_json::from_str(message).unwrap());
+        rest = next;
+    }
+    assert_eq!(rest, "");
+    messages
+}
+
+/// The body of the response to `command`, the `nth` one if it was sent
+/// more than once.
+fn response<'a>(messages: &'a [Value], command: &str, nth: usize) -> &'a Value {
+    let response = messages
+        .iter()
+        .filter(|message| message["type"] == "response" && message["command"] == command)
+        .nth(nth)
+        .unwrap();
+    assert_eq!(response["success"], true, "{}", response);
+    &response["body"]
+}
+
+fn events(messages: &[Value]) -> Vec<(String, Value)> {
+    messages
+        .iter()
+        .filter(|message| message["type"] == "event")
+        .map(|event| {
+            let name = event["event"].as_str().unwrap().to_string();
+            (name, event["body"].clone())
+        })
+        .collect()
+}
+
+fn script(name: &str) -> String {
+    script_with(name, SCRIPT)
+}
+
+fn script_with(name: &str, source: &str) -> String {
+    let path = format!("{}/{}", env!("CARGO_TARGET_TMPDIR"), name);
+    fs::write(&path, source).unwrap();
+    path
+}
+
+/// The names and values a `variables` response lists.
+fn variables(body: &Value) -> Vec<(&str, &str)> {
+    body["variables"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|variable| {
+            let name = variable["name"].as_str().unwrap();
+            (name, variable["value"].as_str().unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn debugs_a_script() {
+    let program = script("dap_add.nox");
+    let messages = session(&[
+        json!({ "command": "initialize", "arguments": { "adapterID": "nox" } }),
+        json!({ "command": "launch", "arguments": { "program": program } }),
+        json!({
+            "command": "setBreakpoints",
+            "arguments": {
+                "source": { "path": program },
+                "breakpoints": [{ "line": 2 }, { "line": 40 }],
+            },
+        }),
+        json!({ "command": "configurationDone" }),
+        // Stopped at the breakpoint in add.
+        json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+        json!({ "command": "scopes", "arguments": { "frameId": 2 } }),
+        json!({ "command": "variables", "arguments": { "variablesReference": 3 } }),
+        json!({ "command": "evaluate", "arguments": { "expression": "a + b * 10", "frameId": 1 } }),
+        json!({ "command": "evaluate", "arguments": { "expression": "nope(", "frameId": 1 } }),
+        json!({ "command": "next", "arguments": { "threadId": 1 } }),
+        json!({ "command": "variables", "arguments": { "variablesReference": 2 } }),
+        json!({
+            "command": "setBreakpoints",
+            "arguments": { "source": { "path": program }, "breakpoints": [] },
+        }),
+        json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        json!({ "command": "disconnect" }),
+    ]);
+
+    let capabilities = response(&messages, "initialize", 0);
+    assert_eq!(capabilities["supportsConfigurationDoneRequest"], true);
+    assert_eq!(
+        response(&messages, "setBreakpoints", 0)["breakpoints"],
+        json!([{ "verified": true, "line": 2 }, { "verified": false, "line": 40 }])
+    );
+    assert_eq!(
+        response(&messages, "stackTrace", 0)["stackFrames"],
+        json!([
+            { "id": 1, "name": "add", "line": 2, "column": 1, "source": { "path": program } },
+            { "id": 2, "name": "script", "line": 7, "column": 1, "source": { "path": program } },
+        ])
+    );
+    let scopes = &response(&messages, "scopes", 0)["scopes"];
+    assert_eq!(scopes[0]["variablesReference"], 3);
+    assert_eq!(scopes[1]["variablesReference"], 1);
+    assert_eq!(
+        response(&messages, "variables", 0)["variables"],
+        json!([{ "name": "i", "value": "0", "type": "number", "variablesReference": 0 }])
+    );
+    assert_eq!(response(&messages, "evaluate", 0)["result"], "0");
+    let failed = messages
+        .iter()
+        .find(|message| message["command"] == "evaluate" && message["success"] == false)
+        .unwrap();
+    assert_eq!(
+        failed["message"],
+        "eval: [Line 1] Error at ';': Expected expression"
+    );
+    let locals = &response(&messages, "variables", 1)["variables"];
+    let names: Vec<_> = locals
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|local| local["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["a", "b", "sum"]);
+
+    let events = events(&messages);
+    let names: Vec<_> = events.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "initialized",
+            "stopped",
+            "stopped",
+            "output",
+            "exited",
+            "terminated"
+        ]
+    );
+    assert_eq!(events[1].1["reason"], "breakpoint");
+    assert_eq!(events[2].1["reason"], "step");
+    assert_eq!(
+        events[3].1,
+        json!({ "category": "stdout", "output": "3\n" })
+    );
+    assert_eq!(events[4].1, json!({ "exitCode": 0 }));
+}
+
+#[test]
+fn evaluates_with_the_receiver_and_captured_variables() {
+    let program = script_with(
+        "dap_closures.nox",
+        "class P {
+  init(x) { this.x = x; }
+  show(y) {
+    return this.x + y;
+  }
+}
+fun counter() {
+  var count = 0;
+  fun inc() {
+    count = count + 1;
+    return count;
+  }
+  return inc;
+}
+P(5).show(2);
+counter()();
+",
+    );
+    let messages = session(&[
+        json!({ "command": "initialize", "arguments": { "adapterID": "nox" } }),
+        json!({ "command": "launch", "arguments": { "program": program } }),
+        json!({
+            "command": "setBreakpoints",
+            "arguments": {
+                "source": { "path": program },
+                "breakpoints": [{ "line": 4 }, { "line": 10 }],
+            },
+        }),
+        json!({ "command": "configurationDone" }),
+        // Stopped in the method.
+        json!({ "command": "variables", "arguments": { "variablesReference": 2 } }),
+        json!({ "command": "evaluate", "arguments": { "expression": "this.x * y", "frameId": 1 } }),
+        json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        // Stopped in the closure.
+        json!({ "command": "variables", "arguments": { "variablesReference": 2 } }),
+        json!({ "command": "evaluate", "arguments": { "expression": "count + 1", "frameId": 1 } }),
+        json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        json!({ "command": "disconnect" }),
+    ]);
+
+    assert_eq!(
+        variables(response(&messages, "variables", 0)),
+        [("this", "P instance"), ("y", "2")]
+    );
+    assert_eq!(response(&messages, "evaluate", 0)["result"], "10");
+    assert_eq!(
+        variables(response(&messages, "variables", 1)),
+        [("count", "0")]
+    );
+    assert_eq!(response(&messages, "evaluate", 1)["result"], "1");
+}
+
+#[test]
+fn disconnecting_ends_the_script() {
+    let program = script("dap_entry.nox");
+    let messages = session(&[
+        json!({ "command": "initialize", "arguments": { "adapterID": "nox" } }),
+        json!({ "command": "launch", "arguments": { "program": program, "stopOnEntry": true } }),
+        json!({ "command": "configurationDone" }),
+        json!({ "command": "stepIn", "arguments": { "threadId": 1 } }),
+        json!({ "command": "threads" }),
+        json!({ "command": "disconnect" }),
+    ]);
+    let events = events(&messages);
+    let stops: Vec<_> = events
+        .iter()
+        .filter(|(name, _)| name == "stopped")
+        .map(|(_, body)| body["reason"].as_str().unwrap())
+        .collect();
+    assert_eq!(stops, ["entry", "step"]);
+    assert!(!events.iter().any(|(name, _)| name == "output"));
+    assert_eq!(
+        response(&messages, "threads", 0)["threads"],
+        json!([{ "id": 1, "name": "main" }])
+    );
+    response(&messages, "disconnect", 0);
+}
+
+#[test]
+fn breakpoints_belong_to_their_source() {
+    let program = script("dap_sources.nox");
+    let messages = session(&[
+        json!({ "command": "initialize", "arguments": { "adapterID": "nox" } }),
+        // Before the launch, any source's lines are taken on trust.
+        json!({
+            "command": "setBreakpoints",
+            "arguments": { "source": { "path": "other.nox" }, "breakpoints": [{ "line": 2 }] },
+        }),
+        json!({ "command": "launch", "arguments": { "program": program } }),
+        json!({ "command": "configurationDone" }),
+        json!({ "command": "disconnect" }),
+    ]);
+    assert_eq!(
+        response(&messages, "setBreakpoints", 0)["breakpoints"],
+        json!([{ "verified": true, "line": 2 }])
+    );
+    // Line 2 of the launched script runs, but nothing stops it.
+    let events = events(&messages);
+    assert!(!events.iter().any(|(name, _)| name == "stopped"));
+    assert!(events.iter().any(|(name, _)| name == "terminated"));
+}
+
+#[test]
+fn malformed_messages_are_answered_and_skipped() {
+    let messages = raw_session(&[
+        "{ not json".to_string(),
+        json!({ "seq": 2, "type": "request", "command": "threads" }).to_string(),
+    ]);
+    assert_eq!(messages[0]["success"], false);
+    assert_eq!(messages[0]["request_seq"], 0);
+    assert!(
+        messages[0]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Malformed message: ")
+    );
+    assert_eq!(
+        response(&messages, "threads", 0)["threads"],
+        json!([{ "id": 1, "name": "main" }])
+    );
+}
+
+#[test]
+fn messages_without_a_valid_length_are_answered_and_skipped() {
+    let threads = json!({ "seq": 2, "type": "request", "command": "threads" }).to_string();
+    let wire = format!(
+        "Content-Length: abc\r\n\r\n{{}}Content-Length: {}\r\n\r\n{}",
+        threads.len(),
+        threads
+    );
+    let messages = wire_session(&wire);
+    assert_eq!(messages[0]["success"], false);
+    assert_eq!(
+        messages[0]["message"],
+        "Malformed message: missing or invalid Content-Length."
+    );
+    assert_eq!(
+        response(&messages, "threads", 0)["threads"],
+        json!([{ "id": 1, "name": "main" }])
+    );
+}
+
+#[test]
+fn oversized_messages_are_answered_not_allocated() {
+    let messages = wire_session("Content-Length: 99999999999999\r\n\r\n{}");
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0]["success"], false);
+    assert!(
+        messages[0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("over the limit")
+    );
+}
@@ -0,0 +1,126 @@
+//! Differential tests for the baseline JIT: every conformance script, plus a
+//! few that keep the templates busy, must print, fail and count
+//! instructions exactly as the interpreter alone does.
+#![cfg(feature = "jit")]
+
+use std::{fs, path::Path, process::Command};
+
+/// Scripts whose hot functions go through every template and guard.
+const HOT: [&str; 3] = [
+    "
+    fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+    print(fib(20));
+    ",
+    "
+    fun mix(n) {
+        var total = 0;
+        for (var i = 0; i < n; i = i + 1) {
+            if (i == 3 or !(i <= 7) and i != 9) total = total - i / 4;
+            else total = total * 1.5 + -i;
+            if (total > 1000000) total = total / (0 / 0);
+            if (total >= 0 == (0 / 0 < 1)) total = 0;
+        }
+        return total;
+    }
+    for (var i = 0; i < 30; i = i + 1) print(mix(i));
+    print(true == !nil);
+    ",
+    "
+    fun add(a, b) { return a + b; }
+    for (var i = 0; i < 5; i = i + 1) print(add(i, 1));
+    print(add(\"a\", \"b\"));
+    print(add(\"a\", 1));
+    ",
+];
+
+/// A script that makes `g` hot and then calls it below `depth` frames of
+/// `f`, which between them nearly fill the value stack: 60 leave just room
+/// for `g`'s frame and 61 do not.
+fn near_stack_limit(depth: usize) -> String {
+    let locals = |count: usize| {
+        (0..count)
+            .map(|i| format!("var l{} = n;", i))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let sum = |count: usize, innermost: &str| {
+        let terms: String = (0..count).map(|i| format!("(l{} + ", i)).collect();
+        format!("{}{}{}", terms, innermost, ")".repeat(count))
+    };
+    format!(
+        "fun g(n) {{ {} return {}; }}
+        for (var i = 0; i < 200; i = i + 1) g(i);
+        fun f(n) {{ if (n < 1) return g(n); {} return {}; }}
+        print(f({}));",
+        locals(200),
+        sum(200, "0"),
+        locals(250),
+        sum(12, "f(n - 1)"),
+        depth
+    )
+}
+
+fn run(path: &Path, jit: &str) -> (String, String, Option<i32>) {
+    let output = Command::new(env!("CARGO_BIN_EXE_nlox"))
+        .args([jit, "--instruction-count"])
+        .arg(path)
+        .output()
+        .unwrap();
+    (
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+        output.status.code(),
+    )
+}
+
+fn check(path: &Path) -> Result<(), String> {
+    let interpreted = run(path, "--no-jit");
+    let compiled = run(path, "--jit-threshold=1");
+    if interpreted != compiled {
+        return Err(format!(
+            "interpreter gave {:?}, JIT gave {:?}",
+            interpreted, compiled
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn jit_matches_the_interpreter() {
+    let conformance = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/conformance");
+    let mut scripts: Vec<_> = fs::read_dir(conformance)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "nox"))
+        .collect();
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    for (index, source) in HOT.iter().enumerate() {
+        let path = dir.join(format!("jit_hot_{}.nox", index));
+        fs::write(&path, source).unwrap();
+        scripts.push(path);
+    }
+
+    let mut failures = vec![];
+    for script in scripts {
+        if let Err(message) = check(&script) {
+            failures.push(format!("{}: {}", script.display(), message));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn jit_checks_the_stack_limit() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    for (depth, expected) in [(60, "21960"), (61, "Stack overflow.")] {
+        let path = dir.join(format!("jit_stack_limit_{}.nox", depth));
+        fs::write(&path, near_stack_limit(depth)).unwrap();
+        check(&path).unwrap();
+        let (stdout, stderr, _) = run(&path, "--jit-threshold=1");
+        let first = stdout
+            .lines()
+            .chain(stderr.lines())
+            .find(|line| !line.contains("Warning"));
+        assert_eq!(first, Some(expected), "depth {}", depth);
+    }
+}
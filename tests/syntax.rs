@@ -0,0 +1,30 @@
+//! The parsers as a tool outside the crate sees them.
+
+use nlox::{
+    ast::{self, Expr, Stmt},
+    cst::{self, Kind, NodeKind},
+};
+
+#[test]
+fn cst_keeps_the_source_and_its_errors() {
+    let source = "var a = 1; // one\nprint(a +);";
+    let parse = cst::parse(source);
+    assert_eq!(parse.root.text(), source);
+    assert_eq!(parse.root.child_node(0).kind, NodeKind::VarDecl);
+    assert_eq!(parse.errors.len(), 1);
+    assert_eq!(parse.errors[0].kind, Kind::RightParen);
+}
+
+#[test]
+fn ast_gives_typed_statements() {
+    let program = ast::parse("var a = 1; print(a);").unwrap();
+    assert!(matches!(&program.body[0], Stmt::Var { name, .. } if name.name == "a"));
+    assert!(matches!(
+        &program.body[1],
+        Stmt::Print {
+            expr: Expr::Variable { .. },
+            ..
+        }
+    ));
+    assert!(ast::parse("print(;").is_err());
+}